@@ -0,0 +1,123 @@
+use crate::{Config, Devices};
+use serde_json::{Value, json};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use vkb_sender_core::state::WireState;
+use vkb_sender_core::state::{DeviceState, MAX_BUTTONS, button_bitpos};
+
+// JSON for dashboards and Stream Deck plugins, served next to /metrics. None
+// for a path it doesn't have
+pub fn get(path: &str, devices: &Devices, config: &Mutex<Config>) -> Option<Value> {
+    // Query strings aren't used
+    let path = path.split('?').next().unwrap_or_default();
+    let config = config.lock().unwrap().clone();
+    match path {
+        "/status" => Some(status(devices, &config)),
+        "/devices" => Some(device_list(devices)),
+        "/profile" => Some(profile(&config)),
+        _ => None,
+    }
+}
+
+// Running or paused, where to, the profile and how each dest is doing
+pub fn status(devices: &Devices, config: &Config) -> Value {
+    let total = devices.states.lock().unwrap().len();
+    let stale = devices.stale.lock().unwrap();
+    let waiting = stale
+        .iter()
+        .map(|(k, _)| *k)
+        .collect::<BTreeSet<u8>>()
+        .len();
+    let links: Vec<Value> = devices
+        .metrics
+        .link_counts()
+        .into_iter()
+        .map(|(dest, datagrams, errors)| {
+            json!({ "dest": dest, "datagrams_sent": datagrams, "send_errors": errors })
+        })
+        .collect();
+    json!({
+        "paused": devices.paused.load(Ordering::Relaxed),
+        "send": config.send && !config.dry_run,
+        "send_hz": config.send_hz,
+        "active_profile": config.active_profile,
+        "devices": total,
+        "devices_connected": total - waiting,
+        "links": links,
+    })
+}
+
+// Each vjoy device: its sources, counters, and controls as read and as sent
+// (before and after transforms, axes 0..=32768)
+pub fn device_list(devices: &Devices) -> Value {
+    let states: HashMap<u8, DeviceState> = devices.states.lock().unwrap().clone();
+    let sent = devices.sent.lock().unwrap().clone();
+    let infos = devices.device_infos.read().unwrap();
+    let stale = devices.stale.lock().unwrap();
+    let mut ids: Vec<u8> = states.keys().copied().collect();
+    ids.sort();
+
+    let list: Vec<Value> = ids
+        .into_iter()
+        .map(|k| {
+            let st = &states[&k];
+            let mut sources: Vec<Value> = infos
+                .get(&k)
+                .into_iter()
+                .flatten()
+                .map(|info| {
+                    json!({
+                        "name": info.source,
+                        "device": info.name,
+                        "path": info.path,
+                        "connected": !stale.contains(&(k, info.source.clone())),
+                    })
+                })
+                .collect();
+            // Never opened yet
+            for (_, name) in stale.iter().filter(|(id, name)| {
+                *id == k
+                    && !infos
+                        .get(&k)
+                        .is_some_and(|i| i.iter().any(|i| i.source == *name))
+            }) {
+                sources.push(json!({ "name": name, "connected": false }));
+            }
+            let (events, packets) = devices.metrics.device_counts(k);
+            json!({
+                "id": k,
+                "enabled": st.enabled,
+                "sources": sources,
+                "events_read": events,
+                "packets_sent": packets,
+                "read": controls(&st.wire(), st.button_count),
+                "sent": sent.get(&k).map(|(wire, buttons)| controls(wire, *buttons)),
+            })
+        })
+        .collect();
+    Value::Array(list)
+}
+
+// buttons_on_wire is how many there are, the ones pressed are listed
+fn controls(wire: &WireState, buttons_on_wire: u16) -> Value {
+    let pressed: Vec<u16> = (1..=MAX_BUTTONS)
+        .filter(|b| {
+            let (byte_i, bit_i) = button_bitpos(*b);
+            wire.buttons[byte_i] & (1 << bit_i) != 0
+        })
+        .collect();
+    json!({
+        "axes": wire.axes,
+        "hat": [wire.hat_x, wire.hat_y],
+        "buttons": pressed,
+        "button_count": buttons_on_wire,
+    })
+}
+
+fn profile(config: &Config) -> Value {
+    json!({
+        "active": config.active_profile,
+        "profiles": config.profile.keys().collect::<Vec<_>>(),
+    })
+}
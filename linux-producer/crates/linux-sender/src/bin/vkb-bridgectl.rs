@@ -0,0 +1,51 @@
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use std::env;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+const SOCKET_NAME: &str = "vkb-bridge.sock";
+
+#[derive(Parser, Debug)]
+#[command(about = "Sends a command to a running linux-sender over its control_socket")]
+struct Args {
+    /// The bridge's control_socket, by default $XDG_RUNTIME_DIR/vkb-bridge.sock
+    #[arg(long, value_name = "PATH", env = "VKB_BRIDGE_SOCKET")]
+    socket: Option<PathBuf>,
+
+    /// status, pause, resume, enable <device id>, disable <device id>,
+    /// profile [<name> | none] or dump-config <path>
+    #[arg(required = true, trailing_var_arg = true)]
+    command: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let path = match args.socket {
+        Some(path) => path,
+        None => env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| PathBuf::from(dir).join(SOCKET_NAME))
+            .context("XDG_RUNTIME_DIR is not set, give --socket")?,
+    };
+
+    let mut stream = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "Could not connect to {} (bridge running, with control_socket set?)",
+            path.display()
+        )
+    })?;
+    writeln!(stream, "{}", args.command.join(" "))?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+
+    // Fails the script on what the bridge turned down
+    if let Some(e) = answer.strip_prefix("error: ") {
+        bail!("{}", e.trim_end());
+    }
+    print!("{}", answer);
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use crate::parse_key_code;
+use anyhow::{Context, Result};
+use evdev::KeyCode;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+use vkb_sender_core::state::MAX_BUTTONS;
+
+// Button numbers handed out to each source, kept in a file next to config.toml
+// so they stay put when a device's keys change. Keys seen for the first time
+// get numbers past the highest one handed out, numbers are never reused
+pub struct ButtonStore {
+    path: PathBuf,
+    // Source name -> evdev key name -> button
+    maps: Mutex<BTreeMap<String, BTreeMap<String, u16>>>,
+}
+
+impl ButtonStore {
+    pub fn open(path: &Path) -> Result<ButtonStore> {
+        let maps = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Ok(ButtonStore {
+            path: path.to_path_buf(),
+            maps: Mutex::new(maps),
+        })
+    }
+
+    // Replaces the numbers just derived for a device with the stored ones,
+    // storing what is new
+    pub fn apply(&self, source: &str, buttons: &mut HashMap<KeyCode, u16>) -> Result<()> {
+        let mut maps = self.maps.lock().unwrap();
+        let stored = maps.entry(source.to_string()).or_default();
+
+        // First time: the numbers as derived
+        if stored.is_empty() {
+            for (key, btn) in buttons.iter() {
+                stored.insert(key_name(*key), *btn);
+            }
+            return self.write(&maps);
+        }
+
+        let mut pinned: HashMap<KeyCode, u16> = HashMap::new();
+        for (name, btn) in stored.iter() {
+            let key = parse_key_code(name).with_context(|| self.path.display().to_string())?;
+            if buttons.contains_key(&key) {
+                pinned.insert(key, *btn);
+            }
+        }
+
+        // New keys, in the order they were derived in
+        let mut new: Vec<(u16, KeyCode)> = buttons
+            .iter()
+            .filter(|(key, _)| !pinned.contains_key(key))
+            .map(|(key, btn)| (*btn, *key))
+            .collect();
+        new.sort();
+        let mut next = stored.values().copied().max().unwrap_or(0);
+        let mut changed = false;
+        for (_, key) in new {
+            if next >= MAX_BUTTONS {
+                warn!(
+                    "{}: no button numbers left for {:?} in {}",
+                    source,
+                    key,
+                    self.path.display()
+                );
+                continue;
+            }
+            next += 1;
+            pinned.insert(key, next);
+            stored.insert(key_name(key), next);
+            changed = true;
+        }
+
+        *buttons = pinned;
+        if changed {
+            self.write(&maps)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, maps: &BTreeMap<String, BTreeMap<String, u16>>) -> Result<()> {
+        let text = format!(
+            "# Button numbers by source, written by linux-sender. Edit to renumber,\n\
+             # delete a source to number it afresh\n{}",
+            toml::to_string(maps)?
+        );
+        fs::write(&self.path, text)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+// Its name, or its code for keys evdev has no name for
+fn key_name(key: KeyCode) -> String {
+    let name = format!("{:?}", key);
+    match name.parse::<KeyCode>() {
+        Ok(_) => name,
+        Err(_) => format!("0x{:x}", key.code()),
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{Args, Config, transport};
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use vkb_sender_core::input::{self, Source};
+
+// Goes through what the bridge does at startup without starting it: the
+// config, every device it names (found, opened, has the axes and keys mapped),
+// /dev/uinput and the dests, one line each. Fails if any of them does
+pub fn run(args: &Args) -> Result<()> {
+    let config_path = crate::config_path(args)?;
+    let (config, sources) = match load(&config_path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("config {}: FAIL {:#}", config_path.display(), e);
+            bail!("The config does not load, nothing else checked");
+        }
+    };
+    println!("config {}: ok", config_path.display());
+
+    // The config was one
+    let mut checked = 1;
+    let mut failed = 0;
+    let mut report = |what: String, result: Result<String>| {
+        checked += 1;
+        match result {
+            Ok(found) => println!("{}: ok, {}", what, found),
+            Err(e) => {
+                failed += 1;
+                println!("{}: FAIL {:#}", what, e);
+            }
+        }
+    };
+
+    let claimed = Mutex::new(HashMap::new());
+    for source in sources.iter() {
+        report(
+            format!("{} ({})", source.name, source.select),
+            check_source(source, &claimed),
+        );
+    }
+
+    if !config.uinput.is_empty() {
+        let opened = OpenOptions::new()
+            .write(true)
+            .open("/dev/uinput")
+            .map(|_| "writable".to_string())
+            .context("Could not open it (uinput module loaded? permissions, see setup-udev)");
+        report("/dev/uinput".to_string(), opened);
+    }
+
+    if config.send {
+        for dest in config.dest.iter().chain(&config.failover_dest) {
+            report(format!("dest {}", dest), check_dest(dest));
+        }
+    }
+
+    match failed {
+        0 => {
+            println!("All {} checks passed", checked);
+            Ok(())
+        }
+        _ => bail!("{} of {} checks failed", failed, checked),
+    }
+}
+
+fn load(config_path: &Path) -> Result<(Config, Vec<Source>)> {
+    let mut config = crate::parse(config_path)?;
+    if crate::is_zero_config(&config) {
+        config.vjoy_device = crate::detect_vkb_devices()?;
+    }
+    let sources = crate::build_sources(&config, config_path)?;
+    Ok((config, sources))
+}
+
+// Whether the bridge would find it and read it the way it is configured
+fn check_source(source: &Source, claimed: &Mutex<HashMap<PathBuf, String>>) -> Result<String> {
+    let (path, dev) = input::open_vkb_device(source, claimed).map_err(|e| {
+        // enumerate() leaves out the nodes it can't open
+        match unreadable_nodes() {
+            0 => e,
+            n => e.context(format!(
+                "{} event nodes could not be opened, check permissions (setup-udev)",
+                n
+            )),
+        }
+    })?;
+    let map = input::build_input_map(&dev, source)?;
+    let axes =
+        input::build_axis_ranges(&dev, &map, &source.axis).context("Reading the mapped axes")?;
+    Ok(format!(
+        "{} {} ({} axes, {} buttons)",
+        path.display(),
+        dev.name().unwrap_or("<no name>"),
+        axes.len(),
+        map.buttons.len()
+    ))
+}
+
+fn unreadable_nodes() -> usize {
+    let Ok(entries) = fs::read_dir("/dev/input") else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("event"))
+        .filter(|e| OpenOptions::new().read(true).open(e.path()).is_err())
+        .count()
+}
+
+fn check_dest(dest: &str) -> Result<String> {
+    if let Some(path) = dest.strip_prefix("unix:") {
+        UnixDatagram::unbound()?
+            .connect(Path::new(path))
+            .with_context(|| format!("Nothing is listening on {}", path))?;
+        return Ok("listening".to_string());
+    }
+    if dest.starts_with("vsock:") {
+        return Ok("not checked, connected to at startup".to_string());
+    }
+    let addr = transport::resolve_dest(dest)?;
+    Ok(format!("resolves to {}", addr))
+}
@@ -0,0 +1,196 @@
+use anyhow::{Context, Result, bail};
+use evdev::{AbsoluteAxisCode, KeyCode};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use vkb_sender_core::input::{AxisConfig, InputMap, Source};
+use vkb_sender_core::selector::Selector;
+use vkb_sender_core::state::MAX_BUTTONS;
+
+// Cockpit composition file: the physical devices on the desk and the logical
+// controls bound to them, e.g.
+//
+//   [sources.stick]
+//   vendor_id = 0x231d
+//   product_id = 0x0200
+//
+//   [controls.pitch]
+//   source = "stick"
+//   axis = "ABS_Y"
+//   device = 1
+//   vjoy_axis = 2
+#[derive(Debug, Deserialize)]
+struct Cockpit {
+    // Physical devices by name: vendor_id, product_id, name_contains, serial, path
+    sources: BTreeMap<String, Selector>,
+    controls: BTreeMap<String, Control>,
+}
+
+// One of axis + vjoy_axis, button + vjoy_button or hat = true
+#[derive(Debug, Deserialize)]
+struct Control {
+    source: String,
+    // vjoy device the control ends up on
+    device: u8,
+    // evdev axis name (ABS_Y) -> vJoy axis id (1..=8)
+    axis: Option<String>,
+    vjoy_axis: Option<u8>,
+    // evdev key name (BTN_TRIGGER) -> vJoy button (1..=256)
+    button: Option<String>,
+    vjoy_button: Option<u16>,
+    // ABS_HAT0X/ABS_HAT0Y -> the vJoy hat
+    #[serde(default)]
+    hat: bool,
+    // Axis controls only: invert and min/center/max, as in [vjoy_device.N.axis.A]
+    #[serde(flatten)]
+    axis_config: AxisConfig,
+}
+
+pub fn load(path: &Path) -> Result<Vec<Source>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read cockpit file {}", path.display()))?;
+    let cockpit: Cockpit = crate::decode(path, &text)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    compose(&cockpit)
+}
+
+// One source per (physical device, vjoy device) pair, carrying only the inputs
+// bound by controls
+fn compose(cockpit: &Cockpit) -> Result<Vec<Source>> {
+    let mut maps: BTreeMap<(&str, u8), InputMap> = BTreeMap::new();
+    let mut axis_configs: BTreeMap<(&str, u8), BTreeMap<u8, AxisConfig>> = BTreeMap::new();
+    // (vjoy device, target) -> control that claimed it
+    let mut claimed: HashMap<(u8, String), &str> = HashMap::new();
+
+    for (name, select) in cockpit.sources.iter() {
+        select
+            .validate()
+            .with_context(|| format!("sources.{}", name))?;
+    }
+
+    for (name, control) in cockpit.controls.iter() {
+        if !cockpit.sources.contains_key(&control.source) {
+            bail!("control {}: unknown source {}", name, control.source);
+        }
+        let map = maps
+            .entry((control.source.as_str(), control.device))
+            .or_default();
+
+        if control.axis_config.code.is_some() {
+            bail!("control {}: name the evdev axis with axis, not code", name);
+        }
+        if control.axis.is_none() && control.axis_config != AxisConfig::default() {
+            bail!(
+                "control {}: invert and calibration only apply to axis controls",
+                name
+            );
+        }
+
+        let target = match (
+            &control.axis,
+            control.vjoy_axis,
+            &control.button,
+            control.vjoy_button,
+            control.hat,
+        ) {
+            (Some(axis), Some(vjoy_axis), None, None, false) => {
+                let code: AbsoluteAxisCode = axis
+                    .parse()
+                    .ok()
+                    .with_context(|| format!("control {}: unknown axis {}", name, axis))?;
+                if !(1..=8).contains(&vjoy_axis) {
+                    bail!("control {}: vjoy_axis {} not in 1..=8", name, vjoy_axis);
+                }
+                if map.axes.insert(code, vjoy_axis as usize - 1).is_some() {
+                    bail!(
+                        "control {}: {} of {} is already bound on device {}",
+                        name,
+                        axis,
+                        control.source,
+                        control.device
+                    );
+                }
+                axis_configs
+                    .entry((control.source.as_str(), control.device))
+                    .or_default()
+                    .insert(vjoy_axis, control.axis_config.clone());
+                format!("axis {}", vjoy_axis)
+            }
+            (None, None, Some(button), Some(vjoy_button), false) => {
+                let code: KeyCode = button
+                    .parse()
+                    .ok()
+                    .with_context(|| format!("control {}: unknown button {}", name, button))?;
+                if !(1..=MAX_BUTTONS).contains(&vjoy_button) {
+                    bail!(
+                        "control {}: vjoy_button {} not in 1..={}",
+                        name,
+                        vjoy_button,
+                        MAX_BUTTONS
+                    );
+                }
+                if map.buttons.insert(code, vjoy_button).is_some() {
+                    bail!(
+                        "control {}: {} of {} is already bound on device {}",
+                        name,
+                        button,
+                        control.source,
+                        control.device
+                    );
+                }
+                format!("button {}", vjoy_button)
+            }
+            (None, None, None, None, true) => {
+                map.hat = true;
+                "hat".to_string()
+            }
+            _ => bail!(
+                "control {}: needs axis + vjoy_axis, button + vjoy_button or hat = true",
+                name
+            ),
+        };
+
+        if let Some(other) = claimed.insert((control.device, target.clone()), name) {
+            bail!(
+                "controls {} and {} are both bound to {} of device {}",
+                other,
+                name,
+                target,
+                control.device
+            );
+        }
+    }
+
+    Ok(maps
+        .into_iter()
+        .map(|((source, device_id), map)| {
+            let s = &cockpit.sources[source];
+            Source {
+                name: source.to_string(),
+                device_id,
+                select: s.clone(),
+                force_feedback: false,
+                keyboard: false,
+                allow_full_keyboard: false,
+                raw: false,
+                grab: false,
+                axis: axis_configs
+                    .remove(&(source, device_id))
+                    .unwrap_or_default(),
+                buttons: HashMap::new(),
+                button_offset: 0,
+                scan_codes: false,
+                hat_keys: None,
+                invert_buttons: HashSet::new(),
+                rel: HashMap::new(),
+                rel_pulse: Duration::ZERO,
+                map: Some(map),
+                default_axes: false,
+                hat: false,
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,193 @@
+use crate::{Config, Devices, PROFILE_NONE, Reload, effective, privs};
+use anyhow::{Context, Result, anyhow, bail};
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task;
+use tracing::{debug, info};
+use vkb_sender_core::state::Update;
+
+pub const HELP: &str = "commands: status, pause, resume, enable <device id>, disable <device id>, profile [<name> | none], dump-config <path>";
+
+// What the console and the control socket can ask of the running bridge
+#[derive(Clone)]
+pub struct Commands {
+    pub config: Arc<Mutex<Config>>,
+    pub devices: Devices,
+    pub reload: Sender<Reload>,
+}
+
+impl Commands {
+    // Does what line says. Some is an answer to show (status, the profiles),
+    // None an action done and logged
+    pub fn run(&self, line: &str) -> Result<Option<String>> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("status"), None) => Ok(Some(self.status())),
+            (Some("pause"), None) => {
+                self.pause(true);
+                Ok(None)
+            }
+            (Some("resume"), None) => {
+                self.pause(false);
+                Ok(None)
+            }
+            (Some("enable"), Some(id)) => self.enable(id, true).map(|()| None),
+            (Some("disable"), Some(id)) => self.enable(id, false).map(|()| None),
+            (Some("profile"), Some(name)) => {
+                crate::switch_profile(&self.config, name, &self.reload)
+                    .with_context(|| format!("profile {}", name))
+                    .map(|()| None)
+            }
+            (Some("profile"), None) => Ok(Some(profiles(&self.config.lock().unwrap()))),
+            (Some("dump-config"), Some(path)) => {
+                let path = Path::new(path);
+                let config = self.config.lock().unwrap().clone();
+                let device_infos = self.devices.device_infos.read().unwrap();
+                let states = self.devices.states.lock().unwrap().clone();
+                effective::write_effective_config(path, &config, &device_infos, &states)
+                    .context("dump-config failed")?;
+                info!("Wrote effective config to {}", path.display());
+                Ok(None)
+            }
+            _ => Err(anyhow!("{}", HELP)),
+        }
+    }
+
+    // Every device released and quiet until resumed, each one's own enabled
+    // left as it is
+    fn pause(&self, paused: bool) {
+        if self.devices.paused.swap(paused, Ordering::Relaxed) == paused {
+            return;
+        }
+        match paused {
+            true => info!("Paused, every device released"),
+            false => info!("Resumed"),
+        }
+    }
+
+    fn enable(&self, id: &str, enable: bool) -> Result<()> {
+        let states = self.devices.states.lock().unwrap();
+        let Some(k) = id.parse::<u8>().ok().filter(|k| states.contains_key(k)) else {
+            bail!("unknown device id: {}", id);
+        };
+        let _ = self.devices.updates.send((k, Update::Enable(enable)));
+        info!(
+            "device {} {}",
+            id,
+            if enable { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    // Paused or not, where to, the profile and each vjoy device
+    fn status(&self) -> String {
+        let config = self.config.lock().unwrap();
+        let mut lines = Vec::new();
+        let running = match self.devices.paused.load(Ordering::Relaxed) {
+            true => "paused",
+            false => "running",
+        };
+        let dest = match &*config {
+            config if config.dry_run => "stdout (dry run)".to_string(),
+            config if !config.send => "nowhere (send = false)".to_string(),
+            config => config.dest.join(", "),
+        };
+        lines.push(format!("{}, sending to {}", running, dest));
+        if !config.profile.is_empty() {
+            lines.push(profiles(&config));
+        }
+
+        let states = self.devices.states.lock().unwrap();
+        let stale = self.devices.stale.lock().unwrap();
+        let mut ids: Vec<u8> = states.keys().copied().collect();
+        ids.sort();
+        for k in ids {
+            let mut line = format!(
+                "vjoy device {}: {}",
+                k,
+                if states[&k].enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+            let missing: Vec<&str> = stale
+                .iter()
+                .filter(|(id, _)| *id == k)
+                .map(|(_, name)| name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                line.push_str(&format!(", waiting for {}", missing.join(", ")));
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+fn profiles(config: &Config) -> String {
+    let names: Vec<&str> = config.profile.keys().map(String::as_str).collect();
+    format!(
+        "profiles: {}, active: {}",
+        names.join(", "),
+        config.active_profile.as_deref().unwrap_or(PROFILE_NONE)
+    )
+}
+
+// Listens on path, replacing a socket left by a bridge that didn't get to
+// remove it. Only the user the bridge runs as (after dropping root) may connect
+pub fn bind(path: &Path, user: Option<&str>) -> Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("{} is in use, is another bridge running?", path.display());
+        }
+        fs::remove_file(path).with_context(|| format!("Could not remove {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Could not listen on {}", path.display()))?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    if let Some(user) = user
+        && unsafe { libc::geteuid() } == 0
+    {
+        let (_, uid, gid) = privs::lookup_user(user)?;
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))
+            .with_context(|| format!("Could not hand {} to {}", path.display(), user))?;
+    }
+    Ok(listener)
+}
+
+// A command per line, each answered with its output, ok, or error: and why
+pub async fn serve(listener: UnixListener, commands: Commands) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let commands = commands.clone();
+        task::spawn(async move {
+            if let Err(e) = answer(stream, &commands).await {
+                debug!("control connection failed: {:#}", e);
+            }
+        });
+    }
+}
+
+async fn answer(stream: UnixStream, commands: &Commands) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match commands.run(&line) {
+            Ok(Some(answer)) => answer,
+            Ok(None) => "ok".to_string(),
+            Err(e) => format!("error: {:#}", e),
+        };
+        write.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use crate::{Config, Devices, api};
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+
+// The page served at /, drawing what /ws sends
+pub const PAGE: &str = include_str!("dashboard.html");
+
+// How often the page gets the devices, about a display's frame rate
+const FRAME: Duration = Duration::from_millis(33);
+
+// What switches a GET /ws with this Sec-WebSocket-Key over to WebSocket
+pub fn upgrade(key: &str) -> String {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.trim().as_bytes())
+    )
+}
+
+// /status and /devices in one message every frame, until the page goes away
+pub async fn feed(stream: TcpStream, devices: Devices, config: Arc<Mutex<Config>>) -> Result<()> {
+    let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    let mut frames = time::interval(FRAME);
+    frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = frames.tick() => {
+                let config = config.lock().unwrap().clone();
+                let frame = json!({
+                    "status": api::status(&devices, &config),
+                    "devices": api::device_list(&devices),
+                });
+                ws.send(Message::text(frame.to_string())).await?;
+            }
+            // Pings are answered as they are read
+            incoming = ws.next() => match incoming {
+                None | Some(Ok(Message::Close(_))) => {
+                    let _ = ws.close(None).await;
+                    return Ok(());
+                }
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use evdev::{AbsoluteAxisCode, Device, KeyCode};
+use std::fmt::Write;
+use std::path::PathBuf;
+use vkb_sender_core::selector;
+
+pub const VKB_VENDOR_ID: u16 = 0x231d;
+
+// A joystick-class evdev device found on this machine
+#[derive(Debug)]
+pub struct Detected {
+    pub path: PathBuf,
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    // Among devices with the same vendor/product, in USB port order
+    pub index: usize,
+    pub axes: usize,
+    pub buttons: usize,
+}
+
+// Every device with a stick or gamepad axis or joystick buttons, in USB port
+// order so the same hardware comes out the same way every time
+pub fn joysticks() -> Vec<Detected> {
+    let mut found: Vec<(PathBuf, Device)> = evdev::enumerate()
+        .filter(|(_, dev)| is_joystick(dev))
+        .collect();
+    found.sort_by(|(a_path, a), (b_path, b)| {
+        (a.physical_path(), a_path).cmp(&(b.physical_path(), b_path))
+    });
+
+    let mut out: Vec<Detected> = Vec::new();
+    for (path, dev) in found {
+        let id = dev.input_id();
+        let index = out
+            .iter()
+            .filter(|d| d.vendor_id == id.vendor() && d.product_id == id.product())
+            .count();
+        out.push(Detected {
+            path,
+            name: dev.name().unwrap_or("<no name>").to_string(),
+            vendor_id: id.vendor(),
+            product_id: id.product(),
+            index,
+            axes: dev
+                .supported_absolute_axes()
+                .map_or(0, |a| a.iter().count()),
+            buttons: dev.supported_keys().map_or(0, |k| k.iter().count()),
+        });
+    }
+    out
+}
+
+// Every evdev device, joysticks first, as vjoy_device tables to paste into
+// config.toml with what it has in comments above each
+pub fn list_all() -> String {
+    let mut found: Vec<(PathBuf, Device)> = evdev::enumerate().collect();
+    found.sort_by_cached_key(|(path, dev)| {
+        (
+            !is_joystick(dev),
+            dev.physical_path().map(String::from),
+            path.clone(),
+        )
+    });
+    if found.is_empty() {
+        return "# No input devices found. Check permissions (/dev/input/event*)\n".to_string();
+    }
+
+    let mut out = String::from(
+        "# Every input device, joysticks first. Keep the ones to bridge, numbered\n\
+         # from 1 up to 16, and drop the lines not needed to tell them apart\n",
+    );
+    for (k, (path, dev)) in (1..).zip(found.iter()) {
+        let id = dev.input_id();
+        let name = dev.name().unwrap_or("<no name>");
+        let _ = writeln!(out, "\n# {}: {}", path.display(), name);
+
+        let axes: Vec<String> = match dev.get_absinfo() {
+            Ok(infos) => infos
+                .map(|(code, info)| format!("{:?} {}..{}", code, info.minimum(), info.maximum()))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        match axes.is_empty() {
+            true => out.push_str("# axes: none\n"),
+            false => {
+                let _ = writeln!(out, "# axes: {}", axes.join(", "));
+            }
+        }
+        let keys = dev.supported_keys().map_or(0, |k| k.iter().count());
+        let _ = writeln!(out, "# keys and buttons: {}", keys);
+
+        let _ = writeln!(out, "[vjoy_device.{}]", k);
+        let _ = writeln!(out, "vendor_id = 0x{:04x}", id.vendor());
+        let _ = writeln!(out, "product_id = 0x{:04x}", id.product());
+        let _ = writeln!(out, "name_contains = {:?}", name);
+        if let Some(serial) = selector::serial_of(path, dev) {
+            let _ = writeln!(out, "serial = {:?}", serial);
+        }
+    }
+    out
+}
+
+fn is_joystick(dev: &Device) -> bool {
+    let has_axis = dev.supported_absolute_axes().is_some_and(|axes| {
+        axes.contains(AbsoluteAxisCode::ABS_X) || axes.contains(AbsoluteAxisCode::ABS_THROTTLE)
+    });
+    // BTN_JOYSTICK..BTN_DIGI and the extra BTN_TRIGGER_HAPPY range; touchpads
+    // and tablets have ABS_X too but none of these
+    let has_buttons = dev.supported_keys().is_some_and(|keys| {
+        keys.iter().any(|k| {
+            (KeyCode::BTN_TRIGGER.code()..KeyCode::BTN_TOOL_PEN.code()).contains(&k.code())
+                || k.code() >= KeyCode::BTN_TRIGGER_HAPPY1.code()
+        })
+    });
+    has_axis && has_buttons
+}
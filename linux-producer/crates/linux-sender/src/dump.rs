@@ -0,0 +1,154 @@
+use std::fmt::Write;
+use std::io::{self, Write as _};
+use vkb_sender_core::protocol::{
+    HEADER_LEN, PKT_TYPE_BATCH, PKT_TYPE_RAW, PKT_TYPE_STATE, SECTION_STALE, STATE_PKT_LEN,
+    VERSION_WIDE,
+};
+
+// For --dry-run: each datagram as it would have gone out, what it says and then
+// its bytes in hex
+pub fn datagrams(datagrams: &[&[u8]]) -> io::Result<()> {
+    let mut out = String::new();
+    for datagram in datagrams {
+        describe(&mut out, datagram, "");
+        for line in datagram.chunks(32) {
+            out.push_str("    ");
+            for b in line {
+                let _ = write!(out, " {:02x}", b);
+            }
+            out.push('\n');
+        }
+    }
+    io::stdout().lock().write_all(out.as_bytes())
+}
+
+fn describe(out: &mut String, packet: &[u8], indent: &str) {
+    if packet.len() < HEADER_LEN {
+        let _ = writeln!(out, "{}short packet, {} bytes", indent, packet.len());
+        return;
+    }
+    let device_id = packet[5];
+    let seq = u16::from_le_bytes([packet[7], packet[8]]);
+    let _ = write!(
+        out,
+        "{}{} bytes, device {} seq {}: ",
+        indent,
+        packet.len(),
+        device_id,
+        seq
+    );
+    match packet[6] {
+        PKT_TYPE_STATE => state(out, packet),
+        PKT_TYPE_RAW => raw(out, packet),
+        PKT_TYPE_BATCH => {
+            out.push_str("batch\n");
+            let mut rest = &packet[HEADER_LEN..];
+            while rest.len() >= 2 {
+                let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+                let Some(inner) = rest.get(2..2 + len) else {
+                    let _ = writeln!(out, "{}  truncated", indent);
+                    return;
+                };
+                describe(out, inner, "  ");
+                rest = &rest[2 + len..];
+            }
+        }
+        other => {
+            let _ = writeln!(out, "packet type {}", other);
+        }
+    }
+}
+
+fn state(out: &mut String, packet: &[u8]) {
+    if packet.len() < STATE_PKT_LEN {
+        out.push_str("state, truncated\n");
+        return;
+    }
+    let axes: Vec<String> = packet[HEADER_LEN..HEADER_LEN + 16]
+        .chunks(2)
+        .map(|v| u16::from_le_bytes([v[0], v[1]]).to_string())
+        .collect();
+    let (hat_x, hat_y) = (packet[25] as i8, packet[26] as i8);
+
+    // v2: 16 bytes of buttons, wide: a length byte first
+    let (buttons, sections) = match packet[4] {
+        VERSION_WIDE => {
+            let n = packet[27] as usize;
+            match packet.get(28..28 + n) {
+                Some(buttons) => (buttons, &packet[28 + n..]),
+                None => {
+                    out.push_str("wide state, truncated\n");
+                    return;
+                }
+            }
+        }
+        _ => (&packet[27..43], &packet[43..]),
+    };
+    let pressed: Vec<String> = buttons
+        .iter()
+        .enumerate()
+        .flat_map(|(byte_i, b)| {
+            (0..8)
+                .filter(move |bit_i| b & (1 << bit_i) != 0)
+                .map(move |bit_i| (byte_i * 8 + bit_i + 1).to_string())
+        })
+        .collect();
+
+    let _ = write!(
+        out,
+        "state axes {} hat {},{} buttons [{}]",
+        axes.join(" "),
+        hat_x,
+        hat_y,
+        pressed.join(" ")
+    );
+
+    let mut rest = sections;
+    while rest.len() >= 3 {
+        let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+        match rest[0] {
+            SECTION_STALE => out.push_str(" stale"),
+            other => {
+                let _ = write!(out, " section {} ({} bytes)", other, len);
+            }
+        }
+        rest = rest.get(3 + len..).unwrap_or_default();
+    }
+    out.push('\n');
+}
+
+fn raw(out: &mut String, packet: &[u8]) {
+    // Per axis: code u16, value, min, max i32; per key: code u16, pressed u8
+    let body = &packet[HEADER_LEN..];
+    let axis_count = body.first().copied().unwrap_or(0) as usize;
+    let keys_at = 1 + axis_count * 14;
+    let (Some(axes), Some(&key_count)) = (body.get(1..keys_at), body.get(keys_at)) else {
+        out.push_str("raw, truncated\n");
+        return;
+    };
+    let Some(keys) = body.get(keys_at + 1..keys_at + 1 + key_count as usize * 3) else {
+        out.push_str("raw, truncated\n");
+        return;
+    };
+
+    let axes: Vec<String> = axes
+        .chunks(14)
+        .map(|a| {
+            let code = u16::from_le_bytes([a[0], a[1]]);
+            let value = i32::from_le_bytes([a[2], a[3], a[4], a[5]]);
+            format!("{:#x}={}", code, value)
+        })
+        .collect();
+    let pressed: Vec<String> = keys
+        .chunks(3)
+        .filter(|k| k[2] != 0)
+        .map(|k| format!("{:#x}", u16::from_le_bytes([k[0], k[1]])))
+        .collect();
+    let _ = writeln!(
+        out,
+        "raw axes {} keys pressed [{}] of {}",
+        axes.join(" "),
+        pressed.join(" "),
+        key_count
+    );
+}
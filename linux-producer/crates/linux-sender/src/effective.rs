@@ -0,0 +1,127 @@
+use crate::Config;
+use anyhow::{Context, Result, bail};
+use evdev::{AbsoluteAxisCode, KeyCode};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use vkb_sender_core::state::{AxisRange, DeviceState};
+
+// Everything derived from a device when it was opened
+#[derive(Debug)]
+pub struct DeviceInfo {
+    pub source: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub button_map: HashMap<KeyCode, u16>,
+    // (slot, axis, range) by slot
+    pub axes: Vec<(usize, AbsoluteAxisCode, AxisRange)>,
+}
+
+// What was derived from a device, added to its section of the dump
+#[derive(Serialize)]
+struct DerivedDevice {
+    name: String,
+    path: PathBuf,
+    axes: Vec<EffectiveAxis>,
+    // evdev key name -> wire button number
+    buttons: BTreeMap<String, u16>,
+}
+
+#[derive(Serialize)]
+struct EffectiveAxis {
+    slot: usize,
+    code: String,
+    min: i32,
+    max: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    center: Option<i32>,
+}
+
+// Writes the configuration the sender is actually running with: the parsed config
+// with runtime state applied, plus what was derived from the devices (button
+// numbering, calibration)
+pub fn write_effective_config(
+    path: &Path,
+    config: &Config,
+    devices: &BTreeMap<u8, Vec<DeviceInfo>>,
+    states: &HashMap<u8, DeviceState>,
+) -> Result<()> {
+    let mut effective = config.clone();
+    for (k, d) in effective.vjoy_device.iter_mut() {
+        if let Some(st) = states.get(k) {
+            d.enabled = st.enabled;
+        }
+    }
+
+    let mut value = toml::Table::try_from(&effective)?;
+    let Some(toml::Value::Table(sections)) = value.get_mut("vjoy_device") else {
+        bail!("vjoy_device missing from config");
+    };
+
+    for (k, infos) in devices.iter() {
+        if let Some(toml::Value::Table(section)) = sections.get_mut(&k.to_string()) {
+            // Device from config.toml: one source, or merged sources each
+            // under its own entry
+            for info in infos {
+                let derived = toml::Table::try_from(derive(info))?;
+                let target = match info.source.split_once(".sources.") {
+                    Some((_, name)) => match section
+                        .get_mut("sources")
+                        .and_then(|sources| sources.get_mut(name))
+                    {
+                        Some(toml::Value::Table(target)) => target,
+                        _ => continue,
+                    },
+                    None => &mut *section,
+                };
+                target.extend(derived);
+            }
+            continue;
+        }
+
+        // Device composed by the cockpit file, one entry per source
+        let mut section = toml::Table::new();
+        if let Some(st) = states.get(k) {
+            section.insert("enabled".to_string(), st.enabled.into());
+        }
+        let sources: BTreeMap<&str, DerivedDevice> = infos
+            .iter()
+            .map(|info| (info.source.as_str(), derive(info)))
+            .collect();
+        section.insert("sources".to_string(), toml::Value::try_from(sources)?);
+        sections.insert(k.to_string(), section.into());
+    }
+
+    let toml_str = toml::to_string_pretty(&value)?;
+    fs::write(path, toml_str).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(())
+}
+
+fn derive(info: &DeviceInfo) -> DerivedDevice {
+    let axes = info
+        .axes
+        .iter()
+        .map(|(slot, code, r)| EffectiveAxis {
+            slot: *slot,
+            code: format!("{:?}", code),
+            min: r.min,
+            max: r.max,
+            center: r.center,
+        })
+        .collect();
+
+    let buttons = info
+        .button_map
+        .iter()
+        .map(|(key, btn)| (format!("{:?}", key), *btn))
+        .collect();
+
+    DerivedDevice {
+        name: info.name.clone(),
+        path: info.path.clone(),
+        axes,
+        buttons,
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+// Errors further apart than this aren't one outage; pings go out every second
+const GRACE: Duration = Duration::from_secs(2);
+
+// Which of dest (0) and failover_dest (1) gets the state: the other one once
+// sends to it keep failing or its receiver stops answering pings, dest again
+// once its receiver answers
+pub struct Failover {
+    after: Duration,
+    pub active: usize,
+    switched: Instant,
+    // Since when each has been failing
+    failing_since: [Option<Instant>; 2],
+}
+
+pub enum Why {
+    Failing,
+    Silent,
+    Back,
+}
+
+impl Failover {
+    pub fn new(after: Duration) -> Failover {
+        Failover {
+            after,
+            active: 0,
+            switched: Instant::now(),
+            failing_since: [None; 2],
+        }
+    }
+
+    // The link to switch to, if any. failed is each one's last send error (or
+    // ICMP unreachable), heard its receiver's last packet back, None for never
+    // (it may not answer at all)
+    pub fn check(
+        &mut self,
+        failed: [Option<Instant>; 2],
+        heard: [Option<Instant>; 2],
+        now: Instant,
+    ) -> Option<(usize, Why)> {
+        for (since, failed) in self.failing_since.iter_mut().zip(failed) {
+            *since = failed
+                .filter(|t| now.duration_since(*t) < GRACE)
+                .map(|t| since.unwrap_or(t));
+        }
+        let failing = |link: usize| {
+            self.failing_since[link].is_some_and(|t| now.duration_since(t) >= self.after)
+        };
+        let silent = |link: usize| heard[link].is_some_and(|t| now.duration_since(t) >= self.after);
+        let down = |link: usize| failing(link) || silent(link);
+
+        let (active, other) = (self.active, 1 - self.active);
+        let to = if down(active) && !down(other) {
+            match failing(active) {
+                true => (other, Why::Failing),
+                false => (other, Why::Silent),
+            }
+        } else if active == 1 && heard[0].is_some_and(|t| t > self.switched) && !down(0) {
+            (0, Why::Back)
+        } else {
+            return None;
+        };
+
+        self.active = to.0;
+        self.switched = now;
+        Some(to)
+    }
+}
@@ -0,0 +1,256 @@
+use evdev::{
+    Device, EventType, FFCondition, FFEffect, FFEffectCode, FFEffectData, FFEffectKind, FFEnvelope,
+    FFReplay, FFTrigger, FFWaveform,
+};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::mpsc::Receiver;
+use tracing::{info_span, warn};
+use vkb_sender_core::protocol::{FfbCommand, FfbCondition, FfbEffect, FfbParams, FfbWaveform};
+
+// Plays force feedback commands coming from the receiver on the physical device.
+// `dev` is a second handle to the device, the input task keeps reading the first one.
+pub fn ffb_thread(mut dev: Device, device_id: u8, rx: Receiver<FfbCommand>) {
+    let _span = info_span!("ffb", device = device_id).entered();
+    // vJoy effect block index -> effect uploaded to the device
+    let mut effects: HashMap<u8, Effect> = HashMap::new();
+
+    for cmd in rx {
+        if let Err(e) = apply(&mut dev, &mut effects, cmd) {
+            warn!(
+                "device {}: force feedback {:?} failed: {}",
+                device_id, cmd, e
+            );
+        }
+    }
+}
+
+fn apply(dev: &mut Device, effects: &mut HashMap<u8, Effect>, cmd: FfbCommand) -> io::Result<()> {
+    match cmd {
+        FfbCommand::SetEffect { index, effect } => {
+            match (effects.get_mut(&index), effect_data(&effect)) {
+                (Some(Effect::Evdev(e)), Some(data)) => e.update(data)?,
+                (Some(Effect::Raw(e)), None) => e.update(&effect)?,
+                // New, or the block held one uploaded the other way
+                (_, data) => {
+                    effects.remove(&index);
+                    let e = match data {
+                        Some(data) => Effect::Evdev(dev.upload_ff_effect(data)?),
+                        None => Effect::Raw(RawEffect::upload(dev, &effect)?),
+                    };
+                    effects.insert(index, e);
+                }
+            }
+        }
+        FfbCommand::Start { index, loop_count } => {
+            if let Some(e) = effects.get_mut(&index) {
+                e.play(loop_count.max(1) as i32)?;
+            }
+        }
+        FfbCommand::Solo { index, loop_count } => {
+            for (i, e) in effects.iter_mut() {
+                if *i != index {
+                    e.stop()?;
+                }
+            }
+            if let Some(e) = effects.get_mut(&index) {
+                e.play(loop_count.max(1) as i32)?;
+            }
+        }
+        FfbCommand::Stop { index } => {
+            if let Some(e) = effects.get_mut(&index) {
+                e.stop()?;
+            }
+        }
+        // Dropping an FFEffect erases it from the device
+        FfbCommand::Free { index } => {
+            effects.remove(&index);
+        }
+        FfbCommand::Gain(gain) => dev.set_ff_gain(gain as u16 * 257)?,
+        FfbCommand::StopAll => {
+            for e in effects.values_mut() {
+                e.stop()?;
+            }
+        }
+        FfbCommand::Reset => effects.clear(),
+    }
+    Ok(())
+}
+
+// An effect uploaded to the device, by evdev or by hand for the kinds evdev
+// can't carry the conditions of
+enum Effect {
+    Evdev(FFEffect),
+    Raw(RawEffect),
+}
+
+impl Effect {
+    fn play(&mut self, count: i32) -> io::Result<()> {
+        match self {
+            Effect::Evdev(e) => e.play(count),
+            Effect::Raw(e) => e.play(count),
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        match self {
+            Effect::Evdev(e) => e.stop(),
+            Effect::Raw(e) => e.play(0),
+        }
+    }
+}
+
+// Damper and inertia: evdev uploads them without parameters, so the kernel would
+// get zero coefficients and the stick play nothing. These go through EVIOCSFF
+// with the conditions filled in, and are erased with EVIOCRMFF when dropped
+struct RawEffect {
+    fd: OwnedFd,
+    id: i16,
+}
+
+const EVIOCSFF: libc::Ioctl = libc::_IOW::<libc::ff_effect>(b'E' as u32, 0x80);
+const EVIOCRMFF: libc::Ioctl = libc::_IOW::<libc::c_int>(b'E' as u32, 0x81);
+
+impl RawEffect {
+    fn upload(dev: &Device, effect: &FfbEffect) -> io::Result<RawEffect> {
+        let mut e = RawEffect {
+            fd: dev.as_fd().try_clone_to_owned()?,
+            id: -1,
+        };
+        e.update(effect)?;
+        Ok(e)
+    }
+
+    fn update(&mut self, effect: &FfbEffect) -> io::Result<()> {
+        let (code, conditions) = match effect.params {
+            FfbParams::Damper(c) => (FFEffectCode::FF_DAMPER, c),
+            FfbParams::Inertia(c) => (FFEffectCode::FF_INERTIA, c),
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let mut raw: libc::ff_effect = unsafe { mem::zeroed() };
+        raw.type_ = code.0;
+        raw.id = self.id;
+        raw.direction = effect.direction;
+        raw.replay.length = effect.duration_ms;
+        // The union's condition member, one per axis
+        let union: &mut [libc::ff_condition_effect; 2] = unsafe { &mut *raw.u.as_mut_ptr().cast() };
+        for (to, c) in union.iter_mut().zip(conditions) {
+            let c = condition(c);
+            to.right_saturation = c.right_saturation;
+            to.left_saturation = c.left_saturation;
+            to.right_coeff = c.right_coefficient;
+            to.left_coeff = c.left_coefficient;
+            to.deadband = c.deadband;
+            to.center = c.center;
+        }
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), EVIOCSFF, &mut raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.id = raw.id;
+        Ok(())
+    }
+
+    fn play(&mut self, count: i32) -> io::Result<()> {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = EventType::FORCEFEEDBACK.0;
+        event.code = self.id as u16;
+        event.value = count;
+        let size = mem::size_of::<libc::input_event>();
+        let n = unsafe { libc::write(self.fd.as_raw_fd(), (&raw const event).cast(), size) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RawEffect {
+    fn drop(&mut self) {
+        if self.id >= 0 {
+            unsafe { libc::ioctl(self.fd.as_raw_fd(), EVIOCRMFF, self.id as libc::c_int) };
+        }
+    }
+}
+
+// None for the kinds evdev can't upload with their parameters
+fn effect_data(effect: &FfbEffect) -> Option<FFEffectData> {
+    let envelope = FFEnvelope {
+        attack_length: 0,
+        attack_level: 0,
+        fade_length: 0,
+        fade_level: 0,
+    };
+
+    let kind = match effect.params {
+        FfbParams::Constant { level } => FFEffectKind::Constant { level, envelope },
+        FfbParams::Ramp { start, end } => FFEffectKind::Ramp {
+            start_level: start,
+            end_level: end,
+            envelope,
+        },
+        FfbParams::Periodic {
+            waveform,
+            magnitude,
+            offset,
+            period_ms,
+            phase,
+        } => FFEffectKind::Periodic {
+            waveform: match waveform {
+                FfbWaveform::Square => FFWaveform::Square,
+                FfbWaveform::Sine => FFWaveform::Sine,
+                FfbWaveform::Triangle => FFWaveform::Triangle,
+                FfbWaveform::SawUp => FFWaveform::SawUp,
+                FfbWaveform::SawDown => FFWaveform::SawDown,
+            },
+            period: period_ms,
+            magnitude,
+            offset,
+            phase,
+            envelope,
+        },
+        FfbParams::Spring(c) => FFEffectKind::Spring {
+            condition: c.map(condition),
+        },
+        FfbParams::Friction(c) => FFEffectKind::Friction {
+            condition: c.map(condition),
+        },
+        FfbParams::Damper(_) | FfbParams::Inertia(_) => return None,
+    };
+
+    Some(FFEffectData {
+        direction: effect.direction,
+        trigger: FFTrigger::default(),
+        replay: FFReplay {
+            length: effect.duration_ms,
+            delay: 0,
+        },
+        kind,
+    })
+}
+
+fn condition(c: FfbCondition) -> FFCondition {
+    FFCondition {
+        right_saturation: c.pos_saturation,
+        left_saturation: c.neg_saturation,
+        right_coefficient: c.pos_coeff,
+        left_coefficient: c.neg_coeff,
+        deadband: c.deadband,
+        center: c.center,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As linux/input.h has them on 64 bit targets
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn raw_ioctls_match_the_kernel() {
+        assert_eq!(mem::size_of::<libc::ff_effect>(), 48);
+        assert_eq!(EVIOCSFF, 0x4030_4580);
+        assert_eq!(EVIOCRMFF, 0x4004_4581);
+    }
+}
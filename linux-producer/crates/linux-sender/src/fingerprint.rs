@@ -0,0 +1,85 @@
+use crate::Config;
+use crate::effective::DeviceInfo;
+use std::collections::BTreeMap;
+use std::fs;
+
+// FNV-1a, stable across builds and machines unlike std's hasher
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+// Hash of each vjoy device's layout: which evdev control feeds which button and
+// axis, and the transforms on top. Calibration and device paths are left out so
+// replugging doesn't change it.
+pub fn fingerprints(config: &Config, devices: &BTreeMap<u8, Vec<DeviceInfo>>) -> BTreeMap<u8, u64> {
+    let mut out = BTreeMap::new();
+
+    for (k, infos) in devices.iter() {
+        let mut h = Fnv(0xcbf29ce484222325);
+
+        for info in infos {
+            h.write(info.source.as_bytes());
+
+            let mut buttons: Vec<(u16, u16)> = info
+                .button_map
+                .iter()
+                .map(|(key, b)| (*b, key.code()))
+                .collect();
+            buttons.sort();
+            for (b, code) in buttons {
+                h.write(&b.to_le_bytes());
+                h.write(&code.to_le_bytes());
+            }
+
+            for (slot, code, _) in info.axes.iter() {
+                h.write(&[*slot as u8]);
+                h.write(&code.0.to_le_bytes());
+            }
+        }
+
+        if config.vjoy_device.get(k).is_some_and(|d| d.raw) {
+            h.write(b"raw");
+        }
+
+        // All of it, remaps to scripts change what reaches the receiver. Maps
+        // are ordered, so the same settings serialize the same
+        if let Some(transform) = crate::active_transforms(config).get(k) {
+            if let Ok(json) = serde_json::to_vec(transform) {
+                h.write(&json);
+            }
+            if let Some(source) = transform.script.as_ref().and_then(|p| fs::read(p).ok()) {
+                h.write(&source);
+            }
+        }
+
+        out.insert(*k, h.0);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(text: &str) -> u64 {
+        let text = format!("dest = \"127.0.0.1:46000\"\nsend_hz = 250\n{}", text);
+        let config: Config = toml::from_str(&text).unwrap();
+        fingerprints(&config, &BTreeMap::from([(1, Vec::new())]))[&1]
+    }
+
+    #[test]
+    fn remap_changes_the_fingerprint() {
+        let plain = fingerprint("[transform.1.remap]\n3 = 4\n");
+        assert_eq!(plain, fingerprint("[transform.1.remap]\n3 = 4\n"));
+        assert_ne!(plain, fingerprint("[transform.1.remap]\n3 = 5\n"));
+        assert_ne!(plain, fingerprint(""));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::detect::{self, Detected};
+use crate::{DEFAULT_PORT, MAX_VJOY_DEVICES};
+use anyhow::{Context, Result, bail};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+// Same rate as the example config
+const DEFAULT_SEND_HZ: u16 = 250;
+
+// Lists the joysticks on this machine, asks which to bridge and where to, and
+// writes a config.toml for them
+pub fn run(output: &Path, dest: Option<&str>, all: bool, force: bool) -> Result<()> {
+    if output.exists() && !force {
+        bail!(
+            "{} already exists, pass --force to overwrite it",
+            output.display()
+        );
+    }
+
+    let found = detect::joysticks();
+    if found.is_empty() {
+        bail!("No joysticks found. Check permissions (/dev/input/event*)");
+    }
+
+    println!("Joysticks found:");
+    for (i, d) in found.iter().enumerate() {
+        println!(
+            "  {}) {}  {:04x}:{:04x}  {} axes, {} buttons  {}",
+            i + 1,
+            d.name,
+            d.vendor_id,
+            d.product_id,
+            d.axes,
+            d.buttons,
+            d.path.display()
+        );
+    }
+
+    let picked: Vec<&Detected> = if all {
+        found.iter().collect()
+    } else {
+        let line = ask("Bridge which devices? (e.g. 1,3, empty for all): ")?;
+        pick(&found, &line)?
+    };
+    if picked.len() > MAX_VJOY_DEVICES as usize {
+        bail!("vJoy has {} devices at most", MAX_VJOY_DEVICES);
+    }
+
+    let dest = match dest {
+        Some(dest) => dest.to_string(),
+        None => ask("Receiver address (host or host:port): ")?,
+    };
+    if dest.is_empty() {
+        bail!("A receiver address is needed");
+    }
+    // Default to the bridge port when none is given
+    let dest = match dest.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, DEFAULT_PORT).to_string(),
+        Err(_) if !dest.contains(':') => format!("{}:{}", dest, DEFAULT_PORT),
+        Err(_) => dest,
+    };
+
+    let text = render(&found, &picked, &dest);
+    fs::write(output, &text).with_context(|| format!("Failed to write {}", output.display()))?;
+    // What was written has to load
+    crate::parse(output)?;
+
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn ask(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+// "1,3" or "1 3", numbers as listed
+fn pick<'a>(found: &'a [Detected], line: &str) -> Result<Vec<&'a Detected>> {
+    if line.is_empty() {
+        return Ok(found.iter().collect());
+    }
+
+    let mut picked: Vec<&Detected> = Vec::new();
+    for part in line.split([',', ' ']).filter(|p| !p.is_empty()) {
+        let n: usize = part
+            .parse()
+            .with_context(|| format!("{} is not a device number", part))?;
+        let Some(d) = n.checked_sub(1).and_then(|i| found.get(i)) else {
+            bail!("There is no device {}", n);
+        };
+        if !picked.iter().any(|p| std::ptr::eq(*p, d)) {
+            picked.push(d);
+        }
+    }
+    Ok(picked)
+}
+
+fn render(found: &[Detected], picked: &[&Detected], dest: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# Written by linux-sender generate-config");
+    let _ = writeln!(out, "dest = {:?}", dest);
+    let _ = writeln!(out, "send_hz = {}", DEFAULT_SEND_HZ);
+
+    for (k, d) in (1..).zip(picked.iter()) {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "[vjoy_device.{}] # {}", k, d.name);
+        let _ = writeln!(out, "vendor_id = 0x{:04x}", d.vendor_id);
+        let _ = writeln!(out, "product_id = 0x{:04x}", d.product_id);
+        // Several of the same model: tell them apart by USB port order
+        let twins = found
+            .iter()
+            .filter(|o| o.vendor_id == d.vendor_id && o.product_id == d.product_id)
+            .count();
+        if twins > 1 {
+            let _ = writeln!(out, "index = {}", d.index);
+        }
+    }
+    out
+}
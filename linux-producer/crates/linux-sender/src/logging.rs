@@ -0,0 +1,75 @@
+use crate::systemd;
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, with the spans (device, link) it happened in
+    Json,
+}
+
+// Log lines go to stdout (stderr if it is taken), colored on a terminal. filter
+// is a level (debug) or levels per module (info,linux_sender::transport=trace)
+pub fn init(filter: &str, format: LogFormat, stderr: bool) -> Result<()> {
+    match stderr {
+        false => install(filter, format, io::stdout().is_terminal(), io::stdout),
+        true => install(filter, format, io::stderr().is_terminal(), io::stderr),
+    }
+}
+
+fn install<W>(filter: &str, format: LogFormat, terminal: bool, writer: W) -> Result<()>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let filter =
+        EnvFilter::try_new(filter).map_err(|e| anyhow!("Bad --log-level {}: {}", filter, e))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Json => builder.json().init(),
+        LogFormat::Text if systemd::under_journald() => {
+            let plain = format::format().without_time().with_level(false);
+            builder
+                .with_ansi(false)
+                .event_format(Journald(plain))
+                .init()
+        }
+        LogFormat::Text => builder.with_ansi(terminal).init(),
+    }
+    Ok(())
+}
+
+// Lines for the journal: the level as a syslog priority prefix (<4>) it turns
+// into the entry's priority, no time since it records its own
+struct Journald(format::Format<format::Full, ()>);
+
+impl<S, N> FormatEvent<S, N> for Journald
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let priority = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        write!(writer, "<{}>", priority)?;
+        self.0.format_event(ctx, writer, event)
+    }
+}
@@ -1,310 +1,2948 @@
-use anyhow::{bail, Context, Result};
-use evdev::{AbsInfo, AbsoluteAxisCode, Device, EventSummary, KeyCode};
-use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::{Arc, Mutex};
+mod api;
+mod buttons;
+mod check;
+mod cockpit;
+mod control;
+mod dashboard;
+mod detect;
+mod dump;
+mod effective;
+mod failover;
+mod ffb;
+mod fingerprint;
+mod generate;
+mod logging;
+mod metrics;
+mod monitor;
+mod pacing;
+mod persist;
+mod ping;
+mod privs;
+mod record;
+mod replay;
+mod sched;
+mod script;
+mod shm;
+mod snapshot;
+mod synthetic;
+mod systemd;
+mod transform;
+mod transport;
+mod udev;
+mod uinput;
+mod watch;
+
+use anyhow::{Context, Result, bail};
+use buttons::ButtonStore;
+use clap::{Parser, Subcommand, ValueEnum};
+use effective::DeviceInfo;
+use evdev::{AbsInfo, AbsoluteAxisCode, Device, KeyCode, RelativeAxisCode};
+use logging::LogFormat;
+use metrics::Metrics;
+use monitor::Monitor;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 use std::{fs, thread};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::runtime;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task;
+use tokio::time;
+use tracing::{Instrument, error, info, info_span, warn};
+use transform::{ModeSource, ModeSourceConfig, Pipeline, TransformConfig};
+use transport::{Link, Peer};
+use vkb_sender_core::input::{
+    AxisConfig, InputMap, RelButtons, Source, build_axis_ranges, build_input_map,
+    check_calibration, open_vkb_device, parse_axis_code, read_input, release,
+};
+use vkb_sender_core::protocol::{
+    self, FfbCommand, HEADER_LEN, PKT_TYPE_CAPS, PKT_TYPE_FFB, PKT_TYPE_PING, PKT_TYPE_PONG,
+    PKT_TYPE_SNAPSHOT_REQUEST, PKT_TYPE_STATUS, SECTION_STALE, STATE_PKT_LEN, Status, V2_BUTTONS,
+    encode_vkb2,
+};
+use vkb_sender_core::raw::RawState;
+use vkb_sender_core::selector::Selector;
+use vkb_sender_core::state::{
+    DeviceState, MAX_BUTTONS, StateTx, States, Update, WireState, button_bitpos,
+};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+// VKB_SEND_HZ=500 sets send_hz over the config file. VKB_BRIDGE_* are options
+// of the command line instead
+const ENV_PREFIX: &str = "VKB_";
+const ENV_ARGS_PREFIX: &str = "VKB_BRIDGE_";
+
+// "profile none" goes back to transform alone
+const PROFILE_NONE: &str = "none";
+// Receiver port, used when an address is given without one
+const DEFAULT_PORT: u16 = 46000;
+
+// vJoy supports devices 1..=16
+const MAX_VJOY_DEVICES: u8 = 16;
+
+// How often a missing device is looked for when nothing in /dev/input changed
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+// Waits before an input reader that died is started again, doubling while it
+// keeps dying
+const RESTART_MIN: Duration = Duration::from_secs(1);
+const RESTART_MAX: Duration = Duration::from_secs(30);
+
+// Under systemd: how often the status is updated, and how long to wait for
+// missing devices before reporting ready anyway
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+const READY_WAIT: Duration = Duration::from_secs(10);
+
+// Neutral packets sent on the way out
+const FINAL_PACKETS: usize = 3;
+
+// Nothing sent changing for this long drops the sender to idle_hz
+const IDLE_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Parser, Debug)]
+#[command(about = "Streams VKB devices to the windows receiver")]
+struct Args {
+    /// Config file, by default the first of ./config.toml,
+    /// $XDG_CONFIG_HOME/vkb-bridge/config.toml and /etc/vkb-bridge/config.toml.
+    /// TOML, or JSON or YAML when named .json, .yaml or .yml
+    #[arg(long, value_name = "PATH", env = "VKB_BRIDGE_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Write the fully resolved configuration (including button maps and axis
+    /// calibration) to PATH and exit
+    #[arg(long, value_name = "PATH")]
+    dump_effective_config: Option<PathBuf>,
+
+    /// Where the mapped state goes: udp sends it to dest, uinput makes every
+    /// vjoy device a joystick on this machine instead (to try the mapping with
+    /// jstest or evtest)
+    #[arg(long, value_enum, default_value_t = Backend::Udp)]
+    backend: Backend,
+
+    /// What gets logged: a level (error, warn, info, debug, trace) or levels
+    /// per module (info,linux_sender::transport=debug)
+    #[arg(
+        long,
+        value_name = "FILTER",
+        default_value = "info",
+        env = "VKB_BRIDGE_LOG"
+    )]
+    log_level: String,
+
+    /// Log lines as text, or as JSON objects one per line
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Show what every vjoy device sends (axes after transforms, hat, pressed
+    /// buttons), redrawn in place; log lines go to stderr meanwhile. Sending
+    /// goes on unless the config has send = false
+    #[arg(long)]
+    monitor: bool,
+
+    /// Read and map the devices as usual, but print every datagram (what it
+    /// says, then its bytes in hex) instead of sending it; log lines go to
+    /// stderr meanwhile
+    #[arg(long, conflicts_with = "monitor")]
+    dry_run: bool,
+
+    /// Append what every vjoy device sends to PATH while bridging, a line of
+    /// JSON each time a device's state changes (seconds since the session
+    /// started, axes after transforms, hat, buttons pressed)
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+
+    /// Open no device: every vjoy device in the config (or vjoy device 1 when
+    /// it lists none) sweeps its axes, turns its hat and presses its first 128
+    /// buttons one after the other, through the transforms as usual. To check
+    /// the receiver, vJoy and game bindings with no hardware
+    #[arg(long)]
+    synthetic: bool,
+
+    /// List every input device (name, vendor/product, serial, axes, key count)
+    /// as config.toml entries and exit
+    #[arg(long)]
+    list_devices: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Backend {
+    Udp,
+    Uinput,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check reachability of a receiver: round trip time and loss on the bridge
+    /// port, with bridge sized packets
+    Ping {
+        /// Receiver address, host or host:port
+        addr: String,
+        /// Number of pings
+        #[arg(short, long, default_value_t = 10)]
+        count: u16,
+        /// Milliseconds between pings
+        #[arg(short, long, default_value_t = 1000)]
+        interval_ms: u64,
+    },
+    /// Send a session recorded with --record to a receiver, with the timing
+    /// it was recorded with, then release every control
+    Replay {
+        /// The recording
+        file: PathBuf,
+        /// Receiver address, host or host:port; the config's first dest when
+        /// left out
+        #[arg(long)]
+        dest: Option<String>,
+        /// How much faster than recorded, 0.5 is half speed
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Datagrams a second per device
+        #[arg(long, default_value_t = 100)]
+        send_hz: u16,
+        /// Start over at the end, until interrupted
+        #[arg(long)]
+        repeat: bool,
+    },
+    /// List the joysticks plugged in, ask which to bridge and write a config
+    /// for them
+    GenerateConfig {
+        /// Where to write the config
+        #[arg(short, long, value_name = "PATH", default_value = CONFIG_FILE_NAME)]
+        output: PathBuf,
+        /// Receiver address, host or host:port (asked for when left out)
+        #[arg(long)]
+        dest: Option<String>,
+        /// Bridge every joystick found without asking
+        #[arg(long)]
+        all: bool,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check the config without starting the bridge: every device it names is
+    /// there, can be opened and has the axes and keys mapped, and every dest
+    /// resolves
+    Check,
+    /// Write udev rules giving access to the configured devices (and
+    /// /dev/uinput when needed), or print them
+    SetupUdev {
+        /// Where to write the rules, e.g. /etc/udev/rules.d/70-vkb-bridge.rules;
+        /// printed when left out
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+        /// Who gets access, by default the config's user, else whoever is
+        /// logged in at the seat
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Config {
+    // host:port, IPv4, [IPv6] or a hostname; a list sends the same stream to
+    // each one
+    #[serde(deserialize_with = "one_or_many")]
+    dest: Vec<String>,
+    // Where the state goes instead while dest is down
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failover_dest: Option<String>,
+    // How long sends to dest have to keep failing, or its receiver stay
+    // silent, before switching
+    #[serde(default = "default_failover_secs")]
+    failover_secs: u64,
+    send_hz: u16,
+    // dest is a broadcast address
+    #[serde(default)]
+    broadcast: bool,
+    // Hops for multicast dest addresses, 1 keeps it on the local network
+    #[serde(default = "default_multicast_ttl")]
+    multicast_ttl: u32,
+    // DSCP (0..=63, e.g. 46 = EF) for every outgoing packet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dscp: Option<u8>,
+    // How often a hostname dest is looked up again, it is also looked up on send errors
+    #[serde(default = "default_resolve_interval_secs")]
+    resolve_interval_secs: u64,
+    // Send all devices in one datagram per tick (needs a receiver that knows batches)
+    #[serde(default)]
+    combine_devices: bool,
+    // Largest datagram to send, combined packets are split to stay under it
+    #[serde(default = "default_max_datagram")]
+    max_datagram: usize,
+    // Keep the button numbers each device got in <config>.buttons.toml, so they
+    // don't move when the device's keys change
+    #[serde(default)]
+    persist_buttons: bool,
+    // Keep toggles and trims in <config>.state.toml when stopping and pick them
+    // up again at startup, shared by every profile or each profile its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    persist_state: Option<PersistState>,
+    // Cockpit composition file, binds logical controls to physical devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cockpit: Option<PathBuf>,
+    // vjoy devices also handed to games on this machine as uinput joysticks,
+    // their physical devices taken by the sender alone
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    uinput: BTreeSet<u8>,
+    // Send to dest; off, only the uinput joysticks get the output
+    #[serde(default = "default_send")]
+    send: bool,
+    // SCHED_FIFO priority (1..=99) for the thread reading devices and sending
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    realtime_priority: Option<u8>,
+    // Niceness (-20..=19) for that thread, instead of realtime priority
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nice: Option<i32>,
+    // CPU that thread stays on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu: Option<usize>,
+    // Started as root: who to run as once the devices, sockets and uinput
+    // joysticks are open (name or id, group defaults to the user's)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    // Tick from a timerfd, to the nanosecond, and report the rate achieved
+    #[serde(default)]
+    precise_pacing: bool,
+    // Rate to send at while nothing changes, full rate again on the first change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_hz: Option<u16>,
+    // Log a line of sender statistics this often
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stats_secs: Option<u64>,
+    // Where to serve Prometheus metrics over HTTP (GET /metrics)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metrics_listen: Option<SocketAddr>,
+    // Unix socket taking console commands from scripts and vkb-bridgectl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    control_socket: Option<PathBuf>,
+    // Shared memory the state as sent is kept in for local tools, a name in
+    // /dev/shm or a file path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shared_memory: Option<PathBuf>,
+    // --monitor and --dry-run, never from the file
+    #[serde(skip)]
+    monitor: bool,
+    #[serde(skip)]
+    dry_run: bool,
+    // --record, never from the file
+    #[serde(skip)]
+    record: Option<PathBuf>,
+    // Profile in use at startup, switched while running with the console's
+    // profile command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    active_profile: Option<String>,
+    // Buttons switching profiles while running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile_switch: Option<ProfileSwitch>,
+    #[serde(default)]
+    vjoy_device: BTreeMap<u8, VJoyDevice>,
+    // Output transforms per vjoy device (config.toml or cockpit devices)
+    #[serde(default)]
+    transform: BTreeMap<u8, TransformConfig>,
+    // Named sets of transforms, e.g. one per game. The active one's devices
+    // take the place of theirs in transform
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profile: BTreeMap<String, Profile>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PersistState {
+    Shared,
+    PerProfile,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+struct Profile {
+    #[serde(default)]
+    transform: BTreeMap<u8, TransformConfig>,
+}
+
+// Chords on one vjoy device's buttons as read, before its transforms. They go
+// out as usual besides
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct ProfileSwitch {
+    device: u8,
+    // Held together: the next profile by name, round to the first after the last
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    next: Vec<u16>,
+    // Held together: that profile (or none). A single button each for the
+    // positions of a mode selector
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    select: BTreeMap<String, Vec<u16>>,
+    // A mode selector picking the profile by its position
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<ModeSwitch>,
+    // Ring the terminal bell on a switch
+    #[serde(default)]
+    bell: bool,
+}
+
+// Position n (from 0) of the selector is profiles[n], "none" included
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct ModeSwitch {
+    #[serde(flatten)]
+    source: ModeSourceConfig,
+    profiles: Vec<String>,
+}
+
+impl ProfileSwitch {
+    fn mode_source(&self) -> Result<Option<ModeSource>> {
+        self.mode
+            .as_ref()
+            .map(|m| ModeSource::new(&m.source, m.profiles.len() as u16))
+            .transpose()
+            .context("profile_switch.mode")
+    }
+
+    // The profile the mode selector asks for when its position moved. One read
+    // off an axis switches once its device is attached, buttons step on from
+    // the active profile
+    fn mode_target(
+        &self,
+        config: &Config,
+        mode: &mut ModeSource,
+        last: &mut Option<u16>,
+        st: &DeviceState,
+    ) -> Option<String> {
+        let profiles = &self.mode.as_ref()?.profiles;
+        match mode.axis() {
+            Some(a) if st.axis_range[a].min == st.axis_range[a].max => return None,
+            Some(_) => {}
+            None => {
+                let active = config.active_profile.as_deref().unwrap_or(PROFILE_NONE);
+                if let Some(i) = profiles.iter().position(|name| name == active) {
+                    mode.set_position(i as u16);
+                    *last = Some(i as u16);
+                }
+            }
+        }
+        let at = mode.read(&st.wire().axes, &mut st.buttons.clone());
+        match last.replace(at) == Some(at) {
+            true => None,
+            false => profiles.get(at as usize).cloned(),
+        }
+    }
+
+    // The profile a chord going down this tick asks for
+    fn target(&self, config: &Config, before: &[u8; 32], now: &[u8; 32]) -> Option<String> {
+        let went_down = |chord: &[u16]| {
+            let held = |buttons: &[u8; 32]| {
+                chord.iter().all(|b| {
+                    let (byte_i, bit_i) = button_bitpos(*b);
+                    buttons[byte_i] & (1 << bit_i) != 0
+                })
+            };
+            !chord.is_empty() && held(now) && !held(before)
+        };
+
+        if let Some((name, _)) = self.select.iter().find(|(_, chord)| went_down(chord)) {
+            return Some(name.clone());
+        }
+        if went_down(&self.next) {
+            let mut names = config.profile.keys();
+            let next = match &config.active_profile {
+                Some(active) => names.clone().find(|name| *name > active),
+                None => None,
+            };
+            return next.or_else(|| names.next()).cloned();
+        }
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct VJoyDevice {
+    // vendor_id, product_id, name_contains, serial, path
+    #[serde(flatten)]
+    select: Selector,
+    // false: its sources aren't read, the device goes out neutral once
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    force_feedback: bool,
+    // Key-only device (macro keypad): keys become buttons, axes stay centered
+    #[serde(default)]
+    keyboard: bool,
+    // Needed to bridge a device that looks like a regular typing keyboard
+    #[serde(default)]
+    allow_full_keyboard: bool,
+    // Send every evdev axis and key as is and leave the mapping to the receiver
+    #[serde(default)]
+    raw: bool,
+    // Take the device for the sender alone (EVIOCGRAB) while bridging, so games
+    // on this machine don't see it too
+    #[serde(default)]
+    grab: bool,
+    // Per vJoy axis id (1..=8)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    axis: BTreeMap<u8, AxisConfig>,
+    // evdev key name or code (BTN_TRIGGER, 0x2c0) -> vJoy button (1..=256). When
+    // given only these keys are bridged, instead of every key in code order
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    buttons: BTreeMap<String, u16>,
+    // Numbered buttons start after this many, e.g. 64 to put this device on
+    // 65..=128 next to another one
+    #[serde(default)]
+    button_offset: u16,
+    // Number buttons by their HID button usage (the MSC_SCAN code), which
+    // firmware updates and profile changes don't move, instead of key code
+    #[serde(default)]
+    scan_codes: bool,
+    // Keys making up a hat (a 4-way switch reporting keys): up, right, down, left
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hat_keys: Vec<String>,
+    // Keys reading pressed when off (normally closed switches), flipped as read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    invert_buttons: Vec<String>,
+    // Encoders: evdev relative axis (REL_DIAL) -> buttons pulsed per detent
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    rel: BTreeMap<String, RelButtons>,
+    #[serde(default = "default_rel_pulse_ms")]
+    rel_pulse_ms: u64,
+    // Several physical devices feeding this one, by name. The device itself
+    // then has no selector or mapping of its own
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sources: BTreeMap<String, SourceConfig>,
+}
+
+// One of the physical devices of a merged vjoy device
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct SourceConfig {
+    // vendor_id, product_id, name_contains, serial, path, index
+    #[serde(flatten)]
+    select: Selector,
+    #[serde(default)]
+    force_feedback: bool,
+    #[serde(default)]
+    keyboard: bool,
+    #[serde(default)]
+    allow_full_keyboard: bool,
+    #[serde(default)]
+    grab: bool,
+    // Only the axes listed are read, each with the code it reads
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    axis: BTreeMap<u8, AxisConfig>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    buttons: BTreeMap<String, u16>,
+    #[serde(default)]
+    button_offset: u16,
+    #[serde(default)]
+    scan_codes: bool,
+    // This device's hat is the vJoy hat
+    #[serde(default)]
+    hat: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hat_keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    invert_buttons: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    rel: BTreeMap<String, RelButtons>,
+    #[serde(default = "default_rel_pulse_ms")]
+    rel_pulse_ms: u64,
+}
+
+// A single dest, or a list of them
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(dest) => vec![dest],
+        OneOrMany::Many(dests) => dests,
+    })
+}
+
+fn default_failover_secs() -> u64 {
+    3
+}
+
+fn default_rel_pulse_ms() -> u64 {
+    50
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_send() -> bool {
+    true
+}
+
+fn default_multicast_ttl() -> u32 {
+    1
+}
+
+fn default_resolve_interval_secs() -> u64 {
+    30
+}
+
+// Leaves room for VPN and tunnel overhead on a 1500 byte link
+fn default_max_datagram() -> usize {
+    1200
+}
+
+// What the tasks share about the devices. Devices come and go, so what
+// depends on the device itself is filled in (and replaced) when it is opened
+#[derive(Clone)]
+struct Devices {
+    // To the sender, which owns the state
+    updates: Sender<(u8, Update)>,
+    // As the sender last applied them, for the console and snapshots
+    states: Arc<Mutex<HashMap<u8, DeviceState>>>,
+    // Raw mode devices only
+    raw_map: HashMap<u8, Arc<Mutex<RawState>>>,
+    // Devices with force feedback, while plugged in
+    ffb_map: Arc<Mutex<HashMap<u8, Sender<FfbCommand>>>>,
+    device_infos: Arc<RwLock<BTreeMap<u8, Vec<DeviceInfo>>>>,
+    // Event node -> source bound to it
+    claimed: Arc<Mutex<HashMap<PathBuf, String>>>,
+    // (vjoy device, source) not being read: unplugged, or its reader restarting
+    stale: Arc<Mutex<BTreeSet<(u8, String)>>>,
+    // For /metrics
+    metrics: Arc<Metrics>,
+    // With persist_buttons
+    button_store: Option<Arc<ButtonStore>>,
+    // Paused from the console or control socket: every device released and
+    // quiet until resumed
+    paused: Arc<AtomicBool>,
+    // As the sender last sent them, after transforms, with the number of
+    // buttons that went (raw devices aside), for the dashboard
+    sent: Arc<Mutex<HashMap<u8, (WireState, u16)>>>,
+    // Each device enabled or not, as the sender last applied it: a disabled
+    // device's sources aren't read
+    enabled: Arc<HashMap<u8, tokio::sync::watch::Sender<bool>>>,
+}
+
+// What the two ends tell each other, shared by the sender and return tasks
+struct Handshake {
+    // Ping times count from here
+    epoch: Instant,
+    // Most buttons per device the receiver takes, 128 until it says otherwise
+    receiver_buttons: AtomicU16,
+    // Per link, micros since epoch (0 for never): the last packet back, and
+    // the last send error or ICMP unreachable
+    heard: Vec<AtomicU64>,
+    failed: Vec<AtomicU64>,
+}
+
+impl Handshake {
+    fn mark(&self, times: &[AtomicU64], link: usize) {
+        let us = self.epoch.elapsed().as_micros().max(1) as u64;
+        times[link].store(us, Ordering::Relaxed);
+    }
+
+    fn when(&self, times: &[AtomicU64], link: usize) -> Option<Instant> {
+        match times[link].load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(self.epoch + Duration::from_micros(us)),
+        }
+    }
+}
+
+// evdev key name (BTN_TRIGGER) or code (288, 0x2c0)
+fn parse_key_code(s: &str) -> Result<KeyCode> {
+    if let Ok(key) = s.parse() {
+        return Ok(key);
+    }
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map(KeyCode::new)
+    .ok()
+    .with_context(|| format!("unknown key {}", s))
+}
+
+fn parse_button_table(table: &BTreeMap<String, u16>) -> Result<HashMap<KeyCode, u16>> {
+    let mut buttons = HashMap::new();
+    let mut taken: HashMap<u16, &str> = HashMap::new();
+    for (name, button) in table.iter() {
+        if !(1..=MAX_BUTTONS).contains(button) {
+            bail!("{}: button {} not in 1..={}", name, button, MAX_BUTTONS);
+        }
+        if let Some(other) = taken.insert(*button, name) {
+            bail!("{} and {} are both button {}", other, name, button);
+        }
+        if buttons.insert(parse_key_code(name)?, *button).is_some() {
+            bail!("{} is listed twice", name);
+        }
+    }
+    Ok(buttons)
+}
+
+// Up, right, down, left
+fn parse_hat_keys(names: &[String]) -> Result<Option<[KeyCode; 4]>> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let Ok(names) = <&[String; 4]>::try_from(names) else {
+        bail!("needs 4 keys: up, right, down, left");
+    };
+    let mut keys = [KeyCode::KEY_RESERVED; 4];
+    for (i, name) in names.iter().enumerate() {
+        keys[i] = parse_key_code(name)?;
+        if keys[..i].contains(&keys[i]) {
+            bail!("{} is listed twice", name);
+        }
+    }
+    Ok(Some(keys))
+}
+
+fn parse_rel_table(
+    table: &BTreeMap<String, RelButtons>,
+) -> Result<HashMap<RelativeAxisCode, RelButtons>> {
+    let mut rel = HashMap::new();
+    for (name, buttons) in table.iter() {
+        let code: RelativeAxisCode = name
+            .parse()
+            .ok()
+            .with_context(|| format!("unknown relative axis {}", name))?;
+        for b in [buttons.up, buttons.down] {
+            if !(1..=MAX_BUTTONS).contains(&b) {
+                bail!("{}: button {} not in 1..={}", name, b, MAX_BUTTONS);
+            }
+        }
+        rel.insert(code, *buttons);
+    }
+    Ok(rel)
+}
+
+fn parse_key_list(names: &[String]) -> Result<HashSet<KeyCode>> {
+    let mut keys = HashSet::new();
+    for name in names.iter() {
+        if !keys.insert(parse_key_code(name)?) {
+            bail!("{} is listed twice", name);
+        }
+    }
+    Ok(keys)
+}
+
+// What a physical device entry can get wrong on its own
+fn check_source(
+    name: &str,
+    select: &Selector,
+    keyboard: bool,
+    raw: bool,
+    axis: &BTreeMap<u8, AxisConfig>,
+    buttons: &BTreeMap<String, u16>,
+    button_offset: u16,
+) -> Result<()> {
+    select.validate().with_context(|| name.to_string())?;
+    for (id, cfg) in axis.iter() {
+        if !(1..=8).contains(id) {
+            bail!("{}.axis.{}: axis ids are 1..=8", name, id);
+        }
+        check_calibration(cfg.min, cfg.center, cfg.max)
+            .with_context(|| format!("{}.axis.{}", name, id))?;
+        if let Some(code) = &cfg.code {
+            parse_axis_code(code).with_context(|| format!("{}.axis.{}", name, id))?;
+            if keyboard {
+                bail!("{}.axis.{}: keyboard devices have no axes", name, id);
+            }
+        }
+    }
+    if raw && !(axis.is_empty() && buttons.is_empty()) {
+        bail!(
+            "{}: raw devices are mapped on the receiver, drop axis and buttons",
+            name
+        );
+    }
+    parse_button_table(buttons).with_context(|| format!("{}.buttons", name))?;
+    if button_offset >= MAX_BUTTONS {
+        bail!(
+            "{}.button_offset: {} leaves no buttons (at most {})",
+            name,
+            button_offset,
+            MAX_BUTTONS - 1
+        );
+    }
+    if button_offset > 0 && (raw || !buttons.is_empty()) {
+        bail!(
+            "{}.button_offset: only for buttons numbered by key code",
+            name
+        );
+    }
+    Ok(())
+}
+
+fn check_devices(config: &Config) -> Result<()> {
+    for (k, d) in config.vjoy_device.iter() {
+        if d.sources.is_empty() {
+            check_source(
+                &format!("vjoy_device.{}", k),
+                &d.select,
+                d.keyboard,
+                d.raw,
+                &d.axis,
+                &d.buttons,
+                d.button_offset,
+            )?;
+            parse_hat_keys(&d.hat_keys).with_context(|| format!("vjoy_device.{}.hat_keys", k))?;
+            parse_key_list(&d.invert_buttons)
+                .with_context(|| format!("vjoy_device.{}.invert_buttons", k))?;
+            parse_rel_table(&d.rel).with_context(|| format!("vjoy_device.{}.rel", k))?;
+            if d.scan_codes && (d.raw || !d.buttons.is_empty()) {
+                bail!("vjoy_device.{}.scan_codes: only for numbered buttons", k);
+            }
+            if d.raw && !(d.hat_keys.is_empty() && d.invert_buttons.is_empty() && d.rel.is_empty())
+            {
+                bail!(
+                    "vjoy_device.{}: raw devices are mapped on the receiver, drop hat_keys, invert_buttons and rel",
+                    k
+                );
+            }
+            continue;
+        }
+
+        let own = d.select != Selector::default()
+            || d.force_feedback
+            || d.keyboard
+            || d.allow_full_keyboard
+            || d.raw
+            || d.grab
+            || !d.axis.is_empty()
+            || !d.buttons.is_empty()
+            || d.button_offset != 0
+            || d.scan_codes
+            || !d.hat_keys.is_empty()
+            || !d.invert_buttons.is_empty()
+            || !d.rel.is_empty();
+        if own {
+            bail!(
+                "vjoy_device.{}: with sources, everything but enabled goes in the sources",
+                k
+            );
+        }
+
+        // vJoy axis id -> source reading it
+        let mut slots: HashMap<u8, &str> = HashMap::new();
+        for (name, s) in d.sources.iter() {
+            let full = format!("vjoy_device.{}.sources.{}", k, name);
+            check_source(
+                &full,
+                &s.select,
+                s.keyboard,
+                false,
+                &s.axis,
+                &s.buttons,
+                s.button_offset,
+            )?;
+            parse_hat_keys(&s.hat_keys).with_context(|| format!("{}.hat_keys", full))?;
+            parse_key_list(&s.invert_buttons)
+                .with_context(|| format!("{}.invert_buttons", full))?;
+            parse_rel_table(&s.rel).with_context(|| format!("{}.rel", full))?;
+            if s.scan_codes && !s.buttons.is_empty() {
+                bail!("{}.scan_codes: only for numbered buttons", full);
+            }
+            for (id, cfg) in s.axis.iter() {
+                if cfg.code.is_none() {
+                    bail!("{}.axis.{}: needs the evdev axis code to read", full, id);
+                }
+                if let Some(other) = slots.insert(*id, name) {
+                    bail!(
+                        "vjoy_device.{}: sources {} and {} both feed axis {}",
+                        k,
+                        other,
+                        name,
+                        id
+                    );
+                }
+            }
+        }
+        if d.sources
+            .values()
+            .filter(|s| s.hat || !s.hat_keys.is_empty())
+            .count()
+            > 1
+        {
+            bail!("vjoy_device.{}: only one source can feed the hat", k);
+        }
+        if d.sources.values().filter(|s| s.force_feedback).count() > 1 {
+            bail!("vjoy_device.{}: only one source can take force feedback", k);
+        }
+    }
+
+    for k in config.uinput.iter() {
+        if !(1..=MAX_VJOY_DEVICES).contains(k) {
+            bail!("uinput: vjoy device {} not in 1..={}", k, MAX_VJOY_DEVICES);
+        }
+        if config.vjoy_device.get(k).is_some_and(|d| d.raw) {
+            bail!(
+                "uinput: vjoy device {} is raw, its mapping is on the receiver",
+                k
+            );
+        }
+    }
+    if !config.send && config.uinput.is_empty() {
+        bail!("send = false needs uinput devices, nothing would get the output");
+    }
+    if let Some(priority) = config.realtime_priority
+        && !(1..=99).contains(&priority)
+    {
+        bail!("realtime_priority {} not in 1..=99", priority);
+    }
+    if let Some(nice) = config.nice
+        && !(-20..=19).contains(&nice)
+    {
+        bail!("nice {} not in -20..=19", nice);
+    }
+    if config.dest.is_empty() {
+        bail!("dest: needs at least one");
+    }
+    if config.failover_dest.is_some() && config.dest.len() > 1 {
+        bail!("failover_dest: only with a single dest");
+    }
+    if let Some(idle_hz) = config.idle_hz
+        && !(1..config.send_hz).contains(&idle_hz)
+    {
+        bail!(
+            "idle_hz {} not in 1..{} (below send_hz)",
+            idle_hz,
+            config.send_hz
+        );
+    }
+    if config.realtime_priority.is_some() && config.nice.is_some() {
+        bail!("set realtime_priority or nice, niceness doesn't apply to realtime threads");
+    }
+    if config.group.is_some() && config.user.is_none() {
+        bail!("group: only together with user");
+    }
+    if let Some(name) = &config.active_profile
+        && !config.profile.contains_key(name)
+    {
+        bail!("active_profile: there is no profile {}", name);
+    }
+    if config.profile.contains_key(PROFILE_NONE) {
+        bail!(
+            "profile.{}: the name is taken, it switches profiles off",
+            PROFILE_NONE
+        );
+    }
+    if let Some(switch) = &config.profile_switch {
+        if switch.next.is_empty() && switch.select.is_empty() && switch.mode.is_none() {
+            bail!("profile_switch: needs next, select or mode");
+        }
+        for name in switch.mode.iter().flat_map(|m| m.profiles.iter()) {
+            if name != PROFILE_NONE && !config.profile.contains_key(name) {
+                bail!("profile_switch.mode: there is no profile {}", name);
+            }
+        }
+        switch.mode_source()?;
+        for (name, chord) in switch.select.iter() {
+            if name != PROFILE_NONE && !config.profile.contains_key(name) {
+                bail!("profile_switch.select: there is no profile {}", name);
+            }
+            if chord.is_empty() {
+                bail!("profile_switch.select.{}: needs buttons", name);
+            }
+        }
+        for b in switch.next.iter().chain(switch.select.values().flatten()) {
+            if !(1..=MAX_BUTTONS).contains(b) {
+                bail!("profile_switch: button {} not in 1..={}", b, MAX_BUTTONS);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_sources(config: &Config, config_path: &Path) -> Result<Vec<Source>> {
+    check_devices(config)?;
+
+    let mut sources: Vec<Source> = Vec::new();
+    for (k, d) in config.vjoy_device.iter() {
+        if d.sources.is_empty() {
+            sources.push(Source {
+                name: format!("vjoy_device.{}", k),
+                device_id: *k,
+                select: d.select.clone(),
+                force_feedback: d.force_feedback,
+                keyboard: d.keyboard,
+                allow_full_keyboard: d.allow_full_keyboard,
+                raw: d.raw,
+                grab: d.grab,
+                map: None,
+                default_axes: true,
+                hat: true,
+                axis: d.axis.clone(),
+                buttons: parse_button_table(&d.buttons)?,
+                button_offset: d.button_offset,
+                scan_codes: d.scan_codes,
+                hat_keys: parse_hat_keys(&d.hat_keys)?,
+                invert_buttons: parse_key_list(&d.invert_buttons)?,
+                rel: parse_rel_table(&d.rel)?,
+                rel_pulse: Duration::from_millis(d.rel_pulse_ms),
+            });
+            continue;
+        }
+
+        // Merged: each physical device brings its own axes, buttons and hat
+        for (name, s) in d.sources.iter() {
+            sources.push(Source {
+                name: format!("vjoy_device.{}.sources.{}", k, name),
+                device_id: *k,
+                select: s.select.clone(),
+                force_feedback: s.force_feedback,
+                keyboard: s.keyboard,
+                allow_full_keyboard: s.allow_full_keyboard,
+                raw: false,
+                grab: s.grab,
+                map: None,
+                default_axes: false,
+                hat: s.hat,
+                axis: s.axis.clone(),
+                buttons: parse_button_table(&s.buttons)?,
+                button_offset: s.button_offset,
+                scan_codes: s.scan_codes,
+                hat_keys: parse_hat_keys(&s.hat_keys)?,
+                invert_buttons: parse_key_list(&s.invert_buttons)?,
+                rel: parse_rel_table(&s.rel)?,
+                rel_pulse: Duration::from_millis(s.rel_pulse_ms),
+            });
+        }
+    }
+
+    if let Some(path) = &config.cockpit {
+        for source in cockpit::load(path)? {
+            if config.vjoy_device.contains_key(&source.device_id) {
+                bail!(
+                    "vjoy device {} is defined in both {} and {}",
+                    source.device_id,
+                    config_path.display(),
+                    path.display()
+                );
+            }
+            sources.push(source);
+        }
+    }
+
+    Ok(sources)
+}
+
+// --config / VKB_BRIDGE_CONFIG, or the first config file found on the search path
+fn config_path(args: &Args) -> Result<PathBuf> {
+    if let Some(path) = &args.config {
+        return Ok(path.clone());
+    }
+
+    let mut candidates = vec![PathBuf::from(CONFIG_FILE_NAME)];
+    let xdg = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = xdg {
+        candidates.push(dir.join("vkb-bridge").join(CONFIG_FILE_NAME));
+    }
+    candidates.push(Path::new("/etc/vkb-bridge").join(CONFIG_FILE_NAME));
+
+    match candidates.iter().find(|p| p.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => bail!(
+            "No config file found, looked for {} (or pass --config)",
+            candidates
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+// No devices listed: bridge every VKB device there is
+fn is_zero_config(config: &Config) -> bool {
+    config.vjoy_device.is_empty() && config.cockpit.is_none()
+}
+
+// One vjoy device per VKB device, numbered from 1 in USB port order
+fn detect_vkb_devices() -> Result<BTreeMap<u8, VJoyDevice>> {
+    let found: Vec<_> = detect::joysticks()
+        .into_iter()
+        .filter(|d| d.vendor_id == detect::VKB_VENDOR_ID)
+        .collect();
+    if found.is_empty() {
+        bail!("The config lists no devices and no VKB device is plugged in");
+    }
+
+    let mut devices = BTreeMap::new();
+    for (k, d) in (1..=MAX_VJOY_DEVICES).zip(found.iter()) {
+        info!(
+            "Auto-detected vjoy device {}: {} ({:04x}:{:04x}, {} axes, {} buttons, {})",
+            k,
+            d.name,
+            d.vendor_id,
+            d.product_id,
+            d.axes,
+            d.buttons,
+            d.path.display()
+        );
+        let select = Selector {
+            vendor_id: Some(d.vendor_id),
+            product_id: Some(d.product_id),
+            index: Some(d.index),
+            ..Default::default()
+        };
+        devices.insert(
+            k,
+            VJoyDevice {
+                select,
+                enabled: true,
+                force_feedback: false,
+                keyboard: false,
+                allow_full_keyboard: false,
+                raw: false,
+                grab: false,
+                axis: BTreeMap::new(),
+                buttons: BTreeMap::new(),
+                button_offset: 0,
+                scan_codes: false,
+                hat_keys: Vec::new(),
+                invert_buttons: Vec::new(),
+                rel: BTreeMap::new(),
+                rel_pulse_ms: default_rel_pulse_ms(),
+                sources: BTreeMap::new(),
+            },
+        );
+    }
+    for d in found.iter().skip(MAX_VJOY_DEVICES as usize) {
+        warn!("no vjoy device left for {} ({})", d.name, d.path.display());
+    }
+    Ok(devices)
+}
+
+// JSON (.json) or YAML (.yaml, .yml) for files written by other tools, TOML
+// otherwise
+fn decode<T: DeserializeOwned>(path: &Path, text: &str) -> Result<T> {
+    let decoded = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(text)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(text)?,
+        _ => toml::from_str(text)?,
+    };
+    Ok(decoded)
+}
+
+// Top level settings VKB_ variables can set. Text ones take the value as is,
+// VKB_NAME=123 stays a name, the others read it as JSON (numbers, true, lists)
+const ENV_TEXT: &[&str] = &[
+    "dest",
+    "failover_dest",
+    "persist_state",
+    "cockpit",
+    "user",
+    "group",
+    "metrics_listen",
+    "control_socket",
+    "shared_memory",
+    "active_profile",
+];
+const ENV_JSON: &[&str] = &[
+    "failover_secs",
+    "send_hz",
+    "broadcast",
+    "multicast_ttl",
+    "dscp",
+    "resolve_interval_secs",
+    "combine_devices",
+    "max_datagram",
+    "persist_buttons",
+    "uinput",
+    "send",
+    "realtime_priority",
+    "nice",
+    "cpu",
+    "precise_pacing",
+    "idle_hz",
+    "stats_secs",
+];
+
+// Top level settings from the environment by key, and the VKB_ variables
+// naming none
+fn env_overrides() -> (BTreeMap<String, serde_json::Value>, Vec<String>) {
+    let mut overrides = BTreeMap::new();
+    let mut unknown = Vec::new();
+    for (name, value) in std::env::vars() {
+        if name.starts_with(ENV_ARGS_PREFIX) {
+            continue;
+        }
+        let Some(key) = name.strip_prefix(ENV_PREFIX).map(str::to_lowercase) else {
+            continue;
+        };
+        let value = if ENV_TEXT.contains(&key.as_str()) {
+            // Several dests go as a JSON list
+            match serde_json::from_str(&value) {
+                Ok(list @ serde_json::Value::Array(_)) if key == "dest" => list,
+                _ => serde_json::Value::String(value),
+            }
+        } else if ENV_JSON.contains(&key.as_str()) {
+            serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value))
+        } else {
+            unknown.push(name);
+            continue;
+        };
+        overrides.insert(key, value);
+    }
+    (overrides, unknown)
+}
+
+// Logs what the environment overrode, once logging is up
+fn log_env_overrides() {
+    let (overrides, unknown) = env_overrides();
+    for (key, v) in overrides {
+        info!("{} = {} from {}{}", key, v, ENV_PREFIX, key.to_uppercase());
+    }
+    for name in unknown {
+        warn!("{} names no top level setting, ignored", name);
+    }
+}
+
+fn parse(path: &Path) -> Result<Config> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let (overrides, _) = env_overrides();
+    // Straight from the file when nothing is overridden, so errors keep
+    // their line numbers
+    let mut decoded: Config = match overrides.is_empty() {
+        true => decode(path, &text),
+        false => decode(path, &text).and_then(|mut value: serde_json::Value| {
+            let Some(table) = value.as_object_mut() else {
+                bail!("expected a table of settings");
+            };
+            table.extend(overrides);
+            serde_json::from_value(value).context("with the settings from VKB_ variables")
+        }),
+    }
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    // The cockpit file and scripts sit next to the config, wherever that is
+    // run from
+    if let Some(dir) = path.parent() {
+        let profiles = decoded.profile.values_mut().map(|p| &mut p.transform);
+        let scripts = [&mut decoded.transform]
+            .into_iter()
+            .chain(profiles)
+            .flat_map(|transforms| transforms.values_mut())
+            .filter_map(|t| t.script.as_mut());
+        for file in decoded.cockpit.iter_mut().chain(scripts) {
+            if file.is_relative() {
+                *file = dir.join(&*file);
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.list_devices {
+        print!("{}", detect::list_all());
+        return Ok(());
+    }
+
+    if let Some(Command::Ping {
+        addr,
+        count,
+        interval_ms,
+    }) = &args.command
+    {
+        let dest = receiver_addr(addr)?;
+        return ping::run(dest, *count, Duration::from_millis(*interval_ms));
+    }
+
+    if let Some(Command::Replay {
+        file,
+        dest,
+        speed,
+        send_hz,
+        repeat,
+    }) = &args.command
+    {
+        let dest = match dest {
+            Some(addr) => receiver_addr(addr)?,
+            None => {
+                let config = parse(&config_path(&args)?)?;
+                transport::resolve_dest(&config.dest[0])?
+            }
+        };
+        return replay::run(file, dest, *speed, *send_hz, *repeat);
+    }
+
+    if let Some(Command::GenerateConfig {
+        output,
+        dest,
+        all,
+        force,
+    }) = &args.command
+    {
+        return generate::run(output, dest.as_deref(), *all, *force);
+    }
+
+    if let Some(Command::Check) = &args.command {
+        return check::run(&args);
+    }
+
+    if let Some(Command::SetupUdev { output, user }) = &args.command {
+        let config_path = config_path(&args)?;
+        let config = parse(&config_path)?;
+        let devices = match is_zero_config(&config) {
+            // Whichever VKB devices are plugged in when the bridge starts
+            true => vec![(
+                "VKB devices".to_string(),
+                Selector {
+                    vendor_id: Some(detect::VKB_VENDOR_ID),
+                    ..Selector::default()
+                },
+            )],
+            false => build_sources(&config, &config_path)?
+                .into_iter()
+                .map(|s| (s.name, s.select))
+                .collect(),
+        };
+        let uinput = !config.uinput.is_empty() || args.backend == Backend::Uinput;
+        let user = user.as_deref().or(config.user.as_deref());
+        return udev::run(&devices, uinput, user, output.as_deref());
+    }
+
+    logging::init(
+        &args.log_level,
+        args.log_format,
+        args.monitor || args.dry_run,
+    )?;
+
+    // Devices, the socket, timers and the config file all wait on one thread
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let result = runtime.block_on(bridge(args));
+    // stdin is read on a blocking thread that never returns on its own
+    runtime.shutdown_background();
+    result
+}
+
+async fn bridge(args: Args) -> Result<()> {
+    let config_path = config_path(&args)?;
+    log_env_overrides();
+    let mut config = parse(&config_path)?;
+    info!("Using config {}: {:?}", config_path.display(), config);
+
+    if is_zero_config(&config) && !args.synthetic {
+        config.vjoy_device = detect_vkb_devices()?;
+    }
+
+    let mut sources = build_sources(&config, &config_path)?;
+    // Made up controls go out mapped like any others
+    if args.synthetic {
+        sources.iter_mut().for_each(|s| s.raw = false);
+    }
+
+    // Tasks on this thread read the devices and send
+    if let Err(e) = sched::apply(&config) {
+        warn!("{:#}", e);
+    }
+
+    config.monitor = args.monitor;
+    config.dry_run = args.dry_run;
+    config.record = args.record.clone();
+
+    // Loopback: nothing goes to dest, every device comes out here
+    if args.backend == Backend::Uinput {
+        for s in sources.iter().filter(|s| s.raw) {
+            warn!(
+                "{} is raw (mapped on the receiver), the uinput backend leaves it out",
+                s.name
+            );
+        }
+        config.uinput = sources
+            .iter()
+            .filter(|s| !s.raw)
+            .map(|s| s.device_id)
+            .collect();
+        config.send = false;
+    }
+    // A game reading the physical device would see it next to its uinput copy
+    for source in sources.iter_mut() {
+        source.grab |= config.uinput.contains(&source.device_id);
+    }
+
+    let (updates_tx, updates_rx) = mpsc::channel();
+    let mut states = States {
+        states: HashMap::new(),
+        updates: updates_rx,
+    };
+    for s in sources.iter() {
+        let st = states
+            .states
+            .entry(s.device_id)
+            .or_insert_with(|| DeviceState {
+                enabled: config
+                    .vjoy_device
+                    .get(&s.device_id)
+                    .is_none_or(|d| d.enabled),
+                ..Default::default()
+            });
+        set_axis_config(st, &s.axis);
+    }
+    if args.synthetic && states.states.is_empty() {
+        states.states.insert(
+            1,
+            DeviceState {
+                enabled: true,
+                ..Default::default()
+            },
+        );
+    }
+
+    let pipelines = build_pipelines(&config, &sources)?;
+
+    let button_store = match config.persist_buttons {
+        true => Some(Arc::new(ButtonStore::open(
+            &config_path.with_extension("buttons.toml"),
+        )?)),
+        false => None,
+    };
+    let devices = Devices {
+        updates: updates_tx,
+        states: Arc::default(),
+        // Filled in when the device is opened
+        raw_map: sources
+            .iter()
+            .filter(|s| s.raw)
+            .map(|s| (s.device_id, Arc::default()))
+            .collect(),
+        ffb_map: Arc::default(),
+        device_infos: Arc::default(),
+        claimed: Arc::default(),
+        stale: Arc::default(),
+        metrics: Arc::new(Metrics::new(
+            states.states.keys().copied(),
+            &config
+                .dest
+                .iter()
+                .chain(&config.failover_dest)
+                .collect::<Vec<_>>(),
+        )),
+        button_store,
+        paused: Arc::default(),
+        sent: Arc::default(),
+        enabled: Arc::new(
+            states
+                .states
+                .iter()
+                .map(|(k, st)| (*k, tokio::sync::watch::Sender::new(st.enabled)))
+                .collect(),
+        ),
+    };
+    let dumping = args.dump_effective_config.is_some();
+
+    // Identical sticks go by USB port order, which moves when they are replugged
+    // into each other's ports
+    for (i, a) in sources.iter().enumerate() {
+        for b in sources[i + 1..].iter() {
+            if a.name != b.name && a.select == b.select && !a.select.is_unique() {
+                warn!(
+                    "{} and {} match the same devices ({}), set serial, path or index to pin each one",
+                    a.name, b.name, a.select
+                );
+            }
+        }
+    }
+
+    let mut inputs = Vec::with_capacity(sources.len());
+    for source in sources.iter().filter(|_| !args.synthetic) {
+        let opened = match open_vkb_device(source, &devices.claimed) {
+            Ok((path, dev)) => {
+                let map = attach(source, &path, &dev, &devices, !dumping)?;
+                Some((path, dev, map))
+            }
+            // Its input task opens it once it's plugged in
+            Err(e) if !dumping => {
+                set_stale(&devices, source, true);
+                warn!(
+                    "{}: {:#}, waiting for it (if it is plugged in, check permissions on /dev/input/event*)",
+                    source.name, e
+                );
+                None
+            }
+            Err(e) => {
+                return Err(e)
+                    .context("Could not open VKB device. Check permissions (/dev/input/event*)");
+            }
+        };
+
+        inputs.push((source.clone(), opened));
+    }
+
+    // What attaching the devices found
+    states.apply_pending();
+    *devices.states.lock().unwrap() = states.states.clone();
+
+    if let Some(path) = &args.dump_effective_config {
+        let device_infos = devices.device_infos.read().unwrap();
+        effective::write_effective_config(path, &config, &device_infos, &states.states)?;
+        info!("Wrote effective config to {}", path.display());
+        return Ok(());
+    }
+
+    // Tasks A: input readers, one per source and started again should one die,
+    // looking again at what is missing when nodes appear in /dev/input
+    let (plugged_tx, plugged) = tokio::sync::watch::channel(());
+    match watch::Watcher::dir(Path::new("/dev/input")) {
+        _ if args.synthetic => {}
+        Ok(watcher) => {
+            task::spawn(async move {
+                if let Err(e) = plug_task(watcher, plugged_tx).await {
+                    warn!("{:#}, looking for missing devices every few seconds", e);
+                }
+            });
+        }
+        Err(e) => warn!("{:#}, looking for missing devices every few seconds", e),
+    }
+    for (source, opened) in inputs {
+        task::spawn(supervise_input(
+            source,
+            opened,
+            devices.clone(),
+            plugged.clone(),
+        ));
+    }
+    if args.synthetic {
+        info!("Synthetic input: no device is opened, every vjoy device moves on its own");
+        for k in states.states.keys() {
+            task::spawn(synthetic::run(StateTx {
+                device_id: *k,
+                tx: devices.updates.clone(),
+            }));
+        }
+    }
+
+    let fingerprints = fingerprint::fingerprints(&config, &devices.device_infos.read().unwrap());
+    for (k, fp) in fingerprints.iter() {
+        info!("vjoy device {}: layout fingerprint {:016x}", k, fp);
+    }
+
+    // A dry run opens no sockets, nothing is sent nor comes back
+    let links = match config.dry_run {
+        true => {
+            info!("Dry run: datagrams go to stdout instead of dest");
+            Vec::new()
+        }
+        false => config
+            .dest
+            .iter()
+            .chain(&config.failover_dest)
+            .map(|dest| transport::open(dest, &config))
+            .collect::<Result<Vec<Link>>>()?,
+    };
+
+    for (k, st) in states.states.iter() {
+        let count = pipelines
+            .get(k)
+            .map_or(0, |p| p.button_count())
+            .max(st.button_count);
+        if count > V2_BUTTONS {
+            info!(
+                "vjoy device {}: {} buttons, those past {} only reach a receiver that takes them",
+                k, count, V2_BUTTONS
+            );
+        }
+    }
+
+    let handshake = Arc::new(Handshake {
+        epoch: Instant::now(),
+        receiver_buttons: AtomicU16::new(V2_BUTTONS),
+        heard: links.iter().map(|_| AtomicU64::new(0)).collect(),
+        failed: links.iter().map(|_| AtomicU64::new(0)).collect(),
+    });
+
+    // Tasks C: packets coming back from each receiver (status, force feedback,
+    // snapshot requests, pings)
+    let names = config.dest.iter().chain(&config.failover_dest);
+    for (i, (link, name)) in links.iter().zip(names).enumerate() {
+        let return_link = link.try_clone()?;
+        let devices = devices.clone();
+        let handshake = Arc::clone(&handshake);
+        let span = info_span!("return", dest = %name);
+        task::spawn(
+            async move {
+                if let Err(e) = return_task(return_link, i, devices, handshake).await {
+                    error!("return channel error: {:#}", e);
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    // The config as currently applied, follows reloads
+    let live = Arc::new(Mutex::new(config.clone()));
 
-const CONFIG_FILE_PATH: &str = "config.toml";
-
-const AXIS_CODES: [AbsoluteAxisCode; 8] = [
-    AbsoluteAxisCode::ABS_X,
-    AbsoluteAxisCode::ABS_Y,
-    AbsoluteAxisCode::ABS_Z,
-    AbsoluteAxisCode::ABS_RX,
-    AbsoluteAxisCode::ABS_RY,
-    AbsoluteAxisCode::ABS_RZ,
-    AbsoluteAxisCode::ABS_THROTTLE,
-    AbsoluteAxisCode::ABS_RUDDER,
-];
+    // Task G: applies edits to the config file while running
+    let (reload_tx, reload_rx) = mpsc::channel();
+    let source_count = sources.len();
+    let switch_tx = reload_tx.clone();
+    let soft = match config.persist_state {
+        Some(persist) => Some(persist::SoftStore::open(
+            &config_path.with_extension("state.toml"),
+            persist == PersistState::PerProfile,
+        )?),
+        None => None,
+    };
+    let reloads = Reloads {
+        rx: reload_rx,
+        tx: reload_tx.clone(),
+        live: Arc::clone(&live),
+        soft,
+    };
+    match watch::Watcher::file(&config_path) {
+        Ok(watcher) => {
+            let live = Arc::clone(&live);
+            task::spawn(async move {
+                let reload = reload_task(watcher, config_path, sources, live, reload_tx);
+                if let Err(e) = reload.await {
+                    error!("config reload error: {:#}", e);
+                }
+            });
+        }
+        Err(e) => warn!("config changes need a restart: {:#}", e),
+    }
 
-const VJOY_AXIS_MAX: u16 = 0x8000; // 32768
+    // Task I: readiness and status for systemd
+    if systemd::notifying() {
+        let stale = Arc::clone(&devices.stale);
+        let live = Arc::clone(&live);
+        task::spawn(systemd_task(source_count, stale, live));
+    }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Config {
-    dest: SocketAddr,
-    send_hz: u16,
-    vjoy_device: BTreeMap<u8, VJoyDevice>,
-}
+    // Task D: console commands (pause, enable/disable devices, switch profiles,
+    // dump config at runtime), on stdin and the control socket
+    let commands = control::Commands {
+        config: Arc::clone(&live),
+        devices: devices.clone(),
+        reload: switch_tx,
+    };
+    if let Some(path) = &config.control_socket {
+        let listener = control::bind(path, config.user.as_deref())?;
+        info!("Taking commands on {}", path.display());
+        let commands = commands.clone();
+        task::spawn(async move {
+            if let Err(e) = control::serve(listener, commands).await {
+                error!("control socket error: {:#}", e);
+            }
+        });
+    }
+    task::spawn(async move {
+        if let Err(e) = console_task(commands).await {
+            error!("console error: {:#}", e);
+        }
+    });
 
-#[derive(Debug, Deserialize, Serialize)]
-struct VJoyDevice {
-    vendor_id: u16,
-    product_id: u16,
+    // Task H: Prometheus metrics and the JSON status API over HTTP
+    if let Some(addr) = config.metrics_listen {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen for metrics on {}", addr))?;
+        info!(
+            "Serving metrics on http://{}/metrics, status on /status, /devices and /profile",
+            addr
+        );
+        let devices = devices.clone();
+        let live = Arc::clone(&live);
+        task::spawn(async move {
+            if let Err(e) = metrics::serve(listener, devices, live).await {
+                error!("metrics server error: {:#}", e);
+            }
+        });
+    }
+
+    // Task B: sender
+    let control_socket = config.control_socket.clone();
+    let sent = sender_task(
+        links, config, devices, states, pipelines, handshake, reloads,
+    )
+    .instrument(info_span!("sender"))
+    .await;
+    if let Some(path) = control_socket {
+        let _ = fs::remove_file(path);
+    }
+    sent
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-struct AxisRange {
-    min: i32,
-    max: i32,
+// A receiver given on the command line, on the bridge port when none is given
+fn receiver_addr(addr: &str) -> Result<SocketAddr> {
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, DEFAULT_PORT)),
+        Err(_) if !addr.contains(':') => {
+            transport::resolve_dest(&format!("{}:{}", addr, DEFAULT_PORT))
+        }
+        Err(_) => transport::resolve_dest(addr),
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct SharedState {
-    axis_range: [AxisRange; 8],
-    axes_raw: [i32; 8],
-    hat_x: i8,
-    hat_y: i8,
-    buttons: [u8; 16], // 128 bits
-    revision: u64,
+// Axis settings that apply while normalizing, by vJoy axis id
+fn set_axis_config(st: &mut DeviceState, axis: &BTreeMap<u8, AxisConfig>) {
+    for (id, cfg) in axis.iter() {
+        st.axis_invert[*id as usize - 1] = cfg.invert;
+    }
 }
 
-impl Default for SharedState {
-    fn default() -> Self {
-        Self {
-            axis_range: [AxisRange::default(); 8],
-            axes_raw: [0; 8],
-            hat_x: 0,
-            hat_y: 0,
-            buttons: [0; 16],
-            revision: 0,
+// Checks every transform, those of profiles not active too, and what
+// profile_switch reads, and builds the active transforms
+fn build_pipelines(config: &Config, sources: &[Source]) -> Result<HashMap<u8, Pipeline>> {
+    let profiles = config
+        .profile
+        .iter()
+        .map(|(name, p)| (format!("profile.{}.transform", name), &p.transform));
+    for (table, transforms) in [("transform".to_string(), &config.transform)]
+        .into_iter()
+        .chain(profiles)
+    {
+        for (k, cfg) in transforms.iter() {
+            if !sources.iter().any(|s| s.device_id == *k) {
+                bail!("{}.{}: there is no vjoy device {}", table, k, k);
+            }
+            if sources.iter().any(|s| s.device_id == *k && s.raw) {
+                bail!("{}.{}: raw devices are mapped on the receiver", table, k);
+            }
+            Pipeline::new(cfg).with_context(|| format!("Invalid {}.{}", table, k))?;
         }
     }
+    if let Some(switch) = &config.profile_switch
+        && !sources
+            .iter()
+            .any(|s| s.device_id == switch.device && !s.raw)
+    {
+        bail!(
+            "profile_switch: there is no vjoy device {}, or it is raw",
+            switch.device
+        );
+    }
+    active_pipelines(config)
 }
 
-fn open_vkb_device(target_vendor: u16, target_product: u16) -> Result<Device> {
-    for (_path, dev) in evdev::enumerate() {
-        let id = dev.input_id();
-        if id.vendor() == target_vendor && id.product() == target_product {
-            return Ok(dev);
-        }
+// transform, with the devices of the active profile in place of their own
+fn active_transforms(config: &Config) -> BTreeMap<u8, &TransformConfig> {
+    let mut transforms: BTreeMap<u8, &TransformConfig> =
+        config.transform.iter().map(|(k, t)| (*k, t)).collect();
+    if let Some(profile) = config
+        .active_profile
+        .as_ref()
+        .and_then(|name| config.profile.get(name))
+    {
+        transforms.extend(profile.transform.iter().map(|(k, t)| (*k, t)));
     }
-    bail!(
-        "Device not found for vendor={:04x} product={:04x}",
-        target_vendor,
-        target_product
-    )
+    transforms
 }
 
-fn parse() -> Result<Config> {
-    let toml_str =
-        fs::read_to_string(CONFIG_FILE_PATH).with_context(|| "Failed to read config file")?;
-    let decoded: Config =
-        toml::from_str(&toml_str).with_context(|| "Failed to parse config.toml")?;
+fn active_pipelines(config: &Config) -> Result<HashMap<u8, Pipeline>> {
+    active_transforms(config)
+        .into_iter()
+        .map(|(k, cfg)| Ok((k, Pipeline::new(cfg)?)))
+        .collect()
+}
 
-    Ok(decoded)
+// A config edit that passed validation, applied by the sender between ticks
+struct Reload {
+    config: Config,
+    pipelines: HashMap<u8, Pipeline>,
 }
 
-fn main() -> Result<()> {
-    let config = parse()?;
-    println!("Using config: {:?}", config);
-    println!("Sending UDP to {}", config.dest);
-
-    let shared_map: HashMap<u8, Arc<Mutex<SharedState>>> = config
-        .vjoy_device
-        .keys()
-        .map(|k| (*k, Arc::new(Mutex::new(SharedState::default()))))
-        .collect();
+// The sender's end: edits and console profile switches come in on rx, its own
+// profile switches go out on tx the same way the console's do
+struct Reloads {
+    rx: Receiver<Reload>,
+    tx: Sender<Reload>,
+    live: Arc<Mutex<Config>>,
+    // With persist_state, toggles and trims carried over to the new transforms
+    soft: Option<persist::SoftStore>,
+}
 
-    for (k, vjoy_device) in config.vjoy_device.iter() {
-        let dev = open_vkb_device(vjoy_device.vendor_id, vjoy_device.product_id)
-            .with_context(|| "Could not open VKB device. Check permissions (/dev/input/event*)")?;
+async fn reload_task(
+    mut watcher: watch::Watcher,
+    path: PathBuf,
+    sources: Vec<Source>,
+    live: Arc<Mutex<Config>>,
+    tx: Sender<Reload>,
+) -> Result<()> {
+    loop {
+        watcher.changed().await?;
 
-        println!("Using device: {}", dev.name().unwrap_or("<no name>"));
+        // A broken edit keeps the running config
+        let mut config = match parse(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Config not reloaded: {:#}", e);
+                continue;
+            }
+        };
+        // A profile switched to stays, unless the edit removed it.
+        // active_profile in the file is the one to start with
+        {
+            let current = live.lock().unwrap();
+            match &current.active_profile {
+                Some(name) if !config.profile.contains_key(name) => {
+                    warn!(
+                        "profile {} is gone from the config, back to {}",
+                        name,
+                        config.active_profile.as_deref().unwrap_or(PROFILE_NONE)
+                    );
+                }
+                active => config.active_profile = active.clone(),
+            }
+        }
+        let pipelines =
+            match check_devices(&config).and_then(|()| build_pipelines(&config, &sources)) {
+                Ok(pipelines) => pipelines,
+                Err(e) => {
+                    warn!("Config not reloaded: {:#}", e);
+                    continue;
+                }
+            };
 
-        // Stable key mapping: KeyCode -> button index (1..=128)
-        let button_map = build_button_map(&dev)?;
+        let mut current = live.lock().unwrap();
+        config.monitor = current.monitor;
+        config.dry_run = current.dry_run;
+        config.record = current.record.clone();
+        // Auto-detected devices stay as found at startup
+        if is_zero_config(&config) && config.cockpit == current.cockpit {
+            config.vjoy_device = current.vjoy_device.clone();
+        }
+        if config == *current {
+            continue;
+        }
 
-        // Axis ranges for normalization (from kernel abs info)
-        let axis_ranges = build_axis_ranges(&dev)?;
+        // Devices, sockets and the cockpit are set up once, the rest applies live
+        let mut applied = current.clone();
+        applied.dest = config.dest.clone();
+        applied.send_hz = config.send_hz;
+        applied.precise_pacing = config.precise_pacing;
+        applied.idle_hz = config.idle_hz;
+        applied.stats_secs = config.stats_secs;
+        applied.combine_devices = config.combine_devices;
+        applied.max_datagram = config.max_datagram;
+        applied.transform = config.transform.clone();
+        applied.profile = config.profile.clone();
+        applied.active_profile = config.active_profile.clone();
+        applied.profile_switch = config.profile_switch.clone();
+        for (k, dev) in applied.vjoy_device.iter_mut() {
+            if let Some(new) = config.vjoy_device.get(k) {
+                dev.enabled = new.enabled;
+                // Which code feeds an axis and its travel are set when the
+                // device is opened, only invert applies live
+                let mut axis: BTreeMap<u8, AxisConfig> = new
+                    .axis
+                    .iter()
+                    .map(|(id, cfg)| {
+                        let live = AxisConfig {
+                            invert: cfg.invert,
+                            ..Default::default()
+                        };
+                        (*id, live)
+                    })
+                    .collect();
+                for (id, cfg) in dev.axis.iter() {
+                    let opened = AxisConfig {
+                        invert: false,
+                        ..cfg.clone()
+                    };
+                    if opened != AxisConfig::default() {
+                        let entry = axis.entry(*id).or_default();
+                        *entry = AxisConfig {
+                            invert: entry.invert,
+                            ..opened
+                        };
+                    }
+                }
+                dev.axis = axis;
+            }
+        }
+        if applied != config {
+            warn!("some config changes (devices, cockpit, socket options) need a restart");
+        }
+        if applied == *current {
+            continue;
+        }
 
-        // Thread A: input reader
+        *current = applied.clone();
+        if tx
+            .send(Reload {
+                config: applied,
+                pipelines,
+            })
+            .is_err()
         {
-            let shared = Arc::clone(&shared_map.get(k).unwrap());
-            {
-                shared.lock().unwrap().axis_range = axis_ranges;
-            }
-            thread::spawn(move || {
-                if let Err(e) = input_thread(dev, shared, button_map) {
-                    eprintln!("input thread error: {:#}", e);
-                }
-            });
+            return Ok(());
         }
     }
+}
 
-    // Thread B: sender
-    sender_thread(config, shared_map)?;
+// Sets up a device that was just opened: its mapping, axis ranges, raw state,
+// force feedback and what the dumps show. Returns what its input is read with
+fn attach(
+    source: &Source,
+    path: &Path,
+    dev: &Device,
+    devices: &Devices,
+    start_ffb: bool,
+) -> Result<InputMap> {
+    let k = &source.device_id;
 
-    Ok(())
-}
+    info!(
+        "Using device: {} ({})",
+        dev.name().unwrap_or("<no name>"),
+        source.name
+    );
 
-fn build_button_map(dev: &Device) -> Result<HashMap<KeyCode, u8>> {
-    let mut keys: Vec<KeyCode> = dev.supported_keys().into_iter().flatten().collect();
+    // Stable key mapping: KeyCode -> button index (1..=256), axes to slots
+    let mut map = build_input_map(dev, source)?;
+    // Numbered buttons only, a table or cockpit bindings are fixed already
+    if let Some(store) = &devices.button_store
+        && source.map.is_none()
+        && source.buttons.is_empty()
+    {
+        store.apply(&source.name, &mut map.buttons)?;
+    }
+
+    // Axis ranges for normalization (from kernel abs info)
+    let axis_ranges =
+        build_axis_ranges(dev, &map, &source.axis).with_context(|| match source.map {
+            None => "Set keyboard = true for devices without axes".to_string(),
+            Some(_) => format!("{} lacks an axis bound in the cockpit file", source.name),
+        })?;
+
+    if let Some(raw) = devices.raw_map.get(k) {
+        *raw.lock().unwrap() = RawState::new(dev)?;
+    }
 
-    keys.sort_by_key(|k| k.code());
+    {
+        // Axes start where they are, not centered until moved
+        let absinfo: HashMap<AbsoluteAxisCode, AbsInfo> = dev.get_absinfo()?.collect();
+        let axes = axis_ranges
+            .iter()
+            .map(|(slot, code, r)| (*slot, *r, absinfo.get(code).map_or(0, |info| info.value())))
+            .collect();
+        // Inverted keys at rest read pressed, and no event says so until moved
+        let keys_down = dev.get_key_state()?;
+        let pressed = map
+            .inverted
+            .iter()
+            .filter(|key| !keys_down.contains(**key))
+            .filter_map(|key| map.buttons.get(key).copied())
+            .collect();
+        let rel_buttons = map.rel.values().flat_map(|b| [b.up, b.down]);
+        let count = map
+            .buttons
+            .values()
+            .copied()
+            .chain(rel_buttons)
+            .max()
+            .unwrap_or(0);
+        let update = Update::Attach {
+            axes,
+            pressed,
+            button_count: count,
+        };
+        let _ = devices.updates.send((*k, update));
+    }
 
-    let mut map = HashMap::new();
-    let mut idx: u16 = 1; // 1-based button ids
+    {
+        let mut device_infos = devices.device_infos.write().unwrap();
+        let infos = device_infos.entry(*k).or_default();
+        infos.retain(|info| info.source != source.name);
+        infos.push(DeviceInfo {
+            source: source.name.clone(),
+            name: dev.name().unwrap_or("<no name>").to_string(),
+            path: path.to_path_buf(),
+            button_map: map.buttons.clone(),
+            axes: axis_ranges,
+        });
+        // Same order however the devices were plugged in, the fingerprint
+        // depends on it
+        infos.sort_by(|a, b| a.source.cmp(&b.source));
+    }
 
-    for k in keys {
-        if idx > 128 {
-            break;
+    // Thread E: force feedback, on its own handle to the device
+    if start_ffb && source.force_feedback {
+        if dev.supported_ff().is_some() {
+            let ff_dev = Device::open(path)
+                .with_context(|| format!("Could not open {} for force feedback", path.display()))?;
+            let (tx, rx) = mpsc::channel();
+            // Replacing the old sender ends the thread of a previous plug-in
+            devices.ffb_map.lock().unwrap().insert(*k, tx);
+            let device_id = *k;
+            thread::spawn(move || ffb::ffb_thread(ff_dev, device_id, rx));
+        } else {
+            warn!("device {} has no force feedback support", k);
         }
-        map.insert(k, idx as u8);
-        idx += 1;
     }
 
     Ok(map)
 }
 
-fn build_axis_ranges(dev: &Device) -> Result<[AxisRange; 8]> {
-    // Build a lookup table from the iterator returned by get_absinfo()
-    let absinfo_map: HashMap<AbsoluteAxisCode, AbsInfo> = dev.get_absinfo()?.collect();
+// Task A's supervisor: a reader that dies (it panicked) has its device released
+// and is started again, a little later each time it keeps dying
+async fn supervise_input(
+    source: Source,
+    mut opened: Option<Attached>,
+    devices: Devices,
+    plugged: tokio::sync::watch::Receiver<()>,
+) {
+    let mut backoff = RESTART_MIN;
+    loop {
+        let held = Arc::new(Mutex::new(None));
+        let started = Instant::now();
+        let span = info_span!("input", source = %source.name, device = source.device_id);
+        let reader = task::spawn(
+            input_task(
+                source.clone(),
+                opened.take(),
+                devices.clone(),
+                plugged.clone(),
+                held.clone(),
+            )
+            .instrument(span),
+        );
+        let Err(e) = reader.await else {
+            return;
+        };
+
+        set_stale(&devices, &source, true);
+        if let Some((path, map)) = held.lock().unwrap().take() {
+            let state = StateTx {
+                device_id: source.device_id,
+                tx: devices.updates.clone(),
+            };
+            let raw = devices.raw_map.get(&source.device_id);
+            detach(&source, &path, &map, &devices, &state, raw.map(|r| &**r));
+        }
+
+        // Dying again right away waits longer
+        if started.elapsed() > RESTART_MAX {
+            backoff = RESTART_MIN;
+        }
+        error!(
+            "Reading {} stopped ({}), sending it neutral and starting again in {}s",
+            source.name,
+            e,
+            backoff.as_secs()
+        );
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RESTART_MAX);
+    }
+}
+
+// Task A: reads one source. Unplugged, its controls go neutral and it is
+// opened again once it comes back. What it holds is in held, for the supervisor
+// to release should it die
+async fn input_task(
+    source: Source,
+    mut opened: Option<Attached>,
+    devices: Devices,
+    mut plugged: tokio::sync::watch::Receiver<()>,
+    held: Arc<Mutex<Option<(PathBuf, InputMap)>>>,
+) {
+    let k = source.device_id;
+    let state = StateTx {
+        device_id: k,
+        tx: devices.updates.clone(),
+    };
+    let raw = devices.raw_map.get(&k).cloned();
+    let mut enabled = devices.enabled[&k].subscribe();
+    // Open failures are reported once per absence
+    let mut reported = false;
+
+    loop {
+        if opened.is_none() {
+            opened = reopen(&source, &devices, &mut reported);
+        }
+
+        let Some((path, dev, map)) = opened.take() else {
+            set_stale(&devices, &source, true);
+            // A node appearing in /dev/input, or another look every few seconds
+            tokio::select! {
+                Ok(()) = plugged.changed() => {}
+                _ = time::sleep(RECONNECT_INTERVAL) => {}
+            }
+            continue;
+        };
+
+        reported = false;
+        *held.lock().unwrap() = Some((path.clone(), map.clone()));
+        set_stale(&devices, &source, false);
+        let events_read = |n| devices.metrics.events_read(state.device_id, n);
+        let reading = read_input(
+            &source,
+            dev,
+            &map,
+            &state,
+            raw.as_deref(),
+            &events_read,
+            &mut enabled,
+        );
+        if let Err(e) = reading.await {
+            warn!("{} is gone ({:#}), sending it neutral", source.name, e);
+        }
+        *held.lock().unwrap() = None;
+        detach(&source, &path, &map, &devices, &state, raw.as_deref());
+    }
+}
+
+// Opens and attaches a missing source, None while it can't be
+fn reopen(source: &Source, devices: &Devices, reported: &mut bool) -> Option<Attached> {
+    let (path, dev) = open_vkb_device(source, &devices.claimed).ok()?;
+    match attach(source, &path, &dev, devices, true) {
+        Ok(map) => Some((path, dev, map)),
+        // Once per absence, it is retried on every change in /dev/input
+        Err(e) => {
+            devices.claimed.lock().unwrap().remove(&path);
+            if !*reported {
+                warn!("Could not open {}: {:#}", source.name, e);
+                *reported = true;
+            }
+            None
+        }
+    }
+}
 
-    let mut out = [AxisRange::default(); 8];
+// A source no longer read: its controls go neutral, its node is free for
+// another source and its force feedback ends
+fn detach(
+    source: &Source,
+    path: &Path,
+    map: &InputMap,
+    devices: &Devices,
+    state: &StateTx,
+    raw: Option<&Mutex<RawState>>,
+) {
+    release(state, map, raw);
+    devices.claimed.lock().unwrap().remove(path);
+    if source.force_feedback {
+        devices.ffb_map.lock().unwrap().remove(&source.device_id);
+    }
+}
+
+fn set_stale(devices: &Devices, source: &Source, stale: bool) {
+    let mut stale_sources = devices.stale.lock().unwrap();
+    let key = (source.device_id, source.name.clone());
+    if stale {
+        stale_sources.insert(key);
+    } else {
+        stale_sources.remove(&key);
+    }
+}
 
-    for (i, code) in AXIS_CODES.iter().enumerate() {
-        let info = absinfo_map
-            .get(code)
-            .with_context(|| format!("Missing AbsInfo for {:?}", code))?;
+// Task I: tells systemd the sender is ready once every source is read (or
+// READY_WAIT passed with some still missing), then how many are as they come
+// and go. Runs on the thread the sender does, so its watchdog pings stop if
+// that one hangs
+async fn systemd_task(
+    total: usize,
+    stale: Arc<Mutex<BTreeSet<(u8, String)>>>,
+    live: Arc<Mutex<Config>>,
+) {
+    let watchdog = systemd::watchdog_interval();
+    let every = watchdog.map_or(STATUS_INTERVAL, |w| w.min(STATUS_INTERVAL));
+    let started = Instant::now();
+    let mut ready = false;
+    let mut last_status = String::new();
 
-        out[i] = AxisRange {
-            min: info.minimum(),
-            max: info.maximum(),
+    loop {
+        let connected = total.saturating_sub(stale.lock().unwrap().len());
+        let dest = match live.lock().unwrap() {
+            config if config.dry_run => "stdout (dry run)".to_string(),
+            config => config.dest.join(", "),
         };
+        let status = format!(
+            "{} of {} devices connected, sending to {}",
+            connected, total, dest
+        );
+        if status != last_status {
+            systemd::notify(&format!("STATUS={}", status));
+            last_status = status;
+        }
+        if !ready && (connected == total || started.elapsed() >= READY_WAIT) {
+            systemd::notify("READY=1");
+            ready = true;
+        }
+        if watchdog.is_some() {
+            systemd::notify("WATCHDOG=1");
+        }
+        time::sleep(every).await;
     }
+}
 
-    Ok(out)
+// Wakes the input tasks when nodes appear in /dev/input, or udev fixes their
+// permissions
+async fn plug_task(
+    mut watcher: watch::Watcher,
+    plugged: tokio::sync::watch::Sender<()>,
+) -> Result<()> {
+    loop {
+        watcher.changed().await?;
+        plugged.send_replace(());
+    }
 }
 
-fn input_thread(
-    mut dev: Device,
-    shared: Arc<Mutex<SharedState>>,
-    button_map: HashMap<KeyCode, u8>,
+// A device opened for a source and attached, not read yet
+type Attached = (PathBuf, Device, InputMap);
+
+async fn sender_task(
+    links: Vec<Link>,
+    config: Config,
+    devices: Devices,
+    mut states: States,
+    mut pipelines: HashMap<u8, Pipeline>,
+    handshake: Arc<Handshake>,
+    mut reloads: Reloads,
 ) -> Result<()> {
+    let Devices {
+        states: published,
+        raw_map,
+        device_infos,
+        stale: stale_sources,
+        metrics,
+        paused,
+        sent,
+        enabled: read_enabled,
+        ..
+    } = devices;
+    let mut config = config;
+    let mut period = send_period(&config);
+    let mut ticks = pacing::Ticks::new(period, config.precise_pacing)?;
+    let mut pacing_stats = pacing::Stats::new();
+
+    let mut joysticks: HashMap<u8, uinput::Joystick> = HashMap::new();
+    for k in config.uinput.iter() {
+        if !states.states.contains_key(k) {
+            bail!("uinput: there is no vjoy device {}", k);
+        }
+        let joystick = uinput::Joystick::create(*k).with_context(|| {
+            format!(
+                "vjoy device {}: could not create its uinput joystick, check permissions on /dev/uinput",
+                k
+            )
+        })?;
+        joysticks.insert(*k, joystick);
+        info!("vjoy device {}: also a local joystick", k);
+    }
+
+    // What needs root is open now: devices, sockets, uinput joysticks
+    if let Some(user) = &config.user {
+        privs::drop_to(user, config.group.as_deref())?;
+    }
+
+    // Made as the user, so it can still remove it on the way out
+    let export = match &config.shared_memory {
+        Some(name) => {
+            let ids: Vec<u8> = states.states.keys().copied().collect();
+            let raw: Vec<u8> = raw_map.keys().copied().collect();
+            let export = shm::Export::create(name, &ids, &raw)?;
+            info!("Exporting state to {}", export.path().display());
+            Some(export)
+        }
+        None => None,
+    };
+    let mut recorder = match &config.record {
+        Some(path) => {
+            let recorder = record::Recorder::open(path)?;
+            info!("Recording to {}", path.display());
+            Some(recorder)
+        }
+        None => None,
+    };
+    let mut record_failing = false;
+    if let Some(soft) = &reloads.soft {
+        soft.restore(profile_key(&config), &mut pipelines);
+        info!("Toggles and trims kept in {}", soft.path().display());
+    }
+
+    let mut seqs: HashMap<u8, u16> = states.states.keys().map(|&k| (k, 0u16)).collect();
+    let mut was_enabled: HashMap<u8, bool> = states.states.keys().map(|&k| (k, true)).collect();
+    // Per dest, each one reported on its own
+    let mut send_failing = vec![false; links.len()];
+    let mut failover = config
+        .failover_dest
+        .as_ref()
+        .filter(|_| !config.dry_run)
+        .map(|_| failover::Failover::new(Duration::from_secs(config.failover_secs)));
+    let mut uinput_failing = false;
+    // Buttons as last sent per device
+    let mut last_buttons: HashMap<u8, [u8; 32]> = HashMap::new();
+
+    let mut packets: Vec<Vec<u8>> = Vec::with_capacity(states.states.len());
+    // Devices with a packet this tick, their seq moves on once it is sent
+    let mut numbered: Vec<u8> = Vec::with_capacity(states.states.len());
+    // The tick before, to tell when nothing changes
+    let mut last_packets: Vec<Vec<u8>> = Vec::new();
+    let mut last_change = Instant::now();
+    let mut last_send = Instant::now();
+    let mut last_tick: Option<Instant> = None;
+    let mut monitor = config.monitor.then(Monitor::new);
+    // Counters at the last stats line
+    let mut stats_since = metrics.totals();
+    let mut batch_seq: u16 = 0;
+
+    // RTT measurement (answered by the return task) and layout hello
+    let mut ping = [0u8; STATE_PKT_LEN];
+    let mut ping_seq: u16 = 0;
+    let mut next_ping = Instant::now();
+    // profile_switch's device as of the last tick
+    let mut switch_buttons = [0u8; 32];
+    // Its mode selector, and the position it last switched for
+    let mut switch_mode = switch_mode_source(&config);
+    let mut mode_position: Option<u16> = None;
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
     loop {
-        for ev in dev.fetch_events()? {
-            match ev.destructure() {
-                EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_HAT0X, value) => {
-                    let mut st = shared.lock().unwrap();
-                    let v = value.clamp(-1, 1) as i8;
-                    if st.hat_x != v {
-                        st.hat_x = v;
-                        st.revision = st.revision.wrapping_add(1);
-                    }
+        tokio::select! {
+            tick = ticks.tick() => tick?,
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+        let now = Instant::now();
+        if let Some(last) = last_tick {
+            metrics.tick(now.duration_since(last).abs_diff(period));
+        }
+        last_tick = Some(now);
+        if let Some(secs) = config.stats_secs
+            && stats_since.age() >= Duration::from_secs(secs.max(1))
+        {
+            let totals = metrics.totals();
+            info!("{}", totals.report_since(&stats_since));
+            stats_since = totals;
+        }
+        if config.precise_pacing {
+            pacing_stats.tick(now, period);
+            if let Some(line) = pacing_stats.report(now, config.send_hz) {
+                info!("{}", line);
+            }
+        }
+
+        // Config edits land between ticks
+        while let Ok(Reload {
+            config: mut new,
+            pipelines: mut new_pipelines,
+        }) = reloads.rx.try_recv()
+        {
+            if new.dest.len() != config.dest.len() {
+                warn!(
+                    "Keeping dest {}: adding or removing dests needs a restart",
+                    config.dest.join(", ")
+                );
+                new.dest = config.dest.clone();
+            }
+            // Each link follows its entry
+            for ((link, old), new) in links.iter().zip(&config.dest).zip(&mut new.dest) {
+                if new != old
+                    && let Err(e) = link.retarget(new)
+                {
+                    warn!("Keeping dest {}: {:#}", old, e);
+                    *new = old.clone();
                 }
-                EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_HAT0Y, value) => {
-                    let mut st = shared.lock().unwrap();
-                    let v = value.clamp(-1, 1) as i8;
-                    if st.hat_y != v {
-                        st.hat_y = v;
-                        st.revision = st.revision.wrapping_add(1);
-                    }
+            }
+            for (k, st) in states.states.iter_mut() {
+                let (old, new) = (config.vjoy_device.get(k), new.vjoy_device.get(k));
+                let (Some(old), Some(new)) = (old, new) else {
+                    continue;
+                };
+                if old.enabled != new.enabled {
+                    st.enabled = new.enabled;
                 }
-                EventSummary::AbsoluteAxis(_, axis, value) => {
-                    // Axes (8 slots)
-                    if let Some(slot) = axis_slot(axis) {
-                        let mut st = shared.lock().unwrap();
-                        if st.axes_raw[slot] != value {
-                            st.axes_raw[slot] = value;
-                            st.revision = st.revision.wrapping_add(1);
-                        }
+                if old.axis != new.axis {
+                    st.axis_invert = [false; 8];
+                    set_axis_config(st, &new.axis);
+                }
+                st.revision = st.revision.wrapping_add(1);
+            }
+            if let Some(soft) = &mut reloads.soft {
+                soft.keep(profile_key(&config), &pipelines);
+                soft.restore(profile_key(&new), &mut new_pipelines);
+            }
+            pipelines = new_pipelines;
+            // The receiver hears of a switch right away. A switch alone was
+            // logged by whoever made it
+            let switched = new.active_profile != config.active_profile;
+            if switched {
+                next_ping = Instant::now();
+            }
+            let edited = !switched
+                || Config {
+                    active_profile: config.active_profile.clone(),
+                    ..new.clone()
+                } != config;
+            if send_period(&new) != period || new.precise_pacing != config.precise_pacing {
+                period = send_period(&new);
+                ticks = pacing::Ticks::new(period, new.precise_pacing)?;
+                pacing_stats = pacing::Stats::new();
+            }
+            if new.profile_switch != config.profile_switch {
+                switch_mode = switch_mode_source(&new);
+                mode_position = None;
+            }
+            config = new;
+            if edited {
+                info!("Config reloaded: {:?}", config);
+            }
+        }
+
+        // What the inputs and the console sent since the last tick
+        states.apply_pending();
+        // A disabled device's sources stop being read, and read back on enable
+        for (k, st) in states.states.iter() {
+            if let Some(tx) = read_enabled.get(k) {
+                tx.send_if_modified(|on| std::mem::replace(on, st.enabled) != st.enabled);
+            }
+        }
+        // A profile_switch chord going down switches as the console would
+        if let Some(switch) = &config.profile_switch
+            && let Some(st) = states.states.get(&switch.device)
+        {
+            let before = std::mem::replace(&mut switch_buttons, st.buttons);
+            let target = switch.target(&config, &before, &st.buttons).or_else(|| {
+                let mode = switch_mode.as_mut()?;
+                switch.mode_target(&config, mode, &mut mode_position, st)
+            });
+            if let Some(name) = target {
+                match switch_profile(&reloads.live, &name, &reloads.tx) {
+                    Ok(()) if switch.bell => {
+                        let _ = io::stderr().write_all(b"\x07");
                     }
+                    Ok(()) => {}
+                    Err(e) => error!("profile {}: {:#}", name, e),
+                }
+            }
+        }
+        {
+            let mut published = published.lock().unwrap();
+            for (k, st) in states.states.iter() {
+                if published.get(k).is_none_or(|p| p.revision != st.revision) {
+                    published.insert(*k, *st);
+                }
+            }
+        }
+
+        packets.clear();
+        numbered.clear();
+
+        // Devices with a source not being read say so
+        let stale: HashSet<u8> = stale_sources
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| *k)
+            .collect();
+        // Every few ticks with --monitor
+        let mut shown = monitor.as_mut().filter(|m| m.due(now));
+        let paused_now = paused.load(Ordering::Relaxed);
+        let mut recorded = Ok(());
+        for (k, st) in states.states.iter_mut() {
+            let mut snapshot = *st;
+            st.changed = [0; 32];
+            let was_enabled = was_enabled.get_mut(k).unwrap();
+
+            // Paused goes as disabled does, its release bypassing the transforms
+            if paused_now {
+                snapshot.enabled = false;
+            }
+            if !snapshot.enabled {
+                if let Some(m) = shown.as_deref_mut() {
+                    m.note(*k, if paused_now { "paused" } else { "disabled" });
                 }
-                EventSummary::Key(_, key, value) => {
-                    if let Some(btn_id) = button_map.get(&key).copied() {
-                        let pressed = value != 0;
-                        let (byte_i, bit_i) = button_bitpos(btn_id);
+                // Disabled: release everything once, then go quiet
+                if !*was_enabled {
+                    continue;
+                }
+                snapshot = snapshot.neutral();
+            }
+            *was_enabled = snapshot.enabled;
 
-                        let mut st = shared.lock().unwrap();
-                        let old = (st.buttons[byte_i] >> bit_i) & 1;
-                        let new = if pressed { 1 } else { 0 };
+            // A button that changed and came back since the last tick goes out in
+            // its other state once, the current state follows on the next tick
+            let last = last_buttons.entry(*k).or_default();
+            for ((b, changed), last) in snapshot
+                .buttons
+                .iter_mut()
+                .zip(snapshot.changed)
+                .zip(last.iter_mut())
+            {
+                *b ^= changed & !(*b ^ *last);
+                *last = *b;
+            }
 
-                        if old != new {
-                            if pressed {
-                                st.buttons[byte_i] |= 1 << bit_i;
-                            } else {
-                                st.buttons[byte_i] &= !(1 << bit_i);
+            let seq = seqs[k];
+            numbered.push(*k);
+
+            if let Some(raw) = raw_map.get(k) {
+                if let Some(m) = shown.as_deref_mut() {
+                    m.note(*k, "raw, mapped on the receiver");
+                }
+                let raw = raw.lock().unwrap();
+                let packet = if snapshot.enabled {
+                    raw.encode(seq, *k)
+                } else {
+                    raw.neutral().encode(seq, *k)
+                };
+                if let Some(recorder) = recorder.as_mut() {
+                    recorded = recorded.and(recorder.record(*k, record::Sent::raw(&packet)));
+                }
+                packets.push(packet);
+            } else {
+                let mut wire = snapshot.wire();
+                // A disabled or paused device's release goes out plain neutral,
+                // toggles, macros and trims left out, as on the way out
+                if snapshot.enabled
+                    && let Some(pipeline) = pipelines.get_mut(k)
+                {
+                    pipeline.apply(&mut wire, now);
+                }
+                if let Some(joystick) = joysticks.get_mut(k) {
+                    match joystick.emit(&wire) {
+                        Ok(()) => uinput_failing = false,
+                        Err(e) => {
+                            if !uinput_failing {
+                                warn!("uinput joystick {} failed: {}", k, e);
                             }
-                            st.revision = st.revision.wrapping_add(1);
+                            uinput_failing = true;
                         }
                     }
                 }
-                _ => {}
+                let buttons = wire_buttons(pipelines.get(k), &snapshot, &handshake);
+                sent.lock().unwrap().insert(*k, (wire, buttons));
+                if let Some(export) = &export {
+                    let mut flags = 0;
+                    if snapshot.enabled {
+                        flags |= shm::FLAG_ENABLED;
+                    }
+                    if stale.contains(k) {
+                        flags |= shm::FLAG_STALE;
+                    }
+                    export.write(*k, &wire, buttons, flags);
+                }
+                if let Some(recorder) = recorder.as_mut() {
+                    let line =
+                        record::Sent::state(&wire, buttons, snapshot.enabled, stale.contains(k));
+                    recorded = recorded.and(recorder.record(*k, line));
+                }
+                if let Some(m) = shown.as_deref_mut()
+                    && snapshot.enabled
+                {
+                    m.wire(*k, &wire, buttons, stale.contains(k));
+                }
+                let mut packet = encode_vkb2(seq, *k, &wire, buttons);
+                if stale.contains(k) {
+                    protocol::push_section(&mut packet, SECTION_STALE, &[]);
+                }
+                packets.push(packet);
             }
         }
-    }
-}
 
-fn axis_slot(axis: AbsoluteAxisCode) -> Option<usize> {
-    AXIS_CODES.iter().position(|c| *c == axis)
-}
+        if let Some(recorder) = recorder.as_mut() {
+            match recorded.and_then(|_| recorder.flush()) {
+                Ok(()) => record_failing = false,
+                Err(e) => {
+                    if !record_failing {
+                        warn!("Recording failed: {}", e);
+                    }
+                    record_failing = true;
+                }
+            }
+        }
 
-fn button_bitpos(btn_id_1_based: u8) -> (usize, u8) {
-    // btn 1 -> bit 0, btn 8 -> bit 7, btn 9 -> next byte bit 0, etc
-    let zero_based = (btn_id_1_based - 1) as usize;
-    (zero_based / 8, (zero_based % 8) as u8)
-}
+        if let Some(m) = shown {
+            // Nothing to do about a closed stdout, the rest goes on
+            let _ = m.draw(now);
+        }
 
-fn sender_thread(config: Config, shared_map: HashMap<u8, Arc<Mutex<SharedState>>>) -> Result<()> {
-    let sock = UdpSocket::bind("0.0.0.0:0")?;
-    sock.connect(config.dest)?;
+        // Idle: with nothing sent changing (seq aside) for IDLE_AFTER, only a
+        // heartbeat at idle_hz goes out until something does
+        let now = Instant::now();
+        let unchanged = packets.len() == last_packets.len()
+            && packets
+                .iter()
+                .zip(last_packets.iter())
+                .all(|(p, last)| p[HEADER_LEN..] == last[HEADER_LEN..]);
+        if !unchanged {
+            last_change = now;
+        }
+        last_packets.clone_from(&packets);
+        let idle = config
+            .idle_hz
+            .filter(|_| now.duration_since(last_change) >= IDLE_AFTER);
+        let heartbeat = idle.is_none_or(|hz| now.duration_since(last_send) >= hz_period(hz));
+        if heartbeat {
+            last_send = now;
+            for k in numbered.iter() {
+                let seq = seqs.get_mut(k).unwrap();
+                *seq = seq.wrapping_add(1);
+                if config.send {
+                    metrics.packet_sent(*k);
+                }
+            }
+        } else {
+            packets.clear();
+        }
 
-    let period = Duration::from_nanos((1_000_000_000u64 / config.send_hz as u64).max(1));
-    let mut next = Instant::now();
+        // Combined packets are split so no datagram exceeds max_datagram
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < packets.len() {
+            let mut end = start + 1;
+            if config.combine_devices {
+                let mut size = HEADER_LEN + 2 + packets[start].len();
+                while end < packets.len() && size + 2 + packets[end].len() <= config.max_datagram {
+                    size += 2 + packets[end].len();
+                    end += 1;
+                }
+            }
+            chunks.push(start..end);
+            start = end;
+        }
 
-    let mut seqs: HashMap<u8, u16> = shared_map.keys().map(|&k| (k, 0u16)).collect();
-    let mut buf: [u8; 43] = [0; 43];
+        let mut batches = Vec::new();
+        for chunk in chunks.iter().filter(|c| c.len() > 1) {
+            let mut batch = Vec::with_capacity(config.max_datagram);
+            protocol::encode_batch(&mut batch, batch_seq, &packets[chunk.clone()]);
+            batch_seq = batch_seq.wrapping_add(1);
+            batches.push(batch);
+        }
+        let mut batches = batches.iter();
+        let datagrams: Vec<&[u8]> = chunks
+            .iter()
+            .map(|c| match c.len() {
+                1 => &packets[c.start][..],
+                _ => &batches.next().unwrap()[..],
+            })
+            .collect();
 
-    loop {
-        next += period;
+        if config.dry_run && config.send && !datagrams.is_empty() {
+            let _ = dump::datagrams(&datagrams);
+        }
 
-        for (k, shared) in shared_map.iter() {
-            let snapshot = { *shared.lock().unwrap() }; // cheap copy
-            let seq = seqs.get_mut(k).unwrap();
+        // Every device in one syscall per dest, nothing with only uinput
+        // joysticks fed. With a failover_dest only one of the two gets it
+        let dests = config.dest.iter().chain(&config.failover_dest);
+        for (i, ((link, dest), failing)) in
+            links.iter().zip(dests).zip(&mut send_failing).enumerate()
+        {
+            if failover.as_ref().is_some_and(|f| f.active != i) {
+                continue;
+            }
+            let sent = match config.send && !datagrams.is_empty() {
+                true => link.send_many(&datagrams),
+                false => Ok(()),
+            };
+            if sent.is_err() {
+                handshake.mark(&handshake.failed, i);
+            }
+            if !datagrams.is_empty() && config.send {
+                metrics.sent(i, datagrams.len(), sent.is_ok());
+            }
+            match sent {
+                Ok(()) if datagrams.is_empty() => {}
+                Ok(()) => *failing = false,
+                // ICMP port unreachable while the receiver isn't listening yet,
+                // reported on whichever call on the socket comes next
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e) => {
+                    // Receiver gone or moved: keep sending, report it once
+                    if !*failing {
+                        warn!("send to {} failed: {}", dest, e);
+                    }
+                    *failing = true;
+                }
+            }
+        }
 
-            encode_vkb2(&mut buf, *seq, *k, &snapshot);
-            *seq = seq.wrapping_add(1);
+        if config.send && Instant::now() >= next_ping {
+            next_ping += Duration::from_secs(1);
+            let sent_us = handshake.epoch.elapsed().as_micros() as u64;
+            protocol::encode_ping(&mut ping, ping_seq, sent_us);
+            // Repeated, so a receiver started later still learns the layout
+            // Follows devices being plugged in and transforms being reloaded
+            let fingerprints = fingerprint::fingerprints(&config, &device_infos.read().unwrap());
+            let hello = protocol::encode_hello(ping_seq, &fingerprints);
+            let profile = match config.profile.is_empty() {
+                true => None,
+                false => Some(protocol::encode_profile(
+                    ping_seq,
+                    config.active_profile.as_deref().unwrap_or_default(),
+                )),
+            };
+            // The standby of a failover too, its answers tell whether it is up
+            for (i, link) in links.iter().enumerate() {
+                let sent = link
+                    .send(&ping)
+                    .and_then(|()| link.send(&hello))
+                    .and_then(|()| profile.as_ref().map_or(Ok(()), |p| link.send(p)));
+                if sent.is_err() {
+                    handshake.mark(&handshake.failed, i);
+                }
+            }
+            ping_seq = ping_seq.wrapping_add(1);
+        }
 
-            sock.send(&buf)?;
+        if let (Some(failover), Some(backup)) = (&mut failover, &config.failover_dest) {
+            let when = |times: &[AtomicU64]| [0, 1].map(|i| handshake.when(times, i));
+            let (failed, heard) = (when(&handshake.failed), when(&handshake.heard));
+            let names = [&config.dest[0], backup];
+            match failover.check(failed, heard, Instant::now()) {
+                Some((to, failover::Why::Failing)) => warn!(
+                    "Switching to {}: sends to {} keep failing",
+                    names[to],
+                    names[1 - to]
+                ),
+                Some((to, failover::Why::Silent)) => warn!(
+                    "Switching to {}: {} stopped answering",
+                    names[to],
+                    names[1 - to]
+                ),
+                Some((to, failover::Why::Back)) => {
+                    info!("Switching back to {}: it answers again", names[to])
+                }
+                None => {}
+            }
         }
+    }
 
-        let now = Instant::now();
-        if next > now {
-            thread::sleep(next - now);
-        } else {
-            next = now;
+    // Stopping: what was held is let go of on the receivers and the uinput
+    // joysticks, a few ticks apart so one lost datagram doesn't leave a button
+    // pressed
+    systemd::notify("STOPPING=1");
+    if let Some(soft) = &mut reloads.soft {
+        soft.keep(profile_key(&config), &pipelines);
+        if let Err(e) = soft.write() {
+            warn!("Toggles and trims not kept: {:#}", e);
+        }
+    }
+    info!("Stopping, sending every device neutral");
+    for _ in 0..FINAL_PACKETS {
+        let mut packets = Vec::with_capacity(states.states.len());
+        for (k, st) in states.states.iter() {
+            let seq = seqs.get_mut(k).unwrap();
+            let neutral = st.neutral();
+            if let Some(raw) = raw_map.get(k) {
+                packets.push(raw.lock().unwrap().neutral().encode(*seq, *k));
+            } else {
+                let wire = neutral.wire();
+                if let Some(joystick) = joysticks.get_mut(k) {
+                    let _ = joystick.emit(&wire);
+                }
+                let buttons = wire_buttons(pipelines.get(k), &neutral, &handshake);
+                packets.push(encode_vkb2(*seq, *k, &wire, buttons));
+            }
+            *seq = seq.wrapping_add(1);
+        }
+        if config.send {
+            let datagrams: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+            if config.dry_run {
+                let _ = dump::datagrams(&datagrams);
+            }
+            for link in links.iter() {
+                let _ = link.send_many(&datagrams);
+            }
         }
+        time::sleep(period).await;
     }
+    Ok(())
+}
+
+// profile_switch's mode selector, checked with the rest of the config
+fn switch_mode_source(config: &Config) -> Option<ModeSource> {
+    let switch = config.profile_switch.as_ref()?;
+    switch.mode_source().ok().flatten()
 }
 
-fn encode_vkb2(buf: &mut [u8; 43], seq: u16, device_id: u8, st: &SharedState) {
-    // header
-    buf[0..4].copy_from_slice(b"VKB2"); // magic
-    buf[4] = 2; // version
-    buf[5] = device_id; // vjoy device id
-    buf[6] = 0; // reserved
-    buf[7..9].copy_from_slice(&seq.to_le_bytes()); // sequence
+// What persist_state = "per_profile" keeps the active profile's toggles and
+// trims under
+fn profile_key(config: &Config) -> &str {
+    config.active_profile.as_deref().unwrap_or(PROFILE_NONE)
+}
 
-    // axes: u16 normalized 0..=32768
-    let mut off = 9;
-    for i in 0..8 {
-        let v = normalize_axis(st.axes_raw[i], st.axis_range[i]);
-        buf[off..off + 2].copy_from_slice(&v.to_le_bytes());
-        off += 2;
+// Buttons the packet carries: up to 128 in the v2 layout, more only if the
+// receiver takes them
+fn wire_buttons(pipeline: Option<&Pipeline>, st: &DeviceState, handshake: &Handshake) -> u16 {
+    let receiver_buttons = handshake.receiver_buttons.load(Ordering::Relaxed);
+    let button_count = pipeline
+        .map_or(0, |p| p.button_count())
+        .max(st.button_count);
+    if button_count > V2_BUTTONS {
+        button_count.min(receiver_buttons)
+    } else {
+        V2_BUTTONS
     }
+}
+
+fn send_period(config: &Config) -> Duration {
+    hz_period(config.send_hz)
+}
+
+fn hz_period(hz: u16) -> Duration {
+    Duration::from_nanos((1_000_000_000u64 / hz.max(1) as u64).max(1))
+}
 
-    buf[off] = st.hat_x as u8;
-    buf[off + 1] = st.hat_y as u8;
-    off += 2;
+async fn console_task(commands: control::Commands) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
 
-    buf[off..off + 16].copy_from_slice(&st.buttons);
+    // None once stdin is closed (e.g. running as a service)
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match commands.run(&line) {
+            Ok(Some(answer)) => println!("{}", answer),
+            Ok(None) => {}
+            Err(e) => println!("{:#}", e),
+        }
+    }
+    Ok(())
 }
 
-fn normalize_axis(raw: i32, r: AxisRange) -> u16 {
-    if r.max == r.min {
-        return VJOY_AXIS_MAX / 2;
+// Makes name (or none) the active profile, its transforms handed to the sender
+// like a config edit
+fn switch_profile(live: &Mutex<Config>, name: &str, reload: &Sender<Reload>) -> Result<()> {
+    let mut current = live.lock().unwrap();
+    let active = match name {
+        PROFILE_NONE => None,
+        _ if current.profile.contains_key(name) => Some(name.to_string()),
+        _ => bail!("there is no such profile"),
+    };
+    if current.active_profile == active {
+        return Ok(());
     }
-    let num = (raw as i64 - r.min as i64) * VJOY_AXIS_MAX as i64;
-    let den = r.max as i64 - r.min as i64;
-    let mut out = num / den;
-    if out < 0 {
-        out = 0;
+
+    let mut config = current.clone();
+    config.active_profile = active;
+    let pipelines = active_pipelines(&config)?;
+    *current = config.clone();
+    reload
+        .send(Reload { config, pipelines })
+        .context("the sender is gone")?;
+    info!("profile {} active", name);
+    Ok(())
+}
+
+async fn return_task(
+    link: Link,
+    index: usize,
+    devices: Devices,
+    handshake: Arc<Handshake>,
+) -> Result<()> {
+    let mut incoming = link.incoming()?;
+    let mut buf = [0u8; 2048];
+    let mut snapshot_seq: u16 = 0;
+    // Latest round trip per receiver, shown with its stats
+    let mut rtts: HashMap<String, Duration> = HashMap::new();
+
+    loop {
+        let (len, from) = match incoming.recv(&mut buf).await {
+            Ok(r) => r,
+            // ICMP port unreachable while the receiver isn't listening yet
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                handshake.mark(&handshake.failed, index);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        handshake.mark(&handshake.heard, index);
+
+        match protocol::packet_type(&buf[..len]) {
+            Some(PKT_TYPE_STATUS) => {
+                log_status(&from, &buf[..len], rtts.get(&from.to_string()).copied())
+            }
+            // The receiver measures its side of the link too
+            Some(PKT_TYPE_PING) => {
+                if let Some(pong) = protocol::encode_pong(&buf[..len]) {
+                    let _ = link.reply(&from, &pong);
+                }
+            }
+            Some(PKT_TYPE_PONG) => {
+                if let Some(sent) = protocol::decode_pong(&buf[..len]) {
+                    let rtt = handshake
+                        .epoch
+                        .elapsed()
+                        .saturating_sub(Duration::from_micros(sent));
+                    rtts.insert(from.to_string(), rtt);
+                }
+            }
+            Some(PKT_TYPE_SNAPSHOT_REQUEST) => {
+                let device_infos = devices.device_infos.read().unwrap();
+                let states = devices.states.lock().unwrap().clone();
+                match snapshot::snapshot(&device_infos, &states) {
+                    Ok(text) => {
+                        let pkt = protocol::encode_snapshot(snapshot_seq, &text);
+                        snapshot_seq = snapshot_seq.wrapping_add(1);
+                        let _ = link.reply(&from, &pkt);
+                    }
+                    Err(e) => warn!("snapshot failed: {:#}", e),
+                }
+            }
+            Some(PKT_TYPE_CAPS) => {
+                if let Some(buttons) = protocol::decode_caps(&buf[..len]) {
+                    let buttons = buttons.min(MAX_BUTTONS);
+                    let prev = handshake.receiver_buttons.swap(buttons, Ordering::Relaxed);
+                    if prev != buttons {
+                        info!("receiver {} takes {} buttons per device", from, buttons);
+                    }
+                }
+            }
+            Some(PKT_TYPE_FFB) => {
+                if let Ok((device_id, cmd)) = protocol::decode_ffb(&buf[..len]) {
+                    // Devices without force feedback enabled just ignore it
+                    if let Some(tx) = devices.ffb_map.lock().unwrap().get(&device_id) {
+                        let _ = tx.send(cmd);
+                    }
+                }
+            }
+            _ => {}
+        }
     }
-    if out > VJOY_AXIS_MAX as i64 {
-        out = VJOY_AXIS_MAX as i64;
+}
+
+fn log_status(from: &Peer, data: &[u8], rtt: Option<Duration>) {
+    match protocol::decode_status(data) {
+        Ok(Status::Message {
+            device_id: 0,
+            severity,
+            text,
+        }) => info!("receiver {} {}: {}", from, severity, text),
+        Ok(Status::Message {
+            device_id,
+            severity,
+            text,
+        }) => info!(
+            "receiver {} {} (device {}): {}",
+            from, severity, device_id, text
+        ),
+        Ok(Status::Stats(s)) => info!(
+            "receiver {} stats: recv={} applied={} bad={} dup={} ooo={} lost~={} rejected={} rtt={}",
+            from,
+            s.received,
+            s.applied,
+            s.bad,
+            s.dup,
+            s.ooo,
+            s.lost_est,
+            s.rejected,
+            rtt.map(|r| format!("{:.2}ms", r.as_secs_f64() * 1000.0))
+                .unwrap_or_else(|| "-".to_string())
+        ),
+        Err(_) => {}
     }
-    out as u16
 }
@@ -0,0 +1,363 @@
+use crate::{Config, Devices, api, dashboard};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::{task, time};
+use tracing::debug;
+use vkb_sender_core::state::DeviceState;
+
+// Requests are a line and a few headers, a client taking longer is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LEN: usize = 8192;
+
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+const PLAIN_TEXT: &str = "text/plain";
+
+// Counters the tasks bump as they go, read by /metrics
+pub struct Metrics {
+    // By vjoy device
+    events: BTreeMap<u8, AtomicU64>,
+    packets: BTreeMap<u8, AtomicU64>,
+    // By dest, in config order (failover_dest last)
+    links: Vec<LinkCounters>,
+    // Send ticks and how far each strayed from the period, in nanoseconds
+    ticks: AtomicU64,
+    jitter_ns: AtomicU64,
+}
+
+// The counters at one point, rates come from two of them
+pub struct Totals {
+    at: Instant,
+    ticks: u64,
+    events: BTreeMap<u8, u64>,
+    packets: BTreeMap<u8, u64>,
+    errors: u64,
+}
+
+impl Totals {
+    pub fn age(&self) -> Duration {
+        self.at.elapsed()
+    }
+
+    // One line with the rates since before: ticks, packets and events per
+    // second by vjoy device, and failed sends
+    pub fn report_since(&self, before: &Totals) -> String {
+        let secs = self.at.duration_since(before.at).as_secs_f64().max(0.001);
+        let rates = |now: &BTreeMap<u8, u64>, then: &BTreeMap<u8, u64>| {
+            now.iter()
+                .map(|(k, n)| {
+                    let rate = (n - then.get(k).copied().unwrap_or(0)) as f64 / secs;
+                    format!("{}={:.0}", k, rate)
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        format!(
+            "sender stats: {:.1} ticks/s, packets/s {}, events/s {}, {} send errors",
+            (self.ticks - before.ticks) as f64 / secs,
+            rates(&self.packets, &before.packets),
+            rates(&self.events, &before.events),
+            self.errors - before.errors
+        )
+    }
+}
+
+struct LinkCounters {
+    dest: String,
+    datagrams: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(device_ids: impl Iterator<Item = u8>, dests: &[&String]) -> Metrics {
+        let device_ids: Vec<u8> = device_ids.collect();
+        let counters = || device_ids.iter().map(|k| (*k, AtomicU64::new(0))).collect();
+        Metrics {
+            events: counters(),
+            packets: counters(),
+            links: dests
+                .iter()
+                .map(|dest| LinkCounters {
+                    dest: dest.to_string(),
+                    datagrams: AtomicU64::new(0),
+                    errors: AtomicU64::new(0),
+                })
+                .collect(),
+            ticks: AtomicU64::new(0),
+            jitter_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub fn events_read(&self, device_id: u8, n: u64) {
+        if let Some(c) = self.events.get(&device_id) {
+            c.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn packet_sent(&self, device_id: u8) {
+        if let Some(c) = self.packets.get(&device_id) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sent(&self, link: usize, datagrams: usize, ok: bool) {
+        let c = &self.links[link];
+        match ok {
+            true => c.datagrams.fetch_add(datagrams as u64, Ordering::Relaxed),
+            false => c.errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn tick(&self, off: Duration) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.jitter_ns
+            .fetch_add(off.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // (dest, datagrams sent, failed sends) by dest
+    pub fn link_counts(&self) -> Vec<(&str, u64, u64)> {
+        let value = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        self.links
+            .iter()
+            .map(|c| (c.dest.as_str(), value(&c.datagrams), value(&c.errors)))
+            .collect()
+    }
+
+    // (events read, packets sent)
+    pub fn device_counts(&self, device_id: u8) -> (u64, u64) {
+        let value = |counters: &BTreeMap<u8, AtomicU64>| {
+            counters
+                .get(&device_id)
+                .map_or(0, |c| c.load(Ordering::Relaxed))
+        };
+        (value(&self.events), value(&self.packets))
+    }
+
+    pub fn totals(&self) -> Totals {
+        let value = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let by_device = |counters: &BTreeMap<u8, AtomicU64>| {
+            counters.iter().map(|(k, c)| (*k, value(c))).collect()
+        };
+        Totals {
+            at: Instant::now(),
+            ticks: value(&self.ticks),
+            events: by_device(&self.events),
+            packets: by_device(&self.packets),
+            errors: self.links.iter().map(|c| value(&c.errors)).sum(),
+        }
+    }
+
+    // Prometheus text format, axes from the states as the sender last applied them
+    fn render(&self, states: &HashMap<u8, DeviceState>) -> String {
+        let mut out = String::new();
+        let value = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        header(
+            &mut out,
+            "vkb_bridge_events_total",
+            "counter",
+            "evdev events read, by vjoy device",
+        );
+        for (k, c) in self.events.iter() {
+            let _ = writeln!(
+                out,
+                "vkb_bridge_events_total{{device=\"{}\"}} {}",
+                k,
+                value(c)
+            );
+        }
+
+        header(
+            &mut out,
+            "vkb_bridge_packets_sent_total",
+            "counter",
+            "State packets sent, by vjoy device",
+        );
+        for (k, c) in self.packets.iter() {
+            let _ = writeln!(
+                out,
+                "vkb_bridge_packets_sent_total{{device=\"{}\"}} {}",
+                k,
+                value(c)
+            );
+        }
+
+        header(
+            &mut out,
+            "vkb_bridge_datagrams_sent_total",
+            "counter",
+            "Datagrams sent, by dest",
+        );
+        for c in self.links.iter() {
+            let _ = writeln!(
+                out,
+                "vkb_bridge_datagrams_sent_total{{dest=\"{}\"}} {}",
+                escape(&c.dest),
+                value(&c.datagrams)
+            );
+        }
+
+        header(
+            &mut out,
+            "vkb_bridge_send_errors_total",
+            "counter",
+            "Sends that failed, by dest",
+        );
+        for c in self.links.iter() {
+            let _ = writeln!(
+                out,
+                "vkb_bridge_send_errors_total{{dest=\"{}\"}} {}",
+                escape(&c.dest),
+                value(&c.errors)
+            );
+        }
+
+        header(
+            &mut out,
+            "vkb_bridge_tick_jitter_seconds",
+            "summary",
+            "How far each send tick strayed from the send_hz period",
+        );
+        let _ = writeln!(
+            out,
+            "vkb_bridge_tick_jitter_seconds_sum {}",
+            value(&self.jitter_ns) as f64 / 1e9
+        );
+        let _ = writeln!(
+            out,
+            "vkb_bridge_tick_jitter_seconds_count {}",
+            value(&self.ticks)
+        );
+
+        header(
+            &mut out,
+            "vkb_bridge_axis_value",
+            "gauge",
+            "Last value of each axis (0..=32768, before transforms), by vjoy device and axis id",
+        );
+        let mut states: Vec<_> = states.iter().collect();
+        states.sort_by_key(|(k, _)| **k);
+        for (k, st) in states {
+            for (i, v) in st.wire().axes.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "vkb_bridge_axis_value{{device=\"{}\",axis=\"{}\"}} {}",
+                    k,
+                    i + 1,
+                    v
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Label values are quoted, dests could hold a quote or backslash
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Serves GET /metrics, the JSON of api and the dashboard to anyone connecting
+pub async fn serve(
+    listener: TcpListener,
+    devices: Devices,
+    config: Arc<Mutex<Config>>,
+) -> Result<()> {
+    loop {
+        let (stream, from) = listener.accept().await?;
+        let devices = devices.clone();
+        let config = Arc::clone(&config);
+        task::spawn(async move {
+            let answer = respond(stream, &devices, &config);
+            match time::timeout(REQUEST_TIMEOUT, answer).await {
+                Ok(Ok(None)) => {}
+                // The dashboard's WebSocket, open as long as the page
+                Ok(Ok(Some(stream))) => {
+                    if let Err(e) = dashboard::feed(stream, devices, config).await {
+                        debug!("dashboard of {} gone: {:#}", from, e);
+                    }
+                }
+                Ok(Err(e)) => debug!("metrics request from {} failed: {:#}", from, e),
+                Err(_) => debug!("metrics request from {} timed out", from),
+            }
+        });
+    }
+}
+
+// The stream back for a WebSocket request, once switched over
+async fn respond(
+    mut stream: TcpStream,
+    devices: &Devices,
+    config: &Mutex<Config>,
+) -> Result<Option<TcpStream>> {
+    // Up to the blank line ending the headers, the body (if any) is ignored
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|b| *b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let path = path.map(String::from_utf8_lossy);
+    if let (Some(b"GET"), Some("/ws")) = (method, path.as_deref()) {
+        let request = String::from_utf8_lossy(&request);
+        let key = request.lines().find_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.eq_ignore_ascii_case("sec-websocket-key")
+                .then_some(value)
+        });
+        if let Some(key) = key {
+            stream.write_all(dashboard::upgrade(key).as_bytes()).await?;
+            return Ok(Some(stream));
+        }
+    }
+
+    let (status, content_type, body) = match (method, path.as_deref()) {
+        (Some(b"GET"), Some("/")) => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            dashboard::PAGE.to_string(),
+        ),
+        (Some(b"GET"), Some("/metrics")) => {
+            let states = devices.states.lock().unwrap().clone();
+            ("200 OK", PROMETHEUS_TEXT, devices.metrics.render(&states))
+        }
+        (Some(b"GET"), Some(path)) => match api::get(path, devices, config) {
+            Some(json) => ("200 OK", "application/json", format!("{}\n", json)),
+            None => (
+                "404 Not Found",
+                PLAIN_TEXT,
+                "/ (the dashboard), /metrics, /status, /devices and /profile here\n".to_string(),
+            ),
+        },
+        _ => ("405 Method Not Allowed", PLAIN_TEXT, String::new()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(None)
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{self, Write as _};
+use std::time::{Duration, Instant};
+use vkb_sender_core::state::WireState;
+use vkb_sender_core::state::button_bitpos;
+
+// A terminal can't keep up with send_hz, nor can a reader
+const REFRESH: Duration = Duration::from_millis(100);
+
+// What every vjoy device sends (axes after transforms), redrawn in place on
+// stdout for --monitor
+pub struct Monitor {
+    next: Instant,
+    // By vjoy device, as of the last drawn tick
+    shown: BTreeMap<u8, String>,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor {
+            next: Instant::now(),
+            shown: BTreeMap::new(),
+        }
+    }
+
+    // Whether this tick is drawn, the devices are added before draw
+    pub fn due(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    pub fn wire(&mut self, device_id: u8, wire: &WireState, buttons: u16, stale: bool) {
+        let mut out = String::new();
+        let _ = write!(out, "vjoy {}", device_id);
+        if stale {
+            out.push_str("  (a source isn't being read, held neutral)");
+        }
+        out.push_str("\n  axes   ");
+        for (i, v) in wire.axes.iter().enumerate() {
+            let _ = write!(out, " {}:{:>5}", i + 1, v);
+        }
+        let _ = write!(out, "\n  hat     {}", hat_name(wire.hat_x, wire.hat_y));
+        out.push_str("\n  buttons");
+        let pressed: Vec<u16> = (1..=buttons)
+            .filter(|btn| {
+                let (byte_i, bit_i) = button_bitpos(*btn);
+                wire.buttons[byte_i] & (1 << bit_i) != 0
+            })
+            .collect();
+        match pressed.is_empty() {
+            true => out.push_str(" none"),
+            false => {
+                for btn in pressed {
+                    let _ = write!(out, " {}", btn);
+                }
+            }
+        }
+        self.shown.insert(device_id, out);
+    }
+
+    pub fn note(&mut self, device_id: u8, what: &str) {
+        self.shown
+            .insert(device_id, format!("vjoy {}  ({})", device_id, what));
+    }
+
+    pub fn draw(&mut self, now: Instant) -> io::Result<()> {
+        self.next = now + REFRESH;
+        // Home, every line cleared to its end and the rest of the screen below
+        let mut screen = String::from("\x1b[H");
+        screen.push_str("vkb-bridge monitor, Ctrl+C to stop\x1b[K\n");
+        for block in self.shown.values() {
+            for line in block.lines() {
+                let _ = writeln!(screen, "{}\x1b[K", line);
+            }
+        }
+        screen.push_str("\x1b[J");
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+fn hat_name(x: i8, y: i8) -> &'static str {
+    match (x, y) {
+        (0, -1) => "up",
+        (1, -1) => "up right",
+        (1, 0) => "right",
+        (1, 1) => "down right",
+        (0, 1) => "down",
+        (-1, 1) => "down left",
+        (-1, 0) => "left",
+        (-1, -1) => "up left",
+        _ => "centered",
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{self, MissedTickBehavior};
+
+// How often precise pacing says how it is doing
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// The sender's tick. Runtime timers round up to the next millisecond, a send_hz
+// of 300 comes out at 250; a timerfd wakes the runtime on the nanosecond
+pub enum Ticks {
+    Runtime(time::Interval),
+    Timerfd(AsyncFd<OwnedFd>),
+}
+
+impl Ticks {
+    pub fn new(period: Duration, precise: bool) -> Result<Ticks> {
+        if !precise {
+            // A late tick pushes the later ones back rather than bunching them up
+            let mut ticks = time::interval(period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            return Ok(Ticks::Runtime(ticks));
+        }
+
+        let flags = libc::TFD_NONBLOCK | libc::TFD_CLOEXEC;
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("timerfd_create failed");
+        }
+        // Closed with the OwnedFd from here on
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let every = libc::timespec {
+            tv_sec: period.as_secs() as libc::time_t,
+            tv_nsec: period.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: every,
+            it_value: every,
+        };
+        if unsafe { libc::timerfd_settime(fd.as_raw_fd(), 0, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error()).context("timerfd_settime failed");
+        }
+        Ok(Ticks::Timerfd(AsyncFd::new(fd)?))
+    }
+
+    // The next tick, those missed meanwhile are skipped
+    pub async fn tick(&mut self) -> Result<()> {
+        let fd = match self {
+            Ticks::Runtime(ticks) => {
+                ticks.tick().await;
+                return Ok(());
+            }
+            Ticks::Timerfd(fd) => fd,
+        };
+        loop {
+            let mut ready = fd.readable().await?;
+            // Ticks since the last read, read so the count starts over
+            let mut expired = [0u8; 8];
+            let read = ready.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), expired.as_mut_ptr().cast(), 8) };
+                match n {
+                    8 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            });
+            match read {
+                Ok(read) => return read.context("Failed to read the timerfd"),
+                // Nothing there after all
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+// Achieved rate and how far ticks stray from the period
+pub struct Stats {
+    since: Instant,
+    last: Option<Instant>,
+    ticks: u32,
+    // Differences between a tick's gap to the previous one and the period
+    off_total: Duration,
+    off_max: Duration,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            since: Instant::now(),
+            last: None,
+            ticks: 0,
+            off_total: Duration::ZERO,
+            off_max: Duration::ZERO,
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant, period: Duration) {
+        if let Some(last) = self.last {
+            let off = now.duration_since(last).abs_diff(period);
+            self.off_total += off;
+            self.off_max = self.off_max.max(off);
+            self.ticks += 1;
+        }
+        self.last = Some(now);
+    }
+
+    // A line every REPORT_INTERVAL, counting again from there
+    pub fn report(&mut self, now: Instant, send_hz: u16) -> Option<String> {
+        let elapsed = now.duration_since(self.since);
+        if elapsed < REPORT_INTERVAL {
+            return None;
+        }
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let line = format!(
+            "send pacing: {:.1} Hz (send_hz {}), jitter {:.3}ms average, {:.3}ms max",
+            f64::from(self.ticks) / elapsed.as_secs_f64(),
+            send_hz,
+            ms(self.off_total / self.ticks.max(1)),
+            ms(self.off_max)
+        );
+        *self = Stats {
+            last: self.last,
+            ..Stats::new()
+        };
+        self.since = now;
+        Some(line)
+    }
+}
@@ -0,0 +1,86 @@
+use crate::transform::{Pipeline, SoftState};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Toggles and trim offsets of every vjoy device, kept in a file next to
+// config.toml so they survive a restart. The sender carries them across config
+// reloads and profile switches and writes the file on the way out
+pub struct SoftStore {
+    path: PathBuf,
+    per_profile: bool,
+    saved: Saved,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Saved {
+    // By vjoy device
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    device: BTreeMap<u8, SoftState>,
+    // per_profile: by profile ("none" for none), then vjoy device
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profile: BTreeMap<String, BTreeMap<u8, SoftState>>,
+}
+
+impl SoftStore {
+    pub fn open(path: &Path, per_profile: bool) -> Result<SoftStore> {
+        let saved = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Ok(SoftStore {
+            path: path.to_path_buf(),
+            per_profile,
+            saved,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Takes in what the devices hold now, under profile when kept per profile
+    pub fn keep(&mut self, profile: &str, pipelines: &HashMap<u8, Pipeline>) {
+        let devices = match self.per_profile {
+            true => self.saved.profile.entry(profile.to_string()).or_default(),
+            false => &mut self.saved.device,
+        };
+        for (k, pipeline) in pipelines.iter() {
+            let soft = pipeline.soft_state();
+            if soft.is_empty() {
+                devices.remove(k);
+            } else {
+                devices.insert(*k, soft);
+            }
+        }
+    }
+
+    // Puts back what was kept, the profile's own when kept per profile
+    pub fn restore(&self, profile: &str, pipelines: &mut HashMap<u8, Pipeline>) {
+        let devices = match self.per_profile {
+            true => self.saved.profile.get(profile),
+            false => Some(&self.saved.device),
+        };
+        for (k, pipeline) in pipelines.iter_mut() {
+            let soft = devices.and_then(|d| d.get(k)).cloned().unwrap_or_default();
+            pipeline.restore(&soft);
+        }
+    }
+
+    pub fn write(&mut self) -> Result<()> {
+        self.saved.profile.retain(|_, devices| !devices.is_empty());
+        let text = format!(
+            "# Toggles and trims by vjoy device, written by linux-sender when it stops.\n\
+             # Delete a device to start it afresh\n{}",
+            toml::to_string(&self.saved)?
+        );
+        fs::write(&self.path, text)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
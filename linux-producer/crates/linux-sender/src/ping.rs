@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use vkb_sender_core::protocol::{self, PKT_TYPE_PONG, STATE_PKT_LEN};
+
+// How long to wait for late replies after the last ping
+const LINGER: Duration = Duration::from_secs(1);
+
+// Pings a receiver on the bridge port with state sized packets and prints RTT
+// and loss, like ping(8)
+pub fn run(dest: SocketAddr, count: u16, interval: Duration) -> Result<()> {
+    let sock = match dest.ip() {
+        IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    sock.connect(dest)
+        .with_context(|| format!("Failed to connect to {}", dest))?;
+
+    println!("PING {} ({} bytes)", dest, STATE_PKT_LEN);
+
+    let mut sent: HashMap<u16, Instant> = HashMap::new();
+    let mut rtts: Vec<Duration> = Vec::new();
+    let mut buf = [0u8; STATE_PKT_LEN];
+    let mut reply = [0u8; 2048];
+
+    for seq in 0..count {
+        protocol::encode_ping(&mut buf, seq, 0);
+        sent.insert(seq, Instant::now());
+        if let Err(e) = sock.send(&buf) {
+            println!("seq={} send failed: {}", seq, e);
+        }
+
+        let wait_until = Instant::now() + if seq + 1 < count { interval } else { LINGER };
+
+        // Collect replies until it's time for the next ping
+        loop {
+            let now = Instant::now();
+            if now >= wait_until {
+                break;
+            }
+            sock.set_read_timeout(Some(wait_until - now))?;
+
+            let len = match sock.recv(&mut reply) {
+                Ok(len) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                // ICMP port unreachable: nothing listens on the port
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    println!("seq={} port unreachable", seq);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let data = &reply[..len];
+            if protocol::packet_type(data) != Some(PKT_TYPE_PONG) {
+                continue;
+            }
+            let reply_seq = u16::from_le_bytes([data[7], data[8]]);
+            if let Some(t) = sent.remove(&reply_seq) {
+                let rtt = t.elapsed();
+                rtts.push(rtt);
+                println!(
+                    "{} bytes from {}: seq={} time={:.2} ms",
+                    len,
+                    dest,
+                    reply_seq,
+                    rtt.as_secs_f64() * 1000.0
+                );
+            }
+        }
+    }
+
+    let received = rtts.len();
+    let loss = 100.0 * (count as usize - received) as f64 / count.max(1) as f64;
+    println!(
+        "--- {} ping statistics ---\n{} sent, {} received, {:.1}% loss",
+        dest, count, received, loss
+    );
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / received as u32;
+        println!(
+            "rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+            min.as_secs_f64() * 1000.0,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        );
+    }
+
+    Ok(())
+}
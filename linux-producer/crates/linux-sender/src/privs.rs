@@ -0,0 +1,114 @@
+use anyhow::{Context, Result, bail};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+use tracing::info;
+
+// Generous for /etc/passwd and /etc/group entries, a group with many members
+// can need more
+const ENTRY_BUF_LEN: usize = 16384;
+
+// Gives up root for user (a name or uid) and group (the user's own if left
+// out), keeping the user's supplementary groups such as input. glibc applies
+// it to every thread of the process
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        info!("Not running as root, user = {} left as is", user);
+        return Ok(());
+    }
+
+    let (name, uid, user_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => user_gid,
+    };
+
+    let c_name = CString::new(name.as_str())?;
+    if unsafe { libc::initgroups(c_name.as_ptr(), gid) } < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Could not set the groups of {}", name));
+    }
+    if unsafe { libc::setgid(gid) } < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Could not switch to group {}", gid));
+    }
+    if unsafe { libc::setuid(uid) } < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Could not switch to user {}", name));
+    }
+    // Dropped for good only if there is no way back
+    if unsafe { libc::setuid(0) } == 0 {
+        bail!("Still able to become root after switching to {}", name);
+    }
+
+    info!("Running as {} (uid {}, gid {})", name, uid, gid);
+    Ok(())
+}
+
+// (name, uid, primary gid)
+pub fn lookup_user(user: &str) -> Result<(String, libc::uid_t, libc::gid_t)> {
+    let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUF_LEN];
+    let mut found = ptr::null_mut();
+    let err = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(
+                uid,
+                pwd.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        },
+        Err(_) => {
+            let c_user = CString::new(user)?;
+            unsafe {
+                libc::getpwnam_r(
+                    c_user.as_ptr(),
+                    pwd.as_mut_ptr(),
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err))
+            .with_context(|| format!("Could not look up user {}", user));
+    }
+    if found.is_null() {
+        bail!("No user {}", user);
+    }
+    let pwd = unsafe { pwd.assume_init() };
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+    Ok((name.to_string_lossy().into_owned(), pwd.pw_uid, pwd.pw_gid))
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let mut grp = MaybeUninit::<libc::group>::uninit();
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUF_LEN];
+    let mut found = ptr::null_mut();
+    let c_group = CString::new(group)?;
+    let err = unsafe {
+        libc::getgrnam_r(
+            c_group.as_ptr(),
+            grp.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err))
+            .with_context(|| format!("Could not look up group {}", group));
+    }
+    if found.is_null() {
+        bail!("No group {}", group);
+    }
+    Ok(unsafe { grp.assume_init() }.gr_gid)
+}
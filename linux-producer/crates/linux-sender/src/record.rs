@@ -0,0 +1,140 @@
+use crate::transform::pressed;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use vkb_sender_core::protocol::HEADER_LEN;
+use vkb_sender_core::state::MAX_BUTTONS;
+use vkb_sender_core::state::WireState;
+
+// Format of the lines, bumped when a reader would notice
+pub const VERSION: u32 = 1;
+
+// First line of each session appended
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub recording: u32,
+    // Unix time, seconds
+    pub started: f64,
+}
+
+// A device sending something else than before
+#[derive(Serialize, Deserialize)]
+pub struct Line {
+    // Seconds since the session's header
+    pub t: f64,
+    pub device: u8,
+    #[serde(flatten)]
+    pub sent: Sent,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Sent {
+    // After transforms, axes 0..=32768, the buttons pressed and how many went
+    // on the wire
+    State {
+        enabled: bool,
+        stale: bool,
+        axes: [u16; 8],
+        hat: [i8; 2],
+        buttons: Vec<u16>,
+        button_count: u16,
+    },
+    // Mapped on the receiver: the datagram after its header, in hex
+    Raw {
+        raw: String,
+    },
+}
+
+impl Sent {
+    pub fn state(wire: &WireState, button_count: u16, enabled: bool, stale: bool) -> Sent {
+        Sent::State {
+            enabled,
+            stale,
+            axes: wire.axes,
+            hat: [wire.hat_x, wire.hat_y],
+            buttons: (1..=MAX_BUTTONS)
+                .filter(|b| pressed(&wire.buttons, *b))
+                .collect(),
+            button_count,
+        }
+    }
+
+    pub fn raw(packet: &[u8]) -> Sent {
+        let mut raw = String::with_capacity((packet.len() - HEADER_LEN) * 2);
+        for b in &packet[HEADER_LEN..] {
+            let _ = write!(raw, "{:02x}", b);
+        }
+        Sent::Raw { raw }
+    }
+}
+
+// --record: every device's state as sent, a JSON line each time it changes,
+// appended to the file
+pub struct Recorder {
+    out: BufWriter<File>,
+    started: Instant,
+    last: HashMap<u8, Sent>,
+    pending: bool,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> Result<Recorder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open {} to record to", path.display()))?;
+        let mut recorder = Recorder {
+            out: BufWriter::new(file),
+            started: Instant::now(),
+            last: HashMap::new(),
+            pending: false,
+        };
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        recorder.put(&Header {
+            recording: VERSION,
+            started,
+        })?;
+        recorder.flush()?;
+        Ok(recorder)
+    }
+
+    // Written when it differs from the device's last line
+    pub fn record(&mut self, device: u8, sent: Sent) -> io::Result<()> {
+        if self.last.get(&device) == Some(&sent) {
+            return Ok(());
+        }
+        let line = Line {
+            t: self.started.elapsed().as_micros() as f64 / 1e6,
+            device,
+            sent,
+        };
+        self.put(&line)?;
+        self.last.insert(device, line.sent);
+        Ok(())
+    }
+
+    // Once a tick, so a crash loses a tick at most
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending {
+            self.pending = false;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, line: &impl Serialize) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, line)?;
+        self.out.write_all(b"\n")?;
+        self.pending = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use crate::record::{Header, Line, Sent, VERSION};
+use crate::transform::set;
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use vkb_sender_core::protocol::encode_vkb2;
+use vkb_sender_core::protocol::{self, SECTION_STALE};
+use vkb_sender_core::raw::RawState;
+use vkb_sender_core::state::WireState;
+use vkb_sender_core::state::{MAX_BUTTONS, VJOY_AXIS_MAX};
+
+// A device as last recorded
+#[derive(Clone)]
+enum Playing {
+    State {
+        wire: WireState,
+        buttons: u16,
+        enabled: bool,
+        stale: bool,
+    },
+    Raw(RawState),
+}
+
+impl Playing {
+    fn encode(&self, seq: u16, device_id: u8) -> Vec<u8> {
+        match self {
+            Playing::State {
+                wire,
+                buttons,
+                stale,
+                ..
+            } => {
+                let mut packet = encode_vkb2(seq, device_id, wire, *buttons);
+                if *stale {
+                    protocol::push_section(&mut packet, SECTION_STALE, &[]);
+                }
+                packet
+            }
+            Playing::Raw(raw) => raw.encode(seq, device_id),
+        }
+    }
+
+    // Centered and released, what the bridge sends when it stops
+    fn neutral(&self) -> Playing {
+        match self {
+            Playing::State { buttons, .. } => Playing::State {
+                wire: WireState {
+                    axes: [VJOY_AXIS_MAX / 2; 8],
+                    hat_x: 0,
+                    hat_y: 0,
+                    buttons: [0; 32],
+                },
+                buttons: *buttons,
+                enabled: true,
+                stale: false,
+            },
+            Playing::Raw(raw) => Playing::Raw(raw.neutral()),
+        }
+    }
+}
+
+// One run of the bridge in the file: (seconds in, vjoy device, what it sent)
+struct Session {
+    lines: Vec<(f64, u8, Playing)>,
+}
+
+// Sends a file written with --record to a receiver as it was recorded, every
+// device at hz, speed times as fast. A file with several sessions plays them
+// one after the other
+pub fn run(path: &Path, dest: SocketAddr, speed: f64, hz: u16, repeat: bool) -> Result<()> {
+    if !(speed > 0.0 && speed.is_finite()) {
+        bail!("speed has to be above 0");
+    }
+    if hz == 0 {
+        bail!("send_hz has to be above 0");
+    }
+    let sessions = load(path)?;
+    if sessions.iter().all(|s| s.lines.is_empty()) {
+        bail!("{}: nothing recorded", path.display());
+    }
+
+    let sock = match dest.ip() {
+        IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    sock.connect(dest)
+        .with_context(|| format!("Failed to connect to {}", dest))?;
+
+    println!(
+        "REPLAY {} to {}, {} session(s) at {}x",
+        path.display(),
+        dest,
+        sessions.len(),
+        speed
+    );
+    let period = Duration::from_secs_f64(1.0 / hz as f64);
+    let mut sent = Counts::default();
+    loop {
+        for (i, session) in sessions.iter().enumerate() {
+            let Some((end, _, _)) = session.lines.last() else {
+                continue;
+            };
+            let mut ids: Vec<u8> = session.lines.iter().map(|(_, k, _)| *k).collect();
+            ids.sort();
+            ids.dedup();
+            println!("session {}: {:.1} s, vjoy device(s) {:?}", i + 1, end, ids);
+            play(&sock, session, speed, period, &mut sent);
+        }
+        if !repeat {
+            break;
+        }
+    }
+    println!("{} datagrams sent, {} sends failed", sent.ok, sent.failed);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Counts {
+    ok: u64,
+    failed: u64,
+}
+
+fn play(sock: &UdpSocket, session: &Session, speed: f64, period: Duration, sent: &mut Counts) {
+    let mut devices: BTreeMap<u8, Playing> = BTreeMap::new();
+    let mut seqs: HashMap<u8, u16> = HashMap::new();
+    // Disabled, released once already
+    let mut quiet: HashSet<u8> = HashSet::new();
+    let mut send = |k: u8, playing: &Playing| {
+        let seq = seqs.entry(k).or_default();
+        match sock.send(&playing.encode(*seq, k)) {
+            Ok(_) => sent.ok += 1,
+            Err(_) => sent.failed += 1,
+        }
+        *seq = seq.wrapping_add(1);
+    };
+
+    let started = Instant::now();
+    let mut next = 0;
+    let mut tick = started;
+    loop {
+        let at = started.elapsed().as_secs_f64() * speed;
+        while let Some((t, k, playing)) = session.lines.get(next)
+            && *t <= at
+        {
+            devices.insert(*k, playing.clone());
+            quiet.remove(k);
+            next += 1;
+        }
+        for (k, playing) in devices.iter() {
+            if quiet.contains(k) {
+                continue;
+            }
+            send(*k, playing);
+            if let Playing::State { enabled: false, .. } = playing {
+                quiet.insert(*k);
+            }
+        }
+        if next == session.lines.len() {
+            break;
+        }
+        tick += period;
+        thread::sleep(tick.saturating_duration_since(Instant::now()));
+    }
+
+    // Nothing stays held on the receiver
+    for (k, playing) in devices.iter() {
+        send(*k, &playing.neutral());
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Session>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let mut sessions: Vec<Session> = Vec::new();
+    for (n, text) in text.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let at = || format!("{}:{}", path.display(), n + 1);
+        let value: serde_json::Value = serde_json::from_str(text).with_context(at)?;
+        if value.get("recording").is_some() {
+            let header: Header = serde_json::from_value(value).with_context(at)?;
+            if header.recording > VERSION {
+                bail!(
+                    "{}: recorded in format {}, this version reads up to {}",
+                    at(),
+                    header.recording,
+                    VERSION
+                );
+            }
+            sessions.push(Session { lines: Vec::new() });
+            continue;
+        }
+        let line: Line = serde_json::from_value(value).with_context(at)?;
+        let playing = playing(line.sent).ok_or_else(|| anyhow!("{}: bad raw state", at()))?;
+        match sessions.last_mut() {
+            Some(session) => session.lines.push((line.t, line.device, playing)),
+            None => bail!("{}: not a recording, it starts without a header", at()),
+        }
+    }
+    Ok(sessions)
+}
+
+fn playing(sent: Sent) -> Option<Playing> {
+    match sent {
+        Sent::State {
+            enabled,
+            stale,
+            axes,
+            hat,
+            buttons,
+            button_count,
+        } => {
+            let mut wire = WireState {
+                axes,
+                hat_x: hat[0],
+                hat_y: hat[1],
+                buttons: [0; 32],
+            };
+            for b in buttons
+                .into_iter()
+                .filter(|b| (1..=MAX_BUTTONS).contains(b))
+            {
+                set(&mut wire.buttons, b, true);
+            }
+            Some(Playing::State {
+                wire,
+                buttons: button_count.min(MAX_BUTTONS),
+                enabled,
+                stale,
+            })
+        }
+        Sent::Raw { raw } => {
+            let payload: Option<Vec<u8>> = (0..raw.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(raw.get(i..i + 2)?, 16).ok())
+                .collect();
+            RawState::decode(&payload?).map(Playing::Raw)
+        }
+    }
+}
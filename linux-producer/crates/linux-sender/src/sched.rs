@@ -0,0 +1,51 @@
+use crate::Config;
+use anyhow::{Context, Result, bail};
+use std::io;
+use std::mem;
+
+// Puts the calling thread (the runtime: reading devices and sending) ahead of
+// the rest of the host. Threads started from it afterwards (force feedback,
+// stdin) stay on its CPU but go back to normal priority
+pub fn apply(config: &Config) -> Result<()> {
+    if config.realtime_priority.is_some() || config.nice.is_some() {
+        let (policy, priority) = match config.realtime_priority {
+            Some(priority) => (libc::SCHED_FIFO, i32::from(priority)),
+            None => (libc::SCHED_OTHER, 0),
+        };
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        // pid 0 is this thread. Reset on fork also undoes a nice below 0 in
+        // new threads
+        let policy = policy | libc::SCHED_RESET_ON_FORK;
+        if unsafe { libc::sched_setscheduler(0, policy, &param) } < 0 {
+            return Err(io::Error::last_os_error()).context(
+                "Could not set the scheduling policy (realtime_priority needs CAP_SYS_NICE or an rtprio limit)",
+            );
+        }
+    }
+
+    if let Some(nice) = config.nice {
+        // Niceness is per thread on Linux
+        let tid = unsafe { libc::gettid() };
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } < 0 {
+            return Err(io::Error::last_os_error()).with_context(|| {
+                format!("Could not set nice {} (below 0 needs CAP_SYS_NICE)", nice)
+            });
+        }
+    }
+
+    if let Some(cpu) = config.cpu {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            bail!("cpu {} is out of range", cpu);
+        }
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+        if unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Could not pin to cpu {}", cpu));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::transform::{pressed, set};
+use anyhow::{Result, anyhow};
+use rhai::{AST, CallFnOptions, Dynamic, Engine, EvalAltResult, FLOAT, FuncArgs, INT, Map, Scope};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{debug, error, info};
+use vkb_sender_core::state::WireState;
+use vkb_sender_core::state::{MAX_BUTTONS, VJOY_AXIS_MAX};
+
+// Operations a hook may take per call, so a script stuck in a loop fails
+// instead of holding up the sender
+const MAX_OPERATIONS: u64 = 100_000;
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+// What the hooks read and what they ask for, shared with the functions
+// registered for them
+#[derive(Default)]
+struct Io {
+    // The device as it came in this tick
+    buttons: [u8; 32],
+    axes: [u16; 8],
+    hat: (i8, i8),
+    // Held until the script lets go: buttons it presses, input buttons it
+    // blocks, axes it sets
+    held: BTreeSet<u16>,
+    blocked: BTreeSet<u16>,
+    set_axes: [Option<u16>; 8],
+}
+
+// A Rhai script run after the other transforms of a device, every send tick.
+// Hooks it may define, `this` being a map it keeps between calls:
+//   init()                     once, when loaded
+//   on_button(button, pressed) per button that changed since the last tick
+//   on_axis(axis, value)       per axis that moved, value 0.0..=1.0
+//   tick()                     every tick, after the others
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    this: Dynamic,
+    io: Arc<Mutex<Io>>,
+    on_button: bool,
+    on_axis: bool,
+    tick: bool,
+    // Buttons and axes the tick before, None before the first
+    last: Option<([u8; 32], [u16; 8])>,
+    // Stopped by an error until the config is loaded again
+    failed: bool,
+    // Highest button it ever pressed, for the wire
+    highest: u16,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script> {
+        let io = Arc::new(Mutex::new(Io::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let name = path.display().to_string();
+        engine.on_print(move |s| info!("{}: {}", name, s));
+        let name = path.display().to_string();
+        engine.on_debug(move |s, _, pos| debug!("{} {}: {}", name, pos, s));
+        register(&mut engine, &io);
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let defines = |hook: &str| ast.iter_functions().any(|f| f.name == hook);
+        let mut script = Script {
+            path: path.to_path_buf(),
+            on_button: defines("on_button"),
+            on_axis: defines("on_axis"),
+            tick: defines("tick"),
+            engine,
+            ast,
+            scope: Scope::new(),
+            this: Dynamic::from_map(Map::new()),
+            io,
+            last: None,
+            failed: false,
+            highest: 0,
+        };
+
+        // Statements outside functions run once too
+        script
+            .engine
+            .run_ast_with_scope(&mut script.scope, &script.ast)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        if script.ast.iter_functions().any(|f| f.name == "init") {
+            script
+                .call("init", ())
+                .map_err(|e| anyhow!("{}: init: {}", path.display(), e))?;
+        }
+        Ok(script)
+    }
+
+    pub fn button_count(&self) -> u16 {
+        self.highest
+    }
+
+    pub fn apply(&mut self, st: &mut WireState) {
+        if self.failed {
+            return;
+        }
+        {
+            let mut io = self.io.lock().unwrap();
+            io.buttons = st.buttons;
+            io.axes = st.axes;
+            io.hat = (st.hat_x, st.hat_y);
+        }
+        if let Err(e) = self.run_hooks(st) {
+            error!(
+                "{}: {}, stopped until the config is reloaded",
+                self.path.display(),
+                e
+            );
+            self.failed = true;
+            // Nothing stays stuck
+            *self.io.lock().unwrap() = Io::default();
+            return;
+        }
+
+        let io = self.io.lock().unwrap();
+        for b in io.blocked.iter() {
+            set(&mut st.buttons, *b, false);
+        }
+        for b in io.held.iter() {
+            set(&mut st.buttons, *b, true);
+        }
+        for (axis, v) in st.axes.iter_mut().zip(io.set_axes) {
+            if let Some(v) = v {
+                *axis = v;
+            }
+        }
+        if let Some(b) = io.held.last() {
+            self.highest = self.highest.max(*b);
+        }
+    }
+
+    fn run_hooks(&mut self, st: &WireState) -> ScriptResult<()> {
+        let now = (st.buttons, st.axes);
+        if let Some((buttons, axes)) = self.last.replace(now) {
+            if self.on_button {
+                for b in 1..=MAX_BUTTONS {
+                    let down = pressed(&st.buttons, b);
+                    if down != pressed(&buttons, b) {
+                        self.call("on_button", (b as INT, down))?;
+                    }
+                }
+            }
+            if self.on_axis {
+                for (i, (v, last)) in st.axes.iter().zip(axes).enumerate() {
+                    if *v != last {
+                        self.call("on_axis", (i as INT + 1, travel(*v)))?;
+                    }
+                }
+            }
+        }
+        if self.tick {
+            self.call("tick", ())?;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, hook: &str, args: impl FuncArgs) -> ScriptResult<()> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.this);
+        self.engine
+            .call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, hook, args)
+            .map(|_| ())
+    }
+}
+
+// The functions scripts get, besides Rhai's own
+fn register(engine: &mut Engine, io: &Arc<Mutex<Io>>) {
+    let started = Instant::now();
+    engine.register_fn("millis", move || started.elapsed().as_millis() as INT);
+
+    // The device as it came in, before the script
+    let shared = Arc::clone(io);
+    engine.register_fn("button", move |b: INT| -> ScriptResult<bool> {
+        Ok(pressed(&shared.lock().unwrap().buttons, button(b)?))
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("axis", move |a: INT| -> ScriptResult<FLOAT> {
+        Ok(travel(shared.lock().unwrap().axes[axis(a)?]))
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("hat_x", move || shared.lock().unwrap().hat.0 as INT);
+    let shared = Arc::clone(io);
+    engine.register_fn("hat_y", move || shared.lock().unwrap().hat.1 as INT);
+
+    // What goes out instead, until let go
+    let shared = Arc::clone(io);
+    engine.register_fn("press", move |b: INT| -> ScriptResult<()> {
+        shared.lock().unwrap().held.insert(button(b)?);
+        Ok(())
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("release", move |b: INT| -> ScriptResult<()> {
+        shared.lock().unwrap().held.remove(&button(b)?);
+        Ok(())
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("block", move |b: INT| -> ScriptResult<()> {
+        shared.lock().unwrap().blocked.insert(button(b)?);
+        Ok(())
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("unblock", move |b: INT| -> ScriptResult<()> {
+        shared.lock().unwrap().blocked.remove(&button(b)?);
+        Ok(())
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("set_axis", move |a: INT, v: FLOAT| -> ScriptResult<()> {
+        let v = (v.clamp(0.0, 1.0) * VJOY_AXIS_MAX as FLOAT).round() as u16;
+        shared.lock().unwrap().set_axes[axis(a)?] = Some(v);
+        Ok(())
+    });
+    let shared = Arc::clone(io);
+    engine.register_fn("free_axis", move |a: INT| -> ScriptResult<()> {
+        shared.lock().unwrap().set_axes[axis(a)?] = None;
+        Ok(())
+    });
+}
+
+fn button(b: INT) -> ScriptResult<u16> {
+    match u16::try_from(b) {
+        Ok(b) if (1..=MAX_BUTTONS).contains(&b) => Ok(b),
+        _ => Err(format!("button {} not in 1..={}", b, MAX_BUTTONS).into()),
+    }
+}
+
+// vJoy axis id (1..=8) -> slot
+fn axis(a: INT) -> ScriptResult<usize> {
+    match a {
+        1..=8 => Ok(a as usize - 1),
+        _ => Err(format!("axis {} not in 1..=8", a).into()),
+    }
+}
+
+fn travel(v: u16) -> FLOAT {
+    v as FLOAT / VJOY_AXIS_MAX as FLOAT
+}
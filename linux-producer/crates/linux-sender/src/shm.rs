@@ -0,0 +1,160 @@
+use anyhow::{Context, Result, bail};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering, fence};
+use std::time::{SystemTime, UNIX_EPOCH};
+use vkb_sender_core::state::WireState;
+
+// Layout of the region, little endian. Bump VERSION on any change readers
+// would notice.
+//
+// Header, HEADER_LEN bytes:
+//   0  magic "VKBS", written last
+//   4  u16 version
+//   6  u16 header length
+//   8  u16 slot length
+//   10 u16 slot count, one per vjoy device by ascending id
+//   12 u32 reserved
+// Slot, SLOT_LEN bytes each, after the header:
+//   0  u32 sequence, odd while the slot is being written: read it, copy the
+//      slot, read it again and retry unless both are the same even number
+//   4  u8 vjoy device id
+//   5  u8 flags, FLAG_*
+//   6  i8 hat x, 7 i8 hat y (-1, 0, 1; y -1 is up)
+//   8  u64 microseconds since the Unix epoch it was written
+//   16 u16 x 8 axes by vJoy axis id, 0..=32768
+//   32 32 bytes of buttons, button 1 is bit 0 of the first
+//   64 u16 buttons sent
+//   66 reserved up to SLOT_LEN
+const MAGIC: &[u8; 4] = b"VKBS";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 16;
+const SLOT_LEN: usize = 80;
+
+pub const FLAG_ENABLED: u8 = 1;
+// A source isn't being read, held neutral
+pub const FLAG_STALE: u8 = 2;
+// Mapped on the receiver, the slot stays empty
+pub const FLAG_RAW: u8 = 4;
+
+// The latest state of each vjoy device, as sent, in shared memory for local
+// tools (overlays, loggers). A bare name goes in /dev/shm, where shm_open
+// finds it as /name
+pub struct Export {
+    path: PathBuf,
+    map: *mut u8,
+    len: usize,
+    // Vjoy device id of each slot
+    slots: Vec<u8>,
+}
+
+// The mapping is the process's, any thread may write it
+unsafe impl Send for Export {}
+
+impl Export {
+    pub fn create(name: &Path, device_ids: &[u8], raw: &[u8]) -> Result<Export> {
+        let path = match name.components().count() {
+            1 if name.is_relative() => Path::new("/dev/shm").join(name),
+            _ => name.to_path_buf(),
+        };
+        let mut slots = device_ids.to_vec();
+        slots.sort();
+        let len = HEADER_LEN + SLOT_LEN * slots.len();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(&path)
+            .with_context(|| format!("Could not create {}", path.display()))?;
+        file.set_len(len as u64)?;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Could not map {}", path.display()));
+        }
+        if slots.len() > u16::MAX as usize {
+            bail!("too many devices");
+        }
+
+        let export = Export {
+            path,
+            map: map as *mut u8,
+            len,
+            slots,
+        };
+        export.put(4, &VERSION.to_le_bytes());
+        export.put(6, &(HEADER_LEN as u16).to_le_bytes());
+        export.put(8, &(SLOT_LEN as u16).to_le_bytes());
+        export.put(10, &(export.slots.len() as u16).to_le_bytes());
+        for (i, k) in export.slots.iter().enumerate() {
+            let flags = if raw.contains(k) { FLAG_RAW } else { 0 };
+            export.put(HEADER_LEN + i * SLOT_LEN + 4, &[*k, flags]);
+        }
+        fence(Ordering::Release);
+        export.put(0, MAGIC);
+        Ok(export)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&self, device_id: u8, wire: &WireState, buttons: u16, flags: u8) {
+        let Ok(i) = self.slots.binary_search(&device_id) else {
+            return;
+        };
+        let at = HEADER_LEN + i * SLOT_LEN;
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+
+        let mut slot = [0u8; SLOT_LEN - 4];
+        slot[0] = device_id;
+        slot[1] = flags;
+        slot[2] = wire.hat_x as u8;
+        slot[3] = wire.hat_y as u8;
+        slot[4..12].copy_from_slice(&micros.to_le_bytes());
+        for (j, v) in wire.axes.iter().enumerate() {
+            slot[12 + j * 2..14 + j * 2].copy_from_slice(&v.to_le_bytes());
+        }
+        slot[28..60].copy_from_slice(&wire.buttons);
+        slot[60..62].copy_from_slice(&buttons.to_le_bytes());
+
+        // Slots start 4 byte aligned, the mapping is page aligned
+        let seq = unsafe { &*(self.map.add(at) as *const AtomicU32) };
+        seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.put(at + 4, &slot);
+        fence(Ordering::Release);
+        seq.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn put(&self, at: usize, bytes: &[u8]) {
+        assert!(at + bytes.len() <= self.len);
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.map.add(at), bytes.len()) };
+    }
+}
+
+// Gone with the bridge, readers still mapping it keep the last state
+impl Drop for Export {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len) };
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::effective::DeviceInfo;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use vkb_sender_core::state::{DeviceState, button_bitpos};
+
+#[derive(Serialize)]
+struct DeviceSnapshot {
+    enabled: bool,
+    // "<device name> (<event path>) via <source>"
+    sources: Vec<String>,
+    // Normalized 0..=32768, before output transforms
+    axes: Vec<u16>,
+    // As read from the kernel
+    axes_raw: Vec<i32>,
+    hat_x: i8,
+    hat_y: i8,
+    // Pressed buttons
+    buttons: Vec<u16>,
+}
+
+// What the sender currently sees, as TOML keyed by vjoy device id
+pub fn snapshot(
+    devices: &BTreeMap<u8, Vec<DeviceInfo>>,
+    states: &HashMap<u8, DeviceState>,
+) -> Result<String> {
+    let mut out: BTreeMap<String, DeviceSnapshot> = BTreeMap::new();
+
+    for (k, st) in states.iter() {
+        let sources = devices
+            .get(k)
+            .into_iter()
+            .flatten()
+            .map(|info| {
+                format!(
+                    "{} ({}) via {}",
+                    info.name,
+                    info.path.display(),
+                    info.source
+                )
+            })
+            .collect();
+
+        let buttons = (1..=st.button_count)
+            .filter(|btn| {
+                let (byte_i, bit_i) = button_bitpos(*btn);
+                st.buttons[byte_i] & (1 << bit_i) != 0
+            })
+            .collect();
+
+        out.insert(
+            k.to_string(),
+            DeviceSnapshot {
+                enabled: st.enabled,
+                sources,
+                axes: st.wire().axes.to_vec(),
+                axes_raw: st.axes_raw.to_vec(),
+                hat_x: st.hat_x,
+                hat_y: st.hat_y,
+                buttons,
+            },
+        );
+    }
+
+    Ok(toml::to_string(&out)?)
+}
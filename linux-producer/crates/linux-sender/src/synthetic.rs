@@ -0,0 +1,77 @@
+use std::time::Duration;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use vkb_sender_core::protocol::V2_BUTTONS;
+use vkb_sender_core::state::{AxisRange, StateTx, Update};
+
+// How often the controls move
+const STEP: Duration = Duration::from_millis(20);
+// One sweep of an axis end to end and back, each axis a slot behind the one
+// before so they tell apart
+const SWEEP: Duration = Duration::from_secs(4);
+// Each hat direction (clockwise from up, then centered) and each button in turn
+const HAT_STEP: Duration = Duration::from_millis(500);
+const BUTTON_STEP: Duration = Duration::from_millis(250);
+const AXIS_MAX: i32 = 32767;
+
+const HAT: [(i8, i8); 9] = [
+    (0, -1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, 0),
+];
+
+// --synthetic: stands in for a vjoy device's sources, sweeping every axis,
+// turning the hat and pressing each of the first 128 buttons in turn
+pub async fn run(tx: StateTx) {
+    let range = AxisRange {
+        min: 0,
+        max: AXIS_MAX,
+        center: None,
+    };
+    tx.send(Update::Attach {
+        axes: (0..8).map(|slot| (slot, range, AXIS_MAX / 2)).collect(),
+        pressed: Vec::new(),
+        button_count: V2_BUTTONS,
+    });
+
+    let started = Instant::now();
+    let mut steps = time::interval(STEP);
+    steps.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut button = 0;
+    loop {
+        let now = steps.tick().await;
+        let elapsed = (now - started).as_secs_f64();
+
+        for slot in 0..8 {
+            let phase = (elapsed / SWEEP.as_secs_f64() + slot as f64 / 8.0).fract();
+            let travel = 1.0 - (2.0 * phase - 1.0).abs();
+            tx.send(Update::Axis {
+                slot,
+                value: (travel * AXIS_MAX as f64).round() as i32,
+            });
+        }
+
+        let (x, y) = HAT[(elapsed / HAT_STEP.as_secs_f64()) as usize % HAT.len()];
+        tx.send(Update::Hat(x, y));
+
+        let now_button = (elapsed / BUTTON_STEP.as_secs_f64()) as u16 % V2_BUTTONS + 1;
+        if now_button != button {
+            if button != 0 {
+                tx.send(Update::Button {
+                    id: button,
+                    pressed: false,
+                });
+            }
+            tx.send(Update::Button {
+                id: now_button,
+                pressed: true,
+            });
+            button = now_button;
+        }
+    }
+}
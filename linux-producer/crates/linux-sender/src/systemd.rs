@@ -0,0 +1,44 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// Tells systemd how the service is doing (READY=1, STATUS=..., STOPPING=1), when
+// started by it as a Type=notify service. Like sd_notify, nothing happens
+// without NOTIFY_SOCKET and failures are ignored
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    // '@' starts an abstract socket name
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&*path),
+    };
+    let (Ok(addr), Ok(sock)) = (addr, UnixDatagram::unbound()) else {
+        return;
+    };
+    let _ = sock.send_to_addr(state.as_bytes(), &addr);
+}
+
+pub fn notifying() -> bool {
+    env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+// How often to send WATCHDOG=1 with WatchdogSec set: twice per timeout
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // Meant for another process when WATCHDOG_PID is set to a different one
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid != std::process::id().to_string()
+    {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+// Started with stdout or stderr connected to the journal
+pub fn under_journald() -> bool {
+    env::var_os("JOURNAL_STREAM").is_some()
+}
@@ -0,0 +1,1672 @@
+use crate::script::Script;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use vkb_sender_core::state::{MAX_BUTTONS, VJOY_AXIS_MAX, WireState};
+
+// How long a button generated from a tap is held on the wire, long enough for
+// sims polling once a frame to see it
+const PULSE: Duration = Duration::from_millis(50);
+
+// Per vjoy device output transforms, [transform.<device id>] in config.toml
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TransformConfig {
+    // Buttons renumbered ahead of every other stage: vJoy button -> the number
+    // it goes on as, e.g. a game's bindings moved without rebinding in it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub remap: BTreeMap<u16, u16>,
+    // Axes driven by a pair of buttons, by vJoy axis id, ahead of the curves
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub button_axis: BTreeMap<u8, ButtonAxisConfig>,
+    // Axes made from two others by vJoy axis id, before the curves
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub merge: BTreeMap<u8, MergeConfig>,
+    // Axes whose deflection sets how fast they move, by vJoy axis id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rate: BTreeMap<u8, RateConfig>,
+    // Response curves by vJoy axis id, applied before the mix
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub curve: BTreeMap<u8, CurveConfig>,
+    // Mixing matrix: vJoy axis id -> (input axis id -> coefficient), on centered
+    // axes (-1..1). Axes without a row pass through unchanged.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mix: BTreeMap<u8, BTreeMap<u8, f32>>,
+    // Trim by vJoy axis id, added ahead of the curves and mix
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trim: BTreeMap<u8, TrimConfig>,
+    // Buttons freezing axes at their value, by vJoy button number
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hold: BTreeMap<u16, HoldConfig>,
+    // Buttons pressed by an axis past a point (detents): vJoy button -> rule
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub threshold: BTreeMap<u16, ThresholdConfig>,
+    // The hat as buttons, for games that can't bind it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hat_buttons: Option<HatButtonsConfig>,
+    // Chords: vJoy button sent while all of these are held, before shifting.
+    // Their own presses are held back for chord_ms waiting for the rest
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub chord: BTreeMap<u16, Vec<u16>>,
+    #[serde(default = "default_chord_ms")]
+    pub chord_ms: u64,
+    // Button banks picked by a mode selector, before shifting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ModeConfig>,
+    // Shift buttons by vJoy button number; several held add up their offsets
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub shift: BTreeMap<u16, ShiftConfig>,
+    // Dual function buttons by vJoy button number, after shifting: a tap sends
+    // the button itself, holding it sends another one instead
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub long_press: BTreeMap<u16, LongPressConfig>,
+    // Auto-repeat: vJoy button -> presses per second while it is held
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub turbo: BTreeMap<u16, f32>,
+    // Buttons, after shifting, that latch: one press holds, the next releases
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub toggle: Vec<u16>,
+    // Macros by the vJoy button starting them, played out over the send ticks
+    #[serde(default, rename = "macro", skip_serializing_if = "BTreeMap::is_empty")]
+    pub macros: BTreeMap<u16, Vec<MacroStep>>,
+    // Rhai script run after every other stage, for what the tables can't say
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<PathBuf>,
+}
+
+// Moves by rate (travel per second) while a button is held, and by step on each
+// press (encoders). Starts at start, fractions of the travel (0..1)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ButtonAxisConfig {
+    pub increase: u16,
+    pub decrease: u16,
+    #[serde(default)]
+    pub rate: f32,
+    #[serde(default)]
+    pub step: f32,
+    #[serde(default = "default_start")]
+    pub start: f32,
+}
+
+fn default_start() -> f32 {
+    0.5
+}
+
+// Two vJoy axes into one, on fractions of their travel (0..1), e.g. toe brakes
+// into one brake axis (max) or into a rudder (difference: first one up)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MergeConfig {
+    pub axes: [u8; 2],
+    #[serde(default)]
+    pub function: MergeFunction,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeFunction {
+    #[default]
+    Average,
+    Max,
+    Min,
+    Sum,
+    Difference,
+}
+
+// Full deflection moves the output speed of its travel per second, within
+// deadzone of center (centered scale, -1..1) it stays put
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RateConfig {
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+    #[serde(default)]
+    pub deadzone: f32,
+    #[serde(default = "default_start")]
+    pub start: f32,
+}
+
+fn default_speed() -> f32 {
+    1.0
+}
+
+// Each press of up or down moves the axis by step on the centered scale (-1..1),
+// reset puts it back. The offset stays until then
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TrimConfig {
+    pub up: u16,
+    pub down: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset: Option<u16>,
+    #[serde(default = "default_trim_step")]
+    pub step: f32,
+}
+
+fn default_trim_step() -> f32 {
+    0.01
+}
+
+// vJoy axis ids kept where they were while the button is held, or from one
+// press to the next with toggle
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct HoldConfig {
+    pub axes: Vec<u8>,
+    #[serde(default)]
+    pub toggle: bool,
+}
+
+// On a vJoy axis after curves and mix, as a fraction of its travel (0..1). One
+// of above or below; hysteresis is how far back it has to go to release
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ThresholdConfig {
+    pub axis: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f32>,
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f32,
+}
+
+fn default_hysteresis() -> f32 {
+    0.02
+}
+
+// Up, right, down, left from button on, both neighbours held on diagonals. With
+// eight, up, up-right, right, ... up-left, one at a time
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct HatButtonsConfig {
+    pub button: u16,
+    #[serde(default)]
+    pub eight: bool,
+    // Send the hat as well
+    #[serde(default)]
+    pub keep_hat: bool,
+}
+
+// Presses, then releases, then waits before the next step. Whatever is still
+// pressed at the end is released
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MacroStep {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub press: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub release: Vec<u16>,
+    #[serde(default)]
+    pub wait_ms: u64,
+}
+
+// A mode selector (VKB's mode wheel or switch): its positions read off a vJoy
+// axis, the travel split evenly between them, or stepped through by two buttons
+// (an encoder's rel buttons), round at the ends. As a transform's mode its step
+// buttons are not sent; profile_switch reads them off a copy, so there they go
+// out like its other buttons
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ModeSourceConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub axis: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub up: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub down: Option<u16>,
+}
+
+// As VKB's firmware does it: in mode position n (from 0) buttons pressed come
+// out n * offset higher, staying there until released
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ModeConfig {
+    #[serde(flatten)]
+    pub source: ModeSourceConfig,
+    pub positions: u16,
+    pub offset: u16,
+    // The buttons it moves, default 1..=offset
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buttons: Vec<u16>,
+}
+
+// While the shift button is held, buttons pressed come out offset higher and
+// stay there until released. The shift button itself is not sent
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ShiftConfig {
+    pub offset: u16,
+    // The buttons it moves, default the ones below the lowest offset
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buttons: Vec<u16>,
+}
+
+// On centered axes (-1..1): the exponent bends both halves alike (2 = softer
+// center), then the points, if any, are interpolated linearly
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CurveConfig {
+    #[serde(default = "default_exponent")]
+    pub exponent: f32,
+    // [input, output] pairs by ascending input
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<[f32; 2]>,
+}
+
+fn default_exponent() -> f32 {
+    1.0
+}
+
+fn default_chord_ms() -> u64 {
+    50
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LongPressConfig {
+    // Sent while held past ms
+    pub button: u16,
+    #[serde(default = "default_long_press_ms")]
+    pub ms: u64,
+}
+
+fn default_long_press_ms() -> u64 {
+    500
+}
+
+// The stages of one device, applied in order every send tick
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+// What a device's stages hold that isn't on any physical control: toggles
+// latched on and trim offsets by vJoy axis id
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SoftState {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub toggle: Vec<u16>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trim: BTreeMap<u8, f32>,
+}
+
+impl SoftState {
+    pub fn is_empty(&self) -> bool {
+        self.toggle.is_empty() && self.trim.is_empty()
+    }
+}
+
+enum Stage {
+    // (from, to)
+    Remap(Vec<(u16, u16)>),
+    ButtonAxis(Vec<ButtonAxis>),
+    // (output, inputs, function)
+    Merge(Vec<(usize, [usize; 2], MergeFunction)>),
+    Rate(Vec<Rate>),
+    Trim(Vec<Trim>),
+    // Per axis, None = passes through
+    Curve([Option<CurveConfig>; 8]),
+    // rows[out][in], None = output passes through
+    Mix([Option<[f32; 8]>; 8]),
+    Hold(Vec<Hold>),
+    Threshold(Vec<Threshold>),
+    HatButtons(HatButtonsConfig),
+    Chord(Vec<Chord>),
+    Mode(Mode),
+    Shift(Shift),
+    LongPress(Vec<LongPress>),
+    Turbo(Vec<Turbo>),
+    Toggle(Vec<Toggle>),
+    Macro(Vec<Macro>),
+    Script(Box<Script>),
+}
+
+struct Macro {
+    trigger: u16,
+    steps: Vec<MacroStep>,
+    down: bool,
+    // While playing: next step and when it is due
+    next: Option<(usize, Instant)>,
+    held: BTreeSet<u16>,
+}
+
+struct ButtonAxis {
+    axis: usize,
+    cfg: ButtonAxisConfig,
+    value: f32,
+    // Buttons last tick, and when that was
+    down: (bool, bool),
+    last: Option<Instant>,
+}
+
+struct Rate {
+    axis: usize,
+    cfg: RateConfig,
+    value: f32,
+    last: Option<Instant>,
+}
+
+struct Trim {
+    axis: usize,
+    cfg: TrimConfig,
+    offset: f32,
+    // up, down, reset last tick
+    down: [bool; 3],
+}
+
+struct Hold {
+    button: u16,
+    axes: Vec<usize>,
+    toggle: bool,
+    down: bool,
+    // Values of axes while frozen
+    frozen: Option<Vec<u16>>,
+}
+
+struct Threshold {
+    button: u16,
+    axis: usize,
+    // Where it presses and where it releases again, in axis units
+    press: u16,
+    release: u16,
+    above: bool,
+    on: bool,
+}
+
+struct Chord {
+    button: u16,
+    members: Vec<u16>,
+    window: Duration,
+    state: ChordState,
+    // Members let go before the chord formed, sent as a tap until then
+    pulse: Option<(Instant, Vec<bool>)>,
+}
+
+enum ChordState {
+    Idle,
+    // Some members down, held back; which ones have been
+    Pending(Instant, Vec<bool>),
+    // All went down together: the chord button, members held back until up
+    Active,
+    // Not a chord after all: members pass until all are up
+    Passing,
+}
+
+struct LongPress {
+    button: u16,
+    long_button: u16,
+    after: Duration,
+    down_since: Option<Instant>,
+    // The tap being sent, until then
+    pulse_until: Option<Instant>,
+}
+
+struct Turbo {
+    button: u16,
+    period: Duration,
+    down_since: Option<Instant>,
+}
+
+struct Toggle {
+    button: u16,
+    // Physical state last tick, and the latched one sent
+    down: bool,
+    on: bool,
+}
+
+pub struct ModeSource {
+    axis: Option<usize>,
+    // up, down
+    steps: Option<(u16, u16)>,
+    positions: u16,
+    position: u16,
+    // up, down last tick
+    down: (bool, bool),
+}
+
+impl ModeSource {
+    pub fn new(cfg: &ModeSourceConfig, positions: u16) -> Result<ModeSource> {
+        if positions < 2 {
+            bail!("needs at least 2 positions");
+        }
+        let steps = match (cfg.up, cfg.down) {
+            (Some(up), Some(down)) => {
+                check_button(up)?;
+                check_button(down)?;
+                Some((up, down))
+            }
+            (None, None) => None,
+            _ => bail!("needs both up and down"),
+        };
+        let axis = cfg.axis.map(axis_index).transpose()?;
+        if axis.is_some() == steps.is_some() {
+            bail!("needs one of axis, or up and down");
+        }
+        Ok(ModeSource {
+            axis,
+            steps,
+            positions,
+            position: 0,
+            down: (false, false),
+        })
+    }
+
+    // Slot of the axis it is read off
+    pub fn axis(&self) -> Option<usize> {
+        self.axis
+    }
+
+    // Where the step buttons go on from
+    pub fn set_position(&mut self, position: u16) {
+        self.position = position.min(self.positions - 1);
+    }
+
+    // Position as of this tick, 0..positions. Step buttons are let go
+    pub fn read(&mut self, axes: &[u16; 8], buttons: &mut [u8; 32]) -> u16 {
+        if let Some(axis) = self.axis {
+            let travel = axes[axis] as f32 / VJOY_AXIS_MAX as f32;
+            self.position = (travel * (self.positions - 1) as f32).round() as u16;
+        }
+        if let Some((up, down)) = self.steps {
+            let now = (pressed(buttons, up), pressed(buttons, down));
+            if now.0 && !self.down.0 {
+                self.position = (self.position + 1) % self.positions;
+            }
+            if now.1 && !self.down.1 {
+                self.position = (self.position + self.positions - 1) % self.positions;
+            }
+            self.down = now;
+            set(buttons, up, false);
+            set(buttons, down, false);
+        }
+        self.position
+    }
+}
+
+struct Mode {
+    source: ModeSource,
+    offset: u16,
+    banked: Vec<u16>,
+    // Offset each banked button went down with, while it is held
+    held: BTreeMap<u16, u16>,
+}
+
+struct Shift {
+    // (button, offset)
+    shifts: Vec<(u16, u16)>,
+    shifted: Vec<u16>,
+    // Offset each shifted button went down with, while it is held
+    held: BTreeMap<u16, u32>,
+}
+
+impl Pipeline {
+    pub fn new(cfg: &TransformConfig) -> Result<Pipeline> {
+        let mut stages = Vec::new();
+
+        if !cfg.remap.is_empty() {
+            let mut remaps = Vec::new();
+            for (from, to) in cfg.remap.iter() {
+                check_button(*from).map_err(|e| e.context("remap"))?;
+                check_button(*to).map_err(|e| e.context(format!("remap {}", from)))?;
+                remaps.push((*from, *to));
+            }
+            stages.push(Stage::Remap(remaps));
+        }
+
+        if !cfg.button_axis.is_empty() {
+            let mut axes = Vec::new();
+            for (axis, c) in cfg.button_axis.iter() {
+                let context = || format!("button_axis {}", axis);
+                check_button(c.increase).with_context(context)?;
+                check_button(c.decrease).with_context(context)?;
+                if !(0.0..=1.0).contains(&c.start) {
+                    bail!("button_axis {}: start {} is outside 0..=1", axis, c.start);
+                }
+                if c.rate.is_nan() || c.step.is_nan() || c.rate < 0.0 || c.step < 0.0 {
+                    bail!("button_axis {}: rate and step can't be negative", axis);
+                }
+                if c.rate == 0.0 && c.step == 0.0 {
+                    bail!("button_axis {}: needs a rate or a step", axis);
+                }
+                axes.push(ButtonAxis {
+                    axis: axis_index(*axis)?,
+                    cfg: c.clone(),
+                    value: c.start,
+                    down: (false, false),
+                    last: None,
+                });
+            }
+            stages.push(Stage::ButtonAxis(axes));
+        }
+
+        if !cfg.merge.is_empty() {
+            let mut merges = Vec::new();
+            for (out, m) in cfg.merge.iter() {
+                let [a, b] = m.axes;
+                merges.push((
+                    axis_index(*out)?,
+                    [axis_index(a)?, axis_index(b)?],
+                    m.function,
+                ));
+            }
+            stages.push(Stage::Merge(merges));
+        }
+
+        if !cfg.rate.is_empty() {
+            let mut rates = Vec::new();
+            for (axis, c) in cfg.rate.iter() {
+                if c.speed.is_nan() || c.speed <= 0.0 {
+                    bail!("rate {}: speed {} must be above 0", axis, c.speed);
+                }
+                if !(0.0..1.0).contains(&c.deadzone) {
+                    bail!("rate {}: deadzone {} is outside 0..1", axis, c.deadzone);
+                }
+                if !(0.0..=1.0).contains(&c.start) {
+                    bail!("rate {}: start {} is outside 0..=1", axis, c.start);
+                }
+                rates.push(Rate {
+                    axis: axis_index(*axis)?,
+                    cfg: c.clone(),
+                    value: c.start,
+                    last: None,
+                });
+            }
+            stages.push(Stage::Rate(rates));
+        }
+
+        if !cfg.trim.is_empty() {
+            let mut trims = Vec::new();
+            for (axis, c) in cfg.trim.iter() {
+                let context = || format!("trim {}", axis);
+                for b in [c.up, c.down].iter().chain(c.reset.iter()) {
+                    check_button(*b).with_context(context)?;
+                }
+                if !(c.step > 0.0 && c.step <= 1.0) {
+                    bail!(
+                        "trim {}: step {} must be above 0 and at most 1",
+                        axis,
+                        c.step
+                    );
+                }
+                trims.push(Trim {
+                    axis: axis_index(*axis)?,
+                    cfg: c.clone(),
+                    offset: 0.0,
+                    down: [false; 3],
+                });
+            }
+            stages.push(Stage::Trim(trims));
+        }
+
+        if !cfg.curve.is_empty() {
+            let mut curves: [Option<CurveConfig>; 8] = Default::default();
+            for (axis, curve) in cfg.curve.iter() {
+                check_curve(curve).map_err(|e| e.context(format!("curve {}", axis)))?;
+                curves[axis_index(*axis)?] = Some(curve.clone());
+            }
+            stages.push(Stage::Curve(curves));
+        }
+
+        if !cfg.mix.is_empty() {
+            let mut rows = [None; 8];
+            for (out, inputs) in cfg.mix.iter() {
+                let mut coeffs = [0.0; 8];
+                for (input, c) in inputs.iter() {
+                    if !c.is_finite() {
+                        bail!(
+                            "mix {}: coefficient {} for axis {} has to be a number",
+                            out,
+                            c,
+                            input
+                        );
+                    }
+                    coeffs[axis_index(*input)?] = *c;
+                }
+                rows[axis_index(*out)?] = Some(coeffs);
+            }
+            stages.push(Stage::Mix(rows));
+        }
+
+        if !cfg.hold.is_empty() {
+            let mut holds = Vec::new();
+            for (b, c) in cfg.hold.iter() {
+                check_button(*b).map_err(|e| e.context("hold"))?;
+                if c.axes.is_empty() {
+                    bail!("hold {}: needs axes", b);
+                }
+                let axes = c
+                    .axes
+                    .iter()
+                    .map(|a| axis_index(*a))
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("hold {}", b))?;
+                holds.push(Hold {
+                    button: *b,
+                    axes,
+                    toggle: c.toggle,
+                    down: false,
+                    frozen: None,
+                });
+            }
+            stages.push(Stage::Hold(holds));
+        }
+
+        if !cfg.threshold.is_empty() {
+            let mut thresholds = Vec::new();
+            for (b, c) in cfg.threshold.iter() {
+                check_button(*b).map_err(|e| e.context("threshold"))?;
+                thresholds
+                    .push(threshold(*b, c).map_err(|e| e.context(format!("threshold {}", b)))?);
+            }
+            stages.push(Stage::Threshold(thresholds));
+        }
+
+        if let Some(hat) = &cfg.hat_buttons {
+            let count = if hat.eight { 8 } else { 4 };
+            check_button(hat.button).map_err(|e| e.context("hat_buttons"))?;
+            check_button(hat.button + count - 1).map_err(|e| e.context("hat_buttons"))?;
+            stages.push(Stage::HatButtons(hat.clone()));
+        }
+
+        if !cfg.chord.is_empty() {
+            let mut chords: Vec<Chord> = Vec::new();
+            for (b, members) in cfg.chord.iter() {
+                check_button(*b).map_err(|e| e.context("chord"))?;
+                if members.len() < 2 {
+                    bail!("chord {}: needs at least two buttons", b);
+                }
+                for m in members.iter() {
+                    check_button(*m).map_err(|e| e.context(format!("chord {}", b)))?;
+                    if chords.iter().any(|c| c.members.contains(m)) {
+                        bail!("chord {}: button {} is already in another chord", b, m);
+                    }
+                }
+                chords.push(Chord {
+                    button: *b,
+                    members: members.clone(),
+                    window: Duration::from_millis(cfg.chord_ms),
+                    state: ChordState::Idle,
+                    pulse: None,
+                });
+            }
+            stages.push(Stage::Chord(chords));
+        }
+
+        if let Some(c) = &cfg.mode {
+            stages.push(Stage::Mode(mode(c).context("mode")?));
+        }
+
+        if !cfg.shift.is_empty() {
+            stages.push(Stage::Shift(shift(&cfg.shift)?));
+        }
+
+        if !cfg.long_press.is_empty() {
+            let mut presses = Vec::new();
+            for (b, c) in cfg.long_press.iter() {
+                check_button(*b).map_err(|e| e.context("long_press"))?;
+                check_button(c.button).map_err(|e| e.context(format!("long_press {}", b)))?;
+                if c.button == *b {
+                    bail!("long_press {}: needs another button for the hold", b);
+                }
+                if c.ms == 0 {
+                    bail!("long_press {}: ms must be above 0", b);
+                }
+                presses.push(LongPress {
+                    button: *b,
+                    long_button: c.button,
+                    after: Duration::from_millis(c.ms),
+                    down_since: None,
+                    pulse_until: None,
+                });
+            }
+            stages.push(Stage::LongPress(presses));
+        }
+
+        if !cfg.turbo.is_empty() {
+            let mut turbos = Vec::new();
+            for (b, hz) in cfg.turbo.iter() {
+                check_button(*b).map_err(|e| e.context("turbo"))?;
+                if !(*hz > 0.0 && *hz <= 50.0) {
+                    bail!(
+                        "turbo {}: {} presses per second, must be above 0 and at most 50",
+                        b,
+                        hz
+                    );
+                }
+                turbos.push(Turbo {
+                    button: *b,
+                    period: Duration::from_secs_f32(1.0 / hz),
+                    down_since: None,
+                });
+            }
+            stages.push(Stage::Turbo(turbos));
+        }
+
+        if !cfg.toggle.is_empty() {
+            let mut toggles = Vec::new();
+            for b in cfg.toggle.iter() {
+                check_button(*b).map_err(|e| e.context("toggle"))?;
+                if toggles.iter().any(|t: &Toggle| t.button == *b) {
+                    bail!("toggle: button {} listed twice", b);
+                }
+                toggles.push(Toggle {
+                    button: *b,
+                    down: false,
+                    on: false,
+                });
+            }
+            stages.push(Stage::Toggle(toggles));
+        }
+
+        if !cfg.macros.is_empty() {
+            let mut macros = Vec::new();
+            for (b, steps) in cfg.macros.iter() {
+                check_button(*b).map_err(|e| e.context("macro"))?;
+                if steps.is_empty() {
+                    bail!("macro {}: has no steps", b);
+                }
+                for step in steps.iter() {
+                    for sb in step.press.iter().chain(step.release.iter()) {
+                        check_button(*sb).map_err(|e| e.context(format!("macro {}", b)))?;
+                    }
+                }
+                macros.push(Macro {
+                    trigger: *b,
+                    steps: steps.clone(),
+                    down: false,
+                    next: None,
+                    held: BTreeSet::new(),
+                });
+            }
+            stages.push(Stage::Macro(macros));
+        }
+
+        if let Some(path) = &cfg.script {
+            stages.push(Stage::Script(Box::new(Script::load(path)?)));
+        }
+
+        Ok(Pipeline { stages })
+    }
+
+    // Highest button number the stages can send
+    pub fn button_count(&self) -> u16 {
+        self.stages
+            .iter()
+            .map(|stage| match stage {
+                Stage::Remap(remaps) => remaps.iter().map(|(_, to)| *to).max().unwrap_or(0),
+                Stage::Mode(mode) => mode
+                    .banked
+                    .iter()
+                    .max()
+                    .map_or(0, |b| b + (mode.source.positions - 1) * mode.offset),
+                Stage::Shift(shift) => {
+                    let total: u32 = shift.shifts.iter().map(|(_, o)| *o as u32).sum();
+                    shift.shifted.iter().max().map_or(0, |b| {
+                        u16::try_from(*b as u32 + total).unwrap_or(MAX_BUTTONS)
+                    })
+                }
+                Stage::Threshold(thresholds) => {
+                    thresholds.iter().map(|t| t.button).max().unwrap_or(0)
+                }
+                Stage::HatButtons(hat) => hat.button + if hat.eight { 7 } else { 3 },
+                Stage::Chord(chords) => chords.iter().map(|c| c.button).max().unwrap_or(0),
+                Stage::LongPress(presses) => {
+                    presses.iter().map(|p| p.long_button).max().unwrap_or(0)
+                }
+                Stage::Macro(macros) => macros
+                    .iter()
+                    .flat_map(|m| m.steps.iter())
+                    .flat_map(|step| step.press.iter().copied())
+                    .max()
+                    .unwrap_or(0),
+                Stage::Script(script) => script.button_count(),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn soft_state(&self) -> SoftState {
+        let mut soft = SoftState::default();
+        for stage in self.stages.iter() {
+            match stage {
+                Stage::Trim(trims) => {
+                    for t in trims.iter().filter(|t| t.offset != 0.0) {
+                        soft.trim.insert(t.axis as u8 + 1, t.offset);
+                    }
+                }
+                Stage::Toggle(toggles) => {
+                    soft.toggle
+                        .extend(toggles.iter().filter(|t| t.on).map(|t| t.button));
+                }
+                _ => {}
+            }
+        }
+        soft
+    }
+
+    // Toggles and trims as saved, those no longer configured left out
+    pub fn restore(&mut self, soft: &SoftState) {
+        for stage in self.stages.iter_mut() {
+            match stage {
+                Stage::Trim(trims) => {
+                    for t in trims.iter_mut() {
+                        let offset = soft.trim.get(&(t.axis as u8 + 1)).copied();
+                        t.offset = offset
+                            .filter(|o| o.is_finite())
+                            .map_or(0.0, |o| o.clamp(-1.0, 1.0));
+                    }
+                }
+                Stage::Toggle(toggles) => {
+                    for t in toggles.iter_mut() {
+                        t.on = soft.toggle.contains(&t.button);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn apply(&mut self, st: &mut WireState, now: Instant) {
+        for stage in self.stages.iter_mut() {
+            match stage {
+                Stage::Remap(remaps) => {
+                    // Sources let go first, so swapping two buttons works
+                    let before = st.buttons;
+                    for (from, _) in remaps.iter() {
+                        set(&mut st.buttons, *from, false);
+                    }
+                    for (from, to) in remaps.iter() {
+                        if pressed(&before, *from) {
+                            set(&mut st.buttons, *to, true);
+                        }
+                    }
+                }
+                Stage::ButtonAxis(axes) => {
+                    for a in axes.iter_mut() {
+                        let dt = a.last.map_or(0.0, |last| (now - last).as_secs_f32());
+                        a.last = Some(now);
+                        let down = (
+                            pressed(&st.buttons, a.cfg.increase),
+                            pressed(&st.buttons, a.cfg.decrease),
+                        );
+                        let mut delta = 0.0;
+                        if down.0 {
+                            delta += a.cfg.rate * dt + if a.down.0 { 0.0 } else { a.cfg.step };
+                        }
+                        if down.1 {
+                            delta -= a.cfg.rate * dt + if a.down.1 { 0.0 } else { a.cfg.step };
+                        }
+                        a.down = down;
+                        a.value = (a.value + delta).clamp(0.0, 1.0);
+                        st.axes[a.axis] = (a.value * VJOY_AXIS_MAX as f32).round() as u16;
+                    }
+                }
+                Stage::Merge(merges) => {
+                    let travel = st.axes.map(|v| v as f32 / VJOY_AXIS_MAX as f32);
+                    for (out, [a, b], function) in merges.iter() {
+                        let (a, b) = (travel[*a], travel[*b]);
+                        let v = match function {
+                            MergeFunction::Average => (a + b) / 2.0,
+                            MergeFunction::Max => a.max(b),
+                            MergeFunction::Min => a.min(b),
+                            MergeFunction::Sum => a + b,
+                            MergeFunction::Difference => (1.0 + a - b) / 2.0,
+                        };
+                        st.axes[*out] = (v.clamp(0.0, 1.0) * VJOY_AXIS_MAX as f32).round() as u16;
+                    }
+                }
+                Stage::Rate(rates) => {
+                    for r in rates.iter_mut() {
+                        let dt = r.last.map_or(0.0, |last| (now - last).as_secs_f32());
+                        r.last = Some(now);
+                        let x = to_centered(st.axes[r.axis]);
+                        if x.abs() > r.cfg.deadzone {
+                            // Rescaled so speed starts from 0 at the deadzone edge
+                            let x =
+                                x.signum() * (x.abs() - r.cfg.deadzone) / (1.0 - r.cfg.deadzone);
+                            r.value = (r.value + x * r.cfg.speed * dt).clamp(0.0, 1.0);
+                        }
+                        st.axes[r.axis] = (r.value * VJOY_AXIS_MAX as f32).round() as u16;
+                    }
+                }
+                Stage::Trim(trims) => {
+                    for t in trims.iter_mut() {
+                        let down = [
+                            pressed(&st.buttons, t.cfg.up),
+                            pressed(&st.buttons, t.cfg.down),
+                            t.cfg.reset.is_some_and(|b| pressed(&st.buttons, b)),
+                        ];
+                        let went_down = |i: usize| down[i] && !t.down[i];
+                        if went_down(0) {
+                            t.offset += t.cfg.step;
+                        }
+                        if went_down(1) {
+                            t.offset -= t.cfg.step;
+                        }
+                        if went_down(2) {
+                            t.offset = 0.0;
+                        }
+                        t.offset = t.offset.clamp(-1.0, 1.0);
+                        t.down = down;
+                        let axis = &mut st.axes[t.axis];
+                        *axis = from_centered(to_centered(*axis) + t.offset);
+                    }
+                }
+                Stage::Curve(curves) => {
+                    for (axis, curve) in st.axes.iter_mut().zip(curves.iter()) {
+                        if let Some(curve) = curve {
+                            *axis = from_centered(apply_curve(curve, to_centered(*axis)));
+                        }
+                    }
+                }
+                Stage::Mix(rows) => {
+                    let inputs = st.axes.map(to_centered);
+                    for (axis, row) in st.axes.iter_mut().zip(rows.iter()) {
+                        if let Some(coeffs) = row {
+                            let v: f32 = coeffs.iter().zip(inputs).map(|(c, x)| c * x).sum();
+                            *axis = from_centered(v);
+                        }
+                    }
+                }
+                Stage::Hold(holds) => {
+                    for h in holds.iter_mut() {
+                        let down = pressed(&st.buttons, h.button);
+                        let freeze = match h.toggle {
+                            true if down && !h.down => h.frozen.is_none(),
+                            true => h.frozen.is_some(),
+                            false => down,
+                        };
+                        h.down = down;
+                        if !freeze {
+                            h.frozen = None;
+                            continue;
+                        }
+                        let frozen = h
+                            .frozen
+                            .get_or_insert_with(|| h.axes.iter().map(|a| st.axes[*a]).collect());
+                        for (a, v) in h.axes.iter().zip(frozen.iter()) {
+                            st.axes[*a] = *v;
+                        }
+                    }
+                }
+                Stage::Threshold(thresholds) => {
+                    for t in thresholds.iter_mut() {
+                        let v = st.axes[t.axis];
+                        t.on = match (t.above, t.on) {
+                            (true, false) => v >= t.press,
+                            (true, true) => v >= t.release,
+                            (false, false) => v <= t.press,
+                            (false, true) => v <= t.release,
+                        };
+                        if t.on {
+                            set(&mut st.buttons, t.button, true);
+                        }
+                    }
+                }
+                Stage::HatButtons(hat) => {
+                    // Hat y goes up negative, as evdev reports it
+                    let (x, y) = (st.hat_x, st.hat_y);
+                    if hat.eight {
+                        let direction = match (x, y) {
+                            (0, -1) => Some(0),
+                            (1, -1) => Some(1),
+                            (1, 0) => Some(2),
+                            (1, 1) => Some(3),
+                            (0, 1) => Some(4),
+                            (-1, 1) => Some(5),
+                            (-1, 0) => Some(6),
+                            (-1, -1) => Some(7),
+                            _ => None,
+                        };
+                        if let Some(d) = direction {
+                            set(&mut st.buttons, hat.button + d, true);
+                        }
+                    } else {
+                        for (d, on) in [y < 0, x > 0, y > 0, x < 0].into_iter().enumerate() {
+                            if on {
+                                set(&mut st.buttons, hat.button + d as u16, true);
+                            }
+                        }
+                    }
+                    if !hat.keep_hat {
+                        st.hat_x = 0;
+                        st.hat_y = 0;
+                    }
+                }
+                Stage::Chord(chords) => {
+                    for c in chords.iter_mut() {
+                        apply_chord(c, &mut st.buttons, now);
+                    }
+                }
+                Stage::Mode(mode) => {
+                    let position = mode.source.read(&st.axes, &mut st.buttons);
+                    let input = st.buttons;
+                    for b in mode.banked.iter() {
+                        set(&mut st.buttons, *b, false);
+                    }
+                    for b in mode.banked.iter() {
+                        if pressed(&input, *b) {
+                            let at = *mode.held.entry(*b).or_insert(position * mode.offset);
+                            set(&mut st.buttons, b + at, true);
+                        } else {
+                            mode.held.remove(b);
+                        }
+                    }
+                }
+                Stage::Shift(shift) => {
+                    let offset: u32 = shift
+                        .shifts
+                        .iter()
+                        .filter(|(b, _)| pressed(&st.buttons, *b))
+                        .map(|(_, offset)| *offset as u32)
+                        .sum();
+                    let input = st.buttons;
+                    for (b, _) in shift.shifts.iter() {
+                        set(&mut st.buttons, *b, false);
+                    }
+                    for b in shift.shifted.iter() {
+                        set(&mut st.buttons, *b, false);
+                    }
+                    for b in shift.shifted.iter() {
+                        if pressed(&input, *b) {
+                            let at = *shift.held.entry(*b).or_insert(offset);
+                            // Checked on load, with every shift held
+                            if let Ok(to) = u16::try_from(*b as u32 + at)
+                                && to <= MAX_BUTTONS
+                            {
+                                set(&mut st.buttons, to, true);
+                            }
+                        } else {
+                            shift.held.remove(b);
+                        }
+                    }
+                }
+                Stage::LongPress(presses) => {
+                    for p in presses.iter_mut() {
+                        let down = pressed(&st.buttons, p.button);
+                        set(&mut st.buttons, p.button, false);
+                        match (down, p.down_since) {
+                            (true, None) => p.down_since = Some(now),
+                            // Let go before it turned into a hold: a tap
+                            (false, Some(since)) if now - since < p.after => {
+                                p.pulse_until = Some(now + PULSE);
+                                p.down_since = None;
+                            }
+                            (false, Some(_)) => p.down_since = None,
+                            _ => {}
+                        }
+                        if p.down_since.is_some_and(|since| now - since >= p.after) {
+                            set(&mut st.buttons, p.long_button, true);
+                        }
+                        if p.pulse_until.is_some_and(|until| now < until) {
+                            set(&mut st.buttons, p.button, true);
+                        } else {
+                            p.pulse_until = None;
+                        }
+                    }
+                }
+                Stage::Turbo(turbos) => {
+                    for t in turbos.iter_mut() {
+                        if !pressed(&st.buttons, t.button) {
+                            t.down_since = None;
+                            continue;
+                        }
+                        // Pressed for the first half of every period
+                        let since = *t.down_since.get_or_insert(now);
+                        let phase = (now - since).as_secs_f32() % t.period.as_secs_f32();
+                        set(
+                            &mut st.buttons,
+                            t.button,
+                            phase < t.period.as_secs_f32() / 2.0,
+                        );
+                    }
+                }
+                Stage::Toggle(toggles) => {
+                    for t in toggles.iter_mut() {
+                        let down = pressed(&st.buttons, t.button);
+                        if down && !t.down {
+                            t.on = !t.on;
+                        }
+                        t.down = down;
+                        set(&mut st.buttons, t.button, t.on);
+                    }
+                }
+                Stage::Macro(macros) => {
+                    for m in macros.iter_mut() {
+                        apply_macro(m, &mut st.buttons, now);
+                    }
+                }
+                Stage::Script(script) => script.apply(st),
+            }
+        }
+    }
+}
+
+fn threshold(button: u16, c: &ThresholdConfig) -> Result<Threshold> {
+    let axis = axis_index(c.axis)?;
+    let (at, above) = match (c.above, c.below) {
+        (Some(at), None) => (at, true),
+        (None, Some(at)) => (at, false),
+        _ => bail!("needs one of above or below"),
+    };
+    if !(0.0..=1.0).contains(&at) {
+        bail!("{} is outside 0..=1", at);
+    }
+    if !(0.0..=1.0).contains(&c.hysteresis) {
+        bail!("hysteresis {} is outside 0..=1", c.hysteresis);
+    }
+    let back = if above {
+        at - c.hysteresis
+    } else {
+        at + c.hysteresis
+    };
+    let units = |f: f32| (f.clamp(0.0, 1.0) * VJOY_AXIS_MAX as f32).round() as u16;
+    Ok(Threshold {
+        button,
+        axis,
+        press: units(at),
+        release: units(back),
+        above,
+        on: false,
+    })
+}
+
+fn apply_chord(c: &mut Chord, buttons: &mut [u8; 32], now: Instant) {
+    let downs: Vec<bool> = c.members.iter().map(|m| pressed(buttons, *m)).collect();
+    let any = downs.iter().any(|d| *d);
+    let all = downs.iter().all(|d| *d);
+
+    c.state = match std::mem::replace(&mut c.state, ChordState::Idle) {
+        ChordState::Idle | ChordState::Pending(..) if all => ChordState::Active,
+        ChordState::Idle if any => ChordState::Pending(now, downs.clone()),
+        ChordState::Pending(_, seen) if !any => {
+            c.pulse = Some((now + PULSE, seen));
+            ChordState::Idle
+        }
+        ChordState::Pending(since, _) if now - since >= c.window => ChordState::Passing,
+        ChordState::Pending(since, seen) => ChordState::Pending(
+            since,
+            seen.iter()
+                .zip(downs.iter())
+                .map(|(s, d)| *s || *d)
+                .collect(),
+        ),
+        ChordState::Active | ChordState::Passing if !any => ChordState::Idle,
+        state => state,
+    };
+
+    match c.state {
+        ChordState::Pending(..) | ChordState::Active => {
+            for m in c.members.iter() {
+                set(buttons, *m, false);
+            }
+        }
+        ChordState::Idle | ChordState::Passing => {}
+    }
+    if matches!(c.state, ChordState::Active) && all {
+        set(buttons, c.button, true);
+    }
+
+    match &c.pulse {
+        Some((until, seen)) if now < *until => {
+            for (m, seen) in c.members.iter().zip(seen.iter()) {
+                if *seen {
+                    set(buttons, *m, true);
+                }
+            }
+        }
+        _ => c.pulse = None,
+    }
+}
+
+fn apply_macro(m: &mut Macro, buttons: &mut [u8; 32], now: Instant) {
+    let down = pressed(buttons, m.trigger);
+    set(buttons, m.trigger, false);
+    // A press while it plays is ignored
+    if down && !m.down && m.next.is_none() {
+        m.next = Some((0, now));
+    }
+    m.down = down;
+
+    // Past the last step (i == len), what it left held goes out for its
+    // wait_ms and is released after
+    while let Some((i, due)) = m.next
+        && now >= due
+    {
+        let Some(step) = m.steps.get(i) else {
+            m.held.clear();
+            m.next = None;
+            break;
+        };
+        m.held.extend(step.press.iter().copied());
+        m.held.retain(|b| !step.release.contains(b));
+        m.next = Some((i + 1, due + Duration::from_millis(step.wait_ms)));
+        // The last step goes out at least once, however short its wait
+        if i + 1 == m.steps.len() {
+            break;
+        }
+    }
+
+    for b in m.held.iter() {
+        set(buttons, *b, true);
+    }
+}
+
+fn mode(cfg: &ModeConfig) -> Result<Mode> {
+    let source = ModeSource::new(&cfg.source, cfg.positions)?;
+    if cfg.offset == 0 {
+        bail!("offset must be above 0");
+    }
+    let mut banked = match cfg.buttons.is_empty() {
+        true => (1..=cfg.offset.min(MAX_BUTTONS)).collect(),
+        false => cfg.buttons.clone(),
+    };
+    banked.sort();
+    banked.dedup();
+    let steps = source.steps.map_or(vec![], |(up, down)| vec![up, down]);
+    banked.retain(|b| !steps.contains(b));
+    let top = (cfg.positions as u32 - 1) * cfg.offset as u32;
+    for b in banked.iter() {
+        check_button(*b)?;
+        if *b as u32 + top > MAX_BUTTONS as u32 {
+            bail!(
+                "button {} in the last position would be {}, past {}",
+                b,
+                *b as u32 + top,
+                MAX_BUTTONS
+            );
+        }
+    }
+    Ok(Mode {
+        source,
+        offset: cfg.offset,
+        banked,
+        held: BTreeMap::new(),
+    })
+}
+
+fn shift(cfg: &BTreeMap<u16, ShiftConfig>) -> Result<Shift> {
+    let mut shifts = Vec::new();
+    let mut shifted = Vec::new();
+    let lowest = cfg.values().map(|c| c.offset).min().unwrap_or(0);
+    let total: u32 = cfg.values().map(|c| c.offset as u32).sum();
+
+    for (button, c) in cfg.iter() {
+        check_button(*button).map_err(|e| e.context(format!("shift {}", button)))?;
+        if c.offset == 0 || c.offset > MAX_BUTTONS {
+            bail!(
+                "shift {}: offset {} must be above 0 and at most {}",
+                button,
+                c.offset,
+                MAX_BUTTONS
+            );
+        }
+        shifts.push((*button, c.offset));
+        match c.buttons.is_empty() {
+            true => shifted.extend(1..=lowest),
+            false => shifted.extend(c.buttons.iter().copied()),
+        }
+    }
+
+    shifted.sort();
+    shifted.dedup();
+    shifted.retain(|b| !cfg.contains_key(b));
+    for b in shifted.iter() {
+        check_button(*b)?;
+        if *b as u32 + total > MAX_BUTTONS as u32 {
+            bail!(
+                "shift: button {} with every shift held would be {}, past {}",
+                b,
+                *b as u32 + total,
+                MAX_BUTTONS
+            );
+        }
+    }
+
+    Ok(Shift {
+        shifts,
+        shifted,
+        held: BTreeMap::new(),
+    })
+}
+
+fn check_curve(curve: &CurveConfig) -> Result<()> {
+    if curve.exponent.is_nan() || curve.exponent <= 0.0 {
+        bail!("exponent {} must be above 0", curve.exponent);
+    }
+    if curve.points.len() == 1 {
+        bail!("needs at least two points");
+    }
+    for [x, y] in curve.points.iter() {
+        if !(-1.0..=1.0).contains(x) || !(-1.0..=1.0).contains(y) {
+            bail!("point [{}, {}] is outside -1..=1", x, y);
+        }
+    }
+    if curve.points.windows(2).any(|w| w[0][0] >= w[1][0]) {
+        bail!("point inputs must go up");
+    }
+    Ok(())
+}
+
+fn apply_curve(curve: &CurveConfig, x: f32) -> f32 {
+    let x = x.signum() * x.abs().powf(curve.exponent);
+
+    let points = &curve.points;
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return x;
+    };
+    // Flat beyond the ends
+    if x <= first[0] {
+        return first[1];
+    }
+    if x >= last[0] {
+        return last[1];
+    }
+    let i = points.partition_point(|p| p[0] <= x);
+    let ([x0, y0], [x1, y1]) = (points[i - 1], points[i]);
+    y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+}
+
+// vJoy axis id (1..=8) -> slot
+fn axis_index(id: u8) -> Result<usize> {
+    if !(1..=8).contains(&id) {
+        bail!("axis {} not in 1..=8", id);
+    }
+    Ok(id as usize - 1)
+}
+
+fn check_button(b: u16) -> Result<()> {
+    if !(1..=MAX_BUTTONS).contains(&b) {
+        bail!("button {} not in 1..={}", b, MAX_BUTTONS);
+    }
+    Ok(())
+}
+
+// Buttons by vJoy number, 1 = bit 0 of the first byte
+pub fn pressed(buttons: &[u8; 32], b: u16) -> bool {
+    let i = b as usize - 1;
+    buttons[i / 8] & (1 << (i % 8)) != 0
+}
+
+pub fn set(buttons: &mut [u8; 32], b: u16, on: bool) {
+    let i = b as usize - 1;
+    if on {
+        buttons[i / 8] |= 1 << (i % 8);
+    } else {
+        buttons[i / 8] &= !(1 << (i % 8));
+    }
+}
+
+fn to_centered(v: u16) -> f32 {
+    let half = VJOY_AXIS_MAX as f32 / 2.0;
+    (v as f32 - half) / half
+}
+
+fn from_centered(v: f32) -> u16 {
+    let half = VJOY_AXIS_MAX as f32 / 2.0;
+    (half + v.clamp(-1.0, 1.0) * half).round() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    // Buttons pressed in a button set, in order
+    fn down(buttons: &[u8; 32]) -> Vec<u16> {
+        (1..=MAX_BUTTONS).filter(|b| pressed(buttons, *b)).collect()
+    }
+
+    fn buttons(pressed: &[u16]) -> [u8; 32] {
+        let mut buttons = [0; 32];
+        for b in pressed {
+            set(&mut buttons, *b, true);
+        }
+        buttons
+    }
+
+    // Buttons a pipeline sends for those pressed, axes centered but the first
+    fn tick(p: &mut Pipeline, axis: f32, pressed: &[u16], now: Instant) -> Vec<u16> {
+        let mut st = WireState {
+            axes: [VJOY_AXIS_MAX / 2; 8],
+            hat_x: 0,
+            hat_y: 0,
+            buttons: buttons(pressed),
+        };
+        st.axes[0] = (axis * VJOY_AXIS_MAX as f32).round() as u16;
+        p.apply(&mut st, now);
+        down(&st.buttons)
+    }
+
+    fn step(press: &[u16], release: &[u16], wait_ms: u64) -> MacroStep {
+        MacroStep {
+            press: press.to_vec(),
+            release: release.to_vec(),
+            wait_ms,
+        }
+    }
+
+    fn play(steps: Vec<MacroStep>) -> Macro {
+        Macro {
+            trigger: 1,
+            steps,
+            down: false,
+            next: None,
+            held: BTreeSet::new(),
+        }
+    }
+
+    #[test]
+    fn macro_last_step_is_held_for_its_wait() {
+        let mut m = play(vec![step(&[10], &[], 100), step(&[11], &[10], 50)]);
+        let t0 = Instant::now();
+        let mut at = |pressed: &[u16], t: Duration| {
+            let mut b = buttons(pressed);
+            apply_macro(&mut m, &mut b, t0 + t);
+            down(&b)
+        };
+        assert_eq!(at(&[1], ms(0)), [10]);
+        assert_eq!(at(&[], ms(99)), [10]);
+        assert_eq!(at(&[], ms(100)), [11]);
+        assert_eq!(at(&[], ms(149)), [11]);
+        assert_eq!(at(&[], ms(150)), Vec::<u16>::new());
+        // Played out, a new press starts it again
+        assert_eq!(at(&[1], ms(160)), [10]);
+    }
+
+    #[test]
+    fn macro_single_step_reaches_the_wire() {
+        let mut m = play(vec![step(&[12], &[], 0)]);
+        let t0 = Instant::now();
+        let mut b = buttons(&[1]);
+        apply_macro(&mut m, &mut b, t0);
+        assert_eq!(down(&b), [12]);
+        let mut b = buttons(&[1]);
+        apply_macro(&mut m, &mut b, t0 + ms(20));
+        assert_eq!(down(&b), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn mix_rejects_coefficients_that_are_not_numbers() {
+        for c in [f32::NAN, f32::INFINITY] {
+            let cfg = TransformConfig {
+                mix: BTreeMap::from([(1, BTreeMap::from([(2, c)]))]),
+                ..Default::default()
+            };
+            assert!(Pipeline::new(&cfg).is_err());
+        }
+    }
+
+    #[test]
+    fn shift_rejects_offsets_past_the_buttons() {
+        for offset in [MAX_BUTTONS + 1, u16::MAX] {
+            let cfg = TransformConfig {
+                shift: BTreeMap::from([(
+                    3,
+                    ShiftConfig {
+                        offset,
+                        buttons: vec![1],
+                    },
+                )]),
+                ..Default::default()
+            };
+            assert!(Pipeline::new(&cfg).is_err());
+        }
+        // Each within range, together past it
+        let cfg = TransformConfig {
+            shift: BTreeMap::from([
+                (
+                    3,
+                    ShiftConfig {
+                        offset: 200,
+                        buttons: vec![1],
+                    },
+                ),
+                (
+                    4,
+                    ShiftConfig {
+                        offset: 200,
+                        buttons: vec![1],
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert!(Pipeline::new(&cfg).is_err());
+    }
+
+    #[test]
+    fn trim_goes_in_ahead_of_the_curve() {
+        let mut p = Pipeline::new(&TransformConfig {
+            curve: BTreeMap::from([(
+                1,
+                CurveConfig {
+                    exponent: 2.0,
+                    points: Vec::new(),
+                },
+            )]),
+            trim: BTreeMap::from([(
+                1,
+                TrimConfig {
+                    up: 10,
+                    down: 11,
+                    reset: None,
+                    step: 0.5,
+                },
+            )]),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut st = WireState {
+            axes: [VJOY_AXIS_MAX / 2; 8],
+            hat_x: 0,
+            hat_y: 0,
+            buttons: buttons(&[10]),
+        };
+        p.apply(&mut st, Instant::now());
+        // Half way up through the curve, not the curve's center moved up
+        assert_eq!(st.axes[0], from_centered(0.25));
+    }
+
+    #[test]
+    fn threshold_releases_past_its_hysteresis() {
+        let cfg = TransformConfig {
+            threshold: BTreeMap::from([(
+                20,
+                ThresholdConfig {
+                    axis: 1,
+                    above: Some(0.8),
+                    below: None,
+                    hysteresis: 0.1,
+                },
+            )]),
+            ..Default::default()
+        };
+        let mut p = Pipeline::new(&cfg).unwrap();
+        let t0 = Instant::now();
+        let sent: Vec<Vec<u16>> = [0.79, 0.8, 0.75, 0.69, 0.75]
+            .into_iter()
+            .map(|v| tick(&mut p, v, &[], t0))
+            .collect();
+        assert_eq!(sent, [vec![], vec![20], vec![20], vec![], vec![]]);
+    }
+
+    fn chord() -> Pipeline {
+        Pipeline::new(&TransformConfig {
+            chord: BTreeMap::from([(30, vec![1, 2])]),
+            chord_ms: 100,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn chord_sends_its_button_once_all_members_are_down() {
+        let mut p = chord();
+        let t0 = Instant::now();
+        assert_eq!(tick(&mut p, 0.5, &[1], t0), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[1, 2], t0 + ms(20)), [30]);
+        assert_eq!(tick(&mut p, 0.5, &[], t0 + ms(40)), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn chord_member_tapped_alone_goes_out_for_a_pulse() {
+        let mut p = chord();
+        let t0 = Instant::now();
+        assert_eq!(tick(&mut p, 0.5, &[1], t0), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[], t0 + ms(20)), [1]);
+        assert_eq!(
+            tick(&mut p, 0.5, &[], t0 + ms(20) + PULSE),
+            Vec::<u16>::new()
+        );
+    }
+
+    #[test]
+    fn chord_member_held_past_the_window_passes_through() {
+        let mut p = chord();
+        let t0 = Instant::now();
+        assert_eq!(tick(&mut p, 0.5, &[1], t0), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[1], t0 + ms(99)), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[1], t0 + ms(100)), [1]);
+        // Too late for the chord, the other member goes out as itself
+        assert_eq!(tick(&mut p, 0.5, &[1, 2], t0 + ms(120)), [1, 2]);
+    }
+
+    fn long_press() -> Pipeline {
+        Pipeline::new(&TransformConfig {
+            long_press: BTreeMap::from([(5, LongPressConfig { button: 6, ms: 300 })]),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn long_press_tap_sends_the_button_for_a_pulse() {
+        let mut p = long_press();
+        let t0 = Instant::now();
+        assert_eq!(tick(&mut p, 0.5, &[5], t0), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[], t0 + ms(100)), [5]);
+        assert_eq!(
+            tick(&mut p, 0.5, &[], t0 + ms(100) + PULSE),
+            Vec::<u16>::new()
+        );
+    }
+
+    #[test]
+    fn long_press_held_sends_the_other_button() {
+        let mut p = long_press();
+        let t0 = Instant::now();
+        assert_eq!(tick(&mut p, 0.5, &[5], t0), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[5], t0 + ms(299)), Vec::<u16>::new());
+        assert_eq!(tick(&mut p, 0.5, &[5], t0 + ms(300)), [6]);
+        assert_eq!(tick(&mut p, 0.5, &[], t0 + ms(400)), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn toggle_latches_on_each_press() {
+        let mut p = Pipeline::new(&TransformConfig {
+            toggle: vec![7],
+            ..Default::default()
+        })
+        .unwrap();
+        let t0 = Instant::now();
+        let sent: Vec<Vec<u16>> = [&[7][..], &[], &[7], &[], &[7]]
+            .into_iter()
+            .map(|pressed| tick(&mut p, 0.5, pressed, t0))
+            .collect();
+        assert_eq!(sent, [vec![7], vec![7], vec![], vec![], vec![7]]);
+    }
+}
@@ -0,0 +1,584 @@
+use crate::Config;
+use anyhow::{Context, Result, bail};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::fmt;
+use std::io::{self, Write};
+use std::mem::{self, MaybeUninit};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio::{task, time};
+use tracing::{info, warn};
+
+// Wait before reading a vsock link again after its connection failed
+const VSOCK_RETRY: Duration = Duration::from_millis(100);
+
+// Where state packets go and where status / force feedback comes back from
+pub enum Link {
+    Udp {
+        sock: UdpSocket,
+        // The dest as configured, changes on config reload
+        name: Arc<Mutex<String>>,
+        // Moves when a hostname dest resolves to a new address
+        dest: Arc<Mutex<SocketAddr>>,
+        // Only takes packets from dest (unicast)
+        connected: bool,
+        // Asks the resolver task for a fresh lookup
+        resolve: mpsc::Sender<()>,
+    },
+    // Consumer on this host (overlay, recorder), no UDP over loopback
+    Unix(UnixDatagram),
+    // Receiver in a local VM, straight through the hypervisor. A stream, so every
+    // datagram is framed as u16 LE length + datagram. Reconnects after errors.
+    Vsock {
+        cid: u32,
+        port: u32,
+        conn: Arc<Mutex<Option<Socket>>>,
+    },
+}
+
+// Who a packet came from, so it can be answered
+pub enum Peer {
+    Udp(SocketAddr),
+    Unix(Option<PathBuf>),
+    Vsock(u32, u32),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Udp(addr) => write!(f, "{}", addr),
+            Peer::Unix(Some(path)) => write!(f, "{}", path.display()),
+            Peer::Unix(None) => f.write_str("unix peer"),
+            Peer::Vsock(cid, port) => write!(f, "vsock {}:{}", cid, port),
+        }
+    }
+}
+
+impl Link {
+    pub fn try_clone(&self) -> io::Result<Link> {
+        Ok(match self {
+            Link::Udp {
+                sock,
+                name,
+                dest,
+                connected,
+                resolve,
+            } => Link::Udp {
+                sock: sock.try_clone()?,
+                name: Arc::clone(name),
+                dest: Arc::clone(dest),
+                connected: *connected,
+                resolve: resolve.clone(),
+            },
+            Link::Unix(sock) => Link::Unix(sock.try_clone()?),
+            Link::Vsock { cid, port, conn } => Link::Vsock {
+                cid: *cid,
+                port: *port,
+                conn: Arc::clone(conn),
+            },
+        })
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Link::Udp {
+                sock,
+                dest,
+                resolve,
+                ..
+            } => {
+                let dest = { *dest.lock().unwrap() };
+                if let Err(e) = sock.send_to(buf, dest) {
+                    // Receiver gone or moved: look the dest up again. Refused
+                    // is the host there answering with no receiver running
+                    if e.kind() != io::ErrorKind::ConnectionRefused {
+                        let _ = resolve.try_send(());
+                    }
+                    return Err(e);
+                }
+            }
+            Link::Unix(sock) => {
+                sock.send(buf)?;
+            }
+            Link::Vsock { cid, port, conn } => {
+                let mut conn = conn.lock().unwrap();
+                if conn.is_none() {
+                    *conn = Some(vsock_connect(*cid, *port)?);
+                }
+                if let Err(e) = write_frame(conn.as_ref().unwrap(), buf) {
+                    *conn = None;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Several datagrams to dest, in one sendmmsg call on UDP
+    pub fn send_many(&self, bufs: &[&[u8]]) -> io::Result<()> {
+        let Link::Udp {
+            sock,
+            dest,
+            resolve,
+            ..
+        } = self
+        else {
+            return bufs.iter().try_for_each(|buf| self.send(buf));
+        };
+
+        let dest = SockAddr::from(*dest.lock().unwrap());
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .map(|iov| {
+                // SAFETY: all zero is a valid mmsghdr, the pointers set below
+                // outlive the sendmmsg calls
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = dest.as_ptr() as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = dest.len();
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+
+        // The kernel may take fewer than asked for, send the rest after
+        let mut sent = 0;
+        while sent < msgs.len() {
+            let rest = &mut msgs[sent..];
+            // SAFETY: rest points to rest.len() initialized headers
+            let n = unsafe {
+                libc::sendmmsg(sock.as_raw_fd(), rest.as_mut_ptr(), rest.len() as u32, 0)
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::ConnectionRefused {
+                    // Receiver gone or moved: look the dest up again
+                    let _ = resolve.try_send(());
+                }
+                return Err(e);
+            }
+            sent += n as usize;
+        }
+        Ok(())
+    }
+
+    // Points a UDP link at a new dest, on the socket it already has
+    pub fn retarget(&self, new: &str) -> Result<()> {
+        let Link::Udp {
+            sock,
+            name,
+            dest,
+            connected,
+            ..
+        } = self
+        else {
+            bail!("only UDP dests can change without a restart");
+        };
+        if new.starts_with("unix:") || new.starts_with("vsock:") {
+            bail!("switching to {} needs a restart", new);
+        }
+
+        let addr = resolve_dest(new)?;
+        let mut dest = dest.lock().unwrap();
+        if addr.is_ipv4() != dest.is_ipv4() {
+            bail!(
+                "{} is a different address family, that needs a restart",
+                new
+            );
+        }
+        if *connected {
+            sock.connect(addr)
+                .with_context(|| format!("Failed to connect to {}", addr))?;
+        }
+        *dest = addr;
+        *name.lock().unwrap() = new.to_string();
+        Ok(())
+    }
+
+    // Answers a packet from recv, rather than sending to dest
+    pub fn reply(&self, to: &Peer, buf: &[u8]) -> io::Result<()> {
+        match (self, to) {
+            (Link::Udp { sock, .. }, Peer::Udp(addr)) => {
+                sock.send_to(buf, addr)?;
+            }
+            (Link::Unix(sock), Peer::Unix(Some(path))) => {
+                sock.send_to(buf, path)?;
+            }
+            _ => self.send(buf)?,
+        }
+        Ok(())
+    }
+
+    // The receiving end, waited on by the runtime
+    pub fn incoming(&self) -> io::Result<Incoming> {
+        Ok(Incoming {
+            link: self.try_clone()?,
+            ready: None,
+            frames: Vec::new(),
+        })
+    }
+}
+
+// Status and force feedback coming back. Reads never block, the socket is
+// shared with the sender and stays in blocking mode for it
+pub struct Incoming {
+    link: Link,
+    // The socket, for vsock the connection current when it was taken
+    ready: Option<AsyncFd<Socket>>,
+    // vsock bytes read so far, datagrams come out once their frame is whole
+    frames: Vec<u8>,
+}
+
+impl Incoming {
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, Peer)> {
+        loop {
+            if let Link::Vsock { cid, port, .. } = self.link
+                && let Some(len) = take_frame(&mut self.frames, buf)?
+            {
+                return Ok((len, Peer::Vsock(cid, port)));
+            }
+
+            if self.ready.is_none() {
+                let sock = match &self.link {
+                    Link::Udp { sock, .. } => Socket::from(sock.try_clone()?),
+                    Link::Unix(sock) => Socket::from(sock.try_clone()?),
+                    // The sender owns (re)connecting, read whatever is current
+                    Link::Vsock { conn, .. } => {
+                        let sock = conn.lock().unwrap().as_ref().map(Socket::try_clone);
+                        match sock {
+                            Some(sock) => sock?,
+                            None => {
+                                time::sleep(VSOCK_RETRY).await;
+                                continue;
+                            }
+                        }
+                    }
+                };
+                self.ready = Some(AsyncFd::with_interest(sock, Interest::READABLE)?);
+            }
+            let ready = self.ready.as_ref().unwrap();
+
+            let mut guard = ready.readable().await?;
+            let read = match &self.link {
+                Link::Vsock { .. } => {
+                    let mut chunk = [0u8; 2048];
+                    recv_now(guard.get_inner(), &mut chunk).and_then(|(len, _)| match len {
+                        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                        _ => {
+                            self.frames.extend_from_slice(&chunk[..len]);
+                            Ok(None)
+                        }
+                    })
+                }
+                Link::Udp { .. } => recv_now(guard.get_inner(), buf)
+                    .map(|(len, from)| from.as_socket().map(|from| (len, Peer::Udp(from)))),
+                Link::Unix(_) => recv_now(guard.get_inner(), buf).map(|(len, from)| {
+                    let path = from.as_pathname().map(Path::to_path_buf);
+                    Some((len, Peer::Unix(path)))
+                }),
+            };
+            match read {
+                Ok(Some(got)) => return Ok(got),
+                Ok(None) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => guard.clear_ready(),
+                // A broken vsock connection, the sender makes a new one
+                Err(_) if matches!(self.link, Link::Vsock { .. }) => {
+                    drop(guard);
+                    self.ready = None;
+                    self.frames.clear();
+                    time::sleep(VSOCK_RETRY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// A read that doesn't wait, whatever mode the socket is in
+fn recv_now(sock: &Socket, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
+    // recv only writes to it, and u8 has no invalid values
+    let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+    sock.recv_from_with_flags(buf, libc::MSG_DONTWAIT)
+}
+
+pub fn resolve_dest(dest: &str) -> Result<SocketAddr> {
+    dest.to_socket_addrs()
+        .with_context(|| format!("Failed to resolve dest {}", dest))?
+        .next()
+        .with_context(|| format!("No address found for dest {}", dest))
+}
+
+// One of the config's dests, with its socket options
+pub fn open(name: &str, config: &Config) -> Result<Link> {
+    if let Some(path) = name.strip_prefix("unix:") {
+        info!("Sending to unix socket {}", path);
+        return open_unix(Path::new(path));
+    }
+    if let Some(addr) = name.strip_prefix("vsock:") {
+        return open_vsock(addr);
+    }
+
+    let dest = resolve_dest(name)?;
+    info!("Sending UDP to {} ({})", name, dest);
+
+    let sock = match dest.ip() {
+        IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    if let Some(dscp) = config.dscp {
+        set_dscp(&sock, dest.is_ipv6(), dscp)?;
+    }
+
+    let connected = match dest.ip() {
+        IpAddr::V4(ip) if ip.is_multicast() => {
+            sock.set_multicast_ttl_v4(config.multicast_ttl)?;
+            false
+        }
+        IpAddr::V6(ip) if ip.is_multicast() => {
+            socket2::SockRef::from(&sock).set_multicast_hops_v6(config.multicast_ttl)?;
+            false
+        }
+        _ if config.broadcast => {
+            sock.set_broadcast(true)?;
+            false
+        }
+        // Unicast: only accept return packets from the receiver itself. Multicast and
+        // broadcast receivers answer from their own addresses, so no connect there.
+        _ => {
+            sock.connect(dest)?;
+            true
+        }
+    };
+
+    let name = Arc::new(Mutex::new(name.to_string()));
+    let dest = Arc::new(Mutex::new(dest));
+
+    // Task F: follows a hostname dest to its current address
+    let (resolve, rx) = mpsc::channel(1);
+    {
+        let sock = sock.try_clone()?;
+        let interval = Duration::from_secs(config.resolve_interval_secs.max(1));
+        let name = Arc::clone(&name);
+        let dest = Arc::clone(&dest);
+        task::spawn(resolver_task(sock, connected, interval, name, dest, rx));
+    }
+
+    Ok(Link::Udp {
+        sock,
+        name,
+        dest,
+        connected,
+        resolve,
+    })
+}
+
+fn open_unix(path: &Path) -> Result<Link> {
+    // Abstract address, so the consumer has somewhere to send replies to; one
+    // per unix dest
+    static OPENED: AtomicUsize = AtomicUsize::new(0);
+    let n = OPENED.fetch_add(1, Ordering::Relaxed);
+    let local =
+        net::SocketAddr::from_abstract_name(format!("vkb-bridge-sender-{}-{}", process::id(), n))?;
+    let sock = UnixDatagram::bind_addr(&local)?;
+    sock.connect(path)
+        .with_context(|| format!("Failed to connect to {}", path.display()))?;
+    Ok(Link::Unix(sock))
+}
+
+// "<cid>:<port>", e.g. "3:46000" for the first guest
+fn open_vsock(addr: &str) -> Result<Link> {
+    let (cid, port) = addr
+        .split_once(':')
+        .and_then(|(cid, port)| Some((cid.parse().ok()?, port.parse().ok()?)))
+        .with_context(|| format!("Bad vsock dest {}, expected vsock:<cid>:<port>", addr))?;
+    info!("Sending to vsock cid {} port {}", cid, port);
+
+    let sock = vsock_connect(cid, port)
+        .with_context(|| format!("Failed to connect to vsock {}:{}", cid, port))?;
+    Ok(Link::Vsock {
+        cid,
+        port,
+        conn: Arc::new(Mutex::new(Some(sock))),
+    })
+}
+
+fn vsock_connect(cid: u32, port: u32) -> io::Result<Socket> {
+    let sock = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+    sock.connect(&SockAddr::vsock(cid, port))?;
+    Ok(sock)
+}
+
+fn write_frame(mut sock: &Socket, data: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + data.len());
+    frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    frame.extend_from_slice(data);
+    sock.write_all(&frame)
+}
+
+// The first whole u16 LE length + datagram frame out of what was read
+fn take_frame(frames: &mut Vec<u8>, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let Some(len) = frames.first_chunk::<2>() else {
+        return Ok(None);
+    };
+    let len = u16::from_le_bytes(*len) as usize;
+    if len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    if frames.len() < 2 + len {
+        return Ok(None);
+    }
+    buf[..len].copy_from_slice(&frames[2..2 + len]);
+    frames.drain(..2 + len);
+    Ok(Some(len))
+}
+
+fn set_dscp(sock: &UdpSocket, ipv6: bool, dscp: u8) -> Result<()> {
+    if dscp > 63 {
+        bail!("dscp {} out of range (0..=63)", dscp);
+    }
+    // DSCP is the upper 6 bits of the TOS / traffic class byte
+    let tos = (dscp as u32) << 2;
+    let sock = socket2::SockRef::from(sock);
+    if ipv6 {
+        sock.set_tclass_v6(tos)
+    } else {
+        sock.set_tos_v4(tos)
+    }
+    .with_context(|| format!("Failed to set DSCP {}", dscp))
+}
+
+async fn resolver_task(
+    sock: UdpSocket,
+    connected: bool,
+    interval: Duration,
+    name: Arc<Mutex<String>>,
+    dest: Arc<Mutex<SocketAddr>>,
+    mut resolve: mpsc::Receiver<()>,
+) {
+    loop {
+        // Periodically, or right away when the sender hits an error
+        if let Ok(None) = time::timeout(interval, resolve.recv()).await {
+            return;
+        }
+
+        // IP literals have nothing to follow
+        let name = name.lock().unwrap().clone();
+        if name.parse::<SocketAddr>().is_ok() {
+            continue;
+        }
+
+        let current = { *dest.lock().unwrap() };
+        // The socket is bound to one address family, stay on it
+        let addr = match tokio::net::lookup_host(name.as_str()).await {
+            Ok(mut addrs) => addrs.find(|a| a.is_ipv4() == current.is_ipv4()),
+            Err(e) => {
+                warn!("Failed to resolve dest {}: {}", name, e);
+                continue;
+            }
+        };
+        let Some(addr) = addr.filter(|a| *a != current) else {
+            continue;
+        };
+
+        if connected && let Err(e) = sock.connect(addr) {
+            warn!("Failed to connect to {}: {}", addr, e);
+            continue;
+        }
+        info!("dest {} moved from {} to {}", name, current, addr);
+        *dest.lock().unwrap() = addr;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn udp_link(to: SocketAddr) -> Link {
+        Link::Udp {
+            sock: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            name: Arc::new(Mutex::new(to.to_string())),
+            dest: Arc::new(Mutex::new(to)),
+            connected: false,
+            resolve: mpsc::channel(1).0,
+        }
+    }
+
+    // A tick's worth of datagrams and more, each its own length and content
+    fn batch() -> Vec<Vec<u8>> {
+        (0..100u8)
+            .map(|i| (0..=i % 60).map(|b| b ^ i).collect())
+            .collect()
+    }
+
+    #[test]
+    fn send_many_delivers_every_datagram_in_order() {
+        let recv = UdpSocket::bind("127.0.0.1:0").unwrap();
+        recv.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let link = udp_link(recv.local_addr().unwrap());
+
+        let bufs = batch();
+        let refs: Vec<&[u8]> = bufs.iter().map(|b| b.as_slice()).collect();
+        link.send_many(&refs).unwrap();
+
+        let mut buf = [0; 256];
+        for sent in bufs.iter() {
+            let n = recv.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], sent.as_slice());
+        }
+    }
+
+    // Times sendmmsg against one send per datagram, for a feel of what it saves:
+    // cargo test -- --ignored --nocapture send_many_against_send
+    #[test]
+    #[ignore]
+    fn send_many_against_send() {
+        let recv = UdpSocket::bind("127.0.0.1:0").unwrap();
+        recv.set_nonblocking(true).unwrap();
+        let link = udp_link(recv.local_addr().unwrap());
+        let bufs = batch();
+        let refs: Vec<&[u8]> = bufs[..8].iter().map(|b| b.as_slice()).collect();
+        let drain = || while recv.recv(&mut [0; 256]).is_ok() {};
+
+        let rounds = 10_000;
+        let start = Instant::now();
+        for _ in 0..rounds {
+            link.send_many(&refs).unwrap();
+            drain();
+        }
+        let many = start.elapsed();
+        let start = Instant::now();
+        for _ in 0..rounds {
+            refs.iter().for_each(|buf| link.send(buf).unwrap());
+            drain();
+        }
+        let single = start.elapsed();
+        println!(
+            "{} batches of {}: sendmmsg {:?}, send {:?}",
+            rounds,
+            refs.len(),
+            many,
+            single
+        );
+    }
+}
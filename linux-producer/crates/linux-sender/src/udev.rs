@@ -0,0 +1,99 @@
+use crate::privs;
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use vkb_sender_core::selector::Selector;
+
+// Where setup-udev suggests writing them. Numbered below 73, where
+// systemd-logind's rules act on the uaccess tag
+pub const RULES_PATH: &str = "/etc/udev/rules.d/70-vkb-bridge.rules";
+
+// Writes (or prints, without output) udev rules that give user, or whoever is
+// logged in at the seat without one, access to the event nodes of devices (name
+// and selector) and to /dev/uinput if it is needed
+pub fn run(
+    devices: &[(String, Selector)],
+    uinput: bool,
+    user: Option<&str>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let access = match user {
+        Some(user) => {
+            let (name, _, _) = privs::lookup_user(user)?;
+            format!("OWNER=\"{}\", MODE=\"0660\"", name)
+        }
+        None => "TAG+=\"uaccess\"".to_string(),
+    };
+
+    let mut rules = String::new();
+    let _ = writeln!(
+        rules,
+        "# Written by vkb-bridge setup-udev, goes in {}",
+        RULES_PATH
+    );
+    let mut written = Vec::new();
+    for (name, select) in devices {
+        let _ = writeln!(rules, "\n# {}: {}", name, select);
+        let Some(keys) = match_keys(select) else {
+            rules.push_str("# left out: known by path or serial only, and not plugged in\n");
+            continue;
+        };
+        // Merged sources are often the same kind of device
+        if written.contains(&keys) {
+            rules.push_str("# covered above\n");
+            continue;
+        }
+        let _ = writeln!(
+            rules,
+            "SUBSYSTEM==\"input\", KERNEL==\"event*\", {}, {}",
+            keys, access
+        );
+        written.push(keys);
+    }
+    if uinput {
+        rules.push_str("\n# uinput joysticks\n");
+        let _ = writeln!(
+            rules,
+            "SUBSYSTEM==\"misc\", KERNEL==\"uinput\", OPTIONS+=\"static_node=uinput\", {}",
+            access
+        );
+    }
+
+    let Some(output) = output else {
+        print!("{}", rules);
+        return Ok(());
+    };
+    fs::write(output, rules)
+        .with_context(|| format!("Could not write {} (as root?)", output.display()))?;
+    println!(
+        "Wrote {}. To apply it to devices already plugged in:",
+        output.display()
+    );
+    println!("  udevadm control --reload && udevadm trigger");
+    Ok(())
+}
+
+// What identifies the device on the input device above the event node. serial
+// and index aren't matched, access to every device of the kind does no harm
+fn match_keys(select: &Selector) -> Option<String> {
+    let (mut vendor, mut product) = (select.vendor_id, select.product_id);
+    // Only a path or serial: the kind of device it is now
+    if vendor.is_none() && product.is_none() && select.name_contains.is_none() {
+        let (_, dev) = select.candidates().into_iter().next()?;
+        vendor = Some(dev.input_id().vendor());
+        product = Some(dev.input_id().product());
+    }
+
+    let mut keys = Vec::new();
+    if let Some(v) = vendor {
+        keys.push(format!("ATTRS{{id/vendor}}==\"{:04x}\"", v));
+    }
+    if let Some(p) = product {
+        keys.push(format!("ATTRS{{id/product}}==\"{:04x}\"", p));
+    }
+    if let Some(part) = &select.name_contains {
+        keys.push(format!("ATTRS{{name}}==\"*{}*\"", part));
+    }
+    Some(keys.join(", "))
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use evdev::uinput::VirtualDevice;
+use evdev::{
+    AbsInfo, AbsoluteAxisCode, AbsoluteAxisEvent, AttributeSet, InputEvent, KeyCode, KeyEvent,
+    UinputAbsSetup,
+};
+use std::io;
+use vkb_sender_core::input::AXIS_CODES;
+use vkb_sender_core::state::VJOY_AXIS_MAX;
+use vkb_sender_core::state::WireState;
+
+// Joystick buttons as Linux games number them: BTN_TRIGGER..=BTN_DEAD, then
+// BTN_TRIGGER_HAPPY1 up to KEY_MAX; vJoy buttons past these 80 aren't copied
+const BUTTON_RANGES: [(u16, u16); 2] = [(0x120, 0x12f), (0x2c0, 0x2ff)];
+
+// A vjoy device's output (after transforms) as a joystick on this machine, for
+// native games
+pub struct Joystick {
+    dev: VirtualDevice,
+    // As last emitted, only changes go out
+    last: Option<WireState>,
+}
+
+impl Joystick {
+    pub fn create(device_id: u8) -> Result<Self> {
+        let mut keys = AttributeSet::<KeyCode>::new();
+        for code in button_codes() {
+            keys.insert(code);
+        }
+
+        let name = format!("vkb-bridge vjoy device {}", device_id);
+        let mut builder = VirtualDevice::builder()?.name(&name).with_keys(&keys)?;
+        for code in AXIS_CODES {
+            let center = i32::from(VJOY_AXIS_MAX / 2);
+            let info = AbsInfo::new(center, 0, i32::from(VJOY_AXIS_MAX), 0, 0, 0);
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(code, info))?;
+        }
+        for code in [AbsoluteAxisCode::ABS_HAT0X, AbsoluteAxisCode::ABS_HAT0Y] {
+            let info = AbsInfo::new(0, -1, 1, 0, 0, 0);
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(code, info))?;
+        }
+
+        Ok(Joystick {
+            dev: builder.build()?,
+            last: None,
+        })
+    }
+
+    pub fn emit(&mut self, wire: &WireState) -> io::Result<()> {
+        let last = self.last;
+        let mut events: Vec<InputEvent> = Vec::new();
+
+        for (i, code) in AXIS_CODES.iter().enumerate() {
+            if last.is_none_or(|l| l.axes[i] != wire.axes[i]) {
+                events.push(*AbsoluteAxisEvent::new(*code, i32::from(wire.axes[i])));
+            }
+        }
+        if last.is_none_or(|l| l.hat_x != wire.hat_x) {
+            let code = AbsoluteAxisCode::ABS_HAT0X;
+            events.push(*AbsoluteAxisEvent::new(code, i32::from(wire.hat_x)));
+        }
+        if last.is_none_or(|l| l.hat_y != wire.hat_y) {
+            let code = AbsoluteAxisCode::ABS_HAT0Y;
+            events.push(*AbsoluteAxisEvent::new(code, i32::from(wire.hat_y)));
+        }
+        for (i, code) in button_codes().enumerate() {
+            let on = |buttons: &[u8; 32]| buttons[i / 8] & (1 << (i % 8)) != 0;
+            if last.is_none_or(|l| on(&l.buttons) != on(&wire.buttons)) {
+                events.push(*KeyEvent::new(code, on(&wire.buttons) as i32));
+            }
+        }
+
+        if !events.is_empty() {
+            self.dev.emit(&events)?;
+        }
+        self.last = Some(*wire);
+        Ok(())
+    }
+}
+
+fn button_codes() -> impl Iterator<Item = KeyCode> {
+    BUTTON_RANGES
+        .into_iter()
+        .flat_map(|(first, last)| (first..=last).map(KeyCode::new))
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result, bail};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time;
+
+// Editors and udev write in bursts (truncate, write, rename, chmod), wait for
+// them to settle
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+// inotify on a directory. A single file is watched through its directory, so
+// editors that save by renaming a new file over it are still seen
+pub struct Watcher {
+    inotify: AsyncFd<File>,
+    // Only events about this name count, None for any
+    name: Option<Vec<u8>>,
+}
+
+impl Watcher {
+    // Writes to one file, or a new file put in its place
+    pub fn file(path: &Path) -> Result<Watcher> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Some(name) = path.file_name() else {
+            bail!("{} is not a file", path.display());
+        };
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+        Watcher::new(dir, mask, Some(name.as_bytes().to_vec()))
+    }
+
+    // Nodes appearing in a directory or changing permissions (/dev/input)
+    pub fn dir(dir: &Path) -> Result<Watcher> {
+        let mask = libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_MOVED_TO;
+        Watcher::new(dir, mask, None)
+    }
+
+    fn new(dir: &Path, mask: u32, name: Option<Vec<u8>>) -> Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("inotify_init1 failed");
+        }
+        // Closed with the File from here on
+        let inotify = unsafe { File::from_raw_fd(fd) };
+
+        let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to watch {}", dir.display()));
+        }
+
+        Ok(Watcher {
+            inotify: AsyncFd::new(inotify)?,
+            name,
+        })
+    }
+
+    // Waits until something changed, and for the burst to settle
+    pub async fn changed(&mut self) -> Result<()> {
+        while !self.read_events().await? {}
+        // Swallow the rest of the burst
+        while let Ok(read) = time::timeout(DEBOUNCE, self.read_events()).await {
+            read?;
+        }
+        Ok(())
+    }
+
+    // Whether any of the events read is about the watched name
+    async fn read_events(&mut self) -> Result<bool> {
+        let mut buf = [0u8; 4096];
+        let n = loop {
+            let mut ready = self.inotify.readable_mut().await?;
+            match ready.try_io(|inotify| inotify.get_mut().read(&mut buf)) {
+                Ok(Ok(n)) => break n,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(Err(e)) => return Err(e).context("Failed to read inotify events"),
+                // Nothing there after all
+                Err(_) => continue,
+            }
+        };
+
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut hit = false;
+        let mut at = 0;
+        while at + header <= n {
+            // inotify_event: wd i32, mask u32, cookie u32, len u32, then the name
+            let mask = u32::from_ne_bytes(buf[at + 4..at + 8].try_into().unwrap());
+            let len = u32::from_ne_bytes(buf[at + 12..at + 16].try_into().unwrap()) as usize;
+            let name = &buf[at + header..(at + header + len).min(n)];
+            // The name is padded with NULs
+            let name = name.split(|b| *b == 0).next().unwrap_or_default();
+            // Events were dropped, the file may be among them
+            hit |= mask & libc::IN_Q_OVERFLOW != 0
+                || self
+                    .name
+                    .as_ref()
+                    .is_none_or(|watched| name == &watched[..]);
+            at += header + len;
+        }
+
+        Ok(hit)
+    }
+}
@@ -0,0 +1,674 @@
+//! Physical devices: which one a [`Source`] means, how its axes and keys map
+//! onto a vjoy device ([`InputMap`]) and reading it into [`Update`]s.
+//!
+//! [`open_vkb_device`] finds and claims a device, [`build_input_map`] and
+//! [`build_axis_ranges`] tell what it feeds, an [`Update::Attach`] with the
+//! ranges goes to the state, then [`read_input`] until it is unplugged and
+//! [`release`] after.
+
+use crate::raw::RawState;
+use crate::selector::Selector;
+use crate::state::{AxisRange, MAX_BUTTONS, StateTx, Update};
+use anyhow::{Context, Result, bail};
+use evdev::{
+    AbsInfo, AbsoluteAxisCode, AttributeSetRef, Device, EventSummary, KeyCode, RelativeAxisCode,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{info, trace, warn};
+
+/// Where each axis slot (vJoy axis id - 1) is read from by default
+pub const AXIS_CODES: [AbsoluteAxisCode; 8] = [
+    AbsoluteAxisCode::ABS_X,
+    AbsoluteAxisCode::ABS_Y,
+    AbsoluteAxisCode::ABS_Z,
+    AbsoluteAxisCode::ABS_RX,
+    AbsoluteAxisCode::ABS_RY,
+    AbsoluteAxisCode::ABS_RZ,
+    AbsoluteAxisCode::ABS_THROTTLE,
+    AbsoluteAxisCode::ABS_RUDDER,
+];
+
+// A device read failing for another reason than being unplugged is read again
+// after READ_RETRY_FIRST, doubling, READ_RETRIES times before it is taken as gone
+const READ_RETRY_FIRST: Duration = Duration::from_millis(50);
+const READ_RETRIES: u32 = 6;
+
+/// Buttons for the two directions of an encoder
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RelButtons {
+    pub up: u16,
+    pub down: u16,
+}
+
+/// How one axis is read, before transforms
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct AxisConfig {
+    /// evdev axis read into this one (ABS_MISC, ABS_WHEEL, ...) instead of the
+    /// default for its slot, config.toml devices only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Device minimum becomes the vJoy maximum (throttles reading 0 at full)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub invert: bool,
+    /// Usable travel in device units, over what the kernel reports. With a
+    /// center, each side of it gets half the vJoy range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub center: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<i32>,
+}
+
+/// One physical device feeding a vjoy device. A cockpit can feed several vjoy
+/// devices from one physical device and one vjoy device from several
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub name: String,
+    pub device_id: u8,
+    pub select: Selector,
+    pub force_feedback: bool,
+    pub keyboard: bool,
+    pub allow_full_keyboard: bool,
+    pub raw: bool,
+    pub grab: bool,
+    /// Explicit bindings, None = every axis, key and the hat in the default order
+    pub map: Option<InputMap>,
+    /// Without a map: read the 8 default axes (overridden by axis codes), else
+    /// only the axes given a code
+    pub default_axes: bool,
+    pub hat: bool,
+    /// By vJoy axis id, for the axes this source feeds
+    pub axis: BTreeMap<u8, AxisConfig>,
+    /// Fixed button numbers, empty = numbered by key code
+    pub buttons: HashMap<KeyCode, u16>,
+    /// Where numbering by key code starts
+    pub button_offset: u16,
+    pub scan_codes: bool,
+    pub hat_keys: Option<[KeyCode; 4]>,
+    pub invert_buttons: HashSet<KeyCode>,
+    pub rel: HashMap<RelativeAxisCode, RelButtons>,
+    pub rel_pulse: Duration,
+}
+
+/// Which inputs of a device go where on its vjoy device
+#[derive(Clone, Debug, Default)]
+pub struct InputMap {
+    /// evdev axis -> axis slot (0..8)
+    pub axes: HashMap<AbsoluteAxisCode, usize>,
+    /// evdev key -> button (1..=256)
+    pub buttons: HashMap<KeyCode, u16>,
+    pub hat: bool,
+    /// evdev key -> hat direction: 0 up, 1 right, 2 down, 3 left
+    pub hat_keys: HashMap<KeyCode, usize>,
+    /// Keys whose state is flipped before it is stored
+    pub inverted: HashSet<KeyCode>,
+    /// Encoders, each detent a button press of rel_pulse
+    pub rel: HashMap<RelativeAxisCode, RelButtons>,
+    pub rel_pulse: Duration,
+}
+
+/// Binds a source to a device no other source has. Sources of the same name
+/// (one cockpit device feeding several vjoy devices) share theirs
+pub fn open_vkb_device(
+    source: &Source,
+    claimed: &Mutex<HashMap<PathBuf, String>>,
+) -> Result<(PathBuf, Device)> {
+    let mut claimed = claimed.lock().unwrap();
+    let mut candidates = source.select.candidates();
+    if candidates.is_empty() {
+        bail!("Device not found for {}", source.select);
+    }
+
+    let pick = candidates
+        .iter()
+        .position(|(path, _)| claimed.get(path) == Some(&source.name))
+        .or_else(|| {
+            candidates
+                .iter()
+                .position(|(path, _)| !claimed.contains_key(path))
+        });
+    let Some(pick) = pick else {
+        bail!(
+            "Every device matching {} is taken by another entry, tell them apart with serial, path or index",
+            source.select
+        );
+    };
+
+    let (path, dev) = candidates.swap_remove(pick);
+    claimed.insert(path.clone(), source.name.clone());
+    Ok((path, dev))
+}
+
+/// An evdev axis by name, ABS_X, ABS_MISC, ...
+pub fn parse_axis_code(name: &str) -> Result<AbsoluteAxisCode> {
+    name.parse()
+        .ok()
+        .with_context(|| format!("unknown axis {}", name))
+}
+
+/// What of min < center < max is known
+pub fn check_calibration(min: Option<i32>, center: Option<i32>, max: Option<i32>) -> Result<()> {
+    let given: Vec<i32> = [min, center, max].into_iter().flatten().collect();
+    if given.windows(2).any(|w| w[0] >= w[1]) {
+        bail!("needs min < center < max");
+    }
+    Ok(())
+}
+
+/// Every key of the device a button, by key code from offset + 1 on
+pub fn build_button_map(dev: &Device, offset: u16) -> Result<HashMap<KeyCode, u16>> {
+    let mut keys: Vec<KeyCode> = dev.supported_keys().into_iter().flatten().collect();
+
+    keys.sort_by_key(|k| k.code());
+
+    let mut map = HashMap::new();
+
+    // 1-based button ids
+    for (idx, k) in (offset + 1..=MAX_BUTTONS).zip(keys) {
+        map.insert(k, idx);
+    }
+
+    Ok(map)
+}
+
+/// Button n for the key the HID usage Button n (0x9000n) maps to, as MSC_SCAN
+/// reports it. None unless every key has one
+pub fn scan_button_map(dev: &Device, offset: u16) -> Option<HashMap<KeyCode, u16>> {
+    let mut map = HashMap::new();
+    for key in dev.supported_keys()?.iter() {
+        let scan = dev.get_scancode_by_keycode(key).ok()?;
+        let usage = u32::from_le_bytes(scan.try_into().ok()?);
+        if usage >> 16 != 0x9 {
+            return None;
+        }
+        let button = (usage & 0xffff) as u16;
+        if button == 0 || button > MAX_BUTTONS - offset {
+            return None;
+        }
+        map.insert(key, button + offset);
+    }
+    Some(map).filter(|map| !map.is_empty())
+}
+
+/// A macro keypad is fine, the keyboard you type on is not: every keystroke would
+/// become a button press on the other machine
+pub fn check_keyboard_interlock(dev: &Device, allow_full_keyboard: bool) -> Result<()> {
+    let typing_keys = [
+        KeyCode::KEY_A,
+        KeyCode::KEY_Z,
+        KeyCode::KEY_SPACE,
+        KeyCode::KEY_ENTER,
+    ];
+    let is_full_keyboard = dev
+        .supported_keys()
+        .is_some_and(|keys| typing_keys.iter().all(|k| keys.contains(*k)));
+
+    if is_full_keyboard && !allow_full_keyboard {
+        bail!(
+            "{} looks like a full keyboard, set allow_full_keyboard = true to bridge it anyway",
+            dev.name().unwrap_or("<no name>")
+        );
+    }
+    Ok(())
+}
+
+/// What of the device goes where: the source's bindings, or its axes, keys
+/// and hat in the default order with what the source changes about them
+pub fn build_input_map(dev: &Device, source: &Source) -> Result<InputMap> {
+    if let Some(map) = &source.map {
+        // Cockpit bindings, only checked against what the device has
+        for key in map.buttons.keys() {
+            if !dev.supported_keys().is_some_and(|keys| keys.contains(*key)) {
+                bail!("{} has no key {:?}", source.name, key);
+            }
+        }
+        return Ok(map.clone());
+    }
+
+    let axes = if source.keyboard {
+        check_keyboard_interlock(dev, source.allow_full_keyboard)?;
+        HashMap::new()
+    } else {
+        let mut axes: HashMap<AbsoluteAxisCode, usize> = match source.default_axes {
+            true => AXIS_CODES
+                .iter()
+                .enumerate()
+                .map(|(slot, code)| (*code, slot))
+                .collect(),
+            false => HashMap::new(),
+        };
+        for (id, cfg) in source.axis.iter() {
+            if let Some(name) = &cfg.code {
+                let code = parse_axis_code(name)?;
+                let slot = *id as usize - 1;
+                // The slot's default, and the slot this code had by default
+                axes.retain(|c, s| *s != slot && *c != code);
+                axes.insert(code, slot);
+            }
+        }
+        axes
+    };
+
+    let scanned = match source.scan_codes && source.buttons.is_empty() {
+        true => scan_button_map(dev, source.button_offset),
+        false => None,
+    };
+    if source.scan_codes && scanned.is_none() {
+        info!(
+            "{}: no HID button scan codes, numbering buttons by key code",
+            source.name
+        );
+    }
+    let mut buttons = if let Some(scanned) = scanned {
+        scanned
+    } else if source.buttons.is_empty() {
+        build_button_map(dev, source.button_offset)?
+    } else {
+        for key in source.buttons.keys() {
+            if !dev.supported_keys().is_some_and(|keys| keys.contains(*key)) {
+                bail!("{} has no key {:?}", source.name, key);
+            }
+        }
+        source.buttons.clone()
+    };
+
+    let mut hat_keys = HashMap::new();
+    for (direction, key) in source.hat_keys.iter().flatten().enumerate() {
+        if !dev.supported_keys().is_some_and(|keys| keys.contains(*key)) {
+            bail!("{} has no key {:?}", source.name, key);
+        }
+        // A hat key is not a button as well
+        buttons.remove(key);
+        hat_keys.insert(*key, direction);
+    }
+
+    for key in source.invert_buttons.iter() {
+        if !dev.supported_keys().is_some_and(|keys| keys.contains(*key)) {
+            bail!("{} has no key {:?}", source.name, key);
+        }
+    }
+    for code in source.rel.keys() {
+        if !dev
+            .supported_relative_axes()
+            .is_some_and(|axes| axes.contains(*code))
+        {
+            bail!("{} has no relative axis {:?}", source.name, code);
+        }
+    }
+
+    Ok(InputMap {
+        axes,
+        buttons,
+        hat: source.hat,
+        hat_keys,
+        inverted: source.invert_buttons.clone(),
+        rel: source.rel.clone(),
+        rel_pulse: source.rel_pulse,
+    })
+}
+
+/// (slot, axis, range) for every mapped axis, by slot. Calibration from the
+/// config goes over what the kernel reports
+pub fn build_axis_ranges(
+    dev: &Device,
+    map: &InputMap,
+    axis: &BTreeMap<u8, AxisConfig>,
+) -> Result<Vec<(usize, AbsoluteAxisCode, AxisRange)>> {
+    // Build a lookup table from the iterator returned by get_absinfo()
+    let absinfo_map: HashMap<AbsoluteAxisCode, AbsInfo> = dev.get_absinfo()?.collect();
+
+    let mut out = Vec::new();
+
+    for (code, slot) in map.axes.iter() {
+        let info = absinfo_map
+            .get(code)
+            .with_context(|| format!("Missing AbsInfo for {:?}", code))?;
+
+        let cfg = axis.get(&(*slot as u8 + 1)).cloned().unwrap_or_default();
+        let range = AxisRange {
+            min: cfg.min.unwrap_or(info.minimum()),
+            max: cfg.max.unwrap_or(info.maximum()),
+            center: cfg.center,
+        };
+        check_calibration(Some(range.min), range.center, Some(range.max)).with_context(|| {
+            format!(
+                "axis {} ({:?}) calibration against the device's {}..={}",
+                slot + 1,
+                code,
+                info.minimum(),
+                info.maximum()
+            )
+        })?;
+        out.push((*slot, *code, range));
+    }
+    out.sort_by_key(|(slot, _, _)| *slot);
+
+    Ok(out)
+}
+
+/// Reads an attached device until it is unplugged (an error), sending what
+/// changes to state. raw, for a raw device, gets every axis and key as well;
+/// events_read is told how many events each read brought. While enabled is
+/// false the device is released and not read, once it is true again what queued
+/// meanwhile is dropped and the device's state read back from the kernel
+pub async fn read_input(
+    source: &Source,
+    mut dev: Device,
+    map: &InputMap,
+    state: &StateTx,
+    raw: Option<&Mutex<RawState>>,
+    events_read: &(dyn Fn(u64) + Sync),
+    enabled: &mut watch::Receiver<bool>,
+) -> Result<()> {
+    if source.grab
+        && let Err(e) = dev.grab()
+    {
+        warn!("could not grab {}: {}", source.name, e);
+    }
+    // The runtime waits, reads drain what is there
+    dev.set_nonblocking(true)?;
+    let mut dev = AsyncFd::new(dev)?;
+    // Hat keys held: up, right, down, left
+    let mut hat_down = [false; 4];
+    // Encoder button -> pulse in progress
+    let mut pulses: HashMap<u16, Pulse> = HashMap::new();
+    // Reads failed in a row, the device still there
+    let mut failures = 0;
+
+    loop {
+        if !*enabled.borrow_and_update() {
+            release(state, map, raw);
+            pulses.clear();
+            // Only gone with the sender, on the way out
+            if enabled.wait_for(|on| *on).await.is_err() {
+                return Ok(());
+            }
+            let dev = dev.get_mut();
+            loop {
+                match dev.fetch_events() {
+                    Ok(events) => events.for_each(drop),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let keys = dev.get_key_state()?;
+            let abs = dev.get_abs_state()?;
+            resync(Some(&keys), Some(&abs), state, map, raw, &mut hat_down);
+        }
+
+        // Pulses end on time, whether events come or not
+        let due = pulses.values().map(|p| p.until).min();
+        tokio::select! {
+            ready = dev.readable_mut() => {
+                let mut ready = ready?;
+                let dev = ready.get_inner_mut();
+                match drain(dev, map, state, raw, &mut hat_down, &mut pulses, events_read) {
+                    Ok(()) => {
+                        if failures > 0 {
+                            info!("Reading {} works again", source.name);
+                            failures = 0;
+                            // What changed while failing, from the kernel
+                            let keys = dev.get_key_state()?;
+                            let abs = dev.get_abs_state()?;
+                            resync(Some(&keys), Some(&abs), state, map, raw, &mut hat_down);
+                        }
+                        ready.clear_ready();
+                    }
+                    // Unplugged, opened again once it is back
+                    Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Err(e.into()),
+                    // Anything else may pass: neutral meanwhile, read again a
+                    // little later each time
+                    Err(e) => {
+                        if failures == READ_RETRIES {
+                            return Err(e).context("reads keep failing");
+                        }
+                        if failures == 0 {
+                            warn!(
+                                "Reading {} failed ({}), sending it neutral and retrying",
+                                source.name, e
+                            );
+                            release(state, map, raw);
+                            pulses.clear();
+                        }
+                        time::sleep(READ_RETRY_FIRST * 2u32.pow(failures)).await;
+                        failures += 1;
+                    }
+                }
+            }
+            _ = time::sleep_until(due.unwrap_or_else(Instant::now).into()), if due.is_some() => {}
+            Ok(()) = enabled.changed() => {}
+        }
+        end_pulses(&mut pulses, map.rel_pulse, state, Instant::now());
+    }
+}
+
+/// Centers the axes, and releases the hat and buttons, of a source that is gone.
+/// For read_input's caller once it returns
+pub fn release(state: &StateTx, map: &InputMap, raw: Option<&Mutex<RawState>>) {
+    if let Some(raw) = raw {
+        let mut raw = raw.lock().unwrap();
+        *raw = raw.neutral();
+    }
+
+    let rel_buttons = map.rel.values().flat_map(|b| [b.up, b.down]);
+    state.send(Update::Release {
+        slots: map.axes.values().copied().collect(),
+        hat: map.hat || !map.hat_keys.is_empty(),
+        buttons: map.buttons.values().copied().chain(rel_buttons).collect(),
+    });
+}
+
+// On for rel_pulse, then off as long before the next detent
+fn end_pulses(
+    pulses: &mut HashMap<u16, Pulse>,
+    rel_pulse: Duration,
+    state: &StateTx,
+    now: Instant,
+) {
+    pulses.retain(|btn_id, p| {
+        if p.until > now {
+            return true;
+        }
+        p.on = !p.on && p.queued > 0;
+        if p.on {
+            p.queued -= 1;
+        }
+        state.send(Update::Button {
+            id: *btn_id,
+            pressed: p.on,
+        });
+        p.until = now + rel_pulse;
+        p.on || p.queued > 0
+    });
+}
+
+// Reads what a readable device has, an error means it is gone
+fn drain(
+    dev: &mut Device,
+    map: &InputMap,
+    state: &StateTx,
+    raw: Option<&Mutex<RawState>>,
+    hat_down: &mut [bool; 4],
+    pulses: &mut HashMap<u16, Pulse>,
+    events_read: &(dyn Fn(u64) + Sync),
+) -> io::Result<()> {
+    // evdev keeps SYN_DROPPED to itself, the state it read back on one carries
+    // the time it did
+    let synced = dev.cached_state().timestamp();
+    loop {
+        let events = match dev.fetch_events() {
+            Ok(events) => events,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let mut read = 0;
+        for ev in events {
+            read += 1;
+            trace!("{:?}", ev);
+            // Raw mode: every axis and key, whatever the mapping
+            if let Some(raw) = &raw {
+                let mut raw = raw.lock().unwrap();
+                match ev.destructure() {
+                    EventSummary::AbsoluteAxis(_, axis, value) => {
+                        if let Some(a) = raw.axes.get_mut(&axis.0) {
+                            a.0 = value;
+                        }
+                    }
+                    EventSummary::Key(_, key, value) => {
+                        if let Some(pressed) = raw.keys.get_mut(&key.code()) {
+                            *pressed = value != 0;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            match ev.destructure() {
+                EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_HAT0X, value) if map.hat => {
+                    state.send(Update::HatX(value.clamp(-1, 1) as i8));
+                }
+                EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_HAT0Y, value) if map.hat => {
+                    state.send(Update::HatY(value.clamp(-1, 1) as i8));
+                }
+                EventSummary::AbsoluteAxis(_, axis, value) => {
+                    // Axes (8 slots)
+                    if let Some(&slot) = map.axes.get(&axis) {
+                        state.send(Update::Axis { slot, value });
+                    }
+                }
+                EventSummary::Key(_, key, value) if map.hat_keys.contains_key(&key) => {
+                    hat_down[map.hat_keys[&key]] = (value != 0) != map.inverted.contains(&key);
+                    let [up, right, down, left] = hat_down.map(i8::from);
+                    state.send(Update::Hat(right - left, down - up));
+                }
+                EventSummary::Key(_, key, value) => {
+                    if let Some(btn_id) = map.buttons.get(&key).copied() {
+                        let pressed = (value != 0) != map.inverted.contains(&key);
+                        state.send(Update::Button {
+                            id: btn_id,
+                            pressed,
+                        });
+                    }
+                }
+                EventSummary::RelativeAxis(_, code, value) if value != 0 => {
+                    if let Some(buttons) = map.rel.get(&code) {
+                        let btn_id = if value > 0 { buttons.up } else { buttons.down };
+                        // Every detent is a press of its own
+                        let detents = value.unsigned_abs();
+                        match pulses.get_mut(&btn_id) {
+                            Some(p) => p.queued += detents,
+                            None => {
+                                state.send(Update::Button {
+                                    id: btn_id,
+                                    pressed: true,
+                                });
+                                let pulse = Pulse {
+                                    until: Instant::now() + map.rel_pulse,
+                                    on: true,
+                                    queued: detents - 1,
+                                };
+                                pulses.insert(btn_id, pulse);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        events_read(read);
+    }
+
+    // After SYN_DROPPED evdev reads the whole device state back (EVIOCGKEY,
+    // EVIOCGABS) on the next fetch, but when that one finds nothing to
+    // read the difference is never replayed as events: take it from there
+    let cached = dev.cached_state();
+    if cached.timestamp() == synced {
+        return Ok(());
+    }
+    resync(
+        cached.key_vals(),
+        cached.abs_vals(),
+        state,
+        map,
+        raw,
+        hat_down,
+    );
+    Ok(())
+}
+
+// This source's controls as evdev last knew the device, or as the kernel has it
+fn resync(
+    keys: Option<&AttributeSetRef<KeyCode>>,
+    abs: Option<&[libc::input_absinfo]>,
+    state: &StateTx,
+    map: &InputMap,
+    raw: Option<&Mutex<RawState>>,
+    hat_down: &mut [bool; 4],
+) {
+    let mut axes = Vec::new();
+    let mut hat = None;
+    let mut buttons = Vec::new();
+
+    if let Some(keys) = keys {
+        for (key, btn_id) in map.buttons.iter() {
+            buttons.push((*btn_id, keys.contains(*key) != map.inverted.contains(key)));
+        }
+        for (key, direction) in map.hat_keys.iter() {
+            hat_down[*direction] = keys.contains(*key) != map.inverted.contains(key);
+        }
+        if let Some(raw) = raw {
+            for (code, pressed) in raw.lock().unwrap().keys.iter_mut() {
+                *pressed = keys.contains(KeyCode::new(*code));
+            }
+        }
+    }
+
+    if let Some(abs) = abs {
+        for (code, slot) in map.axes.iter() {
+            if let Some(info) = abs.get(code.0 as usize) {
+                axes.push((*slot, info.value));
+            }
+        }
+        if map.hat {
+            let hat_axis = |code: AbsoluteAxisCode| {
+                abs.get(code.0 as usize)
+                    .map(|info| info.value.clamp(-1, 1) as i8)
+            };
+            let x = hat_axis(AbsoluteAxisCode::ABS_HAT0X);
+            let y = hat_axis(AbsoluteAxisCode::ABS_HAT0Y);
+            hat = x.zip(y);
+        }
+        if let Some(raw) = raw {
+            for (code, a) in raw.lock().unwrap().axes.iter_mut() {
+                if let Some(info) = abs.get(*code as usize) {
+                    a.0 = info.value;
+                }
+            }
+        }
+    }
+    if !map.hat_keys.is_empty() {
+        let [up, right, down, left] = hat_down.map(i8::from);
+        hat = Some((right - left, down - up));
+    }
+
+    state.send(Update::Sync { axes, hat, buttons });
+}
+
+// An encoder button going on and off, once per detent
+struct Pulse {
+    until: Instant,
+    on: bool,
+    // Detents still to send
+    queued: u32,
+}
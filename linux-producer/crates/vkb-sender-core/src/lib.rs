@@ -0,0 +1,35 @@
+//! The sending half of the bridge without the daemon around it: finding and
+//! reading VKB devices, the per vjoy device state they feed and the VKB2 wire
+//! protocol it goes out in.
+//!
+//! A sender opens each [`input::Source`] with [`input::open_vkb_device`], maps
+//! it with [`input::build_input_map`] and sends an [`state::Update::Attach`]
+//! with its [`input::build_axis_ranges`], then runs [`input::read_input`] on
+//! it. Once a tick it applies what came in ([`state::States::apply_pending`])
+//! and sends each device's [`state::DeviceState::wire`] encoded with
+//! [`protocol::encode_vkb2`]:
+//!
+//! ```no_run
+//! use std::collections::HashMap;
+//! use std::sync::mpsc;
+//! use vkb_sender_core::protocol::{V2_BUTTONS, encode_vkb2};
+//! use vkb_sender_core::state::{DeviceState, States, StateTx, Update};
+//!
+//! let (tx, updates) = mpsc::channel();
+//! let mut states = States {
+//!     states: HashMap::from([(1, DeviceState { enabled: true, ..Default::default() })]),
+//!     updates,
+//! };
+//! let device = StateTx { device_id: 1, tx };
+//! device.send(Update::Button { id: 3, pressed: true });
+//!
+//! states.apply_pending();
+//! let packet = encode_vkb2(0, 1, &states.states[&1].wire(), V2_BUTTONS);
+//! # let _ = packet;
+//! ```
+
+pub mod input;
+pub mod protocol;
+pub mod raw;
+pub mod selector;
+pub mod state;
@@ -0,0 +1,528 @@
+//! The VKB2 wire protocol: packet layouts, and encoding and decoding each type.
+
+use crate::state::WireState;
+use anyhow::{Result, bail};
+use std::collections::BTreeMap;
+
+/// VKB2 header (9 bytes), shared by every packet type:
+///
+/// ```text
+/// 0..4   "VKB2"
+/// 4      version = 2 (3 for wide state packets)
+/// 5      vjoy device id (0 = not device specific)
+/// 6      packet type
+/// 7..9   seq u16 LE
+/// ```
+pub const MAGIC: &[u8; 4] = b"VKB2";
+pub const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 9;
+
+/// State packet (sender -> receiver), 43 bytes:
+///
+/// ```text
+/// 9..25  axes[8] u16 LE (0..=32768)
+/// 25     hat_x i8 (as u8 on wire)
+/// 26     hat_y i8
+/// 27..43 buttons bitset 16 bytes (128 buttons), bit0 = button1
+/// 43..   optional sections to the end of the datagram, each:
+///        0 section type, 1..3 payload length u16 LE, 3.. payload
+///        types 0x80..=0xff are free for custom payloads
+/// Section types:
+/// 1      stale, no payload: some input of the device isn't being read (unplugged,
+///        or its reader restarting), its controls are held neutral meanwhile
+/// ```
+pub const PKT_TYPE_STATE: u8 = 0;
+/// Wide state packet (version byte 3), for more than 128 buttons once the receiver
+/// said it takes them (see PKT_TYPE_CAPS):
+///
+/// ```text
+/// 9..27  axes and hat as above
+/// 27     buttons bitset length n (17..=32)
+/// 28..   buttons bitset n bytes, then optional sections as above
+/// ```
+pub const VERSION_WIDE: u8 = 3;
+pub const STATE_PKT_LEN: usize = 43;
+pub const SECTION_STALE: u8 = 1;
+
+/// Status packet (receiver -> sender), sent back to the source address:
+///
+/// ```text
+/// 9      status kind
+/// message: 10 severity, 11.. utf8 text
+/// stats:   10..34 recv, applied, bad, dup, ooo, lost u32 LE
+///          34..38 rejected u32 LE (newer receivers only)
+/// ```
+pub const PKT_TYPE_STATUS: u8 = 1;
+
+/// Force feedback packet (receiver -> sender), one command per packet:
+///
+/// ```text
+/// 9      op
+/// set effect: 10 effect index, 11 kind, 12..14 duration ms u16 (0 = infinite),
+///             14..16 direction u16 (linux ff units), 16.. kind specific params
+/// start/solo: 10 effect index, 11 loop count
+/// stop/free:  10 effect index
+/// gain:       10 gain u8
+/// ```
+pub const PKT_TYPE_FFB: u8 = 2;
+
+/// Ping (either direction, once a second on a running stream), zero padded to
+/// STATE_PKT_LEN so it travels like a state packet. The other end echoes it back
+/// as a pong:
+///
+/// ```text
+/// 9..17  send time u64 LE, microseconds on the pinging end's clock (0 = unset)
+/// ```
+pub const PKT_TYPE_PING: u8 = 3;
+pub const PKT_TYPE_PONG: u8 = 4;
+
+/// Batch (sender -> receiver), several packets in one datagram so the sender can
+/// stay under the path MTU without IP fragmentation:
+///
+/// ```text
+/// 9..    repeated: packet length u16 LE, packet
+/// ```
+pub const PKT_TYPE_BATCH: u8 = 5;
+
+/// Snapshot request (receiver or debugging tool -> sender), header only
+pub const PKT_TYPE_SNAPSHOT_REQUEST: u8 = 6;
+/// Snapshot (sender -> requester), the decoded state of every device and what
+/// feeds it:
+///
+/// ```text
+/// 9..    utf8 TOML text
+/// ```
+pub const PKT_TYPE_SNAPSHOT: u8 = 7;
+
+/// Raw state (sender -> receiver), a device's evdev state as is, mapped on the
+/// receiver instead of the fixed layout:
+///
+/// ```text
+/// 9      axis count, then per axis: code u16, value i32, min i32, max i32 (LE)
+/// ..     key count, then per key: code u16, pressed u8
+/// ```
+pub const PKT_TYPE_RAW: u8 = 8;
+
+/// Hello (sender -> receiver, once a second), what the sender's layout looks like
+/// so the receiver can tell when the two ends disagree:
+///
+/// ```text
+/// 9..    repeated: vjoy device id u8, layout fingerprint u64 LE
+/// ```
+pub const PKT_TYPE_HELLO: u8 = 9;
+/// Caps (receiver -> sender, answering a hello), what the receiver takes:
+///
+/// ```text
+/// 9..11  most buttons per device u16 LE
+/// ```
+pub const PKT_TYPE_CAPS: u8 = 10;
+/// Profile (sender -> receiver, with every hello and on each switch), the
+/// sender's active set of transforms:
+///
+/// ```text
+/// 9..    utf8 profile name, empty for none
+/// ```
+pub const PKT_TYPE_PROFILE: u8 = 11;
+
+// Keep snapshots inside a single datagram
+const MAX_SNAPSHOT_LEN: usize = 60000;
+
+const FFB_OP_SET_EFFECT: u8 = 0;
+const FFB_OP_START: u8 = 1;
+const FFB_OP_SOLO: u8 = 2;
+const FFB_OP_STOP: u8 = 3;
+const FFB_OP_FREE: u8 = 4;
+const FFB_OP_GAIN: u8 = 5;
+const FFB_OP_STOP_ALL: u8 = 6;
+const FFB_OP_RESET: u8 = 7;
+
+const STATUS_KIND_MESSAGE: u8 = 0;
+const STATUS_KIND_STATS: u8 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Severity {
+    Info,
+    Warn,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkStats {
+    pub received: u32,
+    pub applied: u32,
+    pub bad: u32,
+    pub dup: u32,
+    pub ooo: u32,
+    pub lost_est: u32,
+    pub rejected: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum Status {
+    Message {
+        device_id: u8,
+        severity: Severity,
+        text: String,
+    },
+    Stats(LinkStats),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FfbWaveform {
+    Square,
+    Sine,
+    Triangle,
+    SawUp,
+    SawDown,
+}
+
+/// One axis of a condition effect, in linux ff units
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FfbCondition {
+    pub center: i16,
+    pub pos_coeff: i16,
+    pub neg_coeff: i16,
+    pub pos_saturation: u16,
+    pub neg_saturation: u16,
+    pub deadband: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FfbParams {
+    Constant {
+        level: i16,
+    },
+    Ramp {
+        start: i16,
+        end: i16,
+    },
+    Periodic {
+        waveform: FfbWaveform,
+        magnitude: i16,
+        offset: i16,
+        period_ms: u16,
+        phase: u16,
+    },
+    Spring([FfbCondition; 2]),
+    Damper([FfbCondition; 2]),
+    Inertia([FfbCondition; 2]),
+    Friction([FfbCondition; 2]),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FfbEffect {
+    pub duration_ms: u16,
+    pub direction: u16,
+    pub params: FfbParams,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FfbCommand {
+    SetEffect { index: u8, effect: FfbEffect },
+    Start { index: u8, loop_count: u8 },
+    Solo { index: u8, loop_count: u8 },
+    Stop { index: u8 },
+    Free { index: u8 },
+    Gain(u8),
+    StopAll,
+    Reset,
+}
+
+pub fn write_header(buf: &mut [u8], device_id: u8, pkt_type: u8, seq: u16) {
+    buf[0..4].copy_from_slice(MAGIC);
+    buf[4] = VERSION;
+    buf[5] = device_id;
+    buf[6] = pkt_type;
+    buf[7..9].copy_from_slice(&seq.to_le_bytes());
+}
+
+/// Appends a section to a state packet
+pub fn push_section(buf: &mut Vec<u8>, section_type: u8, payload: &[u8]) {
+    buf.push(section_type);
+    buf.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+pub fn encode_ping(buf: &mut [u8; STATE_PKT_LEN], seq: u16, sent_us: u64) {
+    buf.fill(0);
+    write_header(buf, 0, PKT_TYPE_PING, seq);
+    buf[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&sent_us.to_le_bytes());
+}
+
+/// Pong for a ping packet, None if data isn't a ping
+pub fn encode_pong(data: &[u8]) -> Option<Vec<u8>> {
+    if packet_type(data) != Some(PKT_TYPE_PING) {
+        return None;
+    }
+    let mut out = data.to_vec();
+    out[6] = PKT_TYPE_PONG;
+    Some(out)
+}
+
+/// Send time a pong carries back, None if data isn't a pong
+pub fn decode_pong(data: &[u8]) -> Option<u64> {
+    if packet_type(data) != Some(PKT_TYPE_PONG) {
+        return None;
+    }
+    let sent = data.get(HEADER_LEN..HEADER_LEN + 8)?;
+    Some(u64::from_le_bytes(sent.try_into().unwrap()))
+}
+
+pub fn encode_batch(out: &mut Vec<u8>, seq: u16, packets: &[Vec<u8>]) {
+    out.clear();
+    out.resize(HEADER_LEN, 0);
+    write_header(out, 0, PKT_TYPE_BATCH, seq);
+    for pkt in packets {
+        out.extend_from_slice(&(pkt.len() as u16).to_le_bytes());
+        out.extend_from_slice(pkt);
+    }
+}
+
+pub fn encode_hello(seq: u16, fingerprints: &BTreeMap<u8, u64>) -> Vec<u8> {
+    let mut out = vec![0u8; HEADER_LEN];
+    write_header(&mut out, 0, PKT_TYPE_HELLO, seq);
+    for (device_id, fp) in fingerprints.iter() {
+        out.push(*device_id);
+        out.extend_from_slice(&fp.to_le_bytes());
+    }
+    out
+}
+
+pub fn encode_profile(seq: u16, name: &str) -> Vec<u8> {
+    let mut out = vec![0u8; HEADER_LEN];
+    write_header(&mut out, 0, PKT_TYPE_PROFILE, seq);
+    out.extend_from_slice(name.as_bytes());
+    out
+}
+
+pub fn encode_snapshot(seq: u16, text: &str) -> Vec<u8> {
+    let mut text = text.as_bytes();
+    if text.len() > MAX_SNAPSHOT_LEN {
+        text = &text[..MAX_SNAPSHOT_LEN];
+    }
+
+    let mut out = vec![0u8; HEADER_LEN];
+    write_header(&mut out, 0, PKT_TYPE_SNAPSHOT, seq);
+    out.extend_from_slice(text);
+    out
+}
+
+/// Packet type of a VKB2 packet, None if it isn't one
+pub fn packet_type(data: &[u8]) -> Option<u8> {
+    if data.len() < HEADER_LEN + 1 || &data[0..4] != MAGIC || data[4] != VERSION {
+        return None;
+    }
+    Some(data[6])
+}
+
+/// Buttons per device a caps packet offers, None if data isn't one
+pub fn decode_caps(data: &[u8]) -> Option<u16> {
+    if packet_type(data) != Some(PKT_TYPE_CAPS) {
+        return None;
+    }
+    let b = data.get(HEADER_LEN..HEADER_LEN + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub fn decode_status(data: &[u8]) -> Result<Status> {
+    if packet_type(data) != Some(PKT_TYPE_STATUS) {
+        bail!("not a status packet");
+    }
+
+    let device_id = data[5];
+    let body = &data[HEADER_LEN + 1..];
+
+    match data[HEADER_LEN] {
+        STATUS_KIND_MESSAGE => {
+            let Some((&severity, text)) = body.split_first() else {
+                bail!("too short");
+            };
+            let severity = match severity {
+                0 => Severity::Info,
+                1 => Severity::Warn,
+                _ => Severity::Error,
+            };
+            Ok(Status::Message {
+                device_id,
+                severity,
+                text: String::from_utf8_lossy(text).into_owned(),
+            })
+        }
+        STATUS_KIND_STATS => {
+            if body.len() < 24 {
+                bail!("too short");
+            }
+            let field = |i: usize| {
+                u32::from_le_bytes([
+                    body[i * 4],
+                    body[i * 4 + 1],
+                    body[i * 4 + 2],
+                    body[i * 4 + 3],
+                ])
+            };
+            Ok(Status::Stats(LinkStats {
+                received: field(0),
+                applied: field(1),
+                bad: field(2),
+                dup: field(3),
+                ooo: field(4),
+                lost_est: field(5),
+                rejected: if body.len() >= 28 { field(6) } else { 0 },
+            }))
+        }
+        other => bail!("unknown status kind {other}"),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    off: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8> {
+        let Some(&v) = self.data.get(self.off) else {
+            bail!("too short");
+        };
+        self.off += 1;
+        Ok(v)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn i16(&mut self) -> Result<i16> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn condition(&mut self) -> Result<FfbCondition> {
+        Ok(FfbCondition {
+            center: self.i16()?,
+            pos_coeff: self.i16()?,
+            neg_coeff: self.i16()?,
+            pos_saturation: self.u16()?,
+            neg_saturation: self.u16()?,
+            deadband: self.u16()?,
+        })
+    }
+
+    fn conditions(&mut self) -> Result<[FfbCondition; 2]> {
+        Ok([self.condition()?, self.condition()?])
+    }
+}
+
+pub fn decode_ffb(data: &[u8]) -> Result<(u8, FfbCommand)> {
+    if packet_type(data) != Some(PKT_TYPE_FFB) {
+        bail!("not a force feedback packet");
+    }
+
+    let device_id = data[5];
+    let mut r = Reader {
+        data,
+        off: HEADER_LEN,
+    };
+
+    let cmd = match r.u8()? {
+        FFB_OP_SET_EFFECT => {
+            let index = r.u8()?;
+            let kind = r.u8()?;
+            let duration_ms = r.u16()?;
+            let direction = r.u16()?;
+
+            let params = match kind {
+                0 => FfbParams::Constant { level: r.i16()? },
+                1 => FfbParams::Ramp {
+                    start: r.i16()?,
+                    end: r.i16()?,
+                },
+                2..=6 => FfbParams::Periodic {
+                    waveform: FfbWaveform::from_wire(kind),
+                    magnitude: r.i16()?,
+                    offset: r.i16()?,
+                    period_ms: r.u16()?,
+                    phase: r.u16()?,
+                },
+                7 => FfbParams::Spring(r.conditions()?),
+                8 => FfbParams::Damper(r.conditions()?),
+                9 => FfbParams::Inertia(r.conditions()?),
+                10 => FfbParams::Friction(r.conditions()?),
+                other => bail!("unknown effect kind {other}"),
+            };
+
+            FfbCommand::SetEffect {
+                index,
+                effect: FfbEffect {
+                    duration_ms,
+                    direction,
+                    params,
+                },
+            }
+        }
+        FFB_OP_START => FfbCommand::Start {
+            index: r.u8()?,
+            loop_count: r.u8()?,
+        },
+        FFB_OP_SOLO => FfbCommand::Solo {
+            index: r.u8()?,
+            loop_count: r.u8()?,
+        },
+        FFB_OP_STOP => FfbCommand::Stop { index: r.u8()? },
+        FFB_OP_FREE => FfbCommand::Free { index: r.u8()? },
+        FFB_OP_GAIN => FfbCommand::Gain(r.u8()?),
+        FFB_OP_STOP_ALL => FfbCommand::StopAll,
+        FFB_OP_RESET => FfbCommand::Reset,
+        other => bail!("unknown force feedback op {other}"),
+    };
+
+    Ok((device_id, cmd))
+}
+
+impl FfbWaveform {
+    fn from_wire(kind: u8) -> FfbWaveform {
+        match kind {
+            2 => FfbWaveform::Square,
+            3 => FfbWaveform::Sine,
+            4 => FfbWaveform::Triangle,
+            5 => FfbWaveform::SawUp,
+            _ => FfbWaveform::SawDown,
+        }
+    }
+}
+
+/// Buttons that fit the fixed (v2) state layout
+pub const V2_BUTTONS: u16 = 128;
+
+/// Up to 128 buttons go in the fixed v2 layout, more in the wide (v3) one
+pub fn encode_vkb2(seq: u16, device_id: u8, st: &WireState, buttons: u16) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER_LEN];
+    write_header(&mut buf, device_id, PKT_TYPE_STATE, seq);
+
+    // axes: u16 normalized 0..=32768
+    for v in st.axes {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    buf.push(st.hat_x as u8);
+    buf.push(st.hat_y as u8);
+
+    if buttons > V2_BUTTONS {
+        let n = buttons.div_ceil(8) as usize;
+        buf[4] = VERSION_WIDE;
+        buf.push(n as u8);
+        buf.extend_from_slice(&st.buttons[..n]);
+    } else {
+        buf.extend_from_slice(&st.buttons[..16]);
+    }
+    buf
+}
@@ -0,0 +1,89 @@
+//! Raw mode: a device's evdev state sent as is ([`protocol::PKT_TYPE_RAW`])
+//! for the receiver to map, instead of the fixed state layout.
+
+use crate::protocol::{self, HEADER_LEN, PKT_TYPE_RAW};
+use anyhow::Result;
+use evdev::{AbsInfo, AbsoluteAxisCode, Device};
+use std::collections::{BTreeMap, HashMap};
+
+/// Every axis and key of a raw mode device, by evdev code, mapped on the receiver
+#[derive(Clone, Debug, Default)]
+pub struct RawState {
+    /// code -> (value, min, max)
+    pub axes: BTreeMap<u16, (i32, i32, i32)>,
+    /// code -> pressed
+    pub keys: BTreeMap<u16, bool>,
+}
+
+impl RawState {
+    pub fn new(dev: &Device) -> Result<RawState> {
+        let absinfo: HashMap<AbsoluteAxisCode, AbsInfo> = dev.get_absinfo()?.collect();
+        let axes = absinfo
+            .iter()
+            .map(|(code, info)| (code.0, (info.value(), info.minimum(), info.maximum())))
+            .collect();
+        let keys = dev
+            .supported_keys()
+            .into_iter()
+            .flatten()
+            .map(|k| (k.code(), false))
+            .collect();
+        Ok(RawState { axes, keys })
+    }
+
+    /// Axes centered, keys released
+    pub fn neutral(&self) -> RawState {
+        let mut st = self.clone();
+        for (value, min, max) in st.axes.values_mut() {
+            *value = *min + (*max - *min) / 2;
+        }
+        st.keys.values_mut().for_each(|pressed| *pressed = false);
+        st
+    }
+
+    /// What encode put after the header, None when it doesn't add up
+    pub fn decode(payload: &[u8]) -> Option<RawState> {
+        let mut st = RawState::default();
+        let (&count, mut rest) = payload.split_first()?;
+        for _ in 0..count {
+            let (axis, tail) = rest.split_first_chunk::<14>()?;
+            let i32_at = |i: usize| i32::from_le_bytes(axis[i..i + 4].try_into().unwrap());
+            let code = u16::from_le_bytes([axis[0], axis[1]]);
+            st.axes.insert(code, (i32_at(2), i32_at(6), i32_at(10)));
+            rest = tail;
+        }
+        let (&count, mut rest) = rest.split_first()?;
+        for _ in 0..count {
+            let (key, tail) = rest.split_first_chunk::<3>()?;
+            st.keys
+                .insert(u16::from_le_bytes([key[0], key[1]]), key[2] != 0);
+            rest = tail;
+        }
+        rest.is_empty().then_some(st)
+    }
+
+    pub fn encode(&self, seq: u16, device_id: u8) -> Vec<u8> {
+        // Counts are a byte on the wire
+        let axes: Vec<_> = self.axes.iter().take(u8::MAX as usize).collect();
+        let keys: Vec<_> = self.keys.iter().take(u8::MAX as usize).collect();
+
+        let mut out = vec![0u8; HEADER_LEN];
+        protocol::write_header(&mut out, device_id, PKT_TYPE_RAW, seq);
+
+        out.push(axes.len() as u8);
+        for (code, (value, min, max)) in axes {
+            out.extend_from_slice(&code.to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+            out.extend_from_slice(&min.to_le_bytes());
+            out.extend_from_slice(&max.to_le_bytes());
+        }
+
+        out.push(keys.len() as u8);
+        for (code, pressed) in keys {
+            out.extend_from_slice(&code.to_le_bytes());
+            out.push(*pressed as u8);
+        }
+
+        out
+    }
+}
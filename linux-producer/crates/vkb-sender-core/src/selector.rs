@@ -0,0 +1,141 @@
+//! Which physical device a source means: vendor and product ids, part of its
+//! name, its serial or event node, every one given having to match.
+
+use anyhow::{Result, bail};
+use evdev::Device;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Which physical device a config entry means. Every field given has to match,
+/// so vendor/product can be narrowed down by the others or left out entirely
+/// (firmware updates that change the product id)
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Selector {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor_id: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<u16>,
+    /// Part of the device name, e.g. "EVO R"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    /// Device or USB serial number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Event node, best a stable one from /dev/input/by-id or /dev/input/by-path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Which of several identical matching devices, counted from 0 in USB port
+    /// order. Without it each entry takes the first device no other entry has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+impl Selector {
+    pub fn validate(&self) -> Result<()> {
+        let picks = Selector {
+            index: None,
+            ..self.clone()
+        };
+        if picks == Selector::default() {
+            bail!("needs vendor_id/product_id, name_contains, serial or path");
+        }
+        Ok(())
+    }
+
+    /// Whether it can only ever match one device
+    pub fn is_unique(&self) -> bool {
+        self.serial.is_some() || self.path.is_some() || self.index.is_some()
+    }
+
+    /// Every device matching, in an order that stays the same across boots: by
+    /// the USB port it is plugged in, then by event node. Only the one at index
+    /// when that is set
+    pub fn candidates(&self) -> Vec<(PathBuf, Device)> {
+        let mut found: Vec<(PathBuf, Device)> = evdev::enumerate()
+            .filter(|(path, dev)| self.matches(path, dev))
+            .collect();
+        found.sort_by(|(a_path, a), (b_path, b)| {
+            (a.physical_path(), a_path).cmp(&(b.physical_path(), b_path))
+        });
+
+        match self.index {
+            Some(i) => found.into_iter().nth(i).into_iter().collect(),
+            None => found,
+        }
+    }
+
+    pub fn matches(&self, path: &Path, dev: &Device) -> bool {
+        let id = dev.input_id();
+        if self.vendor_id.is_some_and(|v| v != id.vendor())
+            || self.product_id.is_some_and(|p| p != id.product())
+        {
+            return false;
+        }
+
+        if let Some(part) = &self.name_contains
+            && !dev.name().is_some_and(|name| name.contains(part.as_str()))
+        {
+            return false;
+        }
+
+        if let Some(serial) = &self.serial
+            && serial_of(path, dev).as_deref() != Some(serial.as_str())
+        {
+            return false;
+        }
+
+        // by-id and by-path entries are symlinks to the event node
+        if let Some(want) = &self.path {
+            let want = fs::canonicalize(want).unwrap_or_else(|_| want.clone());
+            let have = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+            if want != have {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The serial the device reports itself, else the one of the USB device it
+/// belongs to
+pub fn serial_of(path: &Path, dev: &Device) -> Option<String> {
+    if let Some(uniq) = dev.unique_name().filter(|u| !u.is_empty()) {
+        return Some(uniq.to_string());
+    }
+    // /sys/class/input/eventN/device is the input device, its parent the USB
+    // interface and that one's parent the USB device
+    let node = path.file_name()?;
+    let sysfs = Path::new("/sys/class/input")
+        .join(node)
+        .join("device/device/../serial");
+    let serial = fs::read_to_string(sysfs).ok()?;
+    Some(serial.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(v) = self.vendor_id {
+            parts.push(format!("vendor={:04x}", v));
+        }
+        if let Some(p) = self.product_id {
+            parts.push(format!("product={:04x}", p));
+        }
+        if let Some(name) = &self.name_contains {
+            parts.push(format!("name~{:?}", name));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial={}", serial));
+        }
+        if let Some(path) = &self.path {
+            parts.push(format!("path={}", path.display()));
+        }
+        if let Some(i) = self.index {
+            parts.push(format!("index={}", i));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
@@ -0,0 +1,267 @@
+//! A vjoy device's state, what changes it, and how it looks on the wire.
+//!
+//! Readers send [`Update`]s through a [`StateTx`]; whoever sends owns the
+//! [`DeviceState`]s (in [`States`]), applies what came in once a tick and
+//! encodes [`DeviceState::wire`].
+
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, Sender};
+
+/// Full travel of a vJoy axis, what a normalized axis goes up to (32768)
+pub const VJOY_AXIS_MAX: u16 = 0x8000;
+
+/// Buttons per device on the wire, past 128 only if the receiver takes them
+pub const MAX_BUTTONS: u16 = 256;
+
+/// Where a physical axis rests at each end, in device units. With a center,
+/// each side of it gets half the vJoy range
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AxisRange {
+    pub min: i32,
+    pub max: i32,
+    pub center: Option<i32>,
+}
+
+/// A vjoy device as read, before normalizing: raw axis values by slot (vJoy
+/// axis id - 1), the hat and 256 buttons
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceState {
+    pub axis_range: [AxisRange; 8],
+    pub axes_raw: [i32; 8],
+    pub hat_x: i8,
+    pub hat_y: i8,
+    /// 256 bits, button 1 is bit 0 of the first byte
+    pub buttons: [u8; 32],
+    /// Buttons that changed since the sender last looked, so a press and release
+    /// between two ticks still reaches the wire
+    pub changed: [u8; 32],
+    /// Highest button number mapped, more than 128 needs the wide layout
+    pub button_count: u16,
+    /// By axis slot
+    pub axis_invert: [bool; 8],
+    /// Moves on with every update applied
+    pub revision: u64,
+    pub enabled: bool,
+}
+
+/// A device's state as it goes on the wire, axes normalized to 0..=32768
+#[derive(Clone, Copy, Debug)]
+pub struct WireState {
+    pub axes: [u16; 8],
+    pub hat_x: i8,
+    pub hat_y: i8,
+    pub buttons: [u8; 32],
+}
+
+impl DeviceState {
+    /// Centered axes, centered hat, all buttons released
+    pub fn neutral(&self) -> DeviceState {
+        let mut st = *self;
+        for (raw, r) in st.axes_raw.iter_mut().zip(st.axis_range) {
+            *raw = r.center.unwrap_or(r.min + (r.max - r.min) / 2);
+        }
+        st.hat_x = 0;
+        st.hat_y = 0;
+        st.buttons = [0; 32];
+        st.changed = [0; 32];
+        st
+    }
+
+    pub fn apply(&mut self, update: Update) {
+        match update {
+            Update::Attach {
+                axes,
+                pressed,
+                button_count,
+            } => {
+                for (slot, range, value) in axes {
+                    self.axis_range[slot] = range;
+                    self.axes_raw[slot] = value;
+                }
+                for btn_id in pressed {
+                    self.set_button(btn_id, true);
+                }
+                self.button_count = self.button_count.max(button_count);
+            }
+            Update::Axis { slot, value } => self.axes_raw[slot] = value,
+            Update::HatX(x) => self.hat_x = x,
+            Update::HatY(y) => self.hat_y = y,
+            Update::Hat(x, y) => (self.hat_x, self.hat_y) = (x, y),
+            Update::Button { id, pressed } => self.set_button(id, pressed),
+            Update::Sync { axes, hat, buttons } => {
+                for (slot, value) in axes {
+                    self.axes_raw[slot] = value;
+                }
+                if let Some((x, y)) = hat {
+                    (self.hat_x, self.hat_y) = (x, y);
+                }
+                for (btn_id, pressed) in buttons {
+                    self.set_button(btn_id, pressed);
+                }
+            }
+            Update::Release {
+                slots,
+                hat,
+                buttons,
+            } => {
+                let neutral = self.neutral();
+                for slot in slots {
+                    self.axes_raw[slot] = neutral.axes_raw[slot];
+                }
+                if hat {
+                    (self.hat_x, self.hat_y) = (0, 0);
+                }
+                for btn_id in buttons {
+                    self.set_button(btn_id, false);
+                }
+            }
+            Update::Enable(enabled) => self.enabled = enabled,
+        }
+        self.revision = self.revision.wrapping_add(1);
+    }
+
+    /// Button 1..=256, marked changed when it is
+    pub fn set_button(&mut self, btn_id: u16, pressed: bool) {
+        let (byte_i, bit_i) = button_bitpos(btn_id);
+        if (self.buttons[byte_i] >> bit_i) & 1 != pressed as u8 {
+            self.buttons[byte_i] ^= 1 << bit_i;
+            self.changed[byte_i] |= 1 << bit_i;
+        }
+    }
+
+    /// Axes normalized (and inverted where set), ready for transforms or
+    /// [`crate::protocol::encode_vkb2`]
+    pub fn wire(&self) -> WireState {
+        WireState {
+            axes: std::array::from_fn(|i| {
+                let v = normalize_axis(self.axes_raw[i], self.axis_range[i]);
+                if self.axis_invert[i] {
+                    VJOY_AXIS_MAX - v
+                } else {
+                    v
+                }
+            }),
+            hat_x: self.hat_x,
+            hat_y: self.hat_y,
+            buttons: self.buttons,
+        }
+    }
+}
+
+/// What the inputs and the console tell the sender, which owns every
+/// device's state and applies these in order at the start of each tick
+pub enum Update {
+    /// A source opened: (axis slot, range, where it rests), the buttons held at
+    /// rest (inverted keys) and the highest button number it maps
+    Attach {
+        axes: Vec<(usize, AxisRange, i32)>,
+        pressed: Vec<u16>,
+        button_count: u16,
+    },
+    /// Raw value of the axis in a slot
+    Axis {
+        slot: usize,
+        value: i32,
+    },
+    /// -1, 0 or 1, y -1 is up
+    HatX(i8),
+    HatY(i8),
+    Hat(i8, i8),
+    Button {
+        id: u16,
+        pressed: bool,
+    },
+    /// A source's controls as evdev last knew the device
+    Sync {
+        axes: Vec<(usize, i32)>,
+        hat: Option<(i8, i8)>,
+        buttons: Vec<(u16, bool)>,
+    },
+    /// A source gone: its axes centered, its hat and buttons released. Other
+    /// sources feeding the same vjoy device keep theirs
+    Release {
+        slots: Vec<usize>,
+        hat: bool,
+        buttons: Vec<u16>,
+    },
+    /// Disabled devices are released once, then nothing is sent for them
+    Enable(bool),
+}
+
+/// One vjoy device's end of the updates channel
+#[derive(Clone)]
+pub struct StateTx {
+    pub device_id: u8,
+    pub tx: Sender<(u8, Update)>,
+}
+
+impl StateTx {
+    /// Fails only once the sender is gone, when everything is
+    pub fn send(&self, update: Update) {
+        let _ = self.tx.send((self.device_id, update));
+    }
+}
+
+/// Every vjoy device's state, by id, and the other end of their [`StateTx`]s
+pub struct States {
+    pub states: HashMap<u8, DeviceState>,
+    pub updates: Receiver<(u8, Update)>,
+}
+
+impl States {
+    /// Applies what came in, true when anything did. Updates for a device not
+    /// in states are dropped
+    pub fn apply_pending(&mut self) -> bool {
+        let mut applied = false;
+        for (k, update) in self.updates.try_iter() {
+            if let Some(st) = self.states.get_mut(&k) {
+                st.apply(update);
+                applied = true;
+            }
+        }
+        applied
+    }
+}
+
+/// Byte and bit of a button (1..=256) in [`DeviceState::buttons`]
+pub fn button_bitpos(btn_id_1_based: u16) -> (usize, u8) {
+    // btn 1 -> bit 0, btn 8 -> bit 7, btn 9 -> next byte bit 0, etc
+    let zero_based = (btn_id_1_based - 1) as usize;
+    (zero_based / 8, (zero_based % 8) as u8)
+}
+
+/// A raw axis value on 0..=[`VJOY_AXIS_MAX`], clamped to its range
+pub fn normalize_axis(raw: i32, r: AxisRange) -> u16 {
+    if r.max == r.min {
+        return VJOY_AXIS_MAX / 2;
+    }
+    // Each side of the center on its half
+    if let Some(center) = r.center {
+        let half = VJOY_AXIS_MAX / 2;
+        return if raw < center {
+            let lower = AxisRange {
+                max: center,
+                center: None,
+                ..r
+            };
+            normalize_axis(raw, lower) / 2
+        } else {
+            let upper = AxisRange {
+                min: center,
+                center: None,
+                ..r
+            };
+            half + normalize_axis(raw, upper) / 2
+        };
+    }
+    let num = (raw as i64 - r.min as i64) * VJOY_AXIS_MAX as i64;
+    let den = r.max as i64 - r.min as i64;
+    let mut out = num / den;
+    if out < 0 {
+        out = 0;
+    }
+    if out > VJOY_AXIS_MAX as i64 {
+        out = VJOY_AXIS_MAX as i64;
+    }
+    out as u16
+}
@@ -0,0 +1,95 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+// Allowlist entry: an address ("192.168.0.16") or a network ("192.168.0.0/24")
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AllowedNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl AllowedNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let diff = u32::from(net) ^ u32::from(ip);
+                diff.checked_shr(32 - self.prefix as u32).unwrap_or(0) == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let diff = u128::from(net) ^ u128::from(ip);
+                diff.checked_shr(128 - self.prefix as u32).unwrap_or(0) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for AllowedNet {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.as_str(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Bad allowed sender {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .with_context(|| format!("Bad prefix length in {}", s))?,
+            None => max,
+        };
+        if addr.is_ipv6() && addr.to_canonical().is_ipv4() {
+            bail!("Use the plain IPv4 form for {}", s);
+        }
+        Ok(AllowedNet { addr, prefix })
+    }
+}
+
+impl From<AllowedNet> for String {
+    fn from(net: AllowedNet) -> String {
+        net.to_string()
+    }
+}
+
+impl fmt::Display for AllowedNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Packet budget of one source, counted over one second windows
+pub struct RateLimit {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    pub fn allow(&mut self, max_per_sec: u32) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= max_per_sec
+    }
+}
@@ -0,0 +1,56 @@
+use std::fmt::Write;
+
+// Axis change (of 0..=32768) that counts as moved, above sensor noise
+const AXIS_THRESHOLD: u16 = 64;
+
+// What changed on one vJoy device since the last stats line
+#[derive(Debug, Default)]
+pub struct Activity {
+    // Axes at the start of the interval
+    start_axes: Option<[u16; 8]>,
+    moved: [bool; 8],
+    button_transitions: u32,
+    last_hat: (i8, i8),
+    hat_changes: u32,
+}
+
+impl Activity {
+    pub fn update(&mut self, axes: &[u16; 8], hat: (i8, i8), button_delta: &[u8; 32]) {
+        let start = self.start_axes.get_or_insert(*axes);
+        for ((moved, start), v) in self.moved.iter_mut().zip(start.iter()).zip(axes) {
+            *moved |= v.abs_diff(*start) > AXIS_THRESHOLD;
+        }
+
+        self.button_transitions += button_delta.iter().map(|b| b.count_ones()).sum::<u32>();
+
+        if hat != self.last_hat {
+            self.last_hat = hat;
+            self.hat_changes += 1;
+        }
+    }
+
+    // e.g. "axes=1,3 buttons=4 hat=0", then starts a new interval
+    pub fn summary(&mut self) -> String {
+        let mut axes = String::new();
+        for (i, _) in self.moved.iter().enumerate().filter(|(_, m)| **m) {
+            if !axes.is_empty() {
+                axes.push(',');
+            }
+            let _ = write!(axes, "{}", i + 1);
+        }
+        if axes.is_empty() {
+            axes.push('-');
+        }
+
+        let out = format!(
+            "axes={} buttons={} hat={}",
+            axes, self.button_transitions, self.hat_changes
+        );
+
+        *self = Activity {
+            last_hat: self.last_hat,
+            ..Activity::default()
+        };
+        out
+    }
+}
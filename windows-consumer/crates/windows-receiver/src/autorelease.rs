@@ -0,0 +1,26 @@
+use std::time::{Duration, Instant};
+
+// Longest a button may stay pressed before it's released here, in case the
+// release never arrives (encoder pulses, macros). Pressed again after the wire
+// shows it released.
+#[derive(Clone, Copy, Debug)]
+pub struct PressLimit {
+    max: Duration,
+    since: Option<Instant>,
+}
+
+impl PressLimit {
+    pub fn new(max: Duration) -> Self {
+        Self { max, since: None }
+    }
+
+    // Whether a button the wire shows as `pressed` may stay pressed
+    pub fn allow(&mut self, pressed: bool, now: Instant) -> bool {
+        if !pressed {
+            self.since = None;
+            return true;
+        }
+        let since = *self.since.get_or_insert(now);
+        now - since < self.max
+    }
+}
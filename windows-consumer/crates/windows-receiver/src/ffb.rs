@@ -0,0 +1,368 @@
+// Force feedback capture. vJoy hands the FFB reports games send to the virtual device
+// to a callback registered in vJoyInterface.dll; the vjoy crate doesn't wrap that part
+// of the SDK, so the handful of functions needed are loaded from the DLL here.
+//
+// The HID PID protocol splits an effect into several reports (effect report, then the
+// type specific parameter report), they are merged here into complete effects before
+// being forwarded to the sender.
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+};
+
+use anyhow::Result;
+use libloading::Library;
+
+use crate::protocol::{FfbCommand, FfbCondition, FfbEffect, FfbParams, FfbWaveform};
+
+const VJOY_DLL_PATH: &str = "C:\\Program Files\\vJoy\\x64\\vJoyInterface.dll";
+
+const ERROR_SUCCESS: u32 = 0;
+
+// FFBPType
+const PT_EFFREP: i32 = 0x01;
+const PT_CONDREP: i32 = 0x03;
+const PT_PRIDREP: i32 = 0x04;
+const PT_CONSTREP: i32 = 0x05;
+const PT_RAMPREP: i32 = 0x06;
+const PT_EFOPREP: i32 = 0x0A;
+const PT_BLKFRREP: i32 = 0x0B;
+const PT_CTRLREP: i32 = 0x0C;
+const PT_GAINREP: i32 = 0x0D;
+
+// FFBEType
+const ET_CONST: i32 = 1;
+const ET_RAMP: i32 = 2;
+const ET_SQR: i32 = 3;
+const ET_SINE: i32 = 4;
+const ET_TRNGL: i32 = 5;
+const ET_STUP: i32 = 6;
+const ET_STDN: i32 = 7;
+const ET_SPRNG: i32 = 8;
+const ET_DMPR: i32 = 9;
+const ET_INRT: i32 = 10;
+const ET_FRCTN: i32 = 11;
+
+// FFBOP
+const EFF_START: i32 = 1;
+const EFF_SOLO: i32 = 2;
+const EFF_STOP: i32 = 3;
+
+// FFB_CTRL
+const CTRL_STOPALL: i32 = 3;
+const CTRL_DEVRST: i32 = 4;
+
+// Structs below mirror public.h of the vJoy 2.1.9 SDK
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EffReport {
+    ebi: u8,
+    effect_type: i32,
+    duration: u16,
+    trigger_rpt: u16,
+    sample_prd: u16,
+    gain: u8,
+    trigger_btn: u8,
+    polar: i32,
+    direction: u8,
+    dir_y: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EffConstant {
+    ebi: u8,
+    magnitude: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EffRamp {
+    ebi: u8,
+    start: i32,
+    end: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EffPeriod {
+    ebi: u8,
+    magnitude: u32,
+    offset: i32,
+    phase: u32,
+    period: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EffCond {
+    ebi: u8,
+    is_y: i32,
+    center: i32,
+    pos_coeff: i32,
+    neg_coeff: i32,
+    pos_satur: u32,
+    neg_satur: u32,
+    dead_band: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EffOp {
+    ebi: u8,
+    op: i32,
+    loop_count: u8,
+}
+
+type FfbGenCb = extern "system" fn(*const c_void, *mut c_void);
+type RegisterGenCb = unsafe extern "C" fn(FfbGenCb, *mut c_void);
+type Getter<T> = unsafe extern "C" fn(*const c_void, *mut T) -> u32;
+
+struct Api {
+    device_id: Getter<i32>,
+    packet_type: Getter<i32>,
+    ebi: Getter<i32>,
+    eff_report: Getter<EffReport>,
+    eff_constant: Getter<EffConstant>,
+    eff_ramp: Getter<EffRamp>,
+    eff_period: Getter<EffPeriod>,
+    eff_cond: Getter<EffCond>,
+    eff_op: Getter<EffOp>,
+    dev_ctrl: Getter<i32>,
+    dev_gain: Getter<u8>,
+}
+
+impl Api {
+    // SAFETY: the symbol types must match the vJoy SDK declarations
+    unsafe fn load(lib: &Library) -> Result<Api> {
+        unsafe {
+            Ok(Api {
+                device_id: *lib.get(b"Ffb_h_DeviceID\0")?,
+                packet_type: *lib.get(b"Ffb_h_Type\0")?,
+                ebi: *lib.get(b"Ffb_h_EBI\0")?,
+                eff_report: *lib.get(b"Ffb_h_Eff_Report\0")?,
+                eff_constant: *lib.get(b"Ffb_h_Eff_Constant\0")?,
+                eff_ramp: *lib.get(b"Ffb_h_Eff_Ramp\0")?,
+                eff_period: *lib.get(b"Ffb_h_Eff_Period\0")?,
+                eff_cond: *lib.get(b"Ffb_h_Eff_Cond\0")?,
+                eff_op: *lib.get(b"Ffb_h_EffOp\0")?,
+                dev_ctrl: *lib.get(b"Ffb_h_DevCtrl\0")?,
+                dev_gain: *lib.get(b"Ffb_h_DevGain\0")?,
+            })
+        }
+    }
+}
+
+// Pieces of one effect block, collected until the effect can be described completely
+#[derive(Default)]
+struct PendingEffect {
+    report: Option<EffReport>,
+    constant: Option<EffConstant>,
+    ramp: Option<EffRamp>,
+    period: Option<EffPeriod>,
+    cond: [Option<EffCond>; 2],
+}
+
+struct Context {
+    api: Api,
+    _lib: Library,
+    // (vjoy device id, effect block index) -> effect
+    effects: Mutex<HashMap<(u8, u8), PendingEffect>>,
+    tx: Sender<(u8, FfbCommand)>,
+}
+
+// Registers the FFB callback. Commands for all vJoy devices come out of the returned channel.
+pub fn start() -> Result<Receiver<(u8, FfbCommand)>> {
+    // SAFETY: vJoyInterface.dll has no initialisation side effects we depend on
+    let lib = unsafe { Library::new(VJOY_DLL_PATH) }?;
+    let api = unsafe { Api::load(&lib) }?;
+    let register: RegisterGenCb = unsafe { *lib.get(b"FfbRegisterGenCB\0")? };
+
+    let (tx, rx) = mpsc::channel();
+
+    // Lives for the rest of the process, vJoy may call back at any time
+    let ctx: &'static Context = Box::leak(Box::new(Context {
+        api,
+        _lib: lib,
+        effects: Mutex::new(HashMap::new()),
+        tx,
+    }));
+    unsafe { register(ffb_callback, ctx as *const Context as *mut c_void) };
+
+    Ok(rx)
+}
+
+extern "system" fn ffb_callback(data: *const c_void, user: *mut c_void) {
+    // SAFETY: user is the leaked Context registered in start()
+    let ctx = unsafe { &*(user as *const Context) };
+
+    if let Some(cmd) = ctx.handle(data) {
+        let _ = ctx.tx.send(cmd);
+    }
+}
+
+fn get<T: Default>(f: Getter<T>, data: *const c_void) -> Option<T> {
+    let mut out = T::default();
+    // SAFETY: data is the FFB_DATA pointer vJoy passed to the callback
+    (unsafe { f(data, &mut out) } == ERROR_SUCCESS).then_some(out)
+}
+
+impl Context {
+    fn handle(&self, data: *const c_void) -> Option<(u8, FfbCommand)> {
+        let api = &self.api;
+        let device_id = get(api.device_id, data)? as u8;
+        let mut effects = self.effects.lock().ok()?;
+
+        let cmd = match get(api.packet_type, data)? {
+            PT_EFFREP => {
+                let r = get(api.eff_report, data)?;
+                let e = effects.entry((device_id, r.ebi)).or_default();
+                e.report = Some(r);
+                e.command(r.ebi)?
+            }
+            PT_CONSTREP => {
+                let c = get(api.eff_constant, data)?;
+                let e = effects.entry((device_id, c.ebi)).or_default();
+                e.constant = Some(c);
+                e.command(c.ebi)?
+            }
+            PT_RAMPREP => {
+                let r = get(api.eff_ramp, data)?;
+                let e = effects.entry((device_id, r.ebi)).or_default();
+                e.ramp = Some(r);
+                e.command(r.ebi)?
+            }
+            PT_PRIDREP => {
+                let p = get(api.eff_period, data)?;
+                let e = effects.entry((device_id, p.ebi)).or_default();
+                e.period = Some(p);
+                e.command(p.ebi)?
+            }
+            PT_CONDREP => {
+                let c = get(api.eff_cond, data)?;
+                let e = effects.entry((device_id, c.ebi)).or_default();
+                e.cond[(c.is_y != 0) as usize] = Some(c);
+                e.command(c.ebi)?
+            }
+            PT_EFOPREP => {
+                let op = get(api.eff_op, data)?;
+                match op.op {
+                    EFF_START => FfbCommand::Start {
+                        index: op.ebi,
+                        loop_count: op.loop_count,
+                    },
+                    EFF_SOLO => FfbCommand::Solo {
+                        index: op.ebi,
+                        loop_count: op.loop_count,
+                    },
+                    EFF_STOP => FfbCommand::Stop { index: op.ebi },
+                    _ => return None,
+                }
+            }
+            PT_BLKFRREP => {
+                let ebi = get(api.ebi, data)? as u8;
+                effects.remove(&(device_id, ebi));
+                FfbCommand::Free { index: ebi }
+            }
+            PT_CTRLREP => match get(api.dev_ctrl, data)? {
+                CTRL_STOPALL => FfbCommand::StopAll,
+                CTRL_DEVRST => {
+                    effects.retain(|(id, _), _| *id != device_id);
+                    FfbCommand::Reset
+                }
+                _ => return None,
+            },
+            PT_GAINREP => FfbCommand::Gain(get(api.dev_gain, data)?),
+            _ => return None,
+        };
+
+        Some((device_id, cmd))
+    }
+}
+
+impl PendingEffect {
+    // None until both the effect report and its parameters arrived
+    fn command(&self, index: u8) -> Option<FfbCommand> {
+        let r = self.report?;
+        let gain = r.gain as i32;
+        let level = |v: i32| scale_signed(v * gain / 255);
+
+        let params = match r.effect_type {
+            ET_CONST => FfbParams::Constant {
+                level: level(self.constant?.magnitude),
+            },
+            ET_RAMP => {
+                let ramp = self.ramp?;
+                FfbParams::Ramp {
+                    start: level(ramp.start),
+                    end: level(ramp.end),
+                }
+            }
+            ET_SQR..=ET_STDN => {
+                let p = self.period?;
+                FfbParams::Periodic {
+                    waveform: match r.effect_type {
+                        ET_SQR => FfbWaveform::Square,
+                        ET_SINE => FfbWaveform::Sine,
+                        ET_TRNGL => FfbWaveform::Triangle,
+                        ET_STUP => FfbWaveform::SawUp,
+                        _ => FfbWaveform::SawDown,
+                    },
+                    magnitude: level(p.magnitude.min(10000) as i32),
+                    offset: level(p.offset),
+                    period_ms: p.period.min(u16::MAX as u32) as u16,
+                    // hundredths of a degree -> ms into the period
+                    phase: (p.phase as u64 * p.period as u64 / 36000).min(u16::MAX as u64) as u16,
+                }
+            }
+            ET_SPRNG => FfbParams::Spring(self.conditions()?),
+            ET_DMPR => FfbParams::Damper(self.conditions()?),
+            ET_INRT => FfbParams::Inertia(self.conditions()?),
+            ET_FRCTN => FfbParams::Friction(self.conditions()?),
+            _ => return None,
+        };
+
+        Some(FfbCommand::SetEffect {
+            index,
+            effect: FfbEffect {
+                // 0xFFFF is infinite in HID PID, 0 is infinite for linux
+                duration_ms: if r.duration == 0xFFFF { 0 } else { r.duration },
+                // polar 0..255 clockwise from north -> linux 0x8000 = up, 0xC000 = right
+                direction: 0x8000u16.wrapping_add((r.direction as u16) << 8),
+                params,
+            },
+        })
+    }
+
+    fn conditions(&self) -> Option<[FfbCondition; 2]> {
+        if self.cond.iter().all(Option::is_none) {
+            return None;
+        }
+        Some(self.cond.map(|c| c.map(condition).unwrap_or_default()))
+    }
+}
+
+fn condition(c: EffCond) -> FfbCondition {
+    FfbCondition {
+        center: scale_signed(c.center),
+        pos_coeff: scale_signed(c.pos_coeff),
+        neg_coeff: scale_signed(c.neg_coeff),
+        pos_saturation: scale_unsigned(c.pos_satur),
+        neg_saturation: scale_unsigned(c.neg_satur),
+        deadband: scale_unsigned(c.dead_band.max(0) as u32),
+    }
+}
+
+// vJoy uses -10000..=10000 for magnitudes, linux the full i16/u16 range
+fn scale_signed(v: i32) -> i16 {
+    (v.clamp(-10000, 10000) * i16::MAX as i32 / 10000) as i16
+}
+
+fn scale_unsigned(v: u32) -> u16 {
+    (v.min(10000) * u16::MAX as u32 / 10000) as u16
+}
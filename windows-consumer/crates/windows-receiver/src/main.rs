@@ -1,32 +1,85 @@
+mod access;
+mod activity;
+mod autorelease;
+mod ffb;
+mod protocol;
+mod raw;
+mod sections;
+mod smoothing;
+mod vsock;
+
 use std::{
-    net::UdpSocket,
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::mpsc::{self, Sender},
+    thread,
     time::{Duration, Instant},
 };
 
-use anyhow::{Result, bail};
+use access::{AllowedNet, RateLimit};
+use activity::Activity;
+use anyhow::{Context, Result, bail};
+use autorelease::PressLimit;
+use protocol::{
+    FfbCommand, LinkStats, MAX_BUTTONS, PKT_TYPE_HELLO, PKT_TYPE_RAW, Packet, Severity, decode_raw,
+    decode_sections, decode_vkb2,
+};
+use raw::RawMap;
+use sections::SectionHandlers;
+use serde::{Deserialize, Serialize};
+use smoothing::AxisFilter;
+use socket2::{Domain, Protocol, Socket, Type};
 use vjoy::{ButtonState, FourWayHat, HatState, VJoy};
 
-const LISTEN_ADDR: &str = "0.0.0.0:46000";
-const VJOY_DEVICE_ID: u32 = 1;
-
-// VKB2 packet layout (42 bytes):
-// 0..4   "VKB2"
-// 4      version = 2
-// 5      reserved
-// 6..8   seq u16 LE
-// 8..24  axes[8] u16 LE (0..=32768 suggested)
-// 24     hat_x i8 (as u8 on wire)
-// 25     hat_y i8
-// 26..42 buttons bitset 16 bytes (128 buttons), bit0 = button1
-const PKT_LEN: usize = 42;
+const LISTEN_PORT: u16 = 46000;
+const CONFIG_FILE_PATH: &str = "config.toml";
+// Packets applied at most before vJoy is updated, even if more are queued
+const MAX_PENDING_UPDATES: u32 = 32;
+
+// Optional: without a config file every device is passed through as received
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Config {
+    // Join this group to receive a multicast stream (sender dest set to the group)
+    multicast_group: Option<IpAddr>,
+    // Local interface address to join an IPv4 group on, any if unset
+    multicast_interface: Option<Ipv4Addr>,
+    // DSCP (0..=63, e.g. 46 = EF) for status and force feedback packets
+    dscp: Option<u8>,
+    // Also accept a sender on the VM host over vsock on this port (needs viosock)
+    vsock_port: Option<u32>,
+    // Only accept packets from these addresses / networks, anyone if empty
+    #[serde(default)]
+    allowed_senders: Vec<AllowedNet>,
+    // Packets per second a single source may send before the rest is dropped
+    max_packets_per_sec: Option<u32>,
+    // Add a line per second saying which axes moved and how many button
+    // transitions arrived on each device
+    #[serde(default)]
+    stats_activity: bool,
+    #[serde(default)]
+    vjoy_device: BTreeMap<u8, VJoyDevice>,
+}
 
-#[derive(Clone, Copy, Debug)]
-struct Packet {
-    seq: u16,
-    axes: [u16; 8],
-    hat_x: i8,
-    hat_y: i8,
-    buttons: [u8; 16],
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct VJoyDevice {
+    // vJoy axis id (1..=8) -> smoothing time in ms
+    #[serde(default)]
+    smoothing: BTreeMap<u8, u64>,
+    // Layout fingerprint the sender prints at startup (16 hex digits), warns when
+    // the sender's differs
+    layout_fingerprint: Option<String>,
+    // Wire button (1..=256) -> longest press in ms before it's released anyway
+    #[serde(default)]
+    max_press_ms: BTreeMap<u16, u64>,
+    // Buttons past this device's own go to this vJoy device, starting at its button 1
+    overflow_device: Option<u8>,
+    // Raw mode senders: evdev axis code -> vJoy axis id (1..=8), default ABS_X..ABS_RUDDER
+    #[serde(default)]
+    raw_axes: BTreeMap<String, u8>,
+    // Raw mode senders: evdev key code -> wire button (1..=256), default code order
+    #[serde(default)]
+    raw_buttons: BTreeMap<String, u16>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -35,190 +88,848 @@ enum HatMode {
     Continuous,
 }
 
+// Per vJoy device, created when the first packet for its device id arrives
+struct DeviceSlot {
+    hats_enabled: bool,
+    hat_mode: HatMode,
+    num_axes: usize,
+    num_buttons: usize,
+    last_buttons: [u8; 32],
+    dropped_buttons_reported: bool,
+    // (vJoy device id, its button count) taking the buttons past num_buttons
+    overflow: Option<(u8, usize)>,
+    filters: [Option<AxisFilter>; 8],
+    // (wire button, limit)
+    press_limits: Vec<(u16, PressLimit)>,
+    activity: Activity,
+    last_update: Option<Instant>,
+}
+
+// Link statistics of one sender address, reported every second
+struct SourceStats {
+    received: u64,
+    applied: u64,
+    bad: u64,
+    dup: u64,
+    ooo: u64,
+    lost_est: u64,
+    // Dropped by the allowlist or the rate limit
+    rejected: u64,
+    allowed: bool,
+    rate: RateLimit,
+    // Round trip of the last ping answered
+    rtt: Option<Duration>,
+    // device id -> layout fingerprint from the last hello
+    layouts: HashMap<u8, u64>,
+    // The sender's active profile as last reported
+    profile: Option<String>,
+    // device id -> last applied seq, each sender numbers its devices separately
+    last_seq: HashMap<u8, u16>,
+    last_applied: Option<u16>,
+    last_seen: Instant,
+}
+
+impl SourceStats {
+    fn new(allowed: bool) -> Self {
+        Self {
+            received: 0,
+            applied: 0,
+            bad: 0,
+            dup: 0,
+            ooo: 0,
+            lost_est: 0,
+            rejected: 0,
+            allowed,
+            rate: RateLimit::new(),
+            rtt: None,
+            layouts: HashMap::new(),
+            profile: None,
+            last_seq: HashMap::new(),
+            last_applied: None,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+// Packets back to the sender over the same socket: status reports (also logged
+// locally) and force feedback
+struct ReturnChannel<'a> {
+    sock: &'a UdpSocket,
+    seq: u16,
+}
+
+impl<'a> ReturnChannel<'a> {
+    fn new(sock: &'a UdpSocket) -> Self {
+        Self { sock, seq: 0 }
+    }
+
+    fn report(&mut self, to: SocketAddr, device_id: u8, severity: Severity, text: &str) {
+        println!("{:?}: {}", severity, text);
+
+        let pkt = protocol::encode_status_message(self.seq, device_id, severity, text);
+        self.seq = self.seq.wrapping_add(1);
+        // Best effort: the sender may not be listening for status at all
+        let _ = self.sock.send_to(&pkt, to);
+    }
+
+    fn stats(&mut self, to: SocketAddr, stats: &LinkStats) {
+        let pkt = protocol::encode_status_stats(self.seq, stats);
+        self.seq = self.seq.wrapping_add(1);
+        let _ = self.sock.send_to(&pkt, to);
+    }
+
+    fn caps(&mut self, to: SocketAddr, max_buttons: u16) {
+        let pkt = protocol::encode_caps(self.seq, max_buttons);
+        self.seq = self.seq.wrapping_add(1);
+        let _ = self.sock.send_to(&pkt, to);
+    }
+
+    fn ping(&mut self, to: SocketAddr, sent_us: u64) {
+        let pkt = protocol::encode_ping(self.seq, sent_us);
+        self.seq = self.seq.wrapping_add(1);
+        let _ = self.sock.send_to(&pkt, to);
+    }
+
+    fn snapshot_request(&mut self, to: SocketAddr) {
+        let pkt = protocol::encode_snapshot_request(self.seq);
+        self.seq = self.seq.wrapping_add(1);
+        let _ = self.sock.send_to(&pkt, to);
+    }
+
+    fn ffb(&mut self, to: SocketAddr, device_id: u8, cmd: &FfbCommand) {
+        let pkt = protocol::encode_ffb(self.seq, device_id, cmd);
+        self.seq = self.seq.wrapping_add(1);
+        let _ = self.sock.send_to(&pkt, to);
+    }
+}
+
+fn parse() -> Result<Config> {
+    let toml_str = match fs::read_to_string(CONFIG_FILE_PATH) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).with_context(|| "Failed to read config file"),
+    };
+    let decoded: Config =
+        toml::from_str(&toml_str).with_context(|| "Failed to parse config.toml")?;
+
+    Ok(decoded)
+}
+
 fn main() -> Result<()> {
-    let sock = UdpSocket::bind(LISTEN_ADDR)?;
-    println!("Listening on UDP {LISTEN_ADDR}");
+    let config = parse()?;
+    println!("Using config: {:?}", config);
 
-    let mut vjoy = VJoy::from_default_dll_location()?;
+    let sock = bind_socket(&config)?;
+    println!("Listening on UDP {}", sock.local_addr()?);
+    if let Some(dscp) = config.dscp {
+        set_dscp(&sock, dscp)?;
+    }
 
-    let (hats_enabled, hat_mode, num_axes, num_buttons, num_hats) = {
-        let device = vjoy.get_device_state_mut(VJOY_DEVICE_ID)?;
+    if let Some(port) = config.vsock_port {
+        vsock::start(port)?;
+    }
 
-        let hat_mode = match device.hat_type() {
-            HatState::Discrete(_) => HatMode::Discrete,
-            HatState::Continuous(_) => HatMode::Continuous,
-        };
+    let mut vjoy = VJoy::from_default_dll_location()?;
+    let mut status = ReturnChannel::new(&sock);
 
-        (
-            device.num_hats() >= 1,
-            hat_mode,
-            device.num_axes(),
-            device.num_buttons(),
-            device.num_hats(),
-        )
+    let ffb_rx = match ffb::start() {
+        Ok(rx) => Some(rx),
+        Err(e) => {
+            println!("Force feedback disabled: {:#}", e);
+            None
+        }
     };
 
-    println!(
-        "vJoy device {}: axes={} buttons={} hats={}",
-        VJOY_DEVICE_ID, num_axes, num_buttons, num_hats
-    );
+    // Console commands, handled between packets
+    let (console_tx, console_rx) = mpsc::channel();
+    thread::spawn(move || console_thread(console_tx));
 
-    if num_buttons < 128 {
-        println!("Warning: vJoy device has fewer than 128 buttons enabled in vJoyConf.exe");
+    // Custom packet sections, register handlers here
+    let mut section_handlers = SectionHandlers::default();
+
+    // Layouts the config expects senders to have
+    let mut expected_layouts: HashMap<u8, u64> = HashMap::new();
+    for (id, dev) in config.vjoy_device.iter() {
+        if let Some(fp) = &dev.layout_fingerprint {
+            let fp = u64::from_str_radix(fp, 16)
+                .with_context(|| format!("Bad layout_fingerprint for vjoy device {}", id))?;
+            expected_layouts.insert(*id, fp);
+        }
     }
-    if num_axes < 8 {
-        println!("Warning: vJoy device has fewer than 8 axes enabled in vJoyConf.exe");
+
+    // Mappings for raw mode senders, devices without config get the default one
+    let mut raw_maps: HashMap<u8, RawMap> = HashMap::new();
+    for (id, dev) in config.vjoy_device.iter() {
+        let map = RawMap::new(&dev.raw_axes, &dev.raw_buttons)
+            .with_context(|| format!("Bad raw mapping for vjoy device {}", id))?;
+        raw_maps.insert(*id, map);
     }
 
-    let mut buf = [0u8; 2048];
+    // None = the vJoy device could not be acquired, packets for it are ignored
+    let mut devices: HashMap<u8, Option<DeviceSlot>> = HashMap::new();
 
-    let mut last_seq: Option<u16> = None;
-    let mut last_buttons = [0u8; 16];
+    // Large enough for a snapshot reply
+    let mut buf = [0u8; 65536];
 
     // Stats (1 Hz)
-    let mut received: u64 = 0;
-    let mut applied: u64 = 0;
-    let mut bad: u64 = 0;
-    let mut dup: u64 = 0;
-    let mut ooo: u64 = 0;
-    let mut lost_est: u64 = 0;
+    let mut sources: HashMap<SocketAddr, SourceStats> = HashMap::new();
     let mut last_report = Instant::now();
+    // Ping send times count from here
+    let epoch = Instant::now();
+
+    // Wake up now and then to release held buttons even if the sender went quiet
+    let press_limits = config
+        .vjoy_device
+        .values()
+        .any(|d| !d.max_press_ms.is_empty());
+    if press_limits {
+        sock.set_read_timeout(Some(Duration::from_millis(50)))?;
+    }
+
+    // Packets applied but not yet pushed to vJoy. Meanwhile the socket doesn't
+    // block, so a burst (one packet per device) is drained first and costs a
+    // single driver update.
+    let mut pending: u32 = 0;
 
     loop {
-        let (len, from) = sock.recv_from(&mut buf)?;
-        received += 1;
+        let (len, from) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if pending > 0 {
+                    vjoy.update_all_devices()?;
+                    pending = 0;
+                    sock.set_nonblocking(false)?;
+                    continue;
+                }
 
-        let pkt = match decode_vkb2(&buf[..len]) {
-            Ok(p) => p,
-            Err(_) => {
-                bad += 1;
+                let mut released = false;
+                for (device_id, slot) in devices.iter_mut() {
+                    if let Some(slot) = slot {
+                        released |= release_expired(&mut vjoy, *device_id, slot)?;
+                    }
+                }
+                if released {
+                    vjoy.update_all_devices()?;
+                }
                 continue;
             }
+            Err(e) => return Err(e.into()),
         };
-
-        let should_apply = match last_seq {
-            None => true,
-            Some(prev) => {
-                if pkt.seq == prev {
-                    dup += 1;
-                    false
-                } else if is_newer_u16(pkt.seq, prev) {
-                    let diff = pkt.seq.wrapping_sub(prev) as u32;
-                    if diff > 1 {
-                        lost_est += (diff - 1) as u64;
+        // IPv4 senders show up as ::ffff:a.b.c.d on the dual-stack socket
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+
+        for cmd in console_rx.try_iter() {
+            match cmd {
+                ConsoleCommand::Snapshot => {
+                    for (addr, _) in sources.iter().filter(|(_, s)| s.allowed) {
+                        status.snapshot_request(*addr);
                     }
-                    true
-                } else {
-                    ooo += 1;
-                    false
                 }
             }
-        };
+        }
 
-        if should_apply {
-            last_seq = Some(pkt.seq);
-            applied += 1;
+        let src = sources
+            .entry(from)
+            .or_insert_with(|| SourceStats::new(is_allowed(&config, from.ip())));
+        src.last_seen = Instant::now();
+
+        let within_rate = config
+            .max_packets_per_sec
+            .is_none_or(|max| src.rate.allow(max));
+        if !src.allowed || !within_rate {
+            src.rejected += 1;
+            continue;
+        }
 
-            {
-                let device = vjoy.get_device_state_mut(VJOY_DEVICE_ID)?;
+        // Answer to the console's snapshot command
+        if let Some(text) = protocol::decode_snapshot(&buf[..len]) {
+            println!("snapshot from {}:\n{}", from, text);
+            continue;
+        }
 
-                // Axes: map packet axes[0..8] to vJoy axis IDs 1..=8
-                // If your sender uses 0..=32768, passing that as i32 is fine.
-                for (i, v) in pkt.axes.iter().enumerate() {
-                    let axis_id = (i as u32) + 1;
-                    device.set_axis(axis_id, *v as i32)?;
-                }
+        // Which profile the sender has active, logged as it changes
+        if let Some(name) = protocol::decode_profile(&buf[..len]) {
+            if src.profile.as_ref() != Some(&name) {
+                let shown = if name.is_empty() { "none" } else { name.as_str() };
+                println!("{}: profile {} active", from, shown);
+                src.profile = Some(name);
+            }
+            continue;
+        }
 
-                // Hat: ABS_HAT0X/ABS_HAT0Y come as -1..=1.
-                // If your vJoy hat is discrete, diagonals get reduced to a cardinal direction.
-                if hats_enabled {
-                    let hs = hatstate_from_xy(pkt.hat_x, pkt.hat_y, hat_mode);
-                    device.set_hat(1, hs)?;
+        // Layout check, once a second from each sender
+        if protocol::packet_type(&buf[..len]) == Some(PKT_TYPE_HELLO) {
+            match protocol::decode_hello(&buf[..len]) {
+                Ok(layouts) => {
+                    check_layouts(&expected_layouts, src, from, &layouts, &mut status);
+                    status.caps(from, MAX_BUTTONS);
                 }
+                Err(_) => src.bad += 1,
+            }
+            continue;
+        }
 
-                // Buttons: only update changed bits (keeps it fast)
-                let delta = xor_16(pkt.buttons, last_buttons);
-                if delta != [0u8; 16] {
-                    for byte_i in 0..16 {
-                        let changed = delta[byte_i];
-                        if changed == 0 {
-                            continue;
-                        }
-                        for bit in 0..8 {
-                            if (changed & (1 << bit)) == 0 {
-                                continue;
+        // The sender's RTT pings, and `linux-sender ping`
+        if let Some(pong) = protocol::encode_pong(&buf[..len]) {
+            let _ = sock.send_to(&pong, from);
+            continue;
+        }
+
+        // Answer to our own ping
+        if let Some(sent) = protocol::decode_pong(&buf[..len]) {
+            src.rtt = Some(epoch.elapsed().saturating_sub(Duration::from_micros(sent)));
+            continue;
+        }
+
+        src.received += 1;
+
+        // Force feedback goes to whoever is streaming to us
+        if let Some(rx) = &ffb_rx {
+            for (device_id, cmd) in rx.try_iter() {
+                status.ffb(from, device_id, &cmd);
+            }
+        }
+
+        // A batch carries several devices' packets in one datagram
+        let Ok(packets) = protocol::unbatch(&buf[..len]) else {
+            src.bad += 1;
+            continue;
+        };
+
+        for data in packets {
+            let decoded = if protocol::packet_type(data) == Some(PKT_TYPE_RAW) {
+                // Raw mode: map the evdev codes here, no sections
+                decode_raw(data).map(|raw| {
+                    let map = raw_maps.entry(raw.device_id).or_default();
+                    (map.to_packet(&raw), Vec::new())
+                })
+            } else {
+                decode_vkb2(data).and_then(|p| Ok((p, decode_sections(data)?)))
+            };
+            let Ok((pkt, sections)) = decoded else {
+                src.bad += 1;
+                continue;
+            };
+
+            let slot = devices.entry(pkt.device_id).or_insert_with(|| {
+                let cfg = config.vjoy_device.get(&pkt.device_id);
+                acquire_device(&mut vjoy, pkt.device_id, cfg, from, &mut status)
+            });
+
+            if let Some(slot) = slot {
+                let should_apply = match src.last_seq.get(&pkt.device_id).copied() {
+                    None => true,
+                    Some(prev) => {
+                        if pkt.seq == prev {
+                            src.dup += 1;
+                            false
+                        } else if is_newer_u16(pkt.seq, prev) {
+                            let diff = pkt.seq.wrapping_sub(prev) as u32;
+                            if diff > 1 {
+                                src.lost_est += (diff - 1) as u64;
                             }
-                            let btn_id_1_based = (byte_i * 8 + bit + 1) as u8;
-                            let pressed = (pkt.buttons[byte_i] & (1 << bit)) != 0;
-                            device.set_button(
-                                btn_id_1_based,
-                                if pressed {
-                                    ButtonState::Pressed
-                                } else {
-                                    ButtonState::Released
-                                },
-                            )?;
+                            true
+                        } else {
+                            src.ooo += 1;
+                            false
                         }
                     }
-                    last_buttons = pkt.buttons;
+                };
+
+                if should_apply {
+                    src.last_seq.insert(pkt.device_id, pkt.seq);
+                    src.last_applied = Some(pkt.seq);
+                    src.applied += 1;
+
+                    apply_packet(&mut vjoy, slot, &pkt, from, &mut status)?;
+                    if pending == 0 {
+                        sock.set_nonblocking(true)?;
+                    }
+                    pending += 1;
+                    // A flood that never drains still reaches vJoy
+                    if pending >= MAX_PENDING_UPDATES {
+                        vjoy.update_all_devices()?;
+                        pending = 0;
+                        sock.set_nonblocking(false)?;
+                    }
+
+                    section_handlers.dispatch(pkt.device_id, &sections);
                 }
             }
-
-            vjoy.update_all_devices()?;
         }
 
         if last_report.elapsed() >= Duration::from_secs(1) {
             last_report = Instant::now();
-            let last = last_seq
-                .map(|s| s.to_string())
-                .unwrap_or_else(|| "-".to_string());
-            println!(
-                "stats: from={} recv={} applied={} bad={} dup={} ooo={} lost~={} last_seq={}",
-                from, received, applied, bad, dup, ooo, lost_est, last
-            );
+
+            // Forget senders that went away
+            sources.retain(|_, s| s.last_seen.elapsed() < Duration::from_secs(10));
+
+            for (from, s) in sources.iter() {
+                let last = s
+                    .last_applied
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                let rtt = s
+                    .rtt
+                    .map(|r| format!("{:.2}ms", r.as_secs_f64() * 1000.0))
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "stats: from={} recv={} applied={} bad={} dup={} ooo={} lost~={} rejected={} rtt={} last_seq={}",
+                    from,
+                    s.received,
+                    s.applied,
+                    s.bad,
+                    s.dup,
+                    s.ooo,
+                    s.lost_est,
+                    s.rejected,
+                    rtt,
+                    last
+                );
+
+                // Never answer sources that aren't allowed
+                if !s.allowed {
+                    continue;
+                }
+
+                status.stats(
+                    *from,
+                    &LinkStats {
+                        received: s.received as u32,
+                        applied: s.applied as u32,
+                        bad: s.bad as u32,
+                        dup: s.dup as u32,
+                        ooo: s.ooo as u32,
+                        lost_est: s.lost_est as u32,
+                        rejected: s.rejected as u32,
+                    },
+                );
+                status.ping(*from, epoch.elapsed().as_micros() as u64);
+            }
+
+            if config.stats_activity {
+                let mut ids: Vec<_> = devices.keys().copied().collect();
+                ids.sort();
+                for id in ids {
+                    if let Some(Some(slot)) = devices.get_mut(&id) {
+                        println!("activity: device={} {}", id, slot.activity.summary());
+                    }
+                }
+            }
         }
     }
 }
 
-fn decode_vkb2(data: &[u8]) -> Result<Packet> {
-    if data.len() < PKT_LEN {
-        bail!("too short");
+// Reports a sender's layout the first time it's seen and whenever it changes
+fn check_layouts(
+    expected: &HashMap<u8, u64>,
+    src: &mut SourceStats,
+    from: SocketAddr,
+    layouts: &[(u8, u64)],
+    status: &mut ReturnChannel,
+) {
+    for &(device_id, fp) in layouts {
+        let prev = src.layouts.insert(device_id, fp);
+        if prev == Some(fp) {
+            continue;
+        }
+
+        match expected.get(&device_id) {
+            Some(&want) if want != fp => status.report(
+                from,
+                device_id,
+                Severity::Warn,
+                &format!(
+                    "vJoy device {}: sender layout {:016x} doesn't match layout_fingerprint {:016x}, \
+                     was the config changed on one machine only?",
+                    device_id, fp, want
+                ),
+            ),
+            _ if prev.is_some() => status.report(
+                from,
+                device_id,
+                Severity::Warn,
+                &format!("vJoy device {}: sender layout changed to {:016x}", device_id, fp),
+            ),
+            _ => println!("vJoy device {}: sender layout {:016x}", device_id, fp),
+        }
     }
-    if &data[0..4] != b"VKB2" {
-        bail!("bad magic");
+}
+
+enum ConsoleCommand {
+    // Ask every sender for its decoded state
+    Snapshot,
+}
+
+fn console_thread(tx: Sender<ConsoleCommand>) {
+    let stdin = io::stdin();
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        // stdin closed (e.g. running as a service)
+        if !matches!(stdin.read_line(&mut line), Ok(n) if n > 0) {
+            return;
+        }
+
+        match line.trim() {
+            "snapshot" => {
+                if tx.send(ConsoleCommand::Snapshot).is_err() {
+                    return;
+                }
+            }
+            "" => {}
+            _ => println!("commands: snapshot"),
+        }
     }
-    if data[4] != 2 {
-        bail!("bad version");
+}
+
+fn is_allowed(config: &Config, ip: IpAddr) -> bool {
+    // The vsock relay forwards over loopback
+    if config.vsock_port.is_some() && ip.is_loopback() {
+        return true;
     }
+    config.allowed_senders.is_empty() || config.allowed_senders.iter().any(|n| n.contains(ip))
+}
+
+fn bind_socket(config: &Config) -> Result<UdpSocket> {
+    let any_v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, LISTEN_PORT));
+    let any_v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, LISTEN_PORT));
+
+    let Some(group) = config.multicast_group else {
+        // Dual-stack, so both IPv4 and IPv6 senders reach us
+        return match bind_udp(any_v6, false) {
+            Ok(sock) => Ok(sock),
+            Err(e) => {
+                println!("IPv6 unavailable ({}), listening on IPv4 only", e);
+                Ok(bind_udp(any_v4, false)?)
+            }
+        };
+    };
+
+    // Reuse lets a logger or overlay on the same machine subscribe to the group too
+    match group {
+        IpAddr::V4(group) => {
+            let sock = bind_udp(any_v4, true)?;
+            let iface = config.multicast_interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+            sock.join_multicast_v4(&group, &iface)
+                .with_context(|| format!("Failed to join multicast group {}", group))?;
+            println!("Joined multicast group {group} on {iface}");
+            Ok(sock)
+        }
+        IpAddr::V6(group) => {
+            let sock = bind_udp(any_v6, true)?;
+            sock.join_multicast_v6(&group, 0)
+                .with_context(|| format!("Failed to join multicast group {}", group))?;
+            println!("Joined multicast group {group}");
+            Ok(sock)
+        }
+    }
+}
+
+fn bind_udp(addr: SocketAddr, reuse: bool) -> io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        // Windows defaults to IPv6 only
+        sock.set_only_v6(false)?;
+    }
+    if reuse {
+        sock.set_reuse_address(true)?;
+    }
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+// Windows only puts the mark on the wire when a QoS policy allows it, and only
+// for IPv4 traffic
+fn set_dscp(sock: &UdpSocket, dscp: u8) -> Result<()> {
+    if dscp > 63 {
+        bail!("dscp {} out of range (0..=63)", dscp);
+    }
+    if let Err(e) = socket2::SockRef::from(sock).set_tos_v4((dscp as u32) << 2) {
+        println!("Warning: could not set DSCP {} ({})", dscp, e);
+    }
+    Ok(())
+}
+
+fn acquire_device(
+    vjoy: &mut VJoy,
+    device_id: u8,
+    cfg: Option<&VJoyDevice>,
+    from: SocketAddr,
+    status: &mut ReturnChannel,
+) -> Option<DeviceSlot> {
+    let device = match vjoy.get_device_state_mut(device_id as u32) {
+        Ok(device) => device,
+        Err(e) => {
+            status.report(
+                from,
+                device_id,
+                Severity::Error,
+                &format!("vJoy device {} not acquired: {}", device_id, e),
+            );
+            return None;
+        }
+    };
+
+    let hat_mode = match device.hat_type() {
+        HatState::Discrete(_) => HatMode::Discrete,
+        HatState::Continuous(_) => HatMode::Continuous,
+    };
+    let num_axes = device.num_axes() as usize;
+    let num_buttons = device.num_buttons() as usize;
+    let num_hats = device.num_hats() as usize;
+
+    status.report(
+        from,
+        device_id,
+        Severity::Info,
+        &format!(
+            "vJoy device {}: axes={} buttons={} hats={}",
+            device_id, num_axes, num_buttons, num_hats
+        ),
+    );
 
-    let seq = u16::from_le_bytes([data[6], data[7]]);
+    if num_buttons < 128 {
+        status.report(
+            from,
+            device_id,
+            Severity::Warn,
+            &format!(
+                "vJoy device {} has fewer than 128 buttons enabled in vJoyConf.exe",
+                device_id
+            ),
+        );
+    }
+    if num_axes < 8 {
+        status.report(
+            from,
+            device_id,
+            Severity::Warn,
+            &format!(
+                "vJoy device {} has fewer than 8 axes enabled in vJoyConf.exe, axes >{} dropped",
+                device_id, num_axes
+            ),
+        );
+    }
 
-    let mut axes = [0u16; 8];
-    let mut off = 8;
-    for i in 0..8 {
-        axes[i] = u16::from_le_bytes([data[off], data[off + 1]]);
-        off += 2;
+    let mut filters = [None; 8];
+    for (axis_id, ms) in cfg.map(|c| &c.smoothing).into_iter().flatten() {
+        match filters.get_mut((*axis_id as usize).wrapping_sub(1)) {
+            Some(f) => *f = Some(AxisFilter::new(Duration::from_millis(*ms))),
+            None => status.report(
+                from,
+                device_id,
+                Severity::Warn,
+                &format!("smoothing: no axis {} (expected 1..=8)", axis_id),
+            ),
+        }
     }
 
-    let hat_x = data[off] as i8;
-    let hat_y = data[off + 1] as i8;
-    off += 2;
+    let mut press_limits = Vec::new();
+    for (button, ms) in cfg.map(|c| &c.max_press_ms).into_iter().flatten() {
+        if (1..=MAX_BUTTONS).contains(button) {
+            press_limits.push((*button, PressLimit::new(Duration::from_millis(*ms))));
+        } else {
+            status.report(
+                from,
+                device_id,
+                Severity::Warn,
+                &format!(
+                    "max_press_ms: no button {} (expected 1..={})",
+                    button, MAX_BUTTONS
+                ),
+            );
+        }
+    }
 
-    let mut buttons = [0u8; 16];
-    buttons.copy_from_slice(&data[off..off + 16]);
+    let overflow = match cfg.and_then(|c| c.overflow_device) {
+        Some(id) => match vjoy.get_device_state_mut(id as u32) {
+            Ok(dev) => {
+                let n = dev.num_buttons() as usize;
+                status.report(
+                    from,
+                    device_id,
+                    Severity::Info,
+                    &format!(
+                        "vJoy device {}: buttons {}..={} go to vJoy device {}",
+                        device_id,
+                        num_buttons + 1,
+                        num_buttons + n,
+                        id
+                    ),
+                );
+                Some((id, n))
+            }
+            Err(e) => {
+                status.report(
+                    from,
+                    device_id,
+                    Severity::Error,
+                    &format!("overflow vJoy device {} not acquired: {}", id, e),
+                );
+                None
+            }
+        },
+        None => None,
+    };
 
-    Ok(Packet {
-        seq,
-        axes,
-        hat_x,
-        hat_y,
-        buttons,
+    Some(DeviceSlot {
+        hats_enabled: num_hats >= 1,
+        hat_mode,
+        num_axes,
+        num_buttons,
+        last_buttons: [0u8; 32],
+        dropped_buttons_reported: false,
+        overflow,
+        filters,
+        press_limits,
+        activity: Activity::default(),
+        last_update: None,
     })
 }
 
-fn xor_16(a: [u8; 16], b: [u8; 16]) -> [u8; 16] {
-    let mut out = [0u8; 16];
-    for i in 0..16 {
+fn apply_packet(
+    vjoy: &mut VJoy,
+    slot: &mut DeviceSlot,
+    pkt: &Packet,
+    from: SocketAddr,
+    status: &mut ReturnChannel,
+) -> Result<()> {
+    let device = vjoy.get_device_state_mut(pkt.device_id as u32)?;
+
+    let now = Instant::now();
+    let dt = slot.last_update.map(|t| now - t).unwrap_or_default();
+    slot.last_update = Some(now);
+
+    // Axes: map packet axes[0..8] to vJoy axis IDs 1..=8
+    // If your sender uses 0..=32768, passing that as i32 is fine.
+    for (i, v) in pkt.axes.iter().enumerate().take(slot.num_axes) {
+        let axis_id = (i as u32) + 1;
+        let value = match &mut slot.filters[i] {
+            Some(filter) => filter.update(*v as i32, dt),
+            None => *v as i32,
+        };
+        device.set_axis(axis_id, value)?;
+    }
+
+    // Hat: ABS_HAT0X/ABS_HAT0Y come as -1..=1.
+    // If your vJoy hat is discrete, diagonals get reduced to a cardinal direction.
+    if slot.hats_enabled {
+        let hs = hatstate_from_xy(pkt.hat_x, pkt.hat_y, slot.hat_mode);
+        device.set_hat(1, hs)?;
+    }
+
+    // Buttons held past their limit count as released
+    let mut buttons = pkt.buttons;
+    for (button, limit) in slot.press_limits.iter_mut() {
+        let (byte_i, mask) = button_bit(*button);
+        if !limit.allow(buttons[byte_i] & mask != 0, now) {
+            buttons[byte_i] &= !mask;
+        }
+    }
+
+    // Buttons: only update changed bits (keeps it fast)
+    let delta = xor_buttons(buttons, slot.last_buttons);
+    slot.activity
+        .update(&pkt.axes, (pkt.hat_x, pkt.hat_y), &delta);
+    if delta != [0u8; 32] {
+        let mut dropped = false;
+        for (byte_i, changed) in delta.iter().enumerate() {
+            if *changed == 0 {
+                continue;
+            }
+            for bit in 0..8 {
+                if (changed & (1 << bit)) == 0 {
+                    continue;
+                }
+                let btn_id_1_based = byte_i * 8 + bit + 1;
+                let pressed = (buttons[byte_i] & (1 << bit)) != 0;
+                if !set_button(vjoy, pkt.device_id, slot, btn_id_1_based, pressed)? {
+                    dropped |= pressed;
+                }
+            }
+        }
+        slot.last_buttons = buttons;
+
+        if dropped && !slot.dropped_buttons_reported {
+            slot.dropped_buttons_reported = true;
+            status.report(
+                from,
+                pkt.device_id,
+                Severity::Warn,
+                &format!(
+                    "vJoy device {}: buttons >{} dropped, set overflow_device to keep them",
+                    pkt.device_id,
+                    slot.num_buttons + slot.overflow.map_or(0, |(_, n)| n)
+                ),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Releases buttons held past their limit while no packets arrive
+fn release_expired(vjoy: &mut VJoy, device_id: u8, slot: &mut DeviceSlot) -> Result<bool> {
+    let now = Instant::now();
+    let mut released = false;
+    let mut expired = Vec::new();
+    for (button, limit) in slot.press_limits.iter_mut() {
+        let (byte_i, mask) = button_bit(*button);
+        if slot.last_buttons[byte_i] & mask != 0 && !limit.allow(true, now) {
+            slot.last_buttons[byte_i] &= !mask;
+            released = true;
+            expired.push(*button as usize);
+        }
+    }
+    for button in expired {
+        set_button(vjoy, device_id, slot, button, false)?;
+    }
+    Ok(released)
+}
+
+// Sets a 1-based wire button on the device, or past its own buttons on the
+// overflow device. False if neither has it.
+fn set_button(
+    vjoy: &mut VJoy,
+    device_id: u8,
+    slot: &DeviceSlot,
+    button: usize,
+    pressed: bool,
+) -> Result<bool> {
+    let (id, button) = if button <= slot.num_buttons {
+        (device_id, button)
+    } else {
+        match slot.overflow {
+            Some((id, n)) if button - slot.num_buttons <= n => (id, button - slot.num_buttons),
+            _ => return Ok(false),
+        }
+    };
+    let state = if pressed {
+        ButtonState::Pressed
+    } else {
+        ButtonState::Released
+    };
+    vjoy.get_device_state_mut(id as u32)?
+        .set_button(button as u8, state)?;
+    Ok(true)
+}
+
+// 1-based button id -> (byte, mask) in the buttons bitset
+fn button_bit(button: u16) -> (usize, u8) {
+    let bit = button as usize - 1;
+    (bit / 8, 1 << (bit % 8))
+}
+
+fn xor_buttons(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    for i in 0..32 {
         out[i] = a[i] ^ b[i];
     }
     out
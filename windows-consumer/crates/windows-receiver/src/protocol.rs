@@ -0,0 +1,545 @@
+use anyhow::{Result, bail};
+
+// VKB2 header (9 bytes), shared by every packet type:
+// 0..4   "VKB2"
+// 4      version = 2 (3 for wide state packets)
+// 5      vjoy device id (0 = not device specific)
+// 6      packet type
+// 7..9   seq u16 LE
+pub const MAGIC: &[u8; 4] = b"VKB2";
+pub const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 9;
+
+// State packet (sender -> receiver), 43 bytes:
+// 9..25  axes[8] u16 LE (0..=32768 suggested)
+// 25     hat_x i8 (as u8 on wire)
+// 26     hat_y i8
+// 27..43 buttons bitset 16 bytes (128 buttons), bit0 = button1
+// 43..   optional sections to the end of the datagram, each:
+//        0 section type, 1..3 payload length u16 LE, 3.. payload
+//        types 0x80..=0xff are free for custom payloads
+// Section types:
+// 1      stale, no payload: some input of the device isn't being read (unplugged,
+//        or its reader restarting), its controls are held neutral meanwhile
+pub const PKT_TYPE_STATE: u8 = 0;
+pub const STATE_PKT_LEN: usize = 43;
+// Wide state packet (version byte 3), for more than 128 buttons once this end
+// said it takes them (see PKT_TYPE_CAPS):
+// 9..27  axes and hat as above
+// 27     buttons bitset length n (17..=32)
+// 28..   buttons bitset n bytes, then optional sections as above
+pub const VERSION_WIDE: u8 = 3;
+// Most buttons per device a wide state packet carries
+pub const MAX_BUTTONS: u16 = 256;
+
+// Status packet (receiver -> sender), sent back to the source address:
+// 9      status kind
+// message: 10 severity, 11.. utf8 text
+// stats:   10..34 recv, applied, bad, dup, ooo, lost u32 LE
+//          34..38 rejected u32 LE (newer receivers only)
+pub const PKT_TYPE_STATUS: u8 = 1;
+
+// Force feedback packet (receiver -> sender), one command per packet:
+// 9      op
+// set effect: 10 effect index, 11 kind, 12..14 duration ms u16 (0 = infinite),
+//             14..16 direction u16 (linux ff units), 16.. kind specific params
+// start/solo: 10 effect index, 11 loop count
+// stop/free:  10 effect index
+// gain:       10 gain u8
+pub const PKT_TYPE_FFB: u8 = 2;
+
+// Ping (either direction, once a second on a running stream), zero padded to
+// STATE_PKT_LEN so it travels like a state packet. The other end echoes it back
+// as a pong:
+// 9..17  send time u64 LE, microseconds on the pinging end's clock (0 = unset)
+pub const PKT_TYPE_PING: u8 = 3;
+pub const PKT_TYPE_PONG: u8 = 4;
+
+// Batch (sender -> receiver), several packets in one datagram so the sender can
+// stay under the path MTU without IP fragmentation:
+// 9..    repeated: packet length u16 LE, packet
+pub const PKT_TYPE_BATCH: u8 = 5;
+
+// Snapshot request (receiver or debugging tool -> sender), header only
+pub const PKT_TYPE_SNAPSHOT_REQUEST: u8 = 6;
+// Snapshot (sender -> requester), the decoded state of every device and what
+// feeds it:
+// 9..    utf8 TOML text
+pub const PKT_TYPE_SNAPSHOT: u8 = 7;
+
+// Raw state (sender -> receiver), a device's evdev state as is, mapped here
+// instead of on the sender:
+// 9      axis count, then per axis: code u16, value i32, min i32, max i32 (LE)
+// ..     key count, then per key: code u16, pressed u8
+pub const PKT_TYPE_RAW: u8 = 8;
+
+// Hello (sender -> receiver, once a second), what the sender's layout looks like
+// so the receiver can tell when the two ends disagree:
+// 9..    repeated: vjoy device id u8, layout fingerprint u64 LE
+pub const PKT_TYPE_HELLO: u8 = 9;
+// Caps (receiver -> sender, answering a hello), what the receiver takes:
+// 9..11  most buttons per device u16 LE
+pub const PKT_TYPE_CAPS: u8 = 10;
+// Profile (sender -> receiver, with every hello and on each switch), the
+// sender's active set of transforms:
+// 9..    utf8 profile name, empty for none
+pub const PKT_TYPE_PROFILE: u8 = 11;
+
+const FFB_OP_SET_EFFECT: u8 = 0;
+const FFB_OP_START: u8 = 1;
+const FFB_OP_SOLO: u8 = 2;
+const FFB_OP_STOP: u8 = 3;
+const FFB_OP_FREE: u8 = 4;
+const FFB_OP_GAIN: u8 = 5;
+const FFB_OP_STOP_ALL: u8 = 6;
+const FFB_OP_RESET: u8 = 7;
+
+const STATUS_KIND_MESSAGE: u8 = 0;
+const STATUS_KIND_STATS: u8 = 1;
+
+// Keep status messages well inside a single datagram
+const MAX_MESSAGE_LEN: usize = 512;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Packet {
+    pub device_id: u8,
+    pub seq: u16,
+    pub axes: [u16; 8],
+    pub hat_x: i8,
+    pub hat_y: i8,
+    pub buttons: [u8; 32],
+}
+
+#[derive(Clone, Debug)]
+pub struct RawPacket {
+    pub device_id: u8,
+    pub seq: u16,
+    // (code, value, min, max)
+    pub axes: Vec<(u16, i32, i32, i32)>,
+    // (code, pressed), in code order
+    pub keys: Vec<(u16, bool)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Severity {
+    Info = 0,
+    Warn = 1,
+    Error = 2,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkStats {
+    pub received: u32,
+    pub applied: u32,
+    pub bad: u32,
+    pub dup: u32,
+    pub ooo: u32,
+    pub lost_est: u32,
+    pub rejected: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FfbWaveform {
+    Square = 2,
+    Sine = 3,
+    Triangle = 4,
+    SawUp = 5,
+    SawDown = 6,
+}
+
+// One axis of a condition effect, in linux ff units
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FfbCondition {
+    pub center: i16,
+    pub pos_coeff: i16,
+    pub neg_coeff: i16,
+    pub pos_saturation: u16,
+    pub neg_saturation: u16,
+    pub deadband: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FfbParams {
+    Constant {
+        level: i16,
+    },
+    Ramp {
+        start: i16,
+        end: i16,
+    },
+    Periodic {
+        waveform: FfbWaveform,
+        magnitude: i16,
+        offset: i16,
+        period_ms: u16,
+        phase: u16,
+    },
+    Spring([FfbCondition; 2]),
+    Damper([FfbCondition; 2]),
+    Inertia([FfbCondition; 2]),
+    Friction([FfbCondition; 2]),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FfbEffect {
+    pub duration_ms: u16,
+    pub direction: u16,
+    pub params: FfbParams,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FfbCommand {
+    SetEffect { index: u8, effect: FfbEffect },
+    Start { index: u8, loop_count: u8 },
+    Solo { index: u8, loop_count: u8 },
+    Stop { index: u8 },
+    Free { index: u8 },
+    Gain(u8),
+    StopAll,
+    Reset,
+}
+
+pub fn decode_vkb2(data: &[u8]) -> Result<Packet> {
+    if data.len() < STATE_PKT_LEN {
+        bail!("too short");
+    }
+    if &data[0..4] != MAGIC {
+        bail!("bad magic");
+    }
+    if data[4] != VERSION && data[4] != VERSION_WIDE {
+        bail!("bad version");
+    }
+    if data[6] != PKT_TYPE_STATE {
+        bail!("not a state packet");
+    }
+
+    let device_id = data[5];
+    let seq = u16::from_le_bytes([data[7], data[8]]);
+
+    let mut axes = [0u16; 8];
+    let mut off = HEADER_LEN;
+    for axis in axes.iter_mut() {
+        *axis = u16::from_le_bytes([data[off], data[off + 1]]);
+        off += 2;
+    }
+
+    let hat_x = data[off] as i8;
+    let hat_y = data[off + 1] as i8;
+    off += 2;
+
+    let mut buttons = [0u8; 32];
+    if data[4] == VERSION_WIDE {
+        let n = data[off] as usize;
+        let Some(bits) = data.get(off + 1..off + 1 + n).filter(|_| n <= 32) else {
+            bail!("bad wide buttons");
+        };
+        buttons[..n].copy_from_slice(bits);
+    } else {
+        buttons[..16].copy_from_slice(&data[off..off + 16]);
+    }
+
+    Ok(Packet {
+        device_id,
+        seq,
+        axes,
+        hat_x,
+        hat_y,
+        buttons,
+    })
+}
+
+// Packet type of a VKB2 packet, None if it isn't one
+pub fn packet_type(data: &[u8]) -> Option<u8> {
+    if data.len() < HEADER_LEN || &data[0..4] != MAGIC || data[4] != VERSION {
+        return None;
+    }
+    Some(data[6])
+}
+
+pub fn decode_raw(data: &[u8]) -> Result<RawPacket> {
+    if packet_type(data) != Some(PKT_TYPE_RAW) {
+        bail!("not a raw packet");
+    }
+
+    let device_id = data[5];
+    let seq = u16::from_le_bytes([data[7], data[8]]);
+    let mut rest = &data[HEADER_LEN..];
+
+    let Some((&count, tail)) = rest.split_first() else {
+        bail!("truncated raw packet");
+    };
+    rest = tail;
+    let mut axes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let Some(a) = rest.get(..14) else {
+            bail!("truncated raw axes");
+        };
+        let i32_at = |off: usize| i32::from_le_bytes(a[off..off + 4].try_into().unwrap());
+        axes.push((
+            u16::from_le_bytes([a[0], a[1]]),
+            i32_at(2),
+            i32_at(6),
+            i32_at(10),
+        ));
+        rest = &rest[14..];
+    }
+
+    let Some((&count, tail)) = rest.split_first() else {
+        bail!("truncated raw packet");
+    };
+    rest = tail;
+    let mut keys = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let Some(k) = rest.get(..3) else {
+            bail!("truncated raw keys");
+        };
+        keys.push((u16::from_le_bytes([k[0], k[1]]), k[2] != 0));
+        rest = &rest[3..];
+    }
+
+    Ok(RawPacket {
+        device_id,
+        seq,
+        axes,
+        keys,
+    })
+}
+
+// (vjoy device id, layout fingerprint) pairs of a hello
+pub fn decode_hello(data: &[u8]) -> Result<Vec<(u8, u64)>> {
+    if packet_type(data) != Some(PKT_TYPE_HELLO) {
+        bail!("not a hello packet");
+    }
+    let body = &data[HEADER_LEN..];
+    if !body.len().is_multiple_of(9) {
+        bail!("truncated hello");
+    }
+    Ok(body
+        .chunks_exact(9)
+        .map(|c| (c[0], u64::from_le_bytes(c[1..9].try_into().unwrap())))
+        .collect())
+}
+
+// Sections after the state fields, as (type, payload)
+pub fn decode_sections(data: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut out = Vec::new();
+    let start = match data.get(4) {
+        Some(&VERSION_WIDE) => data.get(27).map_or(data.len(), |n| 28 + *n as usize),
+        _ => STATE_PKT_LEN,
+    };
+    let mut rest = data.get(start..).unwrap_or_default();
+
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            bail!("truncated section header");
+        }
+        let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+        let Some(payload) = rest.get(3..3 + len) else {
+            bail!("truncated section");
+        };
+        out.push((rest[0], payload));
+        rest = &rest[3 + len..];
+    }
+
+    Ok(out)
+}
+
+// Packets carried by a batch, or the datagram itself if it isn't one
+pub fn unbatch(data: &[u8]) -> Result<Vec<&[u8]>> {
+    if data.len() < HEADER_LEN
+        || &data[0..4] != MAGIC
+        || data[4] != VERSION
+        || data[6] != PKT_TYPE_BATCH
+    {
+        return Ok(vec![data]);
+    }
+
+    let mut out = Vec::new();
+    let mut rest = &data[HEADER_LEN..];
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            bail!("truncated batch");
+        }
+        let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        let Some(pkt) = rest.get(2..2 + len) else {
+            bail!("truncated batch");
+        };
+        out.push(pkt);
+        rest = &rest[2 + len..];
+    }
+
+    Ok(out)
+}
+
+pub fn encode_snapshot_request(seq: u16) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN);
+    write_header(&mut out, 0, PKT_TYPE_SNAPSHOT_REQUEST, seq);
+    out
+}
+
+// Snapshot text, None if data isn't a snapshot
+pub fn decode_snapshot(data: &[u8]) -> Option<String> {
+    if data.len() < HEADER_LEN
+        || &data[0..4] != MAGIC
+        || data[4] != VERSION
+        || data[6] != PKT_TYPE_SNAPSHOT
+    {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&data[HEADER_LEN..]).into_owned())
+}
+
+// Name of the sender's active profile, empty for none. None if data isn't a
+// profile packet
+pub fn decode_profile(data: &[u8]) -> Option<String> {
+    if packet_type(data) != Some(PKT_TYPE_PROFILE) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&data[HEADER_LEN..]).into_owned())
+}
+
+// Pong for a ping packet, None if data isn't a ping
+pub fn encode_pong(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < HEADER_LEN
+        || &data[0..4] != MAGIC
+        || data[4] != VERSION
+        || data[6] != PKT_TYPE_PING
+    {
+        return None;
+    }
+    let mut out = data.to_vec();
+    out[6] = PKT_TYPE_PONG;
+    Some(out)
+}
+
+pub fn encode_caps(seq: u16, max_buttons: u16) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + 2);
+    write_header(&mut out, 0, PKT_TYPE_CAPS, seq);
+    out.extend_from_slice(&max_buttons.to_le_bytes());
+    out
+}
+
+pub fn encode_ping(seq: u16, sent_us: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(STATE_PKT_LEN);
+    write_header(&mut out, 0, PKT_TYPE_PING, seq);
+    out.extend_from_slice(&sent_us.to_le_bytes());
+    out.resize(STATE_PKT_LEN, 0);
+    out
+}
+
+// Send time a pong carries back, None if data isn't a pong
+pub fn decode_pong(data: &[u8]) -> Option<u64> {
+    if packet_type(data) != Some(PKT_TYPE_PONG) {
+        return None;
+    }
+    let sent = data.get(HEADER_LEN..HEADER_LEN + 8)?;
+    Some(u64::from_le_bytes(sent.try_into().unwrap()))
+}
+
+fn write_header(out: &mut Vec<u8>, device_id: u8, pkt_type: u8, seq: u16) {
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.push(device_id);
+    out.push(pkt_type);
+    out.extend_from_slice(&seq.to_le_bytes());
+}
+
+pub fn encode_status_message(seq: u16, device_id: u8, severity: Severity, text: &str) -> Vec<u8> {
+    let mut text = text.as_bytes();
+    if text.len() > MAX_MESSAGE_LEN {
+        text = &text[..MAX_MESSAGE_LEN];
+    }
+
+    let mut out = Vec::with_capacity(HEADER_LEN + 2 + text.len());
+    write_header(&mut out, device_id, PKT_TYPE_STATUS, seq);
+    out.push(STATUS_KIND_MESSAGE);
+    out.push(severity as u8);
+    out.extend_from_slice(text);
+    out
+}
+
+pub fn encode_status_stats(seq: u16, stats: &LinkStats) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + 1 + 28);
+    write_header(&mut out, 0, PKT_TYPE_STATUS, seq);
+    out.push(STATUS_KIND_STATS);
+    for v in [
+        stats.received,
+        stats.applied,
+        stats.bad,
+        stats.dup,
+        stats.ooo,
+        stats.lost_est,
+        stats.rejected,
+    ] {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out
+}
+
+pub fn encode_ffb(seq: u16, device_id: u8, cmd: &FfbCommand) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + 40);
+    write_header(&mut out, device_id, PKT_TYPE_FFB, seq);
+
+    match *cmd {
+        FfbCommand::SetEffect { index, effect } => {
+            let kind = match effect.params {
+                FfbParams::Constant { .. } => 0,
+                FfbParams::Ramp { .. } => 1,
+                FfbParams::Periodic { waveform, .. } => waveform as u8,
+                FfbParams::Spring(_) => 7,
+                FfbParams::Damper(_) => 8,
+                FfbParams::Inertia(_) => 9,
+                FfbParams::Friction(_) => 10,
+            };
+            out.extend_from_slice(&[FFB_OP_SET_EFFECT, index, kind]);
+            out.extend_from_slice(&effect.duration_ms.to_le_bytes());
+            out.extend_from_slice(&effect.direction.to_le_bytes());
+
+            match effect.params {
+                FfbParams::Constant { level } => out.extend_from_slice(&level.to_le_bytes()),
+                FfbParams::Ramp { start, end } => {
+                    out.extend_from_slice(&start.to_le_bytes());
+                    out.extend_from_slice(&end.to_le_bytes());
+                }
+                FfbParams::Periodic {
+                    magnitude,
+                    offset,
+                    period_ms,
+                    phase,
+                    ..
+                } => {
+                    out.extend_from_slice(&magnitude.to_le_bytes());
+                    out.extend_from_slice(&offset.to_le_bytes());
+                    out.extend_from_slice(&period_ms.to_le_bytes());
+                    out.extend_from_slice(&phase.to_le_bytes());
+                }
+                FfbParams::Spring(c)
+                | FfbParams::Damper(c)
+                | FfbParams::Inertia(c)
+                | FfbParams::Friction(c) => {
+                    for axis in c {
+                        out.extend_from_slice(&axis.center.to_le_bytes());
+                        out.extend_from_slice(&axis.pos_coeff.to_le_bytes());
+                        out.extend_from_slice(&axis.neg_coeff.to_le_bytes());
+                        out.extend_from_slice(&axis.pos_saturation.to_le_bytes());
+                        out.extend_from_slice(&axis.neg_saturation.to_le_bytes());
+                        out.extend_from_slice(&axis.deadband.to_le_bytes());
+                    }
+                }
+            }
+        }
+        FfbCommand::Start { index, loop_count } => {
+            out.extend_from_slice(&[FFB_OP_START, index, loop_count])
+        }
+        FfbCommand::Solo { index, loop_count } => {
+            out.extend_from_slice(&[FFB_OP_SOLO, index, loop_count])
+        }
+        FfbCommand::Stop { index } => out.extend_from_slice(&[FFB_OP_STOP, index]),
+        FfbCommand::Free { index } => out.extend_from_slice(&[FFB_OP_FREE, index]),
+        FfbCommand::Gain(gain) => out.extend_from_slice(&[FFB_OP_GAIN, gain]),
+        FfbCommand::StopAll => out.push(FFB_OP_STOP_ALL),
+        FfbCommand::Reset => out.push(FFB_OP_RESET),
+    }
+
+    out
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result, bail};
+
+use crate::protocol::{MAX_BUTTONS, Packet, RawPacket};
+
+const VJOY_AXIS_MAX: u16 = 0x8000; // 32768
+
+// evdev codes the sender's fixed layout uses, so unmapped raw devices look the same
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+// ABS_X, ABS_Y, ABS_Z, ABS_RX, ABS_RY, ABS_RZ, ABS_THROTTLE, ABS_RUDDER
+const DEFAULT_AXIS_CODES: [u16; 8] = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
+
+// Turns a raw device's evdev codes into the vJoy layout, per vJoy device
+pub struct RawMap {
+    // evdev axis code -> slot
+    axes: HashMap<u16, usize>,
+    // evdev key code -> 1-based button, None = keys in code order like the sender
+    buttons: Option<HashMap<u16, u16>>,
+}
+
+impl RawMap {
+    pub fn new(
+        raw_axes: &BTreeMap<String, u8>,
+        raw_buttons: &BTreeMap<String, u16>,
+    ) -> Result<Self> {
+        let axes = if raw_axes.is_empty() {
+            default_axes()
+        } else {
+            let mut axes = HashMap::new();
+            for (code, axis) in raw_axes.iter() {
+                if !(1..=8).contains(axis) {
+                    bail!("raw axis {} mapped to {}, not in 1..=8", code, axis);
+                }
+                axes.insert(parse_code(code)?, *axis as usize - 1);
+            }
+            axes
+        };
+
+        let buttons = if raw_buttons.is_empty() {
+            None
+        } else {
+            let mut buttons = HashMap::new();
+            for (code, button) in raw_buttons.iter() {
+                if !(1..=MAX_BUTTONS).contains(button) {
+                    bail!(
+                        "raw button {} mapped to {}, not in 1..={}",
+                        code,
+                        button,
+                        MAX_BUTTONS
+                    );
+                }
+                buttons.insert(parse_code(code)?, *button);
+            }
+            Some(buttons)
+        };
+
+        Ok(Self { axes, buttons })
+    }
+
+    pub fn to_packet(&self, raw: &RawPacket) -> Packet {
+        let mut pkt = Packet {
+            device_id: raw.device_id,
+            seq: raw.seq,
+            axes: [VJOY_AXIS_MAX / 2; 8],
+            hat_x: 0,
+            hat_y: 0,
+            buttons: [0; 32],
+        };
+
+        for &(code, value, min, max) in raw.axes.iter() {
+            match code {
+                ABS_HAT0X => pkt.hat_x = value.signum() as i8,
+                ABS_HAT0Y => pkt.hat_y = value.signum() as i8,
+                _ => {
+                    if let Some(&slot) = self.axes.get(&code) {
+                        pkt.axes[slot] = normalize_axis(value, min, max);
+                    }
+                }
+            }
+        }
+
+        // Keys arrive in code order
+        for (idx, &(code, pressed)) in raw.keys.iter().enumerate() {
+            let button = match &self.buttons {
+                Some(buttons) => buttons.get(&code).copied(),
+                None => u16::try_from(idx + 1).ok().filter(|b| *b <= MAX_BUTTONS),
+            };
+            if let Some(b) = button
+                && pressed
+            {
+                let bit = b as usize - 1;
+                pkt.buttons[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        pkt
+    }
+}
+
+impl Default for RawMap {
+    fn default() -> Self {
+        Self {
+            axes: default_axes(),
+            buttons: None,
+        }
+    }
+}
+
+fn default_axes() -> HashMap<u16, usize> {
+    DEFAULT_AXIS_CODES
+        .iter()
+        .enumerate()
+        .map(|(slot, code)| (*code, slot))
+        .collect()
+}
+
+// Decimal or 0x hex, e.g. "288" or "0x120" for BTN_TRIGGER
+fn parse_code(s: &str) -> Result<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .with_context(|| format!("Bad evdev code {}", s))
+}
+
+fn normalize_axis(value: i32, min: i32, max: i32) -> u16 {
+    if max <= min {
+        return VJOY_AXIS_MAX / 2;
+    }
+    let out = (value as i64 - min as i64) * VJOY_AXIS_MAX as i64 / (max as i64 - min as i64);
+    out.clamp(0, VJOY_AXIS_MAX as i64) as u16
+}
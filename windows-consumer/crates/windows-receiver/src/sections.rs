@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+// Handles one custom section type of incoming state packets, e.g. telemetry
+// piggybacking on the stream. Called after the packet's state was applied.
+pub trait SectionHandler {
+    fn handle(&mut self, device_id: u8, payload: &[u8]);
+}
+
+impl<F: FnMut(u8, &[u8])> SectionHandler for F {
+    fn handle(&mut self, device_id: u8, payload: &[u8]) {
+        self(device_id, payload)
+    }
+}
+
+// Section type -> handler. Sections nobody registered for are skipped.
+#[derive(Default)]
+pub struct SectionHandlers {
+    handlers: HashMap<u8, Box<dyn SectionHandler>>,
+}
+
+impl SectionHandlers {
+    // Extension point for embedders, nothing built in registers yet
+    #[allow(dead_code)]
+    pub fn register(&mut self, section_type: u8, handler: impl SectionHandler + 'static) {
+        self.handlers.insert(section_type, Box::new(handler));
+    }
+
+    pub fn dispatch(&mut self, device_id: u8, sections: &[(u8, &[u8])]) {
+        for (section_type, payload) in sections {
+            if let Some(handler) = self.handlers.get_mut(section_type) {
+                handler.handle(device_id, payload);
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+// Critically damped spring towards the latest received value (the SmoothDamp
+// approximation from Game Programming Gems 4). `smooth_time` is roughly the time it
+// takes to reach the target: higher is smoother, lower has less latency.
+#[derive(Clone, Copy, Debug)]
+pub struct AxisFilter {
+    smooth_time: f32,
+    value: Option<f32>,
+    velocity: f32,
+}
+
+impl AxisFilter {
+    pub fn new(smooth_time: Duration) -> Self {
+        Self {
+            smooth_time: smooth_time.as_secs_f32().max(0.001),
+            value: None,
+            velocity: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, target: i32, dt: Duration) -> i32 {
+        let target = target as f32;
+        let Some(current) = self.value else {
+            // First sample: start where the stick is instead of sweeping from 0
+            self.value = Some(target);
+            return target as i32;
+        };
+
+        let dt = dt.as_secs_f32();
+        let omega = 2.0 / self.smooth_time;
+        let x = omega * dt;
+        let exp = 1.0 / (1.0 + x + 0.48 * x * x + 0.235 * x * x * x);
+
+        let change = current - target;
+        let temp = (self.velocity + omega * change) * dt;
+        self.velocity = (self.velocity - omega * temp) * exp;
+        let out = target + (change + temp) * exp;
+
+        self.value = Some(out);
+        out.round() as i32
+    }
+}
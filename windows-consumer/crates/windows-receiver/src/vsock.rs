@@ -0,0 +1,127 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, UdpSocket},
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::LISTEN_PORT;
+
+// viosock (virtio-win) registers AF_VSOCK with the same number as Linux
+const AF_VSOCK: i32 = 40;
+const VMADDR_CID_ANY: u32 = u32::MAX;
+
+// Accepts senders on the VM host over vsock and relays them into the UDP socket
+// on loopback, so the stream doesn't depend on the guest's network. Each datagram
+// is framed as u16 LE length + datagram on the stream, both ways.
+pub fn start(port: u32) -> Result<()> {
+    let listener = Socket::new(Domain::from(AF_VSOCK), Type::STREAM, None)
+        .with_context(|| "Failed to create vsock socket, is the viosock driver installed?")?;
+    listener
+        .bind(&vsock_addr(VMADDR_CID_ANY, port)?)
+        .with_context(|| format!("Failed to bind vsock port {}", port))?;
+    listener.listen(4)?;
+    println!("Listening on vsock port {}", port);
+
+    thread::spawn(move || {
+        loop {
+            let conn = match listener.accept() {
+                Ok((conn, _)) => conn,
+                Err(e) => {
+                    println!("vsock accept failed: {}", e);
+                    return;
+                }
+            };
+            thread::spawn(move || {
+                if let Err(e) = relay(conn) {
+                    println!("vsock connection closed: {:#}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn relay(conn: Socket) -> Result<()> {
+    let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    udp.connect((Ipv4Addr::LOCALHOST, LISTEN_PORT))?;
+    println!(
+        "vsock sender connected, relaying from {}",
+        udp.local_addr()?
+    );
+
+    let closed = Arc::new(AtomicBool::new(false));
+
+    // Status and force feedback back into the stream
+    {
+        let conn = conn.try_clone()?;
+        let udp = udp.try_clone()?;
+        let closed = Arc::clone(&closed);
+        udp.set_read_timeout(Some(Duration::from_secs(1)))?;
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            while !closed.load(Ordering::Relaxed) {
+                let Ok(len) = udp.recv(&mut buf) else {
+                    continue;
+                };
+                if write_frame(&conn, &buf[..len]).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    let mut buf = [0u8; u16::MAX as usize];
+    let result = loop {
+        match read_frame(&conn, &mut buf) {
+            // Best effort, like the network: the receiver may be restarting
+            Ok(len) => {
+                let _ = udp.send(&buf[..len]);
+            }
+            Err(e) => break Err(e.into()),
+        }
+    };
+    closed.store(true, Ordering::Relaxed);
+    result
+}
+
+// struct sockaddr_vm { u16 family, u16 reserved, u32 port, u32 cid, u8 zero[4] }
+fn vsock_addr(cid: u32, port: u32) -> io::Result<SockAddr> {
+    let mut raw = [0u8; 16];
+    raw[0..2].copy_from_slice(&(AF_VSOCK as u16).to_ne_bytes());
+    raw[4..8].copy_from_slice(&port.to_ne_bytes());
+    raw[8..12].copy_from_slice(&cid.to_ne_bytes());
+
+    let (_, addr) = unsafe {
+        SockAddr::try_init(|storage, len| {
+            ptr::copy_nonoverlapping(raw.as_ptr(), storage.cast::<u8>(), raw.len());
+            *len = raw.len() as _;
+            Ok(())
+        })
+    }?;
+    Ok(addr)
+}
+
+fn write_frame(mut sock: &Socket, data: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + data.len());
+    frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    frame.extend_from_slice(data);
+    sock.write_all(&frame)
+}
+
+fn read_frame(mut sock: &Socket, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = [0u8; 2];
+    sock.read_exact(&mut len)?;
+    let len = u16::from_le_bytes(len) as usize;
+    sock.read_exact(&mut buf[..len])?;
+    Ok(len)
+}